@@ -1,30 +1,58 @@
 use std::{
-    sync::{Arc, Mutex},
-    time::Duration,
+    any::type_name,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
 };
 
 use assign::assign;
 use async_stream::try_stream;
+use bytes::BufMut;
 use futures_core::stream::Stream;
+use http::{
+    header::{HeaderValue, AUTHORIZATION},
+    StatusCode,
+};
 use ruma_client_api::{
     account::register::{self, RegistrationKind},
-    session::login::{self, v3::LoginInfo},
+    discovery::get_supported_versions,
+    session::{
+        login::{self, v3::LoginInfo},
+        refresh_token,
+    },
     sync::sync_events,
     uiaa::UserIdentifier,
 };
 use ruma_common::{
-    api::{MatrixVersion, OutgoingRequest, SendAccessToken},
+    api::{AuthScheme, IncomingResponse, MatrixVersion, OutgoingRequest, SendAccessToken},
     presence::PresenceState,
     DeviceId, UserId,
 };
+use serde::Deserialize;
+use tracing::{info_span, warn, Instrument};
 
 use crate::{
     add_user_id_to_query, send_customized_request, Error, HttpClient, ResponseError, ResponseResult,
 };
 
 mod builder;
+mod discovery;
+#[cfg(feature = "unstable-msc2965")]
+pub mod oidc;
+mod retry;
+mod session;
+mod supported_versions;
+
+pub use self::{
+    builder::ClientBuilder,
+    discovery::{DiscoveryError, DiscoveryFailure},
+    retry::RetryPolicy,
+    session::Session,
+    supported_versions::SupportedVersions,
+};
+use self::{discovery::ServerHints, retry::is_idempotent, session::SessionCallback};
 
-pub use self::builder::ClientBuilder;
+/// How long before the expiration of the access token it is refreshed.
+const REFRESH_MARGIN: Duration = Duration::from_secs(30);
 
 /// A client for the Matrix client-server API.
 #[derive(Clone, Debug)]
@@ -39,11 +67,26 @@ struct ClientData<C> {
     /// The underlying HTTP client.
     http_client: C,
 
-    /// The access token, if logged in.
-    access_token: Mutex<Option<String>>,
+    /// The URLs of other servers advertised by the homeserver.
+    hints: ServerHints,
+
+    /// The session, if logged in.
+    session: Mutex<Option<Session>>,
+
+    /// The lock to refresh the access token only once at a time.
+    refresh_lock: futures_util::lock::Mutex<()>,
 
-    /// The (known) Matrix versions the homeserver supports.
-    supported_matrix_versions: Vec<MatrixVersion>,
+    /// The function to call when the session changes, if any.
+    session_callback: Option<SessionCallback>,
+
+    /// Whether to ask for a refresh token when logging in or registering.
+    use_refresh_tokens: bool,
+
+    /// The (known) Matrix versions and the unstable features the homeserver supports.
+    supported_versions: RwLock<SupportedVersions>,
+
+    /// The policy to retry failed requests, if any.
+    retry_policy: Option<RetryPolicy>,
 }
 
 impl Client<()> {
@@ -58,7 +101,62 @@ impl<C> Client<C> {
     ///
     /// Useful for serializing and persisting the session to be restored later.
     pub fn access_token(&self) -> Option<String> {
-        self.0.access_token.lock().expect("session mutex was poisoned").clone()
+        self.session().map(|session| session.access_token)
+    }
+
+    /// Get a copy of the current session, if any.
+    ///
+    /// Useful for serializing and persisting the session to be restored later. The session changes
+    /// when the access token is refreshed, use [`ClientBuilder::session_callback()`] to be
+    /// notified of the changes.
+    pub fn session(&self) -> Option<Session> {
+        self.0.session.lock().expect("session mutex was poisoned").clone()
+    }
+
+    /// The URL of the homeserver.
+    pub fn homeserver_url(&self) -> &str {
+        &self.0.homeserver_url
+    }
+
+    /// The URL of the identity server advertised by the homeserver, if any.
+    ///
+    /// This is only set when the homeserver was discovered with
+    /// [`ClientBuilder::server_name_or_user_id()`].
+    pub fn identity_server_url(&self) -> Option<&str> {
+        self.0.hints.identity_server_url.as_deref()
+    }
+
+    /// The URL of the sliding sync proxy advertised by the homeserver, if any.
+    ///
+    /// This is only set when the homeserver was discovered with
+    /// [`ClientBuilder::server_name_or_user_id()`].
+    #[cfg(feature = "unstable-msc3575")]
+    pub fn sliding_sync_proxy(&self) -> Option<&str> {
+        self.0.hints.sliding_sync_proxy.as_deref()
+    }
+
+    /// Get a copy of the Matrix versions and unstable features supported by the homeserver.
+    pub fn supported_versions(&self) -> SupportedVersions {
+        self.0.supported_versions.read().expect("supported versions lock was poisoned").clone()
+    }
+
+    /// The Matrix versions supported by the homeserver.
+    fn matrix_versions(&self) -> Vec<MatrixVersion> {
+        self.0
+            .supported_versions
+            .read()
+            .expect("supported versions lock was poisoned")
+            .versions
+            .clone()
+    }
+
+    /// Replace the session and notify the session callback.
+    fn set_session(&self, session: Option<Session>) {
+        *self.0.session.lock().expect("session mutex was poisoned") = session.clone();
+
+        if let (Some(callback), Some(session)) = (&self.0.session_callback, &session) {
+            (callback.0)(session);
+        }
     }
 }
 
@@ -69,30 +167,116 @@ impl<C: HttpClient> Client<C> {
     }
 
     /// Makes a request to a Matrix API endpoint including additional URL parameters.
+    ///
+    /// If the access token expires soon, it is refreshed before sending the request. If the
+    /// homeserver responds that the access token has expired, it is refreshed and the request is
+    /// sent again. If a [`RetryPolicy`] was set, the request is sent again when it fails according
+    /// to that policy. `customize` is called each time the request is sent.
     pub async fn send_customized_request<R, F>(
         &self,
         request: R,
-        customize: F,
+        mut customize: F,
     ) -> ResponseResult<C, R>
     where
         R: OutgoingRequest,
-        F: FnOnce(&mut http::Request<C::RequestBody>) -> Result<(), ResponseError<C, R>>,
+        F: FnMut(&mut http::Request<C::RequestBody>) -> Result<(), ResponseError<C, R>>,
     {
-        let access_token = self.access_token();
-        let send_access_token = match access_token.as_deref() {
-            Some(at) => SendAccessToken::IfRequired(at),
+        let requires_access_token = R::METADATA.authentication == AuthScheme::AccessToken;
+        if requires_access_token {
+            self.refresh_expiring_access_token().await;
+        }
+
+        let session = self.session();
+        let can_refresh = requires_access_token
+            && session.as_ref().is_some_and(|session| session.refresh_token.is_some());
+        let retry_policy =
+            self.0.retry_policy.as_ref().filter(|_| is_idempotent(&R::METADATA.method));
+
+        let send_access_token = match &session {
+            Some(session) => SendAccessToken::IfRequired(&session.access_token),
             None => SendAccessToken::None,
         };
 
-        send_customized_request(
-            &self.0.http_client,
-            &self.0.homeserver_url,
-            send_access_token,
-            &self.0.supported_matrix_versions,
-            request,
-            customize,
-        )
-        .await
+        // The stable or unstable path of the endpoint is selected according to these versions.
+        let matrix_versions = self.matrix_versions();
+
+        if !can_refresh && retry_policy.is_none() {
+            return send_customized_request(
+                &self.0.http_client,
+                &self.0.homeserver_url,
+                send_access_token,
+                &matrix_versions,
+                request,
+                customize,
+            )
+            .await;
+        }
+
+        // The request is consumed by the serialization, so keep the bytes to build the request
+        // again for each attempt.
+        let (parts, body) = request
+            .try_into_http_request::<Vec<u8>>(
+                &self.0.homeserver_url,
+                send_access_token,
+                &matrix_versions,
+            )?
+            .into_parts();
+
+        let mut access_token = session.map(|session| session.access_token);
+        let mut refreshed = false;
+        let start = Instant::now();
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+
+            let mut request_body = C::RequestBody::default();
+            request_body.put_slice(&body);
+            let mut http_request = http::Request::new(request_body);
+            *http_request.method_mut() = parts.method.clone();
+            *http_request.uri_mut() = parts.uri.clone();
+            *http_request.version_mut() = parts.version;
+            *http_request.headers_mut() = parts.headers.clone();
+
+            if let (true, Some(access_token)) = (refreshed, &access_token) {
+                let authorization = HeaderValue::try_from(format!("Bearer {access_token}"))
+                    .map_err(|error| Error::Url(error.into()))?;
+                http_request.headers_mut().insert(AUTHORIZATION, authorization);
+            }
+
+            customize(&mut http_request)?;
+            http_request.extensions_mut().insert(R::METADATA);
+
+            let send_span = info_span!(
+                "send_request",
+                request_type = type_name::<R>(),
+                http_client = type_name::<C>(),
+                homeserver_url = self.0.homeserver_url,
+                attempts,
+            );
+            let result =
+                self.0.http_client.send_http_request(http_request).instrument(send_span).await;
+
+            if can_refresh && !refreshed && result.as_ref().is_ok_and(is_soft_logout) {
+                match self.refresh_access_token_inner(access_token.as_deref()).await {
+                    Ok(()) => {
+                        refreshed = true;
+                        access_token = self.access_token();
+                        continue;
+                    }
+                    Err(_) => warn!("Failed to refresh the expired access token"),
+                }
+            }
+
+            if let Some(retry_policy) = retry_policy {
+                if let Some(delay) = retry_policy.retry_delay(attempts, start, &result) {
+                    retry_policy.sleep(delay).await;
+                    continue;
+                }
+            }
+
+            let http_response = result.map_err(Error::Response)?;
+            return Ok(R::IncomingResponse::try_from_http_response(http_response)?);
+        }
     }
 
     /// Makes a request to a Matrix API endpoint as a virtual user.
@@ -107,6 +291,104 @@ impl<C: HttpClient> Client<C> {
         self.send_customized_request(request, add_user_id_to_query::<C, R>(user_id)).await
     }
 
+    /// Query the Matrix versions and unstable features supported by the homeserver again.
+    ///
+    /// The homeserver might advertise more features to authenticated users, so this can be
+    /// useful after logging in.
+    pub async fn refresh_supported_versions(
+        &self,
+    ) -> Result<SupportedVersions, Error<C::Error, ruma_client_api::Error>> {
+        let access_token = self.access_token();
+        let send_access_token = match access_token.as_deref() {
+            Some(access_token) => SendAccessToken::Always(access_token),
+            None => SendAccessToken::None,
+        };
+
+        let supported_versions = SupportedVersions::from(
+            send_customized_request(
+                &self.0.http_client,
+                &self.0.homeserver_url,
+                send_access_token,
+                &[MatrixVersion::V1_0],
+                get_supported_versions::Request::new(),
+                |_| Ok(()),
+            )
+            .await?,
+        );
+
+        *self.0.supported_versions.write().expect("supported versions lock was poisoned") =
+            supported_versions.clone();
+
+        Ok(supported_versions)
+    }
+
+    /// Refresh the access token with the refresh token of the session.
+    ///
+    /// The access token is refreshed automatically when it is about to expire and when the
+    /// homeserver responds that it has expired, so this method should rarely be needed.
+    pub async fn refresh_access_token(
+        &self,
+    ) -> Result<(), Error<C::Error, ruma_client_api::Error>> {
+        self.refresh_access_token_inner(None).await
+    }
+
+    /// Refresh the access token if it expires soon.
+    async fn refresh_expiring_access_token(&self) {
+        let Some(session) = self.session() else {
+            return;
+        };
+        if session.refresh_token.is_none() || !session.expires_within(REFRESH_MARGIN) {
+            return;
+        }
+
+        if self.refresh_access_token_inner(Some(&session.access_token)).await.is_err() {
+            warn!("Failed to refresh the access token before it expires");
+        }
+    }
+
+    /// Refresh the access token.
+    ///
+    /// If `stale_access_token` is set and the current access token is different, it was already
+    /// refreshed concurrently so it is not refreshed again.
+    async fn refresh_access_token_inner(
+        &self,
+        stale_access_token: Option<&str>,
+    ) -> Result<(), Error<C::Error, ruma_client_api::Error>> {
+        let _guard = self.0.refresh_lock.lock().await;
+
+        let session = self.session().ok_or(Error::AuthenticationRequired)?;
+        if stale_access_token.is_some_and(|token| token != session.access_token) {
+            return Ok(());
+        }
+        let refresh_token = session.refresh_token.ok_or(Error::AuthenticationRequired)?;
+
+        #[cfg(feature = "unstable-msc2965")]
+        if let Some(oidc) = &session.oidc {
+            let session = self.refresh_oidc_access_token(oidc, refresh_token).await?;
+            self.set_session(Some(session));
+            return Ok(());
+        }
+
+        let response = send_customized_request(
+            &self.0.http_client,
+            &self.0.homeserver_url,
+            SendAccessToken::None,
+            &self.matrix_versions(),
+            refresh_token::v3::Request::new(refresh_token.clone()),
+            |_| Ok(()),
+        )
+        .await?;
+
+        self.set_session(Some(Session::with_lifetime(
+            response.access_token,
+            // The old refresh token can be reused if there is no new one.
+            response.refresh_token.or(Some(refresh_token)),
+            response.expires_in_ms,
+        )));
+
+        Ok(())
+    }
+
     /// Log in with a username and password.
     ///
     /// In contrast to [`send_request`][Self::send_request], this method stores the access token
@@ -126,10 +408,15 @@ impl<C: HttpClient> Client<C> {
             .send_request(assign!(login::v3::Request::new(login_info), {
                 device_id: device_id.map(ToOwned::to_owned),
                 initial_device_display_name: initial_device_display_name.map(ToOwned::to_owned),
+                refresh_token: self.0.use_refresh_tokens,
             }))
             .await?;
 
-        *self.0.access_token.lock().unwrap() = Some(response.access_token.clone());
+        self.set_session(Some(Session::with_lifetime(
+            response.access_token.clone(),
+            response.refresh_token.clone(),
+            response.expires_in,
+        )));
 
         Ok(response)
     }
@@ -142,10 +429,13 @@ impl<C: HttpClient> Client<C> {
         &self,
     ) -> Result<register::v3::Response, Error<C::Error, ruma_client_api::uiaa::UiaaResponse>> {
         let response = self
-            .send_request(assign!(register::v3::Request::new(), { kind: RegistrationKind::Guest }))
+            .send_request(assign!(register::v3::Request::new(), {
+                kind: RegistrationKind::Guest,
+                refresh_token: self.0.use_refresh_tokens,
+            }))
             .await?;
 
-        *self.0.access_token.lock().unwrap() = response.access_token.clone();
+        self.set_register_session(&response);
 
         Ok(response)
     }
@@ -165,15 +455,28 @@ impl<C: HttpClient> Client<C> {
         let response = self
             .send_request(assign!(register::v3::Request::new(), {
                 username: username.map(ToOwned::to_owned),
-                password: Some(password.to_owned())
+                password: Some(password.to_owned()),
+                refresh_token: self.0.use_refresh_tokens,
             }))
             .await?;
 
-        *self.0.access_token.lock().unwrap() = response.access_token.clone();
+        self.set_register_session(&response);
 
         Ok(response)
     }
 
+    /// Replace the session with the one returned by the registration endpoint.
+    fn set_register_session(&self, response: &register::v3::Response) {
+        let session = response.access_token.clone().map(|access_token| {
+            Session::with_lifetime(
+                access_token,
+                response.refresh_token.clone(),
+                response.expires_in,
+            )
+        });
+        self.set_session(session);
+    }
+
     /// Convenience method that represents repeated calls to the sync_events endpoint as a stream.
     ///
     /// # Example:
@@ -227,3 +530,202 @@ impl<C: HttpClient> Client<C> {
         }
     }
 }
+
+/// Whether the given response is an `M_UNKNOWN_TOKEN` error with `soft_logout` set to `true`,
+/// meaning that the access token has expired and can be refreshed.
+fn is_soft_logout<T: AsRef<[u8]>>(response: &http::Response<T>) -> bool {
+    #[derive(Deserialize)]
+    struct UnknownTokenBody {
+        errcode: String,
+        #[serde(default)]
+        soft_logout: bool,
+    }
+
+    response.status() == StatusCode::UNAUTHORIZED
+        && serde_json::from_slice::<UnknownTokenBody>(response.body().as_ref())
+            .is_ok_and(|body| body.errcode == "M_UNKNOWN_TOKEN" && body.soft_logout)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::{Duration, SystemTime},
+    };
+
+    use futures_util::future::join;
+    use http::{header::AUTHORIZATION, StatusCode};
+    use ruma_client_api::account::whoami;
+    use ruma_common::api::MatrixVersion;
+    use serde_json::json;
+
+    use super::{Client, Session};
+    use crate::test_utils::{json_response, MockHttpClient, MockResult};
+
+    type Sessions = Arc<Mutex<Vec<Session>>>;
+
+    /// A client with the given session, that returns the given responses and records the sessions
+    /// it is notified of.
+    async fn client(
+        session: Session,
+        responses: impl IntoIterator<Item = MockResult>,
+    ) -> (Client<MockHttpClient>, Sessions) {
+        let sessions = Sessions::default();
+        let recorded_sessions = sessions.clone();
+        let client = Client::builder()
+            .homeserver_url("https://matrix.example.org".to_owned())
+            .supported_matrix_versions(vec![MatrixVersion::V1_3])
+            .session(Some(session))
+            .session_callback(move |session| {
+                recorded_sessions.lock().unwrap().push(session.clone());
+            })
+            .http_client(MockHttpClient::new(responses))
+            .await
+            .unwrap();
+        (client, sessions)
+    }
+
+    /// A session with a refresh token, whose access token expires after the given duration.
+    fn session(expires_in: Duration) -> Session {
+        Session {
+            refresh_token: Some("refresh".to_owned()),
+            expires_at: Some(SystemTime::now() + expires_in),
+            ..Session::new("old".to_owned())
+        }
+    }
+
+    fn refreshed() -> MockResult {
+        json_response(
+            StatusCode::OK,
+            json!({
+                "access_token": "new",
+                "refresh_token": "new refresh",
+                "expires_in_ms": 300_000,
+            }),
+        )
+    }
+
+    fn whoami() -> MockResult {
+        json_response(StatusCode::OK, json!({ "user_id": "@alice:example.org" }))
+    }
+
+    fn unknown_token(soft_logout: bool) -> MockResult {
+        json_response(
+            StatusCode::UNAUTHORIZED,
+            json!({
+                "errcode": "M_UNKNOWN_TOKEN",
+                "error": "Access token has expired",
+                "soft_logout": soft_logout,
+            }),
+        )
+    }
+
+    /// The path and the `Authorization` header of the requests received by the client.
+    fn requests(client: &Client<MockHttpClient>) -> Vec<(String, Option<String>)> {
+        let requests = client.0.http_client.requests();
+        requests
+            .iter()
+            .map(|request| {
+                let authorization = request.headers().get(AUTHORIZATION);
+                (
+                    request.uri().path().to_owned(),
+                    authorization.map(|value| value.to_str().unwrap().to_owned()),
+                )
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn refresh_before_expiry() {
+        let (client, sessions) =
+            client(session(Duration::from_secs(10)), [refreshed(), whoami()]).await;
+
+        client.send_request(whoami::v3::Request::new()).await.unwrap();
+        assert_eq!(
+            requests(&client),
+            [
+                ("/_matrix/client/v3/refresh".to_owned(), None),
+                ("/_matrix/client/v3/account/whoami".to_owned(), Some("Bearer new".to_owned())),
+            ]
+        );
+
+        let session = client.session().unwrap();
+        assert_eq!(session.access_token, "new");
+        assert_eq!(session.refresh_token.as_deref(), Some("new refresh"));
+        assert!(!session.expires_within(Duration::from_secs(60)));
+
+        let sessions = sessions.lock().unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].access_token, "new");
+    }
+
+    #[tokio::test]
+    async fn no_refresh_long_before_expiry() {
+        let (client, sessions) = client(session(Duration::from_secs(300)), [whoami()]).await;
+
+        client.send_request(whoami::v3::Request::new()).await.unwrap();
+        assert_eq!(
+            requests(&client),
+            [("/_matrix/client/v3/account/whoami".to_owned(), Some("Bearer old".to_owned()))]
+        );
+        assert!(sessions.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn refresh_on_soft_logout() {
+        let responses = [unknown_token(true), refreshed(), whoami()];
+        let (client, sessions) = client(session(Duration::from_secs(300)), responses).await;
+
+        client.send_request(whoami::v3::Request::new()).await.unwrap();
+        assert_eq!(
+            requests(&client),
+            [
+                ("/_matrix/client/v3/account/whoami".to_owned(), Some("Bearer old".to_owned())),
+                ("/_matrix/client/v3/refresh".to_owned(), None),
+                ("/_matrix/client/v3/account/whoami".to_owned(), Some("Bearer new".to_owned())),
+            ]
+        );
+        assert_eq!(client.access_token().as_deref(), Some("new"));
+        assert_eq!(sessions.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn no_refresh_on_hard_logout() {
+        let (client, sessions) =
+            client(session(Duration::from_secs(300)), [unknown_token(false)]).await;
+
+        client.send_request(whoami::v3::Request::new()).await.unwrap_err();
+        assert_eq!(requests(&client).len(), 1);
+        assert_eq!(client.access_token().as_deref(), Some("old"));
+        assert!(sessions.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn refresh_only_once_for_concurrent_requests() {
+        let responses = [refreshed(), whoami(), whoami()];
+        let (client, sessions) = client(session(Duration::from_secs(10)), responses).await;
+
+        let (first, second) = join(
+            client.send_request(whoami::v3::Request::new()),
+            client.send_request(whoami::v3::Request::new()),
+        )
+        .await;
+        first.unwrap();
+        second.unwrap();
+
+        let requests = requests(&client);
+        let refreshes = requests.iter().filter(|(path, _)| path.ends_with("/refresh")).count();
+        assert_eq!(refreshes, 1);
+        assert_eq!(sessions.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn no_refresh_of_already_refreshed_access_token() {
+        let (client, sessions) = client(session(Duration::from_secs(300)), []).await;
+
+        // The access token was refreshed since "stale" was used.
+        client.refresh_access_token_inner(Some("stale")).await.unwrap();
+        assert!(requests(&client).is_empty());
+        assert!(sessions.lock().unwrap().is_empty());
+    }
+}
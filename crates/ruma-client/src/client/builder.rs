@@ -1,36 +1,100 @@
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
 
 use ruma_client_api::discovery::get_supported_versions;
 use ruma_common::api::{MatrixVersion, SendAccessToken};
 
-use super::{Client, ClientData};
-use crate::{DefaultConstructibleHttpClient, Error, HttpClient, HttpClientExt};
+use super::{
+    discovery::{discover_homeserver, ServerHints},
+    Client, ClientData, RetryPolicy, Session, SessionCallback, SupportedVersions,
+};
+use crate::{
+    middleware::MiddlewareStack, DefaultConstructibleHttpClient, Error, HttpClient, HttpClientExt,
+};
 
 /// A [`Client`] builder.
 ///
 /// This type can be used to construct a `Client` through a few method calls.
-pub struct ClientBuilder {
-    homeserver_url: Option<String>,
-    access_token: Option<String>,
-    supported_matrix_versions: Option<Vec<MatrixVersion>>,
+///
+/// The type parameter `L` is the stack of [middleware] that wraps the HTTP client.
+///
+/// [middleware]: crate::middleware
+pub struct ClientBuilder<L = ()> {
+    homeserver: Option<Homeserver>,
+    session: Option<Session>,
+    session_callback: Option<SessionCallback>,
+    use_refresh_tokens: bool,
+    supported_versions: Option<SupportedVersions>,
+    retry_policy: Option<RetryPolicy>,
+    middleware: L,
 }
 
 impl ClientBuilder {
     pub(super) fn new() -> Self {
-        Self { homeserver_url: None, access_token: None, supported_matrix_versions: None }
+        Self {
+            homeserver: None,
+            session: None,
+            session_callback: None,
+            use_refresh_tokens: false,
+            supported_versions: None,
+            retry_policy: None,
+            middleware: (),
+        }
     }
+}
 
+impl<L> ClientBuilder<L> {
     /// Set the homeserver URL.
     ///
-    /// The homeserver URL must be set before calling [`build()`][Self::build] or
-    /// [`http_client()`][Self::http_client].
+    /// The homeserver URL or the [server name or user ID][Self::server_name_or_user_id] must be
+    /// set before calling [`build()`][Self::build] or [`http_client()`][Self::http_client].
     pub fn homeserver_url(self, url: String) -> Self {
-        Self { homeserver_url: Some(url), ..self }
+        Self { homeserver: Some(Homeserver::Url(url)), ..self }
+    }
+
+    /// Set the server name or user ID to discover the homeserver from.
+    ///
+    /// The [`build()`][Self::build] or [`http_client()`][Self::http_client] method will look up
+    /// the homeserver in the `.well-known/matrix/client` document of the server name, and check
+    /// that it responds to a [`get_supported_versions`] request. The URLs of the identity server
+    /// and of the sliding sync proxy of the document, if any, are available on the [`Client`].
+    ///
+    /// If the discovery fails, the error is an [`Error::Discovery`] and its
+    /// [`failure()`][crate::DiscoveryError::failure] says whether the user should be prompted for
+    /// the homeserver URL.
+    pub fn server_name_or_user_id(self, server_name_or_user_id: String) -> Self {
+        Self { homeserver: Some(Homeserver::ServerNameOrUserId(server_name_or_user_id)), ..self }
     }
 
     /// Set the access token.
+    ///
+    /// This creates a session with an access token that doesn't expire, use
+    /// [`session()`][Self::session] to restore a session with a refresh token.
     pub fn access_token(self, access_token: Option<String>) -> Self {
-        Self { access_token, ..self }
+        Self { session: access_token.map(Session::new), ..self }
+    }
+
+    /// Set the session.
+    pub fn session(self, session: Option<Session>) -> Self {
+        Self { session, ..self }
+    }
+
+    /// Set a function to call when the session changes.
+    ///
+    /// It is called when logging in or registering, and when the access token is refreshed. It
+    /// can be used to persist the session.
+    pub fn session_callback<F>(self, callback: F) -> Self
+    where
+        F: Fn(&Session) + Send + Sync + 'static,
+    {
+        Self { session_callback: Some(SessionCallback(Arc::new(callback))), ..self }
+    }
+
+    /// Set whether to ask the homeserver for a refresh token when logging in or registering.
+    ///
+    /// If the homeserver returns one, the access token expires and the client refreshes it
+    /// automatically. Defaults to `false`.
+    pub fn use_refresh_tokens(self, use_refresh_tokens: bool) -> Self {
+        Self { use_refresh_tokens, ..self }
     }
 
     /// Set the supported Matrix versions.
@@ -39,18 +103,56 @@ impl ClientBuilder {
     /// [`http_client()`][Self::http_client] method will take care of doing a
     /// [`get_supported_versions`] request to find out about the supported versions.
     pub fn supported_matrix_versions(self, versions: Vec<MatrixVersion>) -> Self {
-        Self { supported_matrix_versions: Some(versions), ..self }
+        Self { supported_versions: Some(SupportedVersions::new(versions)), ..self }
+    }
+
+    /// Set the supported Matrix versions and unstable features.
+    ///
+    /// Like [`supported_matrix_versions`][Self::supported_matrix_versions], this method generally
+    /// *shouldn't* be called, except to restore the versions from a previous
+    /// [`get_supported_versions`] request.
+    pub fn supported_versions(self, supported_versions: SupportedVersions) -> Self {
+        Self { supported_versions: Some(supported_versions), ..self }
+    }
+
+    /// Set the policy to retry requests that failed because of rate limiting, a server error or a
+    /// transport error.
+    ///
+    /// By default, requests are not retried.
+    pub fn retry_policy(self, retry_policy: RetryPolicy) -> Self {
+        Self { retry_policy: Some(retry_policy), ..self }
+    }
+
+    /// Add a middleware that wraps the HTTP client.
+    ///
+    /// Requests go through the middleware in the order they were added, then to the HTTP client.
+    /// The retries of the [`RetryPolicy`], if any, go through the middleware too.
+    pub fn middleware<M>(self, middleware: M) -> ClientBuilder<(L, M)> {
+        ClientBuilder {
+            homeserver: self.homeserver,
+            session: self.session,
+            session_callback: self.session_callback,
+            use_refresh_tokens: self.use_refresh_tokens,
+            supported_versions: self.supported_versions,
+            retry_policy: self.retry_policy,
+            middleware: (self.middleware, middleware),
+        }
     }
 
     /// Finish building the [`Client`].
     ///
     /// Uses [`DefaultConstructibleHttpClient::default()`] to create an HTTP client instance.
     /// Unless the supported Matrix versions were manually set via
-    /// [`supported_matrix_versions`][Self::supported_matrix_versions], this will do a
-    /// [`get_supported_versions`] request to find out about the supported versions.
-    pub async fn build<C>(self) -> Result<Client<C>, Error<C::Error, ruma_client_api::Error>>
+    /// [`supported_matrix_versions`][Self::supported_matrix_versions] or the homeserver was
+    /// discovered from a [server name or user ID][Self::server_name_or_user_id], this will do a
+    /// [`get_supported_versions`] request to find out about the supported versions and unstable
+    /// features.
+    pub async fn build<C>(
+        self,
+    ) -> Result<Client<L::Client>, Error<<L::Client as HttpClient>::Error, ruma_client_api::Error>>
     where
         C: DefaultConstructibleHttpClient,
+        L: MiddlewareStack<C>,
     {
         self.http_client(C::default()).await
     }
@@ -58,21 +160,33 @@ impl ClientBuilder {
     /// Set the HTTP client to finish building the [`Client`].
     ///
     /// Unless the supported Matrix versions were manually set via
-    /// [`supported_matrix_versions`][Self::supported_matrix_versions], this will do a
-    /// [`get_supported_versions`] request to find out about the supported versions.
+    /// [`supported_matrix_versions`][Self::supported_matrix_versions] or the homeserver was
+    /// discovered from a [server name or user ID][Self::server_name_or_user_id], this will do a
+    /// [`get_supported_versions`] request to find out about the supported versions and unstable
+    /// features.
     pub async fn http_client<C>(
         self,
         http_client: C,
-    ) -> Result<Client<C>, Error<C::Error, ruma_client_api::Error>>
+    ) -> Result<Client<L::Client>, Error<<L::Client as HttpClient>::Error, ruma_client_api::Error>>
     where
         C: HttpClient,
+        L: MiddlewareStack<C>,
     {
-        let homeserver_url = self
-            .homeserver_url
-            .expect("homeserver URL has to be set prior to calling .build() or .http_client()");
+        let http_client = self.middleware.wrap(http_client);
+        let homeserver = self.homeserver.expect(
+            "homeserver URL or server name has to be set prior to calling .build() or .http_client()",
+        );
+
+        let (homeserver_url, discovered_versions, hints) = match homeserver {
+            Homeserver::Url(url) => (url, None, ServerHints::default()),
+            Homeserver::ServerNameOrUserId(server_name_or_user_id) => {
+                let discovery = discover_homeserver(&http_client, &server_name_or_user_id).await?;
+                (discovery.homeserver_url, Some(discovery.supported_versions), discovery.hints)
+            }
+        };
 
-        let supported_matrix_versions = match self.supported_matrix_versions {
-            Some(versions) => versions,
+        let supported_versions = match self.supported_versions.or(discovered_versions) {
+            Some(supported_versions) => supported_versions,
             None => http_client
                 .send_matrix_request(
                     &homeserver_url,
@@ -81,15 +195,28 @@ impl ClientBuilder {
                     get_supported_versions::Request::new(),
                 )
                 .await?
-                .known_versions()
-                .collect(),
+                .into(),
         };
 
         Ok(Client(Arc::new(ClientData {
             homeserver_url,
             http_client,
-            access_token: Mutex::new(self.access_token),
-            supported_matrix_versions,
+            hints,
+            session: Mutex::new(self.session),
+            refresh_lock: Default::default(),
+            session_callback: self.session_callback,
+            use_refresh_tokens: self.use_refresh_tokens,
+            supported_versions: RwLock::new(supported_versions),
+            retry_policy: self.retry_policy,
         })))
     }
 }
+
+/// How to find the homeserver.
+enum Homeserver {
+    /// The URL of the homeserver.
+    Url(String),
+
+    /// The server name or user ID to discover the homeserver from.
+    ServerNameOrUserId(String),
+}
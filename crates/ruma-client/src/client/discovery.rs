@@ -0,0 +1,468 @@
+use std::fmt::{self, Debug, Display, Formatter};
+
+use http::{Method, StatusCode, Uri};
+use ruma_client_api::discovery::{discover_homeserver, get_supported_versions};
+use ruma_common::{
+    api::{
+        error::{DeserializationError, FromHttpResponseError},
+        MatrixVersion, SendAccessToken,
+    },
+    IdParseError, OwnedServerName, ServerName, UserId,
+};
+
+use super::SupportedVersions;
+use crate::{Error, HttpClient, HttpClientExt};
+
+/// The result of the discovery of a homeserver.
+pub(super) struct Discovery {
+    /// The base URL of the homeserver.
+    pub(super) homeserver_url: String,
+
+    /// The versions supported by the homeserver, from the request that validated its URL.
+    pub(super) supported_versions: SupportedVersions,
+
+    /// The other servers advertised by the homeserver.
+    pub(super) hints: ServerHints,
+}
+
+/// The URLs of other servers advertised in the `.well-known/matrix/client` document.
+#[derive(Debug, Default)]
+pub(super) struct ServerHints {
+    /// The base URL of the identity server, if any.
+    pub(super) identity_server_url: Option<String>,
+
+    /// The URL of the sliding sync proxy, if any.
+    #[cfg(feature = "unstable-msc3575")]
+    pub(super) sliding_sync_proxy: Option<String>,
+}
+
+/// Discover the homeserver of the given server name or user ID, as described in the [spec].
+///
+/// [spec]: https://spec.matrix.org/latest/client-server-api/#well-known-uri
+pub(super) async fn discover_homeserver<C: HttpClient>(
+    http_client: &C,
+    server_name_or_user_id: &str,
+) -> Result<Discovery, Error<C::Error, ruma_client_api::Error>> {
+    let server_name = parse_server_name(server_name_or_user_id)
+        .map_err(|err| Error::Discovery(DiscoveryError::InvalidServerName(err)))?;
+    let server_url = format!("https://{server_name}");
+
+    let well_known = match http_client
+        .send_matrix_request(
+            &server_url,
+            SendAccessToken::None,
+            &[MatrixVersion::V1_0],
+            discover_homeserver::Request::new(),
+        )
+        .await
+    {
+        Ok(response) => Some(response),
+        Err(Error::FromHttpResponse(FromHttpResponseError::Server(err)))
+            if err.status_code == StatusCode::NOT_FOUND =>
+        {
+            None
+        }
+        Err(err) => {
+            return Err(match err {
+                Error::Response(err) => Error::Discovery(DiscoveryError::WellKnownRequest(err)),
+                Error::FromHttpResponse(FromHttpResponseError::Server(err)) => {
+                    Error::Discovery(DiscoveryError::WellKnownStatus(err.status_code))
+                }
+                Error::FromHttpResponse(FromHttpResponseError::Deserialization(err)) => {
+                    Error::Discovery(DiscoveryError::InvalidWellKnown(err))
+                }
+                err => err,
+            })
+        }
+    };
+
+    // Without a well-known document, the homeserver is assumed to be at the server name.
+    let Some(well_known) = well_known else {
+        let supported_versions =
+            validate_homeserver(http_client, &server_url).await.map_err(|err| match err {
+                Error::Discovery(err) => {
+                    Error::Discovery(DiscoveryError::NoWellKnown(Box::new(err)))
+                }
+                err => err,
+            })?;
+        return Ok(Discovery {
+            homeserver_url: server_url,
+            supported_versions,
+            hints: ServerHints::default(),
+        });
+    };
+
+    let homeserver_url = base_url(&well_known.homeserver.base_url).ok_or_else(|| {
+        Error::Discovery(DiscoveryError::InvalidHomeserverUrl(well_known.homeserver.base_url))
+    })?;
+    let supported_versions = validate_homeserver(http_client, &homeserver_url).await?;
+
+    let identity_server_url = match well_known.identity_server {
+        Some(identity_server) => {
+            let url = base_url(&identity_server.base_url).ok_or_else(|| {
+                Error::Discovery(DiscoveryError::InvalidIdentityServerUrl(identity_server.base_url))
+            })?;
+            validate_identity_server(http_client, &url).await?;
+            Some(url)
+        }
+        None => None,
+    };
+
+    Ok(Discovery {
+        homeserver_url,
+        supported_versions,
+        hints: ServerHints {
+            identity_server_url,
+            #[cfg(feature = "unstable-msc3575")]
+            sliding_sync_proxy: well_known.sliding_sync_proxy.map(|proxy| proxy.url),
+        },
+    })
+}
+
+/// Get the server name from the given server name or user ID.
+fn parse_server_name(server_name_or_user_id: &str) -> Result<OwnedServerName, IdParseError> {
+    if server_name_or_user_id.starts_with('@') {
+        Ok(UserId::parse(server_name_or_user_id)?.server_name().to_owned())
+    } else {
+        ServerName::parse(server_name_or_user_id)
+    }
+}
+
+/// Check that the given string is a valid HTTP(S) URL and remove its trailing slashes.
+fn base_url(url: &str) -> Option<String> {
+    let uri = url.parse::<Uri>().ok()?;
+    let valid_scheme = uri.scheme_str().is_some_and(|scheme| scheme == "https" || scheme == "http");
+
+    (valid_scheme && uri.authority().is_some()).then(|| url.trim_end_matches('/').to_owned())
+}
+
+/// Check that there is a homeserver at the given base URL, and get the versions it supports.
+async fn validate_homeserver<C: HttpClient>(
+    http_client: &C,
+    homeserver_url: &str,
+) -> Result<SupportedVersions, Error<C::Error, ruma_client_api::Error>> {
+    let response = http_client
+        .send_matrix_request(
+            homeserver_url,
+            SendAccessToken::None,
+            &[MatrixVersion::V1_0],
+            get_supported_versions::Request::new(),
+        )
+        .await
+        .map_err(|err| match err {
+            Error::Response(err) => Error::Discovery(DiscoveryError::HomeserverRequest(err)),
+            Error::FromHttpResponse(err) => {
+                Error::Discovery(DiscoveryError::InvalidHomeserver(err))
+            }
+            err => err,
+        })?;
+
+    Ok(response.into())
+}
+
+/// Check that there is an identity server at the given base URL.
+async fn validate_identity_server<C: HttpClient>(
+    http_client: &C,
+    identity_server_url: &str,
+) -> Result<(), Error<C::Error, ruma_client_api::Error>> {
+    let request = http::Request::builder()
+        .method(Method::GET)
+        .uri(format!("{identity_server_url}/_matrix/identity/v2"))
+        .body(C::RequestBody::default())
+        .map_err(Error::Url)?;
+
+    let response = http_client
+        .send_http_request(request)
+        .await
+        .map_err(|err| Error::Discovery(DiscoveryError::IdentityServerRequest(err)))?;
+
+    if !response.status().is_success() {
+        return Err(Error::Discovery(DiscoveryError::IdentityServerStatus(response.status())));
+    }
+
+    Ok(())
+}
+
+/// An error when discovering the homeserver of a server name or user ID.
+///
+/// The spec distinguishes two kinds of failures, returned by
+/// [`failure()`][DiscoveryError::failure].
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum DiscoveryError<E> {
+    /// The server name or user ID is invalid.
+    InvalidServerName(IdParseError),
+
+    /// Couldn't obtain the `.well-known/matrix/client` document.
+    WellKnownRequest(E),
+
+    /// The server returned an error other than `404 Not Found` for the
+    /// `.well-known/matrix/client` document.
+    WellKnownStatus(StatusCode),
+
+    /// The `.well-known/matrix/client` document is invalid.
+    InvalidWellKnown(DeserializationError),
+
+    /// The server has no `.well-known/matrix/client` document, and is not a homeserver itself.
+    ///
+    /// Contains the error of the request for the versions supported by the server.
+    NoWellKnown(Box<DiscoveryError<E>>),
+
+    /// The base URL of the homeserver is not a valid URL.
+    InvalidHomeserverUrl(String),
+
+    /// Couldn't obtain the versions supported by the homeserver.
+    HomeserverRequest(E),
+
+    /// The homeserver returned an error or an invalid response for the supported versions.
+    InvalidHomeserver(FromHttpResponseError<ruma_client_api::Error>),
+
+    /// The base URL of the identity server is not a valid URL.
+    InvalidIdentityServerUrl(String),
+
+    /// Couldn't obtain a response from the identity server.
+    IdentityServerRequest(E),
+
+    /// The identity server returned an error.
+    IdentityServerStatus(StatusCode),
+}
+
+impl<E> DiscoveryError<E> {
+    /// The kind of failure, as defined by the spec.
+    pub fn failure(&self) -> DiscoveryFailure {
+        match self {
+            Self::InvalidServerName(_)
+            | Self::WellKnownRequest(_)
+            | Self::WellKnownStatus(_)
+            | Self::InvalidWellKnown(_)
+            | Self::NoWellKnown(_) => DiscoveryFailure::Prompt,
+            Self::InvalidHomeserverUrl(_)
+            | Self::HomeserverRequest(_)
+            | Self::InvalidHomeserver(_)
+            | Self::InvalidIdentityServerUrl(_)
+            | Self::IdentityServerRequest(_)
+            | Self::IdentityServerStatus(_) => DiscoveryFailure::Error,
+        }
+    }
+}
+
+impl<E: Display> Display for DiscoveryError<E> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidServerName(err) => write!(f, "Invalid server name or user ID: {err}"),
+            Self::WellKnownRequest(err) => {
+                write!(f, "Couldn't obtain the well-known document: {err}")
+            }
+            Self::WellKnownStatus(status) => {
+                write!(f, "The well-known document request failed with status {status}")
+            }
+            Self::InvalidWellKnown(err) => write!(f, "Invalid well-known document: {err}"),
+            Self::NoWellKnown(err) => {
+                write!(f, "No well-known document and the server is not a homeserver: {err}")
+            }
+            Self::InvalidHomeserverUrl(url) => write!(f, "Invalid homeserver URL: {url}"),
+            Self::HomeserverRequest(err) => {
+                write!(f, "Couldn't obtain a response from the homeserver: {err}")
+            }
+            Self::InvalidHomeserver(err) => write!(f, "Invalid homeserver: {err}"),
+            Self::InvalidIdentityServerUrl(url) => write!(f, "Invalid identity server URL: {url}"),
+            Self::IdentityServerRequest(err) => {
+                write!(f, "Couldn't obtain a response from the identity server: {err}")
+            }
+            Self::IdentityServerStatus(status) => {
+                write!(f, "The identity server request failed with status {status}")
+            }
+        }
+    }
+}
+
+impl<E: Debug + Display> std::error::Error for DiscoveryError<E> {}
+
+/// The kind of failure of the discovery of a homeserver, as defined by the [spec].
+///
+/// [spec]: https://spec.matrix.org/latest/client-server-api/#well-known-uri
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum DiscoveryFailure {
+    /// `FAIL_PROMPT`: the discovery failed because of invalid or empty data, the user should be
+    /// prompted for the homeserver URL.
+    Prompt,
+
+    /// `FAIL_ERROR`: the discovery didn't return any usable URL, the login should not continue.
+    Error,
+}
+
+#[cfg(test)]
+mod tests {
+    use http::StatusCode;
+    use serde_json::json;
+
+    use super::{discover_homeserver, DiscoveryError, DiscoveryFailure};
+    use crate::{
+        test_utils::{json_response, MockHttpClient, MockResult, TransportError},
+        Error,
+    };
+
+    fn well_known(base_url: &str) -> MockResult {
+        json_response(StatusCode::OK, json!({ "m.homeserver": { "base_url": base_url } }))
+    }
+
+    fn versions() -> MockResult {
+        json_response(StatusCode::OK, json!({ "versions": ["v1.1"] }))
+    }
+
+    fn not_found() -> MockResult {
+        json_response(
+            StatusCode::NOT_FOUND,
+            json!({ "errcode": "M_NOT_FOUND", "error": "Not found" }),
+        )
+    }
+
+    /// The discovery error for the given server name or user ID with the given responses.
+    async fn discovery_error(
+        server_name_or_user_id: &str,
+        responses: impl IntoIterator<Item = MockResult>,
+    ) -> DiscoveryError<TransportError> {
+        let http_client = MockHttpClient::new(responses);
+        match discover_homeserver(&http_client, server_name_or_user_id).await {
+            Err(Error::Discovery(err)) => err,
+            Err(err) => panic!("unexpected error: {err}"),
+            Ok(_) => panic!("discovery should fail"),
+        }
+    }
+
+    #[tokio::test]
+    async fn discover_homeserver_from_well_known() {
+        let http_client =
+            MockHttpClient::new([well_known("https://matrix.example.org/"), versions()]);
+
+        let discovery = discover_homeserver(&http_client, "@alice:example.org").await.unwrap();
+        assert_eq!(discovery.homeserver_url, "https://matrix.example.org");
+        assert_eq!(discovery.hints.identity_server_url, None);
+
+        let requests = http_client.requests();
+        assert_eq!(requests[0].uri().to_string(), "https://example.org/.well-known/matrix/client");
+        assert_eq!(
+            requests[1].uri().to_string(),
+            "https://matrix.example.org/_matrix/client/versions"
+        );
+    }
+
+    #[tokio::test]
+    async fn discover_homeserver_at_server_name_without_well_known() {
+        let http_client = MockHttpClient::new([not_found(), versions()]);
+
+        let discovery = discover_homeserver(&http_client, "example.org").await.unwrap();
+        assert_eq!(discovery.homeserver_url, "https://example.org");
+        assert!(discovery
+            .supported_versions
+            .supports_version(ruma_common::api::MatrixVersion::V1_1));
+    }
+
+    #[tokio::test]
+    async fn invalid_server_name_prompts() {
+        let err = discovery_error("not a server name", []).await;
+        assert!(matches!(err, DiscoveryError::InvalidServerName(_)), "{err:?}");
+        assert_eq!(err.failure(), DiscoveryFailure::Prompt);
+    }
+
+    #[tokio::test]
+    async fn well_known_transport_error_prompts() {
+        let err = discovery_error("example.org", [Err(TransportError)]).await;
+        assert!(matches!(err, DiscoveryError::WellKnownRequest(_)), "{err:?}");
+        assert_eq!(err.failure(), DiscoveryFailure::Prompt);
+    }
+
+    #[tokio::test]
+    async fn well_known_error_status_prompts() {
+        let response = json_response(StatusCode::INTERNAL_SERVER_ERROR, json!({}));
+        let err = discovery_error("example.org", [response]).await;
+        assert!(
+            matches!(err, DiscoveryError::WellKnownStatus(StatusCode::INTERNAL_SERVER_ERROR)),
+            "{err:?}"
+        );
+        assert_eq!(err.failure(), DiscoveryFailure::Prompt);
+    }
+
+    #[tokio::test]
+    async fn invalid_well_known_prompts() {
+        let response = json_response(StatusCode::OK, json!({ "m.homeserver": {} }));
+        let err = discovery_error("example.org", [response]).await;
+        assert!(matches!(err, DiscoveryError::InvalidWellKnown(_)), "{err:?}");
+        assert_eq!(err.failure(), DiscoveryFailure::Prompt);
+    }
+
+    #[tokio::test]
+    async fn no_well_known_and_no_homeserver_prompts() {
+        let err = discovery_error("example.org", [not_found(), not_found()]).await;
+        let DiscoveryError::NoWellKnown(fallback_err) = &err else {
+            panic!("unexpected error: {err:?}");
+        };
+        assert!(matches!(**fallback_err, DiscoveryError::InvalidHomeserver(_)), "{err:?}");
+        assert_eq!(err.failure(), DiscoveryFailure::Prompt);
+
+        let err = discovery_error("example.org", [not_found(), Err(TransportError)]).await;
+        let DiscoveryError::NoWellKnown(fallback_err) = &err else {
+            panic!("unexpected error: {err:?}");
+        };
+        assert!(matches!(**fallback_err, DiscoveryError::HomeserverRequest(_)), "{err:?}");
+        assert_eq!(err.failure(), DiscoveryFailure::Prompt);
+    }
+
+    #[tokio::test]
+    async fn invalid_homeserver_url_fails() {
+        let err = discovery_error("example.org", [well_known("matrix.example.org")]).await;
+        assert!(matches!(err, DiscoveryError::InvalidHomeserverUrl(_)), "{err:?}");
+        assert_eq!(err.failure(), DiscoveryFailure::Error);
+    }
+
+    #[tokio::test]
+    async fn homeserver_transport_error_fails() {
+        let responses = [well_known("https://matrix.example.org"), Err(TransportError)];
+        let err = discovery_error("example.org", responses).await;
+        assert!(matches!(err, DiscoveryError::HomeserverRequest(_)), "{err:?}");
+        assert_eq!(err.failure(), DiscoveryFailure::Error);
+    }
+
+    #[tokio::test]
+    async fn invalid_homeserver_fails() {
+        let err =
+            discovery_error("example.org", [well_known("https://matrix.example.org"), not_found()])
+                .await;
+        assert!(matches!(err, DiscoveryError::InvalidHomeserver(_)), "{err:?}");
+        assert_eq!(err.failure(), DiscoveryFailure::Error);
+    }
+
+    #[tokio::test]
+    async fn identity_server_errors_fail() {
+        let with_identity_server = |base_url: &str| {
+            json_response(
+                StatusCode::OK,
+                json!({
+                    "m.homeserver": { "base_url": "https://matrix.example.org" },
+                    "m.identity_server": { "base_url": base_url },
+                }),
+            )
+        };
+
+        let err =
+            discovery_error("example.org", [with_identity_server("identity"), versions()]).await;
+        assert!(matches!(err, DiscoveryError::InvalidIdentityServerUrl(_)), "{err:?}");
+        assert_eq!(err.failure(), DiscoveryFailure::Error);
+
+        let responses =
+            [with_identity_server("https://identity.example.org"), versions(), Err(TransportError)];
+        let err = discovery_error("example.org", responses).await;
+        assert!(matches!(err, DiscoveryError::IdentityServerRequest(_)), "{err:?}");
+        assert_eq!(err.failure(), DiscoveryFailure::Error);
+
+        let responses =
+            [with_identity_server("https://identity.example.org"), versions(), not_found()];
+        let err = discovery_error("example.org", responses).await;
+        assert!(
+            matches!(err, DiscoveryError::IdentityServerStatus(StatusCode::NOT_FOUND)),
+            "{err:?}"
+        );
+        assert_eq!(err.failure(), DiscoveryFailure::Error);
+    }
+}
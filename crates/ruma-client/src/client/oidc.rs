@@ -0,0 +1,581 @@
+//! Login with an OpenID Connect provider.
+//!
+//! Homeservers that delegate authentication to an OpenID Connect provider advertise its issuer in
+//! the `authentication` field of their `.well-known/matrix/client` document, as described in
+//! [MSC2965]. The client logs in with the authorization code flow with PKCE, requesting the
+//! scopes of [MSC2967].
+//!
+//! The login happens in three steps:
+//!
+//! 1. Get the metadata of the provider with [`Client::oidc_provider_metadata()`].
+//! 2. Create an authorization request with [`OidcAuthorizationData::new()`], and send the user to
+//!    its [`url`][OidcAuthorizationData::url].
+//! 3. When the provider redirects the user to the redirect URI, finish the login with
+//!    [`Client::oidc_log_in()`] and the `code` and `state` query parameters of the redirect.
+//!
+//! The access token is then refreshed with the provider like any other session.
+//!
+//! The client must already be registered with the provider.
+//!
+//! [MSC2965]: https://github.com/matrix-org/matrix-spec-proposals/pull/2965
+//! [MSC2967]: https://github.com/matrix-org/matrix-spec-proposals/pull/2967
+
+use std::{
+    fmt::{self, Display, Formatter},
+    time::Duration,
+};
+
+use bytes::BufMut;
+use http::{
+    header::{ACCEPT, CONTENT_TYPE},
+    Method, StatusCode,
+};
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use ruma_common::{
+    serde::{base64::UrlSafe, Base64},
+    DeviceId, OwnedDeviceId,
+};
+use serde::{de::DeserializeOwned, Deserialize};
+use sha2::{Digest, Sha256};
+
+use super::{Client, Session};
+use crate::{Error, HttpClient};
+
+/// The scope that grants access to the client-server API, from MSC2967.
+const API_SCOPE: &str = "urn:matrix:org.matrix.msc2967.client:api:*";
+
+/// The prefix of the scope that requests a device ID, from MSC2967.
+const DEVICE_SCOPE_PREFIX: &str = "urn:matrix:org.matrix.msc2967.client:device:";
+
+/// The metadata of an OpenID Connect provider, from its discovery document.
+#[derive(Clone, Debug, Deserialize)]
+#[non_exhaustive]
+pub struct OidcProviderMetadata {
+    /// The issuer of the provider.
+    pub issuer: String,
+
+    /// The URL of the authorization endpoint.
+    pub authorization_endpoint: String,
+
+    /// The URL of the token endpoint.
+    pub token_endpoint: String,
+
+    /// The PKCE code challenge methods supported by the provider.
+    #[serde(default)]
+    pub code_challenge_methods_supported: Vec<String>,
+}
+
+/// The data needed to refresh an access token with an OpenID Connect provider.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct OidcSession {
+    /// The ID of the client with the provider.
+    pub client_id: String,
+
+    /// The URL of the token endpoint of the provider.
+    pub token_endpoint: String,
+}
+
+impl OidcSession {
+    /// Creates a new `OidcSession` with the given client ID and token endpoint.
+    pub fn new(client_id: String, token_endpoint: String) -> Self {
+        Self { client_id, token_endpoint }
+    }
+}
+
+/// The data of an authorization request, needed to finish the login.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct OidcAuthorizationData {
+    /// The URL where the user must be sent to authorize the login.
+    pub url: String,
+
+    /// The random state of the request, that must match the state of the redirect.
+    pub state: String,
+
+    /// The random PKCE code verifier of the request.
+    pub code_verifier: String,
+
+    /// The device ID that was requested.
+    pub device_id: OwnedDeviceId,
+
+    /// The ID of the client with the provider.
+    pub client_id: String,
+
+    /// The URI where the provider redirects the user.
+    pub redirect_uri: String,
+
+    /// The URL of the token endpoint of the provider.
+    pub token_endpoint: String,
+}
+
+impl OidcAuthorizationData {
+    /// Creates a new authorization request to the given provider, for the client with the given
+    /// ID and redirect URI.
+    ///
+    /// If `device_id` is `None`, a random device ID is generated.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the provider doesn't support PKCE with the `S256` method.
+    pub fn new(
+        provider: &OidcProviderMetadata,
+        client_id: String,
+        redirect_uri: String,
+        device_id: Option<OwnedDeviceId>,
+    ) -> Result<Self, OidcError> {
+        // The provider must support PKCE, but might not advertise it.
+        let supported_methods = &provider.code_challenge_methods_supported;
+        if !supported_methods.is_empty() && !supported_methods.iter().any(|method| method == "S256")
+        {
+            return Err(OidcError::PkceNotSupported);
+        }
+
+        let device_id = device_id.unwrap_or_else(DeviceId::new);
+        let state = random_string(32);
+        let code_verifier = random_string(64);
+        let code_challenge = Base64::<UrlSafe, _>::new(Sha256::digest(&code_verifier)).encode();
+        let scope = format!("openid {API_SCOPE} {DEVICE_SCOPE_PREFIX}{device_id}");
+
+        let query = serde_html_form::to_string([
+            ("response_type", "code"),
+            ("client_id", &client_id),
+            ("redirect_uri", &redirect_uri),
+            ("scope", &scope),
+            ("state", &state),
+            ("code_challenge", &code_challenge),
+            ("code_challenge_method", "S256"),
+        ])
+        .expect("query parameters should be serializable");
+        let separator = if provider.authorization_endpoint.contains('?') { '&' } else { '?' };
+        let url = format!("{}{separator}{query}", provider.authorization_endpoint);
+
+        Ok(Self {
+            url,
+            state,
+            code_verifier,
+            device_id,
+            client_id,
+            redirect_uri,
+            token_endpoint: provider.token_endpoint.clone(),
+        })
+    }
+}
+
+impl<C: HttpClient> Client<C> {
+    /// Get the metadata of the OpenID Connect provider with the given issuer.
+    ///
+    /// The issuer is advertised by the homeserver in the `authentication` field of the response of
+    /// [`discover_homeserver`](ruma_client_api::discovery::discover_homeserver).
+    ///
+    /// # Errors
+    ///
+    /// Returns an [`OidcError::IssuerMismatch`] if the issuer in the metadata is not the given
+    /// issuer.
+    pub async fn oidc_provider_metadata(
+        &self,
+        issuer: &str,
+    ) -> Result<OidcProviderMetadata, Error<C::Error, ruma_client_api::Error>> {
+        let url = format!("{}/.well-known/openid-configuration", issuer.trim_end_matches('/'));
+        let request = http::Request::builder()
+            .method(Method::GET)
+            .uri(url)
+            .header(ACCEPT, "application/json")
+            .body(Vec::new())
+            .map_err(Error::Url)?;
+
+        let metadata: OidcProviderMetadata = self.send_oidc_request(request).await?;
+
+        // The issuer must be identical to the advertised one, to prevent mix-up attacks.
+        if metadata.issuer != issuer {
+            return Err(Error::Oidc(OidcError::IssuerMismatch {
+                expected: issuer.to_owned(),
+                found: metadata.issuer,
+            }));
+        }
+
+        Ok(metadata)
+    }
+
+    /// Finish logging in with an OpenID Connect provider.
+    ///
+    /// `code` and `state` are the query parameters of the redirect from the provider.
+    ///
+    /// The session is stored in this client, in addition to being returned.
+    pub async fn oidc_log_in(
+        &self,
+        authorization_data: &OidcAuthorizationData,
+        code: &str,
+        state: &str,
+    ) -> Result<Session, Error<C::Error, ruma_client_api::Error>> {
+        if state != authorization_data.state {
+            return Err(Error::Oidc(OidcError::StateMismatch));
+        }
+
+        let response: TokenResponse = self
+            .send_token_request(
+                &authorization_data.token_endpoint,
+                &[
+                    ("grant_type", "authorization_code"),
+                    ("code", code),
+                    ("redirect_uri", &authorization_data.redirect_uri),
+                    ("client_id", &authorization_data.client_id),
+                    ("code_verifier", &authorization_data.code_verifier),
+                ],
+            )
+            .await?;
+
+        let oidc = OidcSession::new(
+            authorization_data.client_id.clone(),
+            authorization_data.token_endpoint.clone(),
+        );
+        let session = response.into_session(oidc, None);
+        self.set_session(Some(session.clone()));
+
+        Ok(session)
+    }
+
+    /// Get a new access token from an OpenID Connect provider with the given refresh token.
+    pub(super) async fn refresh_oidc_access_token(
+        &self,
+        oidc: &OidcSession,
+        refresh_token: String,
+    ) -> Result<Session, Error<C::Error, ruma_client_api::Error>> {
+        let response: TokenResponse = self
+            .send_token_request(
+                &oidc.token_endpoint,
+                &[
+                    ("grant_type", "refresh_token"),
+                    ("refresh_token", &refresh_token),
+                    ("client_id", &oidc.client_id),
+                ],
+            )
+            .await?;
+
+        Ok(response.into_session(oidc.clone(), Some(refresh_token)))
+    }
+
+    /// Send a request with the given form parameters to the given token endpoint.
+    async fn send_token_request<T: DeserializeOwned>(
+        &self,
+        token_endpoint: &str,
+        params: &[(&str, &str)],
+    ) -> Result<T, Error<C::Error, ruma_client_api::Error>> {
+        let body = serde_html_form::to_string(params)
+            .expect("form parameters should be serializable")
+            .into_bytes();
+        let request = http::Request::builder()
+            .method(Method::POST)
+            .uri(token_endpoint)
+            .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .header(ACCEPT, "application/json")
+            .body(body)
+            .map_err(Error::Url)?;
+
+        self.send_oidc_request(request).await
+    }
+
+    /// Send a request to an OpenID Connect provider and deserialize the JSON response.
+    async fn send_oidc_request<T: DeserializeOwned>(
+        &self,
+        request: http::Request<Vec<u8>>,
+    ) -> Result<T, Error<C::Error, ruma_client_api::Error>> {
+        let (parts, body) = request.into_parts();
+        let mut request_body = C::RequestBody::default();
+        request_body.put_slice(&body);
+        let request = http::Request::from_parts(parts, request_body);
+
+        let response =
+            self.0.http_client.send_http_request(request).await.map_err(Error::Response)?;
+        let status = response.status();
+        let body = response.body().as_ref();
+
+        if !status.is_success() {
+            let error = serde_json::from_slice::<ErrorResponse>(body).unwrap_or_default();
+            return Err(Error::Oidc(OidcError::Server {
+                status,
+                error: error.error,
+                error_description: error.error_description,
+            }));
+        }
+
+        serde_json::from_slice(body).map_err(|error| Error::Oidc(OidcError::Json(error)))
+    }
+}
+
+/// The successful response of a token endpoint.
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    refresh_token: Option<String>,
+    expires_in: Option<u64>,
+}
+
+impl TokenResponse {
+    /// Convert this response to a session, keeping the previous refresh token if there is no new
+    /// one.
+    fn into_session(self, oidc: OidcSession, previous_refresh_token: Option<String>) -> Session {
+        let mut session = Session::with_lifetime(
+            self.access_token,
+            self.refresh_token.or(previous_refresh_token),
+            self.expires_in.map(Duration::from_secs),
+        );
+        session.oidc = Some(oidc);
+        session
+    }
+}
+
+/// The error response of an OpenID Connect provider.
+#[derive(Default, Deserialize)]
+struct ErrorResponse {
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+/// Generate a random alphanumeric string of the given length.
+fn random_string(length: usize) -> String {
+    thread_rng().sample_iter(Alphanumeric).map(char::from).take(length).collect()
+}
+
+/// An error when logging in or refreshing the access token with an OpenID Connect provider.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum OidcError {
+    /// The provider doesn't support PKCE with the `S256` method.
+    PkceNotSupported,
+
+    /// The state of the redirect doesn't match the state of the authorization request.
+    StateMismatch,
+
+    /// The issuer in the metadata of the provider is not the requested issuer.
+    IssuerMismatch {
+        /// The requested issuer.
+        expected: String,
+
+        /// The issuer in the metadata.
+        found: String,
+    },
+
+    /// The response of the provider could not be deserialized.
+    Json(serde_json::Error),
+
+    /// The provider returned an error.
+    Server {
+        /// The HTTP status code of the response.
+        status: StatusCode,
+
+        /// The error code, if any.
+        error: Option<String>,
+
+        /// The description of the error, if any.
+        error_description: Option<String>,
+    },
+}
+
+impl Display for OidcError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::PkceNotSupported => {
+                write!(f, "The OpenID Connect provider doesn't support PKCE with the S256 method.")
+            }
+            Self::StateMismatch => {
+                write!(f, "The state of the redirect doesn't match the authorization request.")
+            }
+            Self::IssuerMismatch { expected, found } => {
+                write!(
+                    f,
+                    "The issuer of the OpenID Connect provider is {found}, expected {expected}."
+                )
+            }
+            Self::Json(err) => {
+                write!(f, "Invalid response from the OpenID Connect provider: {err}")
+            }
+            Self::Server { status, error, error_description } => {
+                write!(f, "[{status}] {}", error.as_deref().unwrap_or("unknown error"))?;
+                if let Some(description) = error_description {
+                    write!(f, ": {description}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for OidcError {}
+
+#[cfg(test)]
+mod tests {
+    use http::StatusCode;
+    use ruma_common::serde::{base64::UrlSafe, Base64};
+    use serde_json::json;
+    use sha2::{Digest, Sha256};
+
+    use super::{OidcAuthorizationData, OidcError, OidcProviderMetadata, OidcSession};
+    use crate::{
+        test_utils::{json_response, MockHttpClient, MockResult},
+        Client, Error, Session,
+    };
+
+    const ISSUER: &str = "https://auth.example.org/";
+
+    async fn client(responses: impl IntoIterator<Item = MockResult>) -> Client<MockHttpClient> {
+        Client::builder()
+            .homeserver_url("https://matrix.example.org".to_owned())
+            .supported_matrix_versions(vec![ruma_common::api::MatrixVersion::V1_1])
+            .http_client(MockHttpClient::new(responses))
+            .await
+            .unwrap()
+    }
+
+    fn provider_metadata(issuer: &str) -> serde_json::Value {
+        json!({
+            "issuer": issuer,
+            "authorization_endpoint": "https://auth.example.org/authorize",
+            "token_endpoint": "https://auth.example.org/token",
+            "code_challenge_methods_supported": ["plain", "S256"],
+        })
+    }
+
+    fn authorization_data() -> OidcAuthorizationData {
+        let provider: OidcProviderMetadata =
+            serde_json::from_value(provider_metadata(ISSUER)).unwrap();
+        OidcAuthorizationData::new(
+            &provider,
+            "client".to_owned(),
+            "https://app.example.org/callback".to_owned(),
+            Some("DEVICE".into()),
+        )
+        .unwrap()
+    }
+
+    /// The value of the query parameter with the given name in the given URL.
+    fn query_param(url: &str, name: &str) -> String {
+        let (_, query) = url.split_once('?').unwrap();
+        let params: Vec<(String, String)> = serde_html_form::from_str(query).unwrap();
+        params.into_iter().find(|(key, _)| key == name).unwrap().1
+    }
+
+    #[tokio::test]
+    async fn provider_metadata_with_matching_issuer() {
+        let client = client([json_response(StatusCode::OK, provider_metadata(ISSUER))]).await;
+
+        let metadata = client.oidc_provider_metadata(ISSUER).await.unwrap();
+        assert_eq!(metadata.token_endpoint, "https://auth.example.org/token");
+        assert_eq!(
+            client.0.http_client.requests()[0].uri(),
+            "https://auth.example.org/.well-known/openid-configuration"
+        );
+    }
+
+    #[tokio::test]
+    async fn provider_metadata_with_other_issuer() {
+        let metadata = provider_metadata("https://evil.example.org/");
+        let client = client([json_response(StatusCode::OK, metadata)]).await;
+
+        let err = client.oidc_provider_metadata(ISSUER).await.unwrap_err();
+        assert!(
+            matches!(
+                &err,
+                Error::Oidc(OidcError::IssuerMismatch { expected, found })
+                    if expected == ISSUER && found == "https://evil.example.org/"
+            ),
+            "{err:?}"
+        );
+    }
+
+    #[test]
+    fn authorization_url_has_pkce_challenge() {
+        let data = authorization_data();
+
+        assert!(data.url.starts_with("https://auth.example.org/authorize?"));
+        assert_eq!(query_param(&data.url, "response_type"), "code");
+        assert_eq!(query_param(&data.url, "client_id"), "client");
+        assert_eq!(query_param(&data.url, "state"), data.state);
+        assert_eq!(query_param(&data.url, "code_challenge_method"), "S256");
+        let challenge = Base64::<UrlSafe, _>::new(Sha256::digest(&data.code_verifier)).encode();
+        assert_eq!(query_param(&data.url, "code_challenge"), challenge);
+        assert!(query_param(&data.url, "scope")
+            .ends_with(" urn:matrix:org.matrix.msc2967.client:device:DEVICE"));
+
+        // The state and the verifier are random.
+        let other_data = authorization_data();
+        assert_ne!(data.state, other_data.state);
+        assert_ne!(data.code_verifier, other_data.code_verifier);
+    }
+
+    #[test]
+    fn authorization_requires_pkce_with_s256() {
+        let mut provider: OidcProviderMetadata =
+            serde_json::from_value(provider_metadata(ISSUER)).unwrap();
+        provider.code_challenge_methods_supported = vec!["plain".to_owned()];
+
+        let result =
+            OidcAuthorizationData::new(&provider, "client".to_owned(), "uri".to_owned(), None);
+        assert!(matches!(result, Err(OidcError::PkceNotSupported)), "{result:?}");
+    }
+
+    #[tokio::test]
+    async fn log_in_checks_state() {
+        let data = authorization_data();
+        let client = client([]).await;
+
+        let err = client.oidc_log_in(&data, "code", "other state").await.unwrap_err();
+        assert!(matches!(err, Error::Oidc(OidcError::StateMismatch)), "{err:?}");
+        assert!(client.session().is_none());
+        assert!(client.0.http_client.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn log_in_sends_code_verifier() {
+        let data = authorization_data();
+        let client = client([json_response(
+            StatusCode::OK,
+            json!({ "access_token": "access", "refresh_token": "refresh", "expires_in": 300 }),
+        )])
+        .await;
+
+        let session = client.oidc_log_in(&data, "code", &data.state).await.unwrap();
+        assert_eq!(session.access_token, "access");
+        assert_eq!(session.refresh_token.as_deref(), Some("refresh"));
+        assert!(session.expires_at.is_some());
+        assert_eq!(session.oidc.unwrap().token_endpoint, "https://auth.example.org/token");
+        assert_eq!(client.access_token().as_deref(), Some("access"));
+
+        let requests = client.0.http_client.requests();
+        assert_eq!(requests[0].uri(), "https://auth.example.org/token");
+        let params: Vec<(String, String)> =
+            serde_html_form::from_bytes(requests[0].body()).unwrap();
+        assert!(params.contains(&("code".to_owned(), "code".to_owned())));
+        assert!(params.contains(&("code_verifier".to_owned(), data.code_verifier.clone())));
+    }
+
+    #[tokio::test]
+    async fn refresh_with_provider() {
+        let client = client([json_response(
+            StatusCode::OK,
+            json!({ "access_token": "new", "expires_in": 300 }),
+        )])
+        .await;
+        let oidc =
+            OidcSession::new("client".to_owned(), "https://auth.example.org/token".to_owned());
+        client.set_session(Some(Session {
+            refresh_token: Some("refresh".to_owned()),
+            oidc: Some(oidc),
+            ..Session::new("old".to_owned())
+        }));
+
+        client.refresh_access_token().await.unwrap();
+
+        let session = client.session().unwrap();
+        assert_eq!(session.access_token, "new");
+        // The refresh token is kept when the provider doesn't return a new one.
+        assert_eq!(session.refresh_token.as_deref(), Some("refresh"));
+
+        let requests = client.0.http_client.requests();
+        assert_eq!(requests[0].uri(), "https://auth.example.org/token");
+        let params: Vec<(String, String)> =
+            serde_html_form::from_bytes(requests[0].body()).unwrap();
+        assert!(params.contains(&("grant_type".to_owned(), "refresh_token".to_owned())));
+        assert!(params.contains(&("refresh_token".to_owned(), "refresh".to_owned())));
+    }
+}
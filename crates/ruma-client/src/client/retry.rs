@@ -0,0 +1,376 @@
+use std::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use http::{header::RETRY_AFTER, Method, StatusCode};
+use serde::Deserialize;
+use tracing::warn;
+
+type SleepFn = dyn Fn(Duration) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync;
+
+/// A policy to retry the requests that failed because of rate limiting, a server error or a
+/// transport error.
+///
+/// The delay before the next attempt is the one requested by the homeserver in the
+/// `retry_after_ms` field of an `M_LIMIT_EXCEEDED` error or in the `Retry-After` header, if any.
+/// Otherwise, it grows exponentially with each attempt.
+///
+/// Only requests to endpoints that are safe to repeat are retried, i.e. endpoints with an
+/// idempotent HTTP method like `GET`, or `PUT` for endpoints with a transaction ID. Other requests
+/// are sent only once.
+///
+/// This crate doesn't depend on an async runtime, so the policy needs a function to wait between
+/// attempts, like `tokio::time::sleep`.
+#[derive(Clone)]
+pub struct RetryPolicy {
+    sleep: Arc<SleepFn>,
+    max_attempts: u32,
+    max_elapsed_time: Duration,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// Creates a new `RetryPolicy` with the given function to wait between attempts.
+    ///
+    /// By default, a request is attempted at most 5 times during at most 1 minute, and the delay
+    /// between attempts starts at 500 milliseconds and is capped to 30 seconds.
+    ///
+    /// # Example
+    ///
+    /// ```ignore
+    /// let retry_policy = RetryPolicy::new(tokio::time::sleep);
+    /// ```
+    pub fn new<F, Fut>(sleep: F) -> Self
+    where
+        F: Fn(Duration) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        Self {
+            sleep: Arc::new(move |duration| Box::pin(sleep(duration))),
+            max_attempts: 5,
+            max_elapsed_time: Duration::from_secs(60),
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+
+    /// Set the maximum number of attempts for a request, including the first one.
+    pub fn max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    /// Set the maximum time to spend on a request, including all the attempts.
+    ///
+    /// The request is not retried if the delay before the next attempt would exceed this time.
+    pub fn max_elapsed_time(mut self, max_elapsed_time: Duration) -> Self {
+        self.max_elapsed_time = max_elapsed_time;
+        self
+    }
+
+    /// Set the delay before the second attempt, when the homeserver doesn't request one.
+    ///
+    /// The delay is doubled after each attempt.
+    pub fn initial_backoff(mut self, initial_backoff: Duration) -> Self {
+        self.initial_backoff = initial_backoff;
+        self
+    }
+
+    /// Set the maximum delay between attempts, when the homeserver doesn't request one.
+    pub fn max_backoff(mut self, max_backoff: Duration) -> Self {
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    /// The delay before the next attempt, after the given number of attempts.
+    fn backoff(&self, attempts: u32) -> Duration {
+        let factor = 2_u32.saturating_pow(attempts.saturating_sub(1));
+        self.initial_backoff.saturating_mul(factor).min(self.max_backoff)
+    }
+
+    /// The delay before the next attempt of a request, if it should be retried after the given
+    /// number of attempts that started at `start` and the given result of the last attempt.
+    pub(super) fn retry_delay<T: AsRef<[u8]>, E>(
+        &self,
+        attempts: u32,
+        start: Instant,
+        result: &Result<http::Response<T>, E>,
+    ) -> Option<Duration> {
+        let reason = match result {
+            Ok(response) => RetryReason::from_response(response)?,
+            Err(_) => RetryReason::Transport,
+        };
+        let delay = reason.requested_delay().unwrap_or_else(|| self.backoff(attempts));
+
+        if attempts >= self.max_attempts
+            || start.elapsed().saturating_add(delay) > self.max_elapsed_time
+        {
+            return None;
+        }
+
+        warn!("Request failed because of {reason}, retrying in {delay:?}");
+        Some(delay)
+    }
+
+    /// Wait for the given delay.
+    pub(super) async fn sleep(&self, delay: Duration) {
+        (self.sleep)(delay).await;
+    }
+}
+
+impl fmt::Debug for RetryPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RetryPolicy")
+            .field("max_attempts", &self.max_attempts)
+            .field("max_elapsed_time", &self.max_elapsed_time)
+            .field("initial_backoff", &self.initial_backoff)
+            .field("max_backoff", &self.max_backoff)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The reason to retry a request.
+enum RetryReason {
+    /// The homeserver rate-limited the request, with the delay it requested, if any.
+    RateLimited(Option<Duration>),
+
+    /// The homeserver returned a server error, with the delay it requested, if any.
+    ServerError(StatusCode, Option<Duration>),
+
+    /// The response could not be obtained.
+    Transport,
+}
+
+impl RetryReason {
+    /// The reason to retry the request that got the given response, if it should be retried.
+    fn from_response<T: AsRef<[u8]>>(response: &http::Response<T>) -> Option<Self> {
+        let status = response.status();
+        let retry_after = response
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse().ok())
+            .map(Duration::from_secs);
+
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            let retry_after_ms =
+                serde_json::from_slice::<LimitExceededBody>(response.body().as_ref())
+                    .ok()
+                    .filter(|body| body.errcode == "M_LIMIT_EXCEEDED")
+                    .and_then(|body| body.retry_after_ms)
+                    .map(Duration::from_millis);
+
+            Some(Self::RateLimited(retry_after_ms.or(retry_after)))
+        } else if status.is_server_error()
+            && status != StatusCode::NOT_IMPLEMENTED
+            && status != StatusCode::HTTP_VERSION_NOT_SUPPORTED
+        {
+            Some(Self::ServerError(status, retry_after))
+        } else {
+            None
+        }
+    }
+
+    /// The delay requested by the homeserver, if any.
+    fn requested_delay(&self) -> Option<Duration> {
+        match self {
+            Self::RateLimited(delay) | Self::ServerError(_, delay) => *delay,
+            Self::Transport => None,
+        }
+    }
+}
+
+impl fmt::Display for RetryReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::RateLimited(_) => f.write_str("rate limiting"),
+            Self::ServerError(status, _) => write!(f, "a server error ({status})"),
+            Self::Transport => f.write_str("a transport error"),
+        }
+    }
+}
+
+/// The body of an `M_LIMIT_EXCEEDED` error.
+#[derive(Deserialize)]
+struct LimitExceededBody {
+    errcode: String,
+    retry_after_ms: Option<u64>,
+}
+
+/// Whether requests with the given method can be repeated safely.
+pub(super) fn is_idempotent(method: &Method) -> bool {
+    [Method::GET, Method::HEAD, Method::PUT, Method::DELETE, Method::OPTIONS].contains(method)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        future::ready,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use http::{header::RETRY_AFTER, HeaderValue, StatusCode};
+    use ruma_client_api::discovery::get_supported_versions;
+    use ruma_common::api::{error::FromHttpResponseError, MatrixVersion};
+    use serde_json::json;
+
+    use super::RetryPolicy;
+    use crate::{
+        test_utils::{json_response, MockHttpClient, MockResult, TransportError},
+        Client, Error,
+    };
+
+    /// A client with the given retry policy, that returns the given responses.
+    async fn client(
+        retry_policy: RetryPolicy,
+        responses: impl IntoIterator<Item = MockResult>,
+    ) -> Client<MockHttpClient> {
+        Client::builder()
+            .homeserver_url("https://matrix.example.org".to_owned())
+            .supported_matrix_versions(vec![MatrixVersion::V1_1])
+            .retry_policy(retry_policy)
+            .http_client(MockHttpClient::new(responses))
+            .await
+            .unwrap()
+    }
+
+    /// A retry policy that records the delays instead of waiting.
+    fn retry_policy() -> (RetryPolicy, Arc<Mutex<Vec<Duration>>>) {
+        let delays = Arc::new(Mutex::new(Vec::new()));
+        let recorded_delays = delays.clone();
+        let retry_policy = RetryPolicy::new(move |delay| {
+            recorded_delays.lock().unwrap().push(delay);
+            ready(())
+        });
+        (retry_policy, delays)
+    }
+
+    fn versions() -> MockResult {
+        json_response(StatusCode::OK, json!({ "versions": ["v1.1"] }))
+    }
+
+    fn with_retry_after(mut response: MockResult, seconds: &'static str) -> MockResult {
+        let headers = response.as_mut().unwrap().headers_mut();
+        headers.insert(RETRY_AFTER, HeaderValue::from_static(seconds));
+        response
+    }
+
+    #[tokio::test]
+    async fn retry_with_requested_delays() {
+        let (retry_policy, delays) = retry_policy();
+        let limit_exceeded = json_response(
+            StatusCode::TOO_MANY_REQUESTS,
+            json!({
+                "errcode": "M_LIMIT_EXCEEDED",
+                "error": "Too many requests",
+                "retry_after_ms": 1500,
+            }),
+        );
+        let responses = [
+            // `retry_after_ms` takes precedence over the header.
+            with_retry_after(limit_exceeded, "10"),
+            with_retry_after(json_response(StatusCode::SERVICE_UNAVAILABLE, json!({})), "2"),
+            with_retry_after(json_response(StatusCode::TOO_MANY_REQUESTS, json!({})), "3"),
+            versions(),
+        ];
+        let client = client(retry_policy, responses).await;
+
+        let response = client.send_request(get_supported_versions::Request::new()).await.unwrap();
+        assert_eq!(response.versions, ["v1.1"]);
+        assert_eq!(
+            *delays.lock().unwrap(),
+            [Duration::from_millis(1500), Duration::from_secs(2), Duration::from_secs(3)]
+        );
+        assert_eq!(client.0.http_client.requests().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn retry_transport_errors_with_backoff_up_to_max_attempts() {
+        let (retry_policy, delays) = retry_policy();
+        let retry_policy = retry_policy.max_attempts(3).initial_backoff(Duration::from_secs(1));
+        let responses = [Err(TransportError), Err(TransportError), Err(TransportError)];
+        let client = client(retry_policy, responses).await;
+
+        let err = client.send_request(get_supported_versions::Request::new()).await.unwrap_err();
+        assert!(matches!(err, Error::Response(TransportError)), "{err:?}");
+        assert_eq!(*delays.lock().unwrap(), [Duration::from_secs(1), Duration::from_secs(2)]);
+        assert_eq!(client.0.http_client.requests().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn backoff_is_capped() {
+        let (retry_policy, delays) = retry_policy();
+        let retry_policy = retry_policy.max_backoff(Duration::from_millis(800));
+        let server_error = || json_response(StatusCode::BAD_GATEWAY, json!({}));
+        let responses = [server_error(), server_error(), server_error(), versions()];
+        let client = client(retry_policy, responses).await;
+
+        client.send_request(get_supported_versions::Request::new()).await.unwrap();
+        assert_eq!(
+            *delays.lock().unwrap(),
+            [Duration::from_millis(500), Duration::from_millis(800), Duration::from_millis(800)]
+        );
+    }
+
+    #[tokio::test]
+    async fn no_retry_beyond_max_elapsed_time() {
+        let (retry_policy, delays) = retry_policy();
+        let retry_policy = retry_policy.max_elapsed_time(Duration::from_secs(10));
+        let responses =
+            [with_retry_after(json_response(StatusCode::SERVICE_UNAVAILABLE, json!({})), "20")];
+        let client = client(retry_policy, responses).await;
+
+        let err = client.send_request(get_supported_versions::Request::new()).await.unwrap_err();
+        assert!(
+            matches!(
+                &err,
+                Error::FromHttpResponse(FromHttpResponseError::Server(err))
+                    if err.status_code == StatusCode::SERVICE_UNAVAILABLE
+            ),
+            "{err:?}"
+        );
+        assert!(delays.lock().unwrap().is_empty());
+        assert_eq!(client.0.http_client.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn no_retry_for_client_errors() {
+        let (retry_policy, delays) = retry_policy();
+        let responses = [json_response(
+            StatusCode::NOT_FOUND,
+            json!({ "errcode": "M_NOT_FOUND", "error": "Not found" }),
+        )];
+        let client = client(retry_policy, responses).await;
+
+        client.send_request(get_supported_versions::Request::new()).await.unwrap_err();
+        assert!(delays.lock().unwrap().is_empty());
+        assert_eq!(client.0.http_client.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn no_retry_for_non_idempotent_requests() {
+        let (retry_policy, delays) = retry_policy();
+        let responses = [json_response(
+            StatusCode::TOO_MANY_REQUESTS,
+            json!({
+                "errcode": "M_LIMIT_EXCEEDED",
+                "error": "Too many requests",
+                "retry_after_ms": 1500,
+            }),
+        )];
+        let client = client(retry_policy, responses).await;
+
+        client.log_in("alice", "secret", None, None).await.unwrap_err();
+        assert!(delays.lock().unwrap().is_empty());
+
+        let requests = client.0.http_client.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].method(), http::Method::POST);
+    }
+}
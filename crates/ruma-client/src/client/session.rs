@@ -0,0 +1,70 @@
+use std::{
+    fmt,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+/// The session of a logged-in user.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct Session {
+    /// The access token used to authenticate requests.
+    pub access_token: String,
+
+    /// The token used to get a new access token when it expires, if any.
+    pub refresh_token: Option<String>,
+
+    /// The time when the access token expires, if it expires.
+    pub expires_at: Option<SystemTime>,
+
+    /// The data of the OpenID Connect provider that issued the tokens, if any.
+    ///
+    /// If this is set, the access token is refreshed with the OpenID Connect provider instead of
+    /// the homeserver.
+    #[cfg(feature = "unstable-msc2965")]
+    pub oidc: Option<super::oidc::OidcSession>,
+}
+
+impl Session {
+    /// Creates a new `Session` with the given access token, that doesn't expire.
+    pub fn new(access_token: String) -> Self {
+        Self {
+            access_token,
+            refresh_token: None,
+            expires_at: None,
+            #[cfg(feature = "unstable-msc2965")]
+            oidc: None,
+        }
+    }
+
+    /// Creates a new `Session` with the given tokens and lifetime of the access token, as returned
+    /// by the homeserver.
+    pub(super) fn with_lifetime(
+        access_token: String,
+        refresh_token: Option<String>,
+        expires_in: Option<Duration>,
+    ) -> Self {
+        Self {
+            refresh_token,
+            expires_at: expires_in.and_then(|duration| SystemTime::now().checked_add(duration)),
+            ..Self::new(access_token)
+        }
+    }
+
+    /// Whether the access token expires within the given duration.
+    pub(super) fn expires_within(&self, duration: Duration) -> bool {
+        self.expires_at.is_some_and(|expires_at| {
+            expires_at.duration_since(SystemTime::now()).map_or(true, |left| left < duration)
+        })
+    }
+}
+
+/// A function called when the session changes.
+#[derive(Clone)]
+pub(super) struct SessionCallback(pub(super) Arc<dyn Fn(&Session) + Send + Sync>);
+
+impl fmt::Debug for SessionCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SessionCallback").finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,141 @@
+use std::collections::BTreeMap;
+
+use ruma_client_api::discovery::get_supported_versions;
+use ruma_common::api::{MatrixVersion, Metadata, VersioningDecision};
+
+/// The versions of the client-server API and the unstable features supported by a homeserver.
+///
+/// This is negotiated with the [`get_supported_versions`] endpoint when building a [`Client`]. The
+/// Matrix versions are used to choose between the stable and unstable paths of the endpoints.
+///
+/// The unstable features are not used by the `Client`, since the metadata of an endpoint doesn't
+/// say which feature it depends on. Use [`supports_unstable_feature()`] to check whether the
+/// homeserver supports an unstable endpoint before sending a request to it.
+///
+/// [`supports_unstable_feature()`]: Self::supports_unstable_feature
+/// [`Client`]: super::Client
+#[derive(Clone, Debug, Default)]
+#[non_exhaustive]
+pub struct SupportedVersions {
+    /// The known Matrix versions supported by the homeserver, from oldest to latest.
+    pub versions: Vec<MatrixVersion>,
+
+    /// The unstable features advertised by the homeserver, and whether they are enabled.
+    pub unstable_features: BTreeMap<String, bool>,
+}
+
+impl SupportedVersions {
+    /// Creates a new `SupportedVersions` with the given Matrix versions and no unstable features.
+    pub fn new(versions: Vec<MatrixVersion>) -> Self {
+        Self { versions, unstable_features: BTreeMap::new() }
+    }
+
+    /// Whether the homeserver supports the given Matrix version, or a later compatible version.
+    pub fn supports_version(&self, version: MatrixVersion) -> bool {
+        self.versions.iter().any(|supported| supported.is_superset_of(version))
+    }
+
+    /// Whether the homeserver advertises the given unstable feature as enabled.
+    ///
+    /// Unstable features are usually named after the MSC that introduces them, like
+    /// `org.matrix.msc3575`.
+    pub fn supports_unstable_feature(&self, feature: &str) -> bool {
+        self.unstable_features.get(feature).copied().unwrap_or(false)
+    }
+
+    /// Whether the homeserver supports authenticated media, as introduced in [MSC3916].
+    ///
+    /// [MSC3916]: https://github.com/matrix-org/matrix-spec-proposals/pull/3916
+    pub fn supports_authenticated_media(&self) -> bool {
+        self.supports_unstable_feature("org.matrix.msc3916")
+            || self.supports_unstable_feature("org.matrix.msc3916.stable")
+    }
+
+    /// How the endpoint with the given metadata should be queried on the homeserver.
+    ///
+    /// Requests sent with a [`Client`] use the stable path if this returns
+    /// [`VersioningDecision::Stable`], and the unstable path if this returns
+    /// [`VersioningDecision::Unstable`].
+    ///
+    /// [`Client`]: super::Client
+    pub fn versioning_decision(&self, metadata: &Metadata) -> VersioningDecision {
+        metadata.history.versioning_decision_for(&self.versions)
+    }
+}
+
+impl From<get_supported_versions::Response> for SupportedVersions {
+    fn from(response: get_supported_versions::Response) -> Self {
+        Self {
+            versions: response.known_versions().collect(),
+            unstable_features: response.unstable_features,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use ruma_client_api::{discovery::get_supported_versions, room::get_event_by_timestamp};
+    use ruma_common::api::{MatrixVersion, OutgoingRequest, VersioningDecision};
+
+    use super::SupportedVersions;
+
+    #[test]
+    fn from_response() {
+        let mut response = get_supported_versions::Response::new(vec![
+            "r0.6.1".to_owned(),
+            "v1.1".to_owned(),
+            "v9.9".to_owned(),
+        ]);
+        response.unstable_features = BTreeMap::from([
+            ("org.matrix.msc3916".to_owned(), true),
+            ("org.matrix.msc3575".to_owned(), false),
+        ]);
+
+        let supported_versions = SupportedVersions::from(response);
+        assert_eq!(supported_versions.versions, [MatrixVersion::V1_0, MatrixVersion::V1_1]);
+        assert_eq!(supported_versions.unstable_features.len(), 2);
+    }
+
+    #[test]
+    fn supports_version() {
+        let supported_versions = SupportedVersions::new(vec![MatrixVersion::V1_2]);
+
+        assert!(supported_versions.supports_version(MatrixVersion::V1_0));
+        assert!(supported_versions.supports_version(MatrixVersion::V1_2));
+        assert!(!supported_versions.supports_version(MatrixVersion::V1_3));
+    }
+
+    #[test]
+    fn supports_unstable_feature() {
+        let mut supported_versions = SupportedVersions::new(vec![MatrixVersion::V1_1]);
+        supported_versions.unstable_features = BTreeMap::from([
+            ("org.matrix.msc3916.stable".to_owned(), true),
+            ("org.matrix.msc3575".to_owned(), false),
+        ]);
+
+        assert!(supported_versions.supports_unstable_feature("org.matrix.msc3916.stable"));
+        assert!(!supported_versions.supports_unstable_feature("org.matrix.msc3575"));
+        assert!(!supported_versions.supports_unstable_feature("org.matrix.msc2965"));
+        assert!(supported_versions.supports_authenticated_media());
+
+        supported_versions.unstable_features.clear();
+        assert!(!supported_versions.supports_authenticated_media());
+    }
+
+    #[test]
+    fn versioning_decision() {
+        let metadata = &<get_event_by_timestamp::v1::Request as OutgoingRequest>::METADATA;
+
+        let supported_versions = SupportedVersions::new(vec![MatrixVersion::V1_5]);
+        assert_eq!(supported_versions.versioning_decision(metadata), VersioningDecision::Unstable);
+
+        let supported_versions =
+            SupportedVersions::new(vec![MatrixVersion::V1_5, MatrixVersion::V1_6]);
+        assert!(matches!(
+            supported_versions.versioning_decision(metadata),
+            VersioningDecision::Stable { .. }
+        ));
+    }
+}
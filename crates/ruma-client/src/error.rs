@@ -22,6 +22,18 @@ pub enum Error<E, F> {
 
     /// Converting the HTTP response to one of ruma's types failed.
     FromHttpResponse(FromHttpResponseError<F>),
+
+    /// Discovering the homeserver of a server name or user ID failed.
+    #[cfg(feature = "client-api")]
+    Discovery(crate::DiscoveryError<E>),
+
+    /// Signing the request with the key of the origin server failed.
+    #[cfg(feature = "federation-api")]
+    Signing(ruma_server_util::authorization::XMatrixError),
+
+    /// Logging in or refreshing the access token with an OpenID Connect provider failed.
+    #[cfg(feature = "unstable-msc2965")]
+    Oidc(crate::oidc::OidcError),
 }
 
 impl<E: Display, F: Display> Display for Error<E, F> {
@@ -34,6 +46,12 @@ impl<E: Display, F: Display> Display for Error<E, F> {
             Self::Url(err) => write!(f, "Invalid URL: {err}"),
             Self::Response(err) => write!(f, "Couldn't obtain a response: {err}"),
             Self::FromHttpResponse(err) => write!(f, "HTTP response conversion failed: {err}"),
+            #[cfg(feature = "client-api")]
+            Self::Discovery(err) => write!(f, "Homeserver discovery failed: {err}"),
+            #[cfg(feature = "federation-api")]
+            Self::Signing(err) => write!(f, "Signing the request failed: {err}"),
+            #[cfg(feature = "unstable-msc2965")]
+            Self::Oidc(err) => write!(f, "OpenID Connect request failed: {err}"),
         }
     }
 }
@@ -0,0 +1,242 @@
+use std::{fmt, sync::Arc};
+
+use http::header::{HeaderValue, HOST};
+use ruma_common::{
+    api::{MatrixVersion, OutgoingRequest, SendAccessToken},
+    OwnedServerName, ServerName,
+};
+use ruma_server_util::{
+    authorization::sign_request,
+    resolution::{ResolverBackend, ServerResolver},
+};
+use ruma_signatures::KeyPair;
+
+use crate::{send_customized_request, Error, HttpClient, ResponseResult};
+
+/// A client for the Matrix server-server API.
+///
+/// It resolves the server name of the destination of each request with a [`ServerResolver`], and
+/// signs the requests with the signing key of the origin server in an `Authorization` header with
+/// the `X-Matrix` scheme.
+///
+/// The requests are sent to the resolved host and port, with a `Host` header containing the
+/// server name. The [`ResolvedDestination`] is inserted in the extensions of the `http::Request`.
+///
+/// # TLS with SRV delegation
+///
+/// When the destination is delegated with SRV records, the resolved host is the target of the SRV
+/// record, but the TLS certificate must be valid for the [`tls_server_name`] of the destination.
+/// The HTTP clients of this crate use the host of the URL for SNI and to validate the certificate,
+/// so requests to such destinations fail with them, unless the certificate is also valid for the
+/// target. To support these destinations, use an [`HttpClient`] that uses the `tls_server_name`
+/// of the `ResolvedDestination` extension of the request for SNI and certificate validation.
+///
+/// [`ResolvedDestination`]: ruma_server_util::resolution::ResolvedDestination
+/// [`tls_server_name`]: ruma_server_util::resolution::ResolvedDestination::tls_server_name
+pub struct FederationClient<C, K, B>(Arc<FederationClientData<C, K, B>>);
+
+struct FederationClientData<C, K, B> {
+    /// The name of the origin server.
+    origin: OwnedServerName,
+
+    /// The signing key of the origin server.
+    key_pair: K,
+
+    /// The resolver of the server names of destinations.
+    resolver: ServerResolver<B>,
+
+    /// The underlying HTTP client.
+    http_client: C,
+
+    /// The Matrix versions to use to select the paths of the endpoints.
+    supported_matrix_versions: Vec<MatrixVersion>,
+}
+
+impl<C, K, B> FederationClient<C, K, B> {
+    /// Creates a new `FederationClient` with the given HTTP client, origin server, signing key and
+    /// resolver.
+    ///
+    /// The requests are sent with the paths of Matrix 1.0, use
+    /// [`with_supported_matrix_versions()`][Self::with_supported_matrix_versions] to use the
+    /// paths of other versions.
+    pub fn new(
+        http_client: C,
+        origin: OwnedServerName,
+        key_pair: K,
+        resolver: ServerResolver<B>,
+    ) -> Self {
+        Self(Arc::new(FederationClientData {
+            origin,
+            key_pair,
+            resolver,
+            http_client,
+            supported_matrix_versions: vec![MatrixVersion::V1_0],
+        }))
+    }
+
+    /// Creates a new `FederationClient` like [`new()`][Self::new], that uses the paths of the
+    /// endpoints for the given Matrix versions.
+    pub fn with_supported_matrix_versions(
+        http_client: C,
+        origin: OwnedServerName,
+        key_pair: K,
+        resolver: ServerResolver<B>,
+        supported_matrix_versions: Vec<MatrixVersion>,
+    ) -> Self {
+        Self(Arc::new(FederationClientData {
+            origin,
+            key_pair,
+            resolver,
+            http_client,
+            supported_matrix_versions,
+        }))
+    }
+
+    /// The name of the origin server.
+    pub fn origin(&self) -> &ServerName {
+        &self.0.origin
+    }
+}
+
+impl<C, K, B> FederationClient<C, K, B>
+where
+    C: HttpClient,
+    C::RequestBody: AsRef<[u8]>,
+    K: KeyPair,
+    B: ResolverBackend,
+{
+    /// Makes a request to a Matrix API endpoint of the given destination server.
+    pub async fn send_request<R: OutgoingRequest>(
+        &self,
+        destination: &ServerName,
+        request: R,
+    ) -> ResponseResult<C, R> {
+        let resolved = self.0.resolver.resolve(destination).await;
+        let host = HeaderValue::from_str(&resolved.host_header)
+            .map_err(|error| Error::Url(error.into()))?;
+
+        let base_url = resolved.base_url();
+
+        send_customized_request(
+            &self.0.http_client,
+            &base_url,
+            SendAccessToken::None,
+            &self.0.supported_matrix_versions,
+            request,
+            |http_request| {
+                http_request.headers_mut().insert(HOST, host);
+                http_request.extensions_mut().insert(resolved);
+                sign_request(http_request, &self.0.origin, destination, &self.0.key_pair)
+                    .map_err(Error::Signing)
+            },
+        )
+        .await
+    }
+}
+
+impl<C, K, B> Clone for FederationClient<C, K, B> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<C, K, B> fmt::Debug for FederationClient<C, K, B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FederationClient")
+            .field("origin", &self.0.origin)
+            .field("resolver", &self.0.resolver)
+            .field("supported_matrix_versions", &self.0.supported_matrix_versions)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, convert::Infallible};
+
+    use async_trait::async_trait;
+    use headers::authorization::Credentials;
+    use http::{
+        header::{AUTHORIZATION, HOST},
+        StatusCode,
+    };
+    use ruma_common::{owned_server_name, serde::Base64, server_name, user_id};
+    use ruma_federation_api::query::get_profile_information;
+    use ruma_server_util::{
+        authorization::{verify_request, XMatrix},
+        resolution::{
+            ResolvedDestination, ResolverBackend, ServerResolver, SrvRecord, WellKnownResponse,
+        },
+    };
+    use ruma_signatures::Ed25519KeyPair;
+    use serde_json::json;
+
+    use super::FederationClient;
+    use crate::test_utils::{json_response, MockHttpClient};
+
+    /// A backend without `.well-known` delegations, and with the given SRV records.
+    #[derive(Default)]
+    struct MockBackend {
+        srv: BTreeMap<String, Vec<SrvRecord>>,
+    }
+
+    #[async_trait]
+    impl ResolverBackend for MockBackend {
+        type Error = Infallible;
+
+        async fn fetch_well_known(&self, _hostname: &str) -> Result<WellKnownResponse, Infallible> {
+            Ok(WellKnownResponse::default())
+        }
+
+        async fn lookup_srv(&self, name: &str) -> Result<Vec<SrvRecord>, Infallible> {
+            Ok(self.srv.get(name).cloned().unwrap_or_default())
+        }
+    }
+
+    #[tokio::test]
+    async fn send_signed_request_to_resolved_destination() {
+        let key_pair =
+            Ed25519KeyPair::from_der(&Ed25519KeyPair::generate().unwrap(), "1".to_owned()).unwrap();
+        let public_key_map = BTreeMap::from([(
+            "origin.example.org".to_owned(),
+            BTreeMap::from([("ed25519:1".to_owned(), Base64::new(key_pair.public_key().to_vec()))]),
+        )]);
+        let backend = MockBackend {
+            srv: BTreeMap::from([(
+                "_matrix-fed._tcp.example.org".to_owned(),
+                vec![SrvRecord::new(0, 0, 8000, "matrix.example.net".to_owned())],
+            )]),
+        };
+        let client = FederationClient::new(
+            MockHttpClient::new([json_response(StatusCode::OK, json!({ "displayname": "Alice" }))]),
+            owned_server_name!("origin.example.org"),
+            key_pair,
+            ServerResolver::new(backend),
+        );
+
+        let request =
+            get_profile_information::v1::Request::new(user_id!("@alice:example.org").to_owned());
+        let response = client.send_request(server_name!("example.org"), request).await.unwrap();
+        assert_eq!(response.displayname.as_deref(), Some("Alice"));
+
+        let requests = client.0.http_client.requests();
+        let request = &requests[0];
+        assert_eq!(
+            request.uri(),
+            "https://matrix.example.net:8000/_matrix/federation/v1/query/profile\
+             ?user_id=%40alice%3Aexample.org"
+        );
+        assert_eq!(request.headers()[HOST], "example.org");
+
+        let resolved = request.extensions().get::<ResolvedDestination>().unwrap();
+        assert_eq!(resolved.tls_server_name.as_deref(), Some("example.org"));
+
+        let x_matrix = XMatrix::decode(&request.headers()[AUTHORIZATION]).unwrap();
+        assert_eq!(x_matrix.origin, "origin.example.org");
+        assert_eq!(x_matrix.destination.as_deref(), Some(server_name!("example.org")));
+        let origin =
+            verify_request(request, &x_matrix, server_name!("example.org"), &public_key_map)
+                .unwrap();
+        assert_eq!(origin, "origin.example.org");
+    }
+}
@@ -77,8 +77,38 @@
 //! # };
 //! ```
 //!
+//! # Federation
+//!
+//! With the `federation-api` feature, the [`FederationClient`] type can be used to send requests
+//! of the server-server API to other homeservers. It resolves the server name of the destination
+//! and signs each request with the signing key of the origin server:
+//!
+//! ```ignore
+//! # // HACK: "ignore" the doctest here because it needs the federation-api feature.
+//! # async {
+//! use ruma_client::FederationClient;
+//! use ruma_common::{owned_server_name, server_name};
+//! use ruma_federation_api::discovery::get_server_version;
+//! use ruma_server_util::resolution::ServerResolver;
+//!
+//! let client = FederationClient::new(
+//!     http_client,
+//!     owned_server_name!("origin.example.com"),
+//!     signing_key,
+//!     ServerResolver::new(resolver_backend),
+//! );
+//! let response = client
+//!     .send_request(server_name!("example.com"), get_server_version::v1::Request::new())
+//!     .await?;
+//! # Result::<(), ruma_client::Error<_, _>>::Ok(())
+//! # };
+//! ```
+//!
 //! # Crate features
 //!
+//! * `client-api` – enables the [`Client`] type for the client-server API
+//! * `federation-api` – enables the [`FederationClient`] type for the server-server API
+//!
 //! The following features activate http client types in the [`http_client`] module:
 //!
 //! * `hyper`
@@ -110,13 +140,26 @@ use tracing::{info_span, Instrument};
 #[cfg(feature = "client-api")]
 mod client;
 mod error;
+#[cfg(feature = "federation-api")]
+mod federation_client;
 pub mod http_client;
+pub mod middleware;
+#[cfg(test)]
+mod test_utils;
 
+#[cfg(feature = "unstable-msc2965")]
+pub use self::client::oidc;
 #[cfg(feature = "client-api")]
-pub use self::client::{Client, ClientBuilder};
+pub use self::client::{
+    Client, ClientBuilder, DiscoveryError, DiscoveryFailure, RetryPolicy, Session,
+    SupportedVersions,
+};
+#[cfg(feature = "federation-api")]
+pub use self::federation_client::FederationClient;
 pub use self::{
     error::Error,
     http_client::{DefaultConstructibleHttpClient, HttpClient, HttpClientExt},
+    middleware::Middleware,
 };
 
 /// The error type for sending the request `R` with the http client `C`.
@@ -147,6 +190,7 @@ where
                 .map_err(ResponseError::<C, R>::from)
                 .and_then(|mut req| {
                     customize(&mut req)?;
+                    req.extensions_mut().insert(R::METADATA);
                     Ok(req)
                 })
         });
@@ -177,7 +221,7 @@ where
 
 fn add_user_id_to_query<C: HttpClient + ?Sized, R: OutgoingRequest>(
     user_id: &UserId,
-) -> impl FnOnce(&mut http::Request<C::RequestBody>) -> Result<(), ResponseError<C, R>> + '_ {
+) -> impl FnMut(&mut http::Request<C::RequestBody>) -> Result<(), ResponseError<C, R>> + '_ {
     use assign::assign;
     use http::uri::Uri;
 
@@ -0,0 +1,220 @@
+//! Middleware that wraps the requests sent by an [`HttpClient`].
+//!
+//! A [`Middleware`] is called with every request sent through the wrapped HTTP client, and decides
+//! how to send it to the inner client. It can be used for cross-cutting behavior like tracing,
+//! metrics, adding headers, recording requests and responses, or refreshing authentication.
+//!
+//! The [`Metadata`] of the endpoint is available for requests sent by this crate.
+//!
+//! # Example
+//!
+//! ```
+//! use async_trait::async_trait;
+//! use http::header::{HeaderValue, USER_AGENT};
+//! use ruma_client::{middleware::Middleware, HttpClient};
+//! use ruma_common::api::Metadata;
+//!
+//! /// Sets the `User-Agent` header of all requests.
+//! struct UserAgent(HeaderValue);
+//!
+//! #[async_trait]
+//! impl<C: HttpClient> Middleware<C> for UserAgent {
+//!     async fn handle(
+//!         &self,
+//!         mut request: http::Request<C::RequestBody>,
+//!         _metadata: Option<&Metadata>,
+//!         next: &C,
+//!     ) -> Result<http::Response<C::ResponseBody>, C::Error> {
+//!         request.headers_mut().insert(USER_AGENT, self.0.clone());
+//!         next.send_http_request(request).await
+//!     }
+//! }
+//! ```
+
+use async_trait::async_trait;
+use ruma_common::api::Metadata;
+
+use crate::HttpClient;
+
+/// A middleware that wraps the requests sent by an HTTP client of type `C`.
+#[async_trait]
+pub trait Middleware<C: HttpClient>: Send + Sync {
+    /// Handle the given request.
+    ///
+    /// `metadata` is the metadata of the endpoint of the request, if it was sent by this crate.
+    ///
+    /// Implementations usually call `next.send_http_request()` to send the request and get back
+    /// the response.
+    async fn handle(
+        &self,
+        request: http::Request<C::RequestBody>,
+        metadata: Option<&Metadata>,
+        next: &C,
+    ) -> Result<http::Response<C::ResponseBody>, C::Error>;
+}
+
+/// An HTTP client that sends its requests through a middleware.
+#[derive(Clone, Debug)]
+pub struct WithMiddleware<C, M> {
+    inner: C,
+    middleware: M,
+}
+
+impl<C, M> WithMiddleware<C, M> {
+    /// Creates a new `WithMiddleware` that sends the requests to the given HTTP client through the
+    /// given middleware.
+    pub fn new(inner: C, middleware: M) -> Self {
+        Self { inner, middleware }
+    }
+
+    /// The inner HTTP client.
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+
+    /// The middleware.
+    pub fn middleware(&self) -> &M {
+        &self.middleware
+    }
+}
+
+#[async_trait]
+impl<C, M> HttpClient for WithMiddleware<C, M>
+where
+    C: HttpClient,
+    M: Middleware<C>,
+{
+    type RequestBody = C::RequestBody;
+    type ResponseBody = C::ResponseBody;
+    type Error = C::Error;
+
+    async fn send_http_request(
+        &self,
+        req: http::Request<Self::RequestBody>,
+    ) -> Result<http::Response<Self::ResponseBody>, Self::Error> {
+        let metadata = req.extensions().get::<Metadata>().cloned();
+        self.middleware.handle(req, metadata.as_ref(), &self.inner).await
+    }
+}
+
+/// A stack of middleware that can wrap an HTTP client of type `C`.
+///
+/// It is implemented for `()`, which is the empty stack, and for `(L, M)`, which is the stack `L`
+/// followed by the middleware `M`. Requests go through the stack in order, then to the HTTP
+/// client.
+pub trait MiddlewareStack<C> {
+    /// The HTTP client wrapped by this stack.
+    type Client: HttpClient;
+
+    /// Wrap the given HTTP client with this stack.
+    fn wrap(self, http_client: C) -> Self::Client;
+}
+
+impl<C: HttpClient> MiddlewareStack<C> for () {
+    type Client = C;
+
+    fn wrap(self, http_client: C) -> Self::Client {
+        http_client
+    }
+}
+
+impl<C, L, M> MiddlewareStack<C> for (L, M)
+where
+    C: HttpClient,
+    M: Middleware<C>,
+    L: MiddlewareStack<WithMiddleware<C, M>>,
+{
+    type Client = L::Client;
+
+    fn wrap(self, http_client: C) -> Self::Client {
+        let (stack, middleware) = self;
+        stack.wrap(WithMiddleware::new(http_client, middleware))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use async_trait::async_trait;
+    use http::StatusCode;
+    use ruma_client_api::discovery::get_supported_versions;
+    use ruma_common::api::{MatrixVersion, Metadata, SendAccessToken};
+    use serde_json::json;
+
+    use super::{Middleware, MiddlewareStack};
+    use crate::{
+        test_utils::{json_response, MockHttpClient},
+        HttpClient, HttpClientExt,
+    };
+
+    type Log = Arc<Mutex<Vec<String>>>;
+
+    /// Records the requests it handles in a log, with the name of the middleware.
+    struct Recorder {
+        name: &'static str,
+        log: Log,
+    }
+
+    #[async_trait]
+    impl<C: HttpClient> Middleware<C> for Recorder {
+        async fn handle(
+            &self,
+            request: http::Request<C::RequestBody>,
+            metadata: Option<&Metadata>,
+            next: &C,
+        ) -> Result<http::Response<C::ResponseBody>, C::Error> {
+            let endpoint = metadata.map(|metadata| (&metadata.method, metadata.history.added_in()));
+            self.log.lock().unwrap().push(format!("{} before, {endpoint:?}", self.name));
+
+            let response = next.send_http_request(request).await;
+
+            self.log.lock().unwrap().push(format!("{} after", self.name));
+            response
+        }
+    }
+
+    #[tokio::test]
+    async fn stack_calls_middleware_in_order_with_metadata() {
+        let log = Log::default();
+        let first = Recorder { name: "first", log: log.clone() };
+        let second = Recorder { name: "second", log: log.clone() };
+        let http_client = (((), first), second).wrap(MockHttpClient::new([
+            json_response(StatusCode::OK, json!({ "versions": ["v1.1"] })),
+            json_response(StatusCode::OK, json!({})),
+        ]));
+
+        let response = http_client
+            .send_matrix_request(
+                "https://matrix.example.org",
+                SendAccessToken::None,
+                &[MatrixVersion::V1_1],
+                get_supported_versions::Request::new(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.versions, ["v1.1"]);
+        assert_eq!(
+            http_client.inner().inner().requests()[0].uri(),
+            "https://matrix.example.org/_matrix/client/versions"
+        );
+        assert_eq!(
+            *log.lock().unwrap(),
+            [
+                "first before, Some((GET, Some(V1_0)))",
+                "second before, Some((GET, Some(V1_0)))",
+                "second after",
+                "first after",
+            ]
+        );
+
+        // Requests that are not sent by this crate have no metadata.
+        log.lock().unwrap().clear();
+        let request = http::Request::post("https://matrix.example.org").body(Vec::new()).unwrap();
+        http_client.send_http_request(request).await.unwrap();
+        assert_eq!(
+            *log.lock().unwrap(),
+            ["first before, None", "second before, None", "second after", "first after"]
+        );
+    }
+}
@@ -0,0 +1,70 @@
+//! Fixtures shared by the tests of the modules of this crate.
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    sync::{Mutex, MutexGuard},
+};
+
+use async_trait::async_trait;
+use http::StatusCode;
+
+use crate::HttpClient;
+
+/// The result of a request sent with a [`MockHttpClient`].
+pub(crate) type MockResult = Result<http::Response<Vec<u8>>, TransportError>;
+
+/// The error returned by a [`MockHttpClient`] when the response could not be obtained.
+#[derive(Debug)]
+pub(crate) struct TransportError;
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("transport error")
+    }
+}
+
+/// An HTTP client that returns the responses it was given in order, and records the requests.
+#[derive(Default)]
+pub(crate) struct MockHttpClient {
+    responses: Mutex<VecDeque<MockResult>>,
+    requests: Mutex<Vec<http::Request<Vec<u8>>>>,
+}
+
+impl MockHttpClient {
+    /// A client that returns the given responses in order.
+    pub(crate) fn new(responses: impl IntoIterator<Item = MockResult>) -> Self {
+        Self { responses: Mutex::new(responses.into_iter().collect()), ..Default::default() }
+    }
+
+    /// The requests that were received.
+    pub(crate) fn requests(&self) -> MutexGuard<'_, Vec<http::Request<Vec<u8>>>> {
+        self.requests.lock().unwrap()
+    }
+}
+
+#[async_trait]
+impl HttpClient for MockHttpClient {
+    type RequestBody = Vec<u8>;
+    type ResponseBody = Vec<u8>;
+    type Error = TransportError;
+
+    async fn send_http_request(&self, req: http::Request<Vec<u8>>) -> MockResult {
+        // Let concurrent requests make progress, like a real client would.
+        tokio::task::yield_now().await;
+
+        let response = self.responses.lock().unwrap().pop_front();
+        let response = response.unwrap_or_else(|| panic!("unexpected request to {}", req.uri()));
+        self.requests.lock().unwrap().push(req);
+        response
+    }
+}
+
+/// A response with the given status and JSON body.
+pub(crate) fn json_response(status: StatusCode, body: serde_json::Value) -> MockResult {
+    Ok(http::Response::builder()
+        .status(status)
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(serde_json::to_vec(&body).unwrap())
+        .unwrap())
+}
@@ -159,7 +159,6 @@ pub mod key;
 pub mod location;
 #[cfg(feature = "unstable-msc1767")]
 pub mod message;
-#[cfg(feature = "unstable-pdu")]
 pub mod pdu;
 pub mod policy;
 #[cfg(feature = "unstable-msc3381")]
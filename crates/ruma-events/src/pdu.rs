@@ -4,10 +4,17 @@
 //! `RoomV1Pdu` takes an `event_id` field (`RoomV3Pdu` does not), and `auth_events` and
 //! `prev_events` take `Vec<(OwnedEventId, EventHash)>` rather than `Vec<OwnedEventId>` in
 //! `RoomV3Pdu`.
+//!
+//! To check that a PDU has the format of the event format version of its room version, use
+//! `Pdu::from_json_with_rules`, with the `canonical-json` feature.
 
 use std::collections::BTreeMap;
+#[cfg(feature = "canonical-json")]
+use std::collections::BTreeSet;
 
 use js_int::UInt;
+#[cfg(feature = "canonical-json")]
+use ruma_common::{CanonicalJsonValue, EventId};
 use ruma_common::{
     MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedRoomId, OwnedServerName,
     OwnedServerSigningKeyId, OwnedUserId,
@@ -16,6 +23,8 @@ use serde::{
     de::{Error as _, IgnoredAny},
     Deserialize, Deserializer, Serialize,
 };
+#[cfg(feature = "canonical-json")]
+use serde_json::Value as JsonValue;
 use serde_json::{from_str as from_json_str, value::RawValue as RawJsonValue};
 
 use super::TimelineEventType;
@@ -32,6 +41,216 @@ pub enum Pdu {
     RoomV3Pdu(RoomV3Pdu),
 }
 
+impl Pdu {
+    /// The maximum size of a PDU, in bytes.
+    pub const MAX_BYTES: usize = 65_536;
+
+    /// The maximum size of the `event_id`, `room_id`, `sender`, `type` and `state_key` of a PDU,
+    /// in bytes.
+    pub const MAX_FIELD_BYTES: usize = 255;
+
+    /// The maximum number of `auth_events` of a PDU.
+    pub const MAX_AUTH_EVENTS: usize = 10;
+
+    /// The maximum number of `prev_events` of a PDU.
+    pub const MAX_PREV_EVENTS: usize = 20;
+
+    /// Deserialize a PDU and check that it has a valid format for the given rules of its room
+    /// version.
+    ///
+    /// Unlike the `Deserialize` implementation, which only picks the variant based on the presence
+    /// of an `event_id`, this checks that:
+    ///
+    /// * The PDU has an `event_id` if and only if the event format requires it.
+    /// * The `auth_events` and `prev_events` have the shape of the event format, without duplicates
+    ///   and with event IDs in the format of the event format.
+    /// * The PDU, as canonical JSON, and its `event_id`, `room_id`, `sender`, `type` and
+    ///   `state_key` are not larger than the limits of the specification.
+    /// * The PDU only contains integers in the canonical JSON range, if the rules enforce canonical
+    ///   JSON.
+    #[cfg(feature = "canonical-json")]
+    pub fn from_json_with_rules(
+        json: &RawJsonValue,
+        rules: &PduFormatRules,
+    ) -> Result<Self, PduFormatError> {
+        let value = from_json_str::<JsonValue>(json.get())?;
+
+        // Room versions that don't enforce canonical JSON allow floats and integers outside of its
+        // range, the size of those PDUs is measured on the closest JSON representation.
+        let canonical_len = match CanonicalJsonValue::try_from(value.clone()) {
+            Ok(canonical) => canonical.to_string().len(),
+            Err(_) if rules.strict_canonical_json => return Err(PduFormatError::NonCanonicalJson),
+            Err(_) => value.to_string().len(),
+        };
+        if canonical_len > Self::MAX_BYTES {
+            return Err(PduFormatError::TooLarge);
+        }
+
+        let has_event_id = value.get("event_id").is_some();
+        let event_format = rules.event_format;
+        let pdu = match event_format {
+            EventFormatVersion::V1 if has_event_id => Self::RoomV1Pdu(from_json_str(json.get())?),
+            EventFormatVersion::V1 => return Err(PduFormatError::MissingEventId),
+            _ if has_event_id => return Err(PduFormatError::UnexpectedEventId),
+            _ => Self::RoomV3Pdu(from_json_str(json.get())?),
+        };
+
+        let (event_id, room_id, sender, kind, state_key, auth_events, prev_events) = match &pdu {
+            Self::RoomV1Pdu(pdu) => (
+                Some(&pdu.event_id),
+                &pdu.room_id,
+                &pdu.sender,
+                &pdu.kind,
+                pdu.state_key.as_deref(),
+                pdu.auth_events.iter().map(|(id, _)| &**id).collect::<Vec<_>>(),
+                pdu.prev_events.iter().map(|(id, _)| &**id).collect::<Vec<_>>(),
+            ),
+            Self::RoomV3Pdu(pdu) => (
+                None,
+                &pdu.room_id,
+                &pdu.sender,
+                &pdu.kind,
+                pdu.state_key.as_deref(),
+                pdu.auth_events.iter().map(|id| &**id).collect(),
+                pdu.prev_events.iter().map(|id| &**id).collect(),
+            ),
+        };
+
+        let fields = [
+            ("event_id", event_id.map(|id| id.as_str())),
+            ("room_id", Some(room_id.as_str())),
+            ("sender", Some(sender.as_str())),
+            ("type", Some(&kind.to_string())),
+            ("state_key", state_key),
+        ];
+        for (field, value) in fields {
+            if value.is_some_and(|value| value.len() > Self::MAX_FIELD_BYTES) {
+                return Err(PduFormatError::FieldTooLarge(field));
+            }
+        }
+
+        check_event_ids("auth_events", &auth_events, Self::MAX_AUTH_EVENTS, event_format)?;
+        check_event_ids("prev_events", &prev_events, Self::MAX_PREV_EVENTS, event_format)?;
+
+        Ok(pdu)
+    }
+}
+
+/// The format of the events of a room version.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(not(feature = "unstable-exhaustive-types"), non_exhaustive)]
+pub enum EventFormatVersion {
+    /// `$id:server` event ID format, in the PDU, and references to other events include their
+    /// hashes.
+    V1,
+
+    /// MSC1659-style `$hash` event ID format: introduced for room v3.
+    ///
+    /// The event ID is the reference hash of the PDU, encoded in standard unpadded base64.
+    V2,
+
+    /// MSC1884-style `$hash` format: introduced for room v4.
+    ///
+    /// The event ID is the reference hash of the PDU, encoded in URL-safe unpadded base64.
+    V3,
+}
+
+/// The rules of a room version for the format of its PDUs.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(not(feature = "unstable-exhaustive-types"), non_exhaustive)]
+pub struct PduFormatRules {
+    /// The format of the events.
+    pub event_format: EventFormatVersion,
+
+    /// Whether PDUs must only contain integers in the canonical JSON range.
+    pub strict_canonical_json: bool,
+}
+
+impl PduFormatRules {
+    /// Creates new `PduFormatRules` with the given event format and canonical JSON strictness.
+    pub fn new(event_format: EventFormatVersion, strict_canonical_json: bool) -> Self {
+        Self { event_format, strict_canonical_json }
+    }
+}
+
+/// Check the list of event IDs in the given field of a PDU.
+#[cfg(feature = "canonical-json")]
+fn check_event_ids(
+    field: &'static str,
+    event_ids: &[&EventId],
+    max: usize,
+    event_format: EventFormatVersion,
+) -> Result<(), PduFormatError> {
+    if event_ids.len() > max {
+        return Err(PduFormatError::TooManyEvents(field));
+    }
+    if event_ids.iter().collect::<BTreeSet<_>>().len() != event_ids.len() {
+        return Err(PduFormatError::DuplicateEvents(field));
+    }
+
+    let is_valid = |event_id: &EventId| {
+        let is_base64 = |c: char, extra: [char; 2]| c.is_ascii_alphanumeric() || extra.contains(&c);
+        let hash = &event_id.as_str()[1..];
+
+        match event_format {
+            EventFormatVersion::V1 => event_id.server_name().is_some(),
+            // The reference hash is a SHA-256 hash, encoded in 43 base64 characters.
+            EventFormatVersion::V2 => {
+                hash.len() == 43 && hash.chars().all(|c| is_base64(c, ['+', '/']))
+            }
+            EventFormatVersion::V3 => {
+                hash.len() == 43 && hash.chars().all(|c| is_base64(c, ['-', '_']))
+            }
+        }
+    };
+    if !event_ids.iter().all(|id| is_valid(id)) {
+        return Err(PduFormatError::InvalidEventId(field));
+    }
+
+    Ok(())
+}
+
+/// An error encountered when checking the format of a PDU.
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum PduFormatError {
+    /// The PDU, as canonical JSON, is larger than [`Pdu::MAX_BYTES`].
+    #[error("the PDU is too large")]
+    TooLarge,
+
+    /// The given field is larger than [`Pdu::MAX_FIELD_BYTES`].
+    #[error("the `{0}` field is too large")]
+    FieldTooLarge(&'static str),
+
+    /// The event format requires an `event_id`, but the PDU doesn't have one.
+    #[error("missing `event_id` field")]
+    MissingEventId,
+
+    /// The PDU has an `event_id`, but the event format doesn't allow it.
+    #[error("unexpected `event_id` field")]
+    UnexpectedEventId,
+
+    /// The given field contains more event IDs than allowed.
+    #[error("too many events in `{0}`")]
+    TooManyEvents(&'static str),
+
+    /// The given field contains the same event ID several times.
+    #[error("duplicate events in `{0}`")]
+    DuplicateEvents(&'static str),
+
+    /// The given field contains an event ID that doesn't have the event format.
+    #[error("invalid event ID in `{0}`")]
+    InvalidEventId(&'static str),
+
+    /// The PDU contains a float, or an integer outside of the canonical JSON range.
+    #[error("the PDU is not valid canonical JSON")]
+    NonCanonicalJson,
+
+    /// The PDU doesn't match the schema of the room version.
+    #[error(transparent)]
+    Deserialization(#[from] serde_json::Error),
+}
+
 /// A 'persistent data unit' (event) for room versions 1 and 2.
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[allow(clippy::exhaustive_structs)]
@@ -1,5 +1,3 @@
-#![cfg(feature = "unstable-pdu")]
-
 use std::collections::BTreeMap;
 
 use js_int::uint;
@@ -237,3 +235,129 @@ fn deserialize_pdu_as_v3() {
         _ => unreachable!("new PDU version"),
     }
 }
+
+#[cfg(feature = "canonical-json")]
+mod format_rules {
+    use assert_matches2::assert_matches;
+    use ruma_events::pdu::{EventFormatVersion, Pdu, PduFormatError, PduFormatRules};
+    use serde_json::{json, value::to_raw_value as to_raw_json_value, Value as JsonValue};
+
+    fn v4_pdu_json() -> JsonValue {
+        json!({
+            "auth_events": ["$Nhq-Pm5DSMfnr3pUOeBXJqX3SyhsSkZAGQnzDEjbTzE"],
+            "content": { "body": "Hello" },
+            "depth": 12,
+            "hashes": { "sha256": "ThisHashCoversAllFieldsInCaseThisIsRedacted" },
+            "origin_server_ts": 1_234_567_890,
+            "prev_events": ["$5i8PsyVFjyk3zmQ9IAQlOpdrlyp54N_v6zOA8nYpLl8"],
+            "room_id": "!abc123:matrix.org",
+            "sender": "@someone:matrix.org",
+            "signatures": {
+                "example.com": {
+                    "ed25519:key_version": "86BytesOfSignatureOfTheRedactedEvent"
+                }
+            },
+            "type": "m.room.message",
+        })
+    }
+
+    fn check(
+        json: &JsonValue,
+        event_format: EventFormatVersion,
+        strict_canonical_json: bool,
+    ) -> Result<Pdu, PduFormatError> {
+        let rules = PduFormatRules::new(event_format, strict_canonical_json);
+        Pdu::from_json_with_rules(&to_raw_json_value(json).unwrap(), &rules)
+    }
+
+    #[test]
+    fn valid_pdu_for_room_version() {
+        assert_matches!(
+            check(&v4_pdu_json(), EventFormatVersion::V3, true),
+            Ok(Pdu::RoomV3Pdu(pdu))
+        );
+        assert_eq!(pdu.sender, "@someone:matrix.org");
+    }
+
+    #[test]
+    fn event_id_presence_for_room_version() {
+        assert_matches!(
+            check(&v4_pdu_json(), EventFormatVersion::V1, false),
+            Err(PduFormatError::MissingEventId)
+        );
+
+        let mut json = v4_pdu_json();
+        json["event_id"] = json!("$Nhq-Pm5DSMfnr3pUOeBXJqX3SyhsSkZAGQnzDEjbTzE");
+        assert_matches!(
+            check(&json, EventFormatVersion::V3, true),
+            Err(PduFormatError::UnexpectedEventId)
+        );
+    }
+
+    #[test]
+    fn invalid_auth_events_for_room_version() {
+        // The second event format uses standard base64 for event IDs.
+        assert_matches!(
+            check(&v4_pdu_json(), EventFormatVersion::V2, false),
+            Err(PduFormatError::InvalidEventId("auth_events"))
+        );
+
+        let mut json = v4_pdu_json();
+        json["auth_events"] = json!(["$abc123:matrix.org"]);
+        assert_matches!(
+            check(&json, EventFormatVersion::V3, true),
+            Err(PduFormatError::InvalidEventId("auth_events"))
+        );
+
+        let mut json = v4_pdu_json();
+        json["auth_events"] = json!([
+            "$Nhq-Pm5DSMfnr3pUOeBXJqX3SyhsSkZAGQnzDEjbTzE",
+            "$Nhq-Pm5DSMfnr3pUOeBXJqX3SyhsSkZAGQnzDEjbTzE",
+        ]);
+        assert_matches!(
+            check(&json, EventFormatVersion::V3, true),
+            Err(PduFormatError::DuplicateEvents("auth_events"))
+        );
+
+        let mut json = v4_pdu_json();
+        json["auth_events"] = json!([["$abc123:matrix.org", { "sha256": "hash" }]]);
+        assert_matches!(
+            check(&json, EventFormatVersion::V3, true),
+            Err(PduFormatError::Deserialization(_))
+        );
+    }
+
+    #[test]
+    fn oversized_pdu() {
+        let mut json = v4_pdu_json();
+        json["state_key"] = json!("a".repeat(256));
+        assert_matches!(
+            check(&json, EventFormatVersion::V3, true),
+            Err(PduFormatError::FieldTooLarge("state_key"))
+        );
+
+        let mut json = v4_pdu_json();
+        json["content"]["body"] = json!("a".repeat(Pdu::MAX_BYTES));
+        assert_matches!(check(&json, EventFormatVersion::V3, true), Err(PduFormatError::TooLarge));
+    }
+
+    #[test]
+    fn non_canonical_integers() {
+        let mut json = v4_pdu_json();
+        json["content"]["count"] = json!(2_u64.pow(53));
+
+        // Canonical JSON is only enforced by some room versions.
+        assert_matches!(check(&json, EventFormatVersion::V3, false), Ok(_));
+        assert_matches!(
+            check(&json, EventFormatVersion::V3, true),
+            Err(PduFormatError::NonCanonicalJson)
+        );
+
+        let mut json = v4_pdu_json();
+        json["content"]["float"] = json!(1.5);
+        assert_matches!(
+            check(&json, EventFormatVersion::V3, true),
+            Err(PduFormatError::NonCanonicalJson)
+        );
+    }
+}
@@ -1,7 +1,15 @@
 //! Common types for implementing federation authorization.
 
+use std::collections::BTreeMap;
+
 use headers::{authorization::Credentials, HeaderValue};
-use ruma_common::{OwnedServerName, OwnedServerSigningKeyId};
+use http::{header::AUTHORIZATION, Request};
+use ruma_common::{
+    CanonicalJsonObject, CanonicalJsonValue, IdParseError, OwnedServerName,
+    OwnedServerSigningKeyId, ServerName,
+};
+use ruma_signatures::{sign_json, verify_json, KeyPair, PublicKeyMap};
+use serde_json::from_slice as from_json_slice;
 use tracing::debug;
 use yap::{IntoTokens, TokenLocation, Tokens};
 
@@ -39,6 +47,131 @@ impl XMatrix {
     }
 }
 
+/// Sign a federation request and add the `Authorization` header with the signature to it.
+///
+/// The request is signed by `origin` with the given key pair, for `destination`. Its body must be
+/// empty or contain JSON.
+///
+/// # Errors
+///
+/// Returns an error if the body of the request is not valid canonical JSON, or if the signature
+/// failed.
+pub fn sign_request<T: AsRef<[u8]>>(
+    request: &mut Request<T>,
+    origin: &ServerName,
+    destination: &ServerName,
+    key_pair: &impl KeyPair,
+) -> Result<(), XMatrixError> {
+    let mut json = request_json(request, origin, destination)?;
+    sign_json(origin.as_str(), key_pair, &mut json)?;
+
+    // The signatures only contain the signature that was just added.
+    let Some(CanonicalJsonValue::Object(signatures)) = json.remove("signatures") else {
+        unreachable!("sign_json adds the signatures");
+    };
+    let Some(CanonicalJsonValue::Object(signature_set)) = signatures.get(origin.as_str()) else {
+        unreachable!("sign_json adds the signature of the origin");
+    };
+    let Some((key, CanonicalJsonValue::String(sig))) = signature_set.iter().next() else {
+        unreachable!("sign_json adds a signature for the key");
+    };
+
+    let x_matrix = XMatrix::new(
+        origin.to_owned(),
+        Some(destination.to_owned()),
+        key.as_str().try_into().map_err(XMatrixError::InvalidKeyId)?,
+        sig.clone(),
+    );
+    request.headers_mut().insert(AUTHORIZATION, x_matrix.encode());
+
+    Ok(())
+}
+
+/// Verify the signature of an incoming federation request.
+///
+/// `x_matrix` is the `Authorization` header of the request, `destination` is the name of the
+/// server that received the request, and `public_key_map` must contain the public keys of the
+/// origin of the request.
+///
+/// Returns the origin of the request if the signature is valid.
+///
+/// # Errors
+///
+/// Returns an error if the destination of the header doesn't match `destination`, if the body of
+/// the request is not valid canonical JSON, or if the signature is invalid.
+pub fn verify_request<T: AsRef<[u8]>>(
+    request: &Request<T>,
+    x_matrix: &XMatrix,
+    destination: &ServerName,
+    public_key_map: &PublicKeyMap,
+) -> Result<OwnedServerName, XMatrixError> {
+    if let Some(header_destination) = &x_matrix.destination {
+        if header_destination != destination {
+            return Err(XMatrixError::DestinationMismatch(header_destination.clone()));
+        }
+    }
+
+    let mut json = request_json(request, &x_matrix.origin, destination)?;
+
+    let signature_set = BTreeMap::from([(
+        x_matrix.key.to_string(),
+        CanonicalJsonValue::String(x_matrix.sig.clone()),
+    )]);
+    let signatures =
+        BTreeMap::from([(x_matrix.origin.to_string(), CanonicalJsonValue::Object(signature_set))]);
+    json.insert("signatures".to_owned(), CanonicalJsonValue::Object(signatures));
+
+    verify_json(public_key_map, &json)?;
+
+    Ok(x_matrix.origin.clone())
+}
+
+/// The JSON object that is signed for a federation request.
+fn request_json<T: AsRef<[u8]>>(
+    request: &Request<T>,
+    origin: &ServerName,
+    destination: &ServerName,
+) -> Result<CanonicalJsonObject, XMatrixError> {
+    let uri = request.uri().path_and_query().map_or("/", |path_and_query| path_and_query.as_str());
+
+    let mut json = BTreeMap::from([
+        ("method".to_owned(), CanonicalJsonValue::String(request.method().to_string())),
+        ("uri".to_owned(), CanonicalJsonValue::String(uri.to_owned())),
+        ("origin".to_owned(), CanonicalJsonValue::String(origin.to_string())),
+        ("destination".to_owned(), CanonicalJsonValue::String(destination.to_string())),
+    ]);
+
+    let body = request.body().as_ref();
+    if !body.is_empty() {
+        let content = from_json_slice(body).map_err(XMatrixError::InvalidBody)?;
+        json.insert("content".to_owned(), content);
+    }
+
+    Ok(json)
+}
+
+/// An error encountered when signing or verifying a federation request.
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum XMatrixError {
+    /// The body of the request is not valid canonical JSON.
+    #[error("invalid request body: {0}")]
+    InvalidBody(#[source] serde_json::Error),
+
+    /// The ID of the signing key is invalid.
+    #[error("invalid signing key ID: {0}")]
+    InvalidKeyId(#[source] IdParseError),
+
+    /// The destination of the `Authorization` header doesn't match the server that received the
+    /// request.
+    #[error("the request is intended for {0}")]
+    DestinationMismatch(OwnedServerName),
+
+    /// The signature couldn't be created or is invalid.
+    #[error(transparent)]
+    Signatures(#[from] ruma_signatures::Error),
+}
+
 fn parse_token<'a>(tokens: &mut impl Tokens<Item = &'a u8>) -> Option<Vec<u8>> {
     tokens.optional(|t| {
         let token: Vec<u8> = t.tokens_while(|c| is_tchar(**c)).copied().collect();
@@ -239,10 +372,34 @@ impl Credentials for XMatrix {
 
 #[cfg(test)]
 mod tests {
+    use std::collections::BTreeMap;
+
     use headers::{authorization::Credentials, HeaderValue};
-    use ruma_common::OwnedServerName;
+    use http::{header::AUTHORIZATION, Request};
+    use ruma_common::{server_name, OwnedServerName};
+    use ruma_signatures::{Ed25519KeyPair, PublicKeyMap};
 
-    use super::XMatrix;
+    use super::{sign_request, verify_request, XMatrix, XMatrixError};
+    use crate::test_utils::{key_pair, public_key_set};
+
+    fn public_key_map(key_pair: &Ed25519KeyPair) -> PublicKeyMap {
+        BTreeMap::from([("origin.hs.example.com".to_owned(), public_key_set(key_pair))])
+    }
+
+    fn signed_request(key_pair: &Ed25519KeyPair) -> Request<Vec<u8>> {
+        let mut request =
+            Request::put("https://destination.hs.example.com/_matrix/federation/v1/send/1")
+                .body(br#"{"pdus":[],"edus":[]}"#.to_vec())
+                .unwrap();
+        sign_request(
+            &mut request,
+            server_name!("origin.hs.example.com"),
+            server_name!("destination.hs.example.com"),
+            key_pair,
+        )
+        .unwrap();
+        request
+    }
 
     #[test]
     fn xmatrix_auth_pre_1_3() {
@@ -280,4 +437,61 @@ mod tests {
 
         assert_eq!(credentials.encode(), header);
     }
+
+    #[test]
+    fn sign_and_verify_request() {
+        let key_pair = key_pair("1");
+        let request = signed_request(&key_pair);
+
+        let x_matrix: XMatrix =
+            Credentials::decode(request.headers().get(AUTHORIZATION).unwrap()).unwrap();
+        assert_eq!(x_matrix.origin, "origin.hs.example.com");
+        assert_eq!(
+            x_matrix.destination.as_deref(),
+            Some(server_name!("destination.hs.example.com"))
+        );
+        assert_eq!(x_matrix.key, "ed25519:1");
+
+        let origin = verify_request(
+            &request,
+            &x_matrix,
+            server_name!("destination.hs.example.com"),
+            &public_key_map(&key_pair),
+        )
+        .unwrap();
+        assert_eq!(origin, "origin.hs.example.com");
+    }
+
+    #[test]
+    fn verify_request_with_other_destination() {
+        let key_pair = key_pair("1");
+        let request = signed_request(&key_pair);
+        let x_matrix: XMatrix =
+            Credentials::decode(request.headers().get(AUTHORIZATION).unwrap()).unwrap();
+
+        let result = verify_request(
+            &request,
+            &x_matrix,
+            server_name!("other.hs.example.com"),
+            &public_key_map(&key_pair),
+        );
+        assert!(matches!(result, Err(XMatrixError::DestinationMismatch(_))));
+    }
+
+    #[test]
+    fn verify_modified_request() {
+        let key_pair = key_pair("1");
+        let request = signed_request(&key_pair);
+        let x_matrix: XMatrix =
+            Credentials::decode(request.headers().get(AUTHORIZATION).unwrap()).unwrap();
+        let request = request.map(|_| br#"{"pdus":[],"edus":[{}]}"#.to_vec());
+
+        let result = verify_request(
+            &request,
+            &x_matrix,
+            server_name!("destination.hs.example.com"),
+            &public_key_map(&key_pair),
+        );
+        assert!(matches!(result, Err(XMatrixError::Signatures(_))));
+    }
 }
@@ -0,0 +1,586 @@
+//! Management of the signing keys of other servers.
+//!
+//! The [`KeyManager`] answers which public keys of a server are valid at a given time, by looking
+//! them up in a [`KeyStorage`], or fetching them with a [`KeyFetcher`] from the server itself or
+//! from notary servers.
+//!
+//! See the [Matrix Server-Server API][spec] for more information.
+//!
+//! [spec]: https://spec.matrix.org/latest/server-server-api/#retrieving-server-keys
+
+use std::{collections::BTreeMap, convert::Infallible, error::Error as StdError, fmt, sync::Mutex};
+
+use async_trait::async_trait;
+use ruma_common::{
+    serde::Raw, CanonicalJsonObject, CanonicalJsonValue, MilliSecondsSinceUnixEpoch,
+    OwnedServerName, ServerName,
+};
+use ruma_federation_api::discovery::{OldVerifyKey, ServerSigningKeys};
+use ruma_signatures::{verify_json, PublicKeyMap, PublicKeySet, TimedPublicKey, TimedPublicKeySet};
+use serde_json::from_str as from_json_str;
+use tracing::warn;
+
+/// Storage for the signing keys of other servers, once they have been verified.
+#[async_trait]
+pub trait KeyStorage: Send + Sync {
+    /// The error type of the storage.
+    type Error: StdError + Send + Sync + 'static;
+
+    /// Get the keys of the given server, if they were stored before.
+    async fn get_server_keys(
+        &self,
+        server_name: &ServerName,
+    ) -> Result<Option<ServerSigningKeys>, Self::Error>;
+
+    /// Store the keys of a server, replacing the ones that were stored before.
+    async fn set_server_keys(&self, keys: ServerSigningKeys) -> Result<(), Self::Error>;
+}
+
+/// A fetcher for the signing keys of other servers.
+///
+/// This is usually implemented by sending the [`get_server_keys`] and [`get_remote_server_keys`]
+/// requests.
+///
+/// [`get_server_keys`]: ruma_federation_api::discovery::get_server_keys
+/// [`get_remote_server_keys`]: ruma_federation_api::discovery::get_remote_server_keys
+#[async_trait]
+pub trait KeyFetcher: Send + Sync {
+    /// The error type of the fetcher.
+    type Error: StdError + Send + Sync + 'static;
+
+    /// Fetch the keys of the given server from the server itself.
+    async fn fetch_server_keys(
+        &self,
+        server_name: &ServerName,
+    ) -> Result<Raw<ServerSigningKeys>, Self::Error>;
+
+    /// Fetch the keys of the given server from the given notary server.
+    async fn fetch_server_keys_from_notary(
+        &self,
+        notary: &ServerName,
+        server_name: &ServerName,
+        minimum_valid_until_ts: MilliSecondsSinceUnixEpoch,
+    ) -> Result<Vec<Raw<ServerSigningKeys>>, Self::Error>;
+}
+
+/// A [`KeyStorage`] that keeps the keys in memory.
+#[derive(Debug, Default)]
+pub struct InMemoryKeyStorage {
+    keys: Mutex<BTreeMap<OwnedServerName, ServerSigningKeys>>,
+}
+
+impl InMemoryKeyStorage {
+    /// Creates an empty `InMemoryKeyStorage`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl KeyStorage for InMemoryKeyStorage {
+    type Error = Infallible;
+
+    async fn get_server_keys(
+        &self,
+        server_name: &ServerName,
+    ) -> Result<Option<ServerSigningKeys>, Self::Error> {
+        Ok(self.keys.lock().unwrap().get(server_name).cloned())
+    }
+
+    async fn set_server_keys(&self, keys: ServerSigningKeys) -> Result<(), Self::Error> {
+        self.keys.lock().unwrap().insert(keys.server_name.clone(), keys);
+        Ok(())
+    }
+}
+
+/// A notary server trusted to fetch the keys of other servers.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct NotaryServer {
+    /// The name of the notary server.
+    pub server_name: OwnedServerName,
+
+    /// The public keys of the notary server, used to verify its signature on the keys it returns.
+    pub verify_keys: PublicKeySet,
+}
+
+impl NotaryServer {
+    /// Creates a new `NotaryServer` with the given name and public keys.
+    pub fn new(server_name: OwnedServerName, verify_keys: PublicKeySet) -> Self {
+        Self { server_name, verify_keys }
+    }
+}
+
+/// Fetches, verifies and stores the signing keys of other servers.
+pub struct KeyManager<S, F> {
+    storage: S,
+    fetcher: F,
+    notary_servers: Vec<NotaryServer>,
+}
+
+impl<S, F> KeyManager<S, F>
+where
+    S: KeyStorage,
+    F: KeyFetcher,
+{
+    /// Creates a new `KeyManager` with the given storage and fetcher, and no notary servers.
+    pub fn new(storage: S, fetcher: F) -> Self {
+        Self { storage, fetcher, notary_servers: Vec::new() }
+    }
+
+    /// Add a notary server to fetch keys from, when they can't be fetched from the server itself.
+    ///
+    /// Notary servers are tried in the order in which they were added.
+    pub fn notary_server(mut self, notary: NotaryServer) -> Self {
+        self.notary_servers.push(notary);
+        self
+    }
+
+    /// Get the public keys of the given server that are valid at the given time.
+    ///
+    /// The stored keys are used if they are valid at that time. Otherwise, the keys are fetched
+    /// from the server itself, then from the notary servers, until keys that are valid at that
+    /// time are found. The fetched keys are verified and stored.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`KeyError::NoValidKeys`] if no keys valid at that time could be found, or an error
+    /// returned by the storage.
+    pub async fn keys_valid_at(
+        &self,
+        server_name: &ServerName,
+        ts: MilliSecondsSinceUnixEpoch,
+    ) -> Result<PublicKeySet, KeyError> {
+        let keys = self.server_keys_valid_at(server_name, ts).await?;
+        Ok(keys_valid_at(&keys, ts))
+    }
+
+    /// Get all the known public keys of the given server, with the time until which each key is
+    /// valid, making sure that at least one of them is valid at the given time.
+    ///
+    /// The keys are looked up and fetched like with [`KeyManager::keys_valid_at()`]. They can be
+    /// used with [`verify_event_with_key_validity()`] to reject signatures made with keys that
+    /// were not valid at the time of the event.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`KeyError::NoValidKeys`] if no keys valid at that time could be found, or an error
+    /// returned by the storage.
+    ///
+    /// [`verify_event_with_key_validity()`]: ruma_signatures::verify_event_with_key_validity
+    pub async fn timed_keys(
+        &self,
+        server_name: &ServerName,
+        ts: MilliSecondsSinceUnixEpoch,
+    ) -> Result<TimedPublicKeySet, KeyError> {
+        let keys = self.server_keys_valid_at(server_name, ts).await?;
+
+        let verify_keys = keys.verify_keys.into_iter().map(|(key_id, key)| {
+            (key_id.to_string(), TimedPublicKey::new(key.key, keys.valid_until_ts))
+        });
+        let old_verify_keys = keys.old_verify_keys.into_iter().map(|(key_id, key)| {
+            (key_id.to_string(), TimedPublicKey::new(key.key, key.expired_ts))
+        });
+
+        Ok(verify_keys.chain(old_verify_keys).collect())
+    }
+
+    /// Get the keys of the given server, if at least one of them is valid at the given time.
+    async fn server_keys_valid_at(
+        &self,
+        server_name: &ServerName,
+        ts: MilliSecondsSinceUnixEpoch,
+    ) -> Result<ServerSigningKeys, KeyError> {
+        let mut stored =
+            self.storage.get_server_keys(server_name).await.map_err(KeyError::storage)?;
+        if let Some(keys) = &stored {
+            if !keys_valid_at(keys, ts).is_empty() {
+                return Ok(keys.clone());
+            }
+        }
+
+        match self.fetcher.fetch_server_keys(server_name).await {
+            Ok(raw) => match verify_server_keys(&raw, server_name) {
+                Ok(keys) => {
+                    let keys = self.store(stored, keys).await?;
+                    if !keys_valid_at(&keys, ts).is_empty() {
+                        return Ok(keys);
+                    }
+                    stored = Some(keys);
+                }
+                Err(e) => warn!("invalid keys returned by {server_name}: {e}"),
+            },
+            Err(e) => warn!("failed to fetch keys from {server_name}: {e}"),
+        }
+
+        for notary in &self.notary_servers {
+            let notary_name = &notary.server_name;
+            let responses = match self
+                .fetcher
+                .fetch_server_keys_from_notary(notary_name, server_name, ts)
+                .await
+            {
+                Ok(responses) => responses,
+                Err(e) => {
+                    warn!("failed to fetch keys of {server_name} from {notary_name}: {e}");
+                    continue;
+                }
+            };
+
+            for raw in responses {
+                let keys = match verify_notary_server_keys(&raw, server_name, notary) {
+                    Ok(keys) => keys,
+                    Err(e) => {
+                        warn!("invalid keys of {server_name} returned by {notary_name}: {e}");
+                        continue;
+                    }
+                };
+
+                let keys = self.store(stored, keys).await?;
+                if !keys_valid_at(&keys, ts).is_empty() {
+                    return Ok(keys);
+                }
+                stored = Some(keys);
+            }
+        }
+
+        Err(KeyError::NoValidKeys(server_name.to_owned()))
+    }
+
+    /// Get the public keys of the given servers that are valid at the given time, as a
+    /// [`PublicKeyMap`] that can be used to verify signatures.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the keys of one of the servers could not be found, see
+    /// [`KeyManager::keys_valid_at()`].
+    pub async fn public_key_map(
+        &self,
+        server_names: impl IntoIterator<Item = &ServerName>,
+        ts: MilliSecondsSinceUnixEpoch,
+    ) -> Result<PublicKeyMap, KeyError> {
+        let mut public_key_map = PublicKeyMap::new();
+        for server_name in server_names {
+            let keys = self.keys_valid_at(server_name, ts).await?;
+            public_key_map.insert(server_name.to_string(), keys);
+        }
+
+        Ok(public_key_map)
+    }
+
+    /// Merge the new keys of a server with the previous ones and store them.
+    ///
+    /// Keys that were valid before but are not in the new keys are kept as old keys, that expired
+    /// when the previous keys had to be refreshed.
+    async fn store(
+        &self,
+        previous: Option<ServerSigningKeys>,
+        mut keys: ServerSigningKeys,
+    ) -> Result<ServerSigningKeys, KeyError> {
+        if let Some(previous) = previous {
+            for (key_id, key) in previous.old_verify_keys {
+                keys.old_verify_keys.entry(key_id).or_insert(key);
+            }
+            for (key_id, key) in previous.verify_keys {
+                if !keys.verify_keys.contains_key(&key_id) {
+                    keys.old_verify_keys
+                        .entry(key_id)
+                        .or_insert_with(|| OldVerifyKey::new(previous.valid_until_ts, key.key));
+                }
+            }
+        }
+
+        self.storage.set_server_keys(keys.clone()).await.map_err(KeyError::storage)?;
+        Ok(keys)
+    }
+}
+
+impl<S, F> fmt::Debug for KeyManager<S, F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyManager").field("notary_servers", &self.notary_servers).finish()
+    }
+}
+
+/// Deserialize the keys of a server and verify that they are signed by the server itself.
+///
+/// # Errors
+///
+/// Returns an error if the keys are not for the given server or if they are not correctly
+/// self-signed.
+pub fn verify_server_keys(
+    raw: &Raw<ServerSigningKeys>,
+    server_name: &ServerName,
+) -> Result<ServerSigningKeys, KeyError> {
+    let object = from_json_str::<CanonicalJsonObject>(raw.json().get())?;
+    let keys = raw.deserialize()?;
+
+    if keys.server_name != server_name {
+        return Err(KeyError::ServerNameMismatch(keys.server_name));
+    }
+
+    let verify_keys = keys
+        .verify_keys
+        .iter()
+        .map(|(key_id, key)| (key_id.to_string(), key.key.clone()))
+        .collect();
+    verify_signature(&object, server_name, &verify_keys)?;
+
+    Ok(keys)
+}
+
+/// Verify the keys of a server returned by a notary server.
+fn verify_notary_server_keys(
+    raw: &Raw<ServerSigningKeys>,
+    server_name: &ServerName,
+    notary: &NotaryServer,
+) -> Result<ServerSigningKeys, KeyError> {
+    let keys = verify_server_keys(raw, server_name)?;
+
+    let object = from_json_str::<CanonicalJsonObject>(raw.json().get())?;
+    verify_signature(&object, &notary.server_name, &notary.verify_keys)?;
+
+    Ok(keys)
+}
+
+/// Verify the signature of the given entity on the object, with the given public keys.
+///
+/// Signatures of other entities, or with other keys, are ignored.
+fn verify_signature(
+    object: &CanonicalJsonObject,
+    entity: &ServerName,
+    public_keys: &PublicKeySet,
+) -> Result<(), KeyError> {
+    let signature_set = match object.get("signatures") {
+        Some(CanonicalJsonValue::Object(signatures)) => match signatures.get(entity.as_str()) {
+            Some(CanonicalJsonValue::Object(signature_set)) => signature_set
+                .iter()
+                .filter(|(key_id, _)| public_keys.contains_key(*key_id))
+                .map(|(key_id, signature)| (key_id.clone(), signature.clone()))
+                .collect::<BTreeMap<_, _>>(),
+            _ => BTreeMap::new(),
+        },
+        _ => BTreeMap::new(),
+    };
+    if signature_set.is_empty() {
+        return Err(KeyError::MissingSignature(entity.to_owned()));
+    }
+
+    let mut object = object.clone();
+    object.insert(
+        "signatures".to_owned(),
+        CanonicalJsonValue::Object(BTreeMap::from([(
+            entity.to_string(),
+            CanonicalJsonValue::Object(signature_set),
+        )])),
+    );
+
+    let public_key_map = PublicKeyMap::from([(entity.to_string(), public_keys.clone())]);
+    verify_json(&public_key_map, &object)?;
+
+    Ok(())
+}
+
+/// The keys that are valid at the given time.
+///
+/// Like in [`ruma_signatures::verify_event_with_key_validity()`], a key is still valid at the
+/// exact time of its `valid_until_ts` or `expired_ts`.
+fn keys_valid_at(keys: &ServerSigningKeys, ts: MilliSecondsSinceUnixEpoch) -> PublicKeySet {
+    let verify_keys = keys
+        .verify_keys
+        .iter()
+        .filter(|_| ts <= keys.valid_until_ts)
+        .map(|(key_id, key)| (key_id.to_string(), key.key.clone()));
+    let old_verify_keys = keys
+        .old_verify_keys
+        .iter()
+        .filter(|(_, key)| ts <= key.expired_ts)
+        .map(|(key_id, key)| (key_id.to_string(), key.key.clone()));
+
+    verify_keys.chain(old_verify_keys).collect()
+}
+
+/// An error encountered when getting the signing keys of a server.
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum KeyError {
+    /// The keys could not be deserialized.
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+
+    /// The keys are for another server than the one that was queried.
+    #[error("the keys are for another server: {0}")]
+    ServerNameMismatch(OwnedServerName),
+
+    /// The keys are not signed by the given server.
+    #[error("the keys are not signed by {0}")]
+    MissingSignature(OwnedServerName),
+
+    /// A signature of the keys is invalid.
+    #[error(transparent)]
+    Signatures(#[from] ruma_signatures::Error),
+
+    /// No keys of the given server that are valid at the requested time could be found.
+    #[error("no valid keys found for {0}")]
+    NoValidKeys(OwnedServerName),
+
+    /// The storage returned an error.
+    #[error("storage error: {0}")]
+    Storage(#[source] Box<dyn StdError + Send + Sync>),
+}
+
+impl KeyError {
+    fn storage<E: StdError + Send + Sync + 'static>(error: E) -> Self {
+        Self::Storage(Box::new(error))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use futures_util::FutureExt;
+    use ruma_common::{
+        serde::{Base64, Raw},
+        server_name, MilliSecondsSinceUnixEpoch, ServerName,
+    };
+    use ruma_federation_api::discovery::{OldVerifyKey, ServerSigningKeys, VerifyKey};
+    use ruma_signatures::{sign_json, Ed25519KeyPair};
+    use serde_json::{from_str as from_json_str, to_string as to_json_string};
+
+    use super::{InMemoryKeyStorage, KeyError, KeyManager, NotaryServer};
+    use crate::test_utils::{key_pair, public_key_set, MockFetcher};
+
+    fn ts(ts: u32) -> MilliSecondsSinceUnixEpoch {
+        MilliSecondsSinceUnixEpoch(ts.into())
+    }
+
+    /// Keys of `server_name` with the given key pair, valid until `valid_until_ts`, signed with
+    /// the given signers.
+    fn server_keys(
+        server_name: &ServerName,
+        key_pair: &Ed25519KeyPair,
+        valid_until_ts: MilliSecondsSinceUnixEpoch,
+        signers: &[(&ServerName, &Ed25519KeyPair)],
+    ) -> Raw<ServerSigningKeys> {
+        let mut keys = ServerSigningKeys::new(server_name.to_owned(), valid_until_ts);
+        keys.verify_keys.insert(
+            "ed25519:1".try_into().unwrap(),
+            VerifyKey::new(Base64::new(key_pair.public_key().to_vec())),
+        );
+        keys.old_verify_keys.insert(
+            "ed25519:0".try_into().unwrap(),
+            OldVerifyKey::new(ts(500), Base64::new(vec![0; 32])),
+        );
+
+        let mut object = from_json_str(&to_json_string(&keys).unwrap()).unwrap();
+        for (signer, key_pair) in signers {
+            sign_json(signer.as_str(), *key_pair, &mut object).unwrap();
+        }
+        Raw::from_json(serde_json::value::to_raw_value(&object).unwrap())
+    }
+
+    #[test]
+    fn fetch_and_store_keys() {
+        let server_name = server_name!("origin.hs.example.com");
+        let key_pair = key_pair("1");
+        let mut fetcher = MockFetcher::default();
+        fetcher.server_keys.insert(
+            server_name.to_owned(),
+            server_keys(server_name, &key_pair, ts(2_000), &[(server_name, &key_pair)]),
+        );
+        let manager = KeyManager::new(InMemoryKeyStorage::new(), fetcher);
+
+        let keys = manager.keys_valid_at(server_name, ts(1_000)).now_or_never().unwrap().unwrap();
+        assert_eq!(keys, public_key_set(&key_pair));
+
+        // The stored keys are used.
+        let keys = manager.keys_valid_at(server_name, ts(1_500)).now_or_never().unwrap().unwrap();
+        assert_eq!(keys, public_key_set(&key_pair));
+        assert_eq!(manager.fetcher.requests.load(Ordering::SeqCst), 1);
+
+        // The keys are still valid at the time they expire.
+        let keys = manager.keys_valid_at(server_name, ts(2_000)).now_or_never().unwrap().unwrap();
+        assert_eq!(keys, public_key_set(&key_pair));
+        assert_eq!(manager.fetcher.requests.load(Ordering::SeqCst), 1);
+
+        // The old key is also valid until it expired.
+        let keys = manager.keys_valid_at(server_name, ts(100)).now_or_never().unwrap().unwrap();
+        assert!(keys.contains_key("ed25519:0"));
+        let keys = manager.keys_valid_at(server_name, ts(500)).now_or_never().unwrap().unwrap();
+        assert!(keys.contains_key("ed25519:0"));
+        let keys = manager.keys_valid_at(server_name, ts(501)).now_or_never().unwrap().unwrap();
+        assert!(!keys.contains_key("ed25519:0"));
+
+        // The timed keys contain all the keys with their validity time.
+        let keys = manager.timed_keys(server_name, ts(1_000)).now_or_never().unwrap().unwrap();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys["ed25519:0"].valid_until_ts, ts(500));
+        assert_eq!(keys["ed25519:1"].valid_until_ts, ts(2_000));
+        assert_eq!(manager.fetcher.requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn reject_keys_not_self_signed() {
+        let server_name = server_name!("origin.hs.example.com");
+        let mut fetcher = MockFetcher::default();
+        fetcher.server_keys.insert(
+            server_name.to_owned(),
+            server_keys(server_name, &key_pair("1"), ts(2_000), &[(server_name, &key_pair("1"))]),
+        );
+        let manager = KeyManager::new(InMemoryKeyStorage::new(), fetcher);
+
+        let result = manager.keys_valid_at(server_name, ts(1_000)).now_or_never().unwrap();
+        assert!(matches!(result, Err(KeyError::NoValidKeys(_))));
+    }
+
+    #[test]
+    fn fall_back_to_notary() {
+        let server_name = server_name!("origin.hs.example.com");
+        let notary_name = server_name!("notary.hs.example.com");
+        let key_pair = key_pair("1");
+        let notary_key_pair = self::key_pair("1");
+
+        let mut fetcher = MockFetcher::default();
+        // The server returns expired keys.
+        fetcher.server_keys.insert(
+            server_name.to_owned(),
+            server_keys(server_name, &key_pair, ts(500), &[(server_name, &key_pair)]),
+        );
+        fetcher.notary_keys.insert(
+            (notary_name.to_owned(), server_name.to_owned()),
+            server_keys(
+                server_name,
+                &key_pair,
+                ts(2_000),
+                &[(server_name, &key_pair), (notary_name, &notary_key_pair)],
+            ),
+        );
+        let manager = KeyManager::new(InMemoryKeyStorage::new(), fetcher).notary_server(
+            NotaryServer::new(notary_name.to_owned(), public_key_set(&notary_key_pair)),
+        );
+
+        let public_key_map =
+            manager.public_key_map([server_name], ts(1_000)).now_or_never().unwrap().unwrap();
+        assert_eq!(public_key_map[server_name.as_str()], public_key_set(&key_pair));
+        assert_eq!(manager.fetcher.requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn reject_notary_keys_not_signed_by_notary() {
+        let server_name = server_name!("origin.hs.example.com");
+        let notary_name = server_name!("notary.hs.example.com");
+        let key_pair = key_pair("1");
+
+        let mut fetcher = MockFetcher::default();
+        fetcher.notary_keys.insert(
+            (notary_name.to_owned(), server_name.to_owned()),
+            server_keys(server_name, &key_pair, ts(2_000), &[(server_name, &key_pair)]),
+        );
+        let manager = KeyManager::new(InMemoryKeyStorage::new(), fetcher).notary_server(
+            NotaryServer::new(notary_name.to_owned(), public_key_set(&self::key_pair("1"))),
+        );
+
+        let result = manager.keys_valid_at(server_name, ts(1_000)).now_or_never().unwrap();
+        assert!(matches!(result, Err(KeyError::NoValidKeys(_))));
+    }
+}
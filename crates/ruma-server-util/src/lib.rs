@@ -4,3 +4,12 @@
 
 #![warn(missing_docs)]
 pub mod authorization;
+pub mod keys;
+pub mod membership;
+pub mod resolution;
+pub mod sending;
+pub mod signing_keys;
+pub mod transactions;
+
+#[cfg(test)]
+mod test_utils;
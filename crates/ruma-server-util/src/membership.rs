@@ -0,0 +1,1475 @@
+//! Membership handshakes with other servers.
+//!
+//! [`MembershipHandshakes`] runs the flows of the server-server API that a homeserver uses to join,
+//! leave or knock on a room through a resident server, and to invite a user of another server:
+//!
+//! * Join: `make_join`, then fill in and sign the template, then `send_join`, then verify the
+//!   signatures of the returned state and auth chain and check them against the authorization
+//!   rules.
+//! * Leave: `make_leave`, then fill in and sign the template, then `send_leave`.
+//! * Knock: `make_knock`, then fill in and sign the template, then `send_knock`.
+//! * Invite: `invite`, then verify the signature of the server of the invited user.
+//!
+//! The requests are sent with a pluggable [`FederationSender`], like the transactions of the
+//! [`TransactionQueue`], and the signatures of the events are verified with the keys of a
+//! [`KeyManager`].
+//!
+//! [`TransactionQueue`]: crate::sending::TransactionQueue
+
+use std::{
+    collections::{btree_map::Entry, BTreeMap, BTreeSet},
+    error::Error as StdError,
+    future::Future,
+};
+
+use js_int::UInt;
+use ruma_common::{
+    canonical_json::redact, serde::Raw, CanonicalJsonObject, CanonicalJsonValue, EventId,
+    MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedRoomId, OwnedServerName, OwnedUserId, RoomId,
+    RoomVersionId, ServerName, TransactionId, UserId,
+};
+use ruma_events::{
+    pdu::Pdu, room::member::MembershipState, AnyStrippedStateEvent, TimelineEventType,
+};
+use ruma_federation_api::{
+    knock::{create_knock_event_template, send_knock},
+    membership::{
+        create_invite, create_join_event, create_leave_event, prepare_join_event,
+        prepare_leave_event,
+    },
+};
+use ruma_signatures::{
+    hash_and_sign_event, verify_event_with_key_validity, verify_json, KeyPair, TimedPublicKeyMap,
+    Verified,
+};
+use ruma_state_res::{check_auth_rules, Event, RoomVersion, StateMap};
+use serde_json::{from_str as from_json_str, value::RawValue as RawJsonValue};
+use thiserror::Error;
+use tracing::warn;
+
+use crate::{
+    keys::{KeyFetcher, KeyManager, KeyStorage},
+    sending::FederationSender,
+    transactions::event_id,
+};
+
+/// Runs the membership handshakes with other servers.
+pub struct MembershipHandshakes<'a, S, K, KS, KF> {
+    sender: &'a S,
+    key_pair: &'a K,
+    key_manager: &'a KeyManager<KS, KF>,
+    supported_room_versions: Vec<RoomVersionId>,
+}
+
+impl<'a, S, K, KS, KF> MembershipHandshakes<'a, S, K, KS, KF>
+where
+    S: FederationSender,
+    K: KeyPair,
+    KS: KeyStorage,
+    KF: KeyFetcher,
+{
+    /// Creates a new `MembershipHandshakes` with the given sender, signing key of the local
+    /// server, and key manager to verify the signatures of other servers.
+    ///
+    /// All the stable room versions are supported by default.
+    pub fn new(sender: &'a S, key_pair: &'a K, key_manager: &'a KeyManager<KS, KF>) -> Self {
+        Self {
+            sender,
+            key_pair,
+            key_manager,
+            supported_room_versions: vec![
+                RoomVersionId::V1,
+                RoomVersionId::V2,
+                RoomVersionId::V3,
+                RoomVersionId::V4,
+                RoomVersionId::V5,
+                RoomVersionId::V6,
+                RoomVersionId::V7,
+                RoomVersionId::V8,
+                RoomVersionId::V9,
+                RoomVersionId::V10,
+                RoomVersionId::V11,
+            ],
+        }
+    }
+
+    /// Set the room versions supported by the local server.
+    ///
+    /// They are sent to the resident server when joining or knocking on a room, and rooms with
+    /// other versions are rejected.
+    pub fn supported_room_versions(mut self, room_versions: Vec<RoomVersionId>) -> Self {
+        self.supported_room_versions = room_versions;
+        self
+    }
+
+    /// Join a room through one of the given resident servers.
+    ///
+    /// The servers are tried in order until one of them returns a template for the join event.
+    ///
+    /// If `omit_members` is `true`, the resident server may omit the `m.room.member` events from
+    /// the state, as described in [MSC3706], and the result is a partial state of the room.
+    ///
+    /// The signatures of the events of the state and auth chain are verified with the keys that
+    /// were valid at the time of each event. The events that have invalid signatures are dropped,
+    /// and those that have an invalid content hash are redacted. Then the events whose auth events
+    /// are missing from the state and auth chain, or that fail the authorization rules based on
+    /// their auth events, are dropped.
+    ///
+    /// In rooms with restricted join rules, the signature of the server of the user that
+    /// authorised the join is verified on the join event returned by the resident server.
+    ///
+    /// [MSC3706]: https://github.com/matrix-org/matrix-spec-proposals/pull/3706
+    pub async fn join_room(
+        &self,
+        room_id: &RoomId,
+        user_id: &UserId,
+        servers: &[OwnedServerName],
+        omit_members: bool,
+    ) -> Result<JoinedRoom, MembershipError<S::Error>> {
+        let (resident_server, template) = first_success(servers, |server| {
+            let mut request =
+                prepare_join_event::v1::Request::new(room_id.to_owned(), user_id.to_owned());
+            request.ver = self.supported_room_versions.clone();
+            async move { self.sender.send_federation_request(&server, request).await }
+        })
+        .await?;
+
+        let room_version = template.room_version.unwrap_or(RoomVersionId::V1);
+        let (event_id, mut event) = self.sign_template(
+            &template.event,
+            &room_version,
+            room_id,
+            user_id,
+            &MembershipState::Join,
+        )?;
+
+        let mut request = create_join_event::v2::Request::new(
+            room_id.to_owned(),
+            event_id.clone(),
+            to_raw_json(&event)?,
+        );
+        request.omit_members = omit_members;
+        let room_state = self
+            .sender
+            .send_federation_request(&resident_server, request)
+            .await
+            .map_err(MembershipError::Request)?
+            .room_state;
+
+        // In rooms with restricted join rules, the server of the authorising user signs the event.
+        if let Some(signed_event) = room_state.event {
+            let signed_event = from_json_str::<CanonicalJsonObject>(signed_event.get())?;
+            if !is_same_event(&event, &signed_event) {
+                return Err(MembershipError::InvalidEvent(
+                    "the event returned by the resident server doesn't match the sent event",
+                ));
+            }
+            let authorising_server =
+                authorising_server(&signed_event).ok_or(MembershipError::InvalidEvent(
+                    "the event returned by the resident server has no authorising user",
+                ))?;
+            self.verify_server_signature(&signed_event, &authorising_server, &room_version).await?;
+            event = signed_event;
+        }
+
+        let state = self.verify_pdus(room_state.state, &room_version).await;
+        let auth_chain = self.verify_pdus(room_state.auth_chain, &room_version).await;
+        let (state, auth_chain) = check_auth_chain(&room_version, state, auth_chain)?;
+
+        let has_create_event = state.iter().chain(&auth_chain).any(|(_, pdu)| {
+            pdu.get("type") == Some(&CanonicalJsonValue::String("m.room.create".to_owned()))
+                && pdu.get("room_id") == Some(&CanonicalJsonValue::String(room_id.to_string()))
+        });
+        if !has_create_event {
+            return Err(MembershipError::MissingCreateEvent);
+        }
+
+        let servers_in_room = room_state
+            .servers_in_room
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|server| OwnedServerName::try_from(server).ok())
+            .collect();
+
+        Ok(JoinedRoom {
+            room_id: room_id.to_owned(),
+            room_version,
+            resident_server,
+            event_id,
+            event,
+            state,
+            auth_chain,
+            members_omitted: room_state.members_omitted,
+            servers_in_room,
+        })
+    }
+
+    /// Leave a room through one of the given resident servers.
+    ///
+    /// The servers are tried in order until one of them returns a template for the leave event.
+    pub async fn leave_room(
+        &self,
+        room_id: &RoomId,
+        user_id: &UserId,
+        servers: &[OwnedServerName],
+    ) -> Result<SentMembershipEvent, MembershipError<S::Error>> {
+        let (resident_server, template) = first_success(servers, |server| {
+            let request =
+                prepare_leave_event::v1::Request::new(room_id.to_owned(), user_id.to_owned());
+            async move { self.sender.send_federation_request(&server, request).await }
+        })
+        .await?;
+
+        let room_version = template.room_version.unwrap_or(RoomVersionId::V1);
+        let (event_id, event) = self.sign_template(
+            &template.event,
+            &room_version,
+            room_id,
+            user_id,
+            &MembershipState::Leave,
+        )?;
+
+        let request = create_leave_event::v2::Request::new(
+            room_id.to_owned(),
+            event_id.clone(),
+            to_raw_json(&event)?,
+        );
+        self.sender
+            .send_federation_request(&resident_server, request)
+            .await
+            .map_err(MembershipError::Request)?;
+
+        Ok(SentMembershipEvent { room_version, resident_server, event_id, event })
+    }
+
+    /// Knock on a room through one of the given resident servers.
+    ///
+    /// The servers are tried in order until one of them returns a template for the knock event.
+    pub async fn knock_room(
+        &self,
+        room_id: &RoomId,
+        user_id: &UserId,
+        servers: &[OwnedServerName],
+    ) -> Result<KnockedRoom, MembershipError<S::Error>> {
+        let (resident_server, template) = first_success(servers, |server| {
+            let mut request = create_knock_event_template::v1::Request::new(
+                room_id.to_owned(),
+                user_id.to_owned(),
+            );
+            request.ver = self.supported_room_versions.clone();
+            async move { self.sender.send_federation_request(&server, request).await }
+        })
+        .await?;
+
+        let room_version = template.room_version;
+        let (event_id, event) = self.sign_template(
+            &template.event,
+            &room_version,
+            room_id,
+            user_id,
+            &MembershipState::Knock,
+        )?;
+
+        let request = send_knock::v1::Request::new(
+            room_id.to_owned(),
+            event_id.clone(),
+            to_raw_json(&event)?,
+        );
+        let knock_room_state = self
+            .sender
+            .send_federation_request(&resident_server, request)
+            .await
+            .map_err(MembershipError::Request)?
+            .knock_room_state;
+
+        Ok(KnockedRoom {
+            event: SentMembershipEvent { room_version, resident_server, event_id, event },
+            knock_room_state,
+        })
+    }
+
+    /// Invite a user of another server to a room.
+    ///
+    /// The invite event must already be hashed and signed by the local server. It is sent to the
+    /// server of the invited user, and the event signed by that server is returned after its
+    /// signature is verified.
+    pub async fn invite_user(
+        &self,
+        room_version: &RoomVersionId,
+        event_id: &EventId,
+        event: &CanonicalJsonObject,
+        invite_room_state: Vec<Raw<AnyStrippedStateEvent>>,
+    ) -> Result<CanonicalJsonObject, MembershipError<S::Error>> {
+        let (Some(CanonicalJsonValue::String(room_id)), Some(CanonicalJsonValue::String(invitee))) =
+            (event.get("room_id"), event.get("state_key"))
+        else {
+            return Err(MembershipError::InvalidEvent(
+                "the invite event is missing required fields",
+            ));
+        };
+        let room_id = OwnedRoomId::try_from(room_id.as_str())
+            .map_err(|_| MembershipError::InvalidEvent("invalid room ID"))?;
+        let invitee = OwnedUserId::try_from(invitee.as_str())
+            .map_err(|_| MembershipError::InvalidEvent("invalid invited user ID"))?;
+        let destination = invitee.server_name();
+
+        let request = create_invite::v2::Request::new(
+            room_id,
+            event_id.to_owned(),
+            room_version.clone(),
+            to_raw_json(event)?,
+            invite_room_state,
+        );
+        let signed_event = self
+            .sender
+            .send_federation_request(destination, request)
+            .await
+            .map_err(MembershipError::Request)?
+            .event;
+        let signed_event = from_json_str::<CanonicalJsonObject>(signed_event.get())?;
+
+        if !is_same_event(event, &signed_event) {
+            return Err(MembershipError::InvalidEvent(
+                "the event returned by the invited user's server doesn't match the sent event",
+            ));
+        }
+
+        // Only check the signature of the invited user's server, the others were already checked.
+        self.verify_server_signature(&signed_event, destination, room_version).await?;
+
+        Ok(signed_event)
+    }
+
+    /// Check that the template of a membership event returned by a resident server is the
+    /// expected membership event of the given user, fill it in, then hash and sign it.
+    fn sign_template(
+        &self,
+        template: &RawJsonValue,
+        room_version: &RoomVersionId,
+        room_id: &RoomId,
+        user_id: &UserId,
+        membership: &MembershipState,
+    ) -> Result<(OwnedEventId, CanonicalJsonObject), MembershipError<S::Error>> {
+        if !self.supported_room_versions.contains(room_version) {
+            return Err(MembershipError::UnsupportedRoomVersion(room_version.clone()));
+        }
+
+        let origin = user_id.server_name();
+        let mut event = from_json_str::<CanonicalJsonObject>(template.get())?;
+        if !is_membership_template(&event, room_id, user_id, membership) {
+            return Err(MembershipError::InvalidEvent(
+                "the template returned by the resident server is not the expected membership event",
+            ));
+        }
+
+        event.insert("origin".to_owned(), CanonicalJsonValue::String(origin.to_string()));
+        event.insert(
+            "origin_server_ts".to_owned(),
+            CanonicalJsonValue::Integer(MilliSecondsSinceUnixEpoch::now().0.into()),
+        );
+        if matches!(room_version, RoomVersionId::V1 | RoomVersionId::V2) {
+            event.insert(
+                "event_id".to_owned(),
+                CanonicalJsonValue::String(format!("${}:{origin}", TransactionId::new())),
+            );
+        }
+
+        hash_and_sign_event(origin.as_str(), self.key_pair, &mut event, room_version)?;
+        let event_id = event_id(&event, room_version)
+            .ok_or(MembershipError::InvalidEvent("could not compute the event ID"))?;
+
+        Ok((event_id, event))
+    }
+
+    /// Verify the signatures and hashes of PDUs returned by a resident server.
+    ///
+    /// The signatures are verified with the keys that were valid at the `origin_server_ts` of each
+    /// PDU. The PDUs with invalid signatures are dropped, and those with an invalid content hash
+    /// are redacted.
+    async fn verify_pdus(
+        &self,
+        pdus: Vec<Box<RawJsonValue>>,
+        room_version: &RoomVersionId,
+    ) -> Vec<(OwnedEventId, CanonicalJsonObject)> {
+        let mut keys = BTreeMap::new();
+        let mut verified_pdus = Vec::new();
+
+        for pdu in pdus {
+            let pdu = match from_json_str::<CanonicalJsonObject>(pdu.get()) {
+                Ok(pdu) => pdu,
+                Err(error) => {
+                    warn!("Dropping PDU that is not a JSON object: {error}");
+                    continue;
+                }
+            };
+            let Some(ts) = origin_server_ts(&pdu) else {
+                warn!("Dropping PDU without a valid origin_server_ts");
+                continue;
+            };
+
+            let servers = match pdu.get("signatures") {
+                Some(CanonicalJsonValue::Object(signatures)) => signatures
+                    .keys()
+                    .filter_map(|server| OwnedServerName::try_from(server.as_str()).ok())
+                    .collect(),
+                _ => Vec::new(),
+            };
+            let mut public_key_map = TimedPublicKeyMap::new();
+            for server in servers {
+                // The keys are fetched once for each server and time.
+                let server_keys = match keys.entry((server.clone(), ts)) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(entry) => {
+                        let (server, ts) = entry.key();
+                        let server_keys = match self.key_manager.timed_keys(server, *ts).await {
+                            Ok(server_keys) => Some(server_keys),
+                            Err(error) => {
+                                warn!("Failed to get the keys of {server}: {error}");
+                                None
+                            }
+                        };
+                        entry.insert(server_keys)
+                    }
+                };
+
+                if let Some(server_keys) = server_keys {
+                    public_key_map.insert(server.to_string(), server_keys.clone());
+                }
+            }
+
+            let pdu = match verify_event_with_key_validity(&public_key_map, &pdu, room_version) {
+                Ok(Verified::All) => pdu,
+                Ok(Verified::Signatures) => match redact(pdu, room_version, None) {
+                    Ok(pdu) => pdu,
+                    Err(error) => {
+                        warn!("Dropping PDU that could not be redacted: {error}");
+                        continue;
+                    }
+                },
+                Err(error) => {
+                    warn!("Dropping PDU with invalid signatures: {error}");
+                    continue;
+                }
+            };
+
+            let Some(event_id) = event_id(&pdu, room_version) else {
+                warn!("Dropping PDU without a valid event ID");
+                continue;
+            };
+            verified_pdus.push((event_id, pdu));
+        }
+
+        verified_pdus
+    }
+
+    /// Verify the signature of the given server on an event.
+    ///
+    /// The other signatures of the event are ignored.
+    async fn verify_server_signature(
+        &self,
+        event: &CanonicalJsonObject,
+        server: &ServerName,
+        room_version: &RoomVersionId,
+    ) -> Result<(), MembershipError<S::Error>> {
+        let ts = origin_server_ts(event).unwrap_or_else(MilliSecondsSinceUnixEpoch::now);
+        let keys = self.key_manager.public_key_map([server], ts).await?;
+        let mut redacted = redact(event.clone(), room_version, None)?;
+        let Some(CanonicalJsonValue::Object(signatures)) = redacted.get_mut("signatures") else {
+            return Err(MembershipError::InvalidEvent("the event is not signed"));
+        };
+        signatures.retain(|signer, _| signer == server.as_str());
+        if signatures.is_empty() {
+            return Err(MembershipError::MissingSignature(server.to_owned()));
+        }
+        verify_json(&keys, &redacted)?;
+
+        Ok(())
+    }
+}
+
+/// Send a request to the given servers in order, until one of them succeeds.
+async fn first_success<T, E, F, Fut>(
+    servers: &[OwnedServerName],
+    mut send: F,
+) -> Result<(OwnedServerName, T), MembershipError<E>>
+where
+    F: FnMut(OwnedServerName) -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+    E: StdError,
+{
+    let mut last_error = None;
+    for server in servers {
+        match send(server.clone()).await {
+            Ok(response) => return Ok((server.clone(), response)),
+            Err(error) => {
+                warn!("Membership request to {server} failed: {error}");
+                last_error = Some(error);
+            }
+        }
+    }
+
+    Err(last_error.map_or(MembershipError::NoServers, MembershipError::Request))
+}
+
+/// Check the state and auth chain returned by a resident server against the authorization rules.
+///
+/// Each event must have all its auth events in the state or auth chain, and must pass the
+/// authorization rules based on them. The events that don't are dropped, as well as the events
+/// that depend on them.
+#[allow(clippy::type_complexity)]
+fn check_auth_chain<E>(
+    room_version_id: &RoomVersionId,
+    state: Vec<(OwnedEventId, CanonicalJsonObject)>,
+    auth_chain: Vec<(OwnedEventId, CanonicalJsonObject)>,
+) -> Result<
+    (Vec<(OwnedEventId, CanonicalJsonObject)>, Vec<(OwnedEventId, CanonicalJsonObject)>),
+    MembershipError<E>,
+> {
+    let room_version = RoomVersion::new(room_version_id)
+        .map_err(|_| MembershipError::UnsupportedRoomVersion(room_version_id.clone()))?;
+
+    let mut events = BTreeMap::new();
+    for (event_id, json) in state.iter().chain(&auth_chain) {
+        match RoomPdu::new(event_id, json) {
+            Ok(pdu) => {
+                events.insert(event_id.clone(), pdu);
+            }
+            Err(error) => warn!("Dropping PDU {event_id} that is not a valid event: {error}"),
+        }
+    }
+
+    // Check the events in topological order of their auth events. The depth is only used to
+    // check the events that are most likely to be ready first.
+    let mut pending = events.values().collect::<Vec<_>>();
+    pending.sort_by_key(|pdu| pdu.depth());
+    let mut accepted = BTreeSet::new();
+    let mut dropped = BTreeSet::new();
+
+    loop {
+        let mut ready = Vec::new();
+        let mut waiting = Vec::new();
+        let mut progress = false;
+
+        for pdu in pending {
+            let mut auth_events = Vec::new();
+            let mut missing = false;
+            let mut is_ready = true;
+            for auth_event_id in pdu.auth_events() {
+                if accepted.contains(auth_event_id) {
+                    auth_events.push(&events[auth_event_id]);
+                } else if !events.contains_key(auth_event_id) || dropped.contains(auth_event_id) {
+                    missing = true;
+                } else {
+                    is_ready = false;
+                }
+            }
+
+            if missing {
+                // Either the auth event is missing from the auth chain, or it was dropped.
+                warn!("Dropping PDU {} with missing or invalid auth events", pdu.event_id());
+                dropped.insert(pdu.event_id().clone());
+                progress = true;
+            } else if is_ready {
+                ready.push((pdu, auth_events));
+            } else {
+                waiting.push(pdu);
+            }
+        }
+
+        for (pdu, auth_events) in ready {
+            progress = true;
+
+            let auth_state = auth_events
+                .into_iter()
+                .filter_map(|auth_event| {
+                    let state_key = auth_event.state_key()?;
+                    let event_type = auth_event.event_type().to_string().into();
+                    Some(((event_type, state_key.to_owned()), auth_event))
+                })
+                .collect::<StateMap<_>>();
+            let third_party_invite = auth_state.values().find(|auth_event| {
+                *auth_event.event_type() == TimelineEventType::RoomThirdPartyInvite
+            });
+
+            match check_auth_rules(&room_version, pdu, third_party_invite.copied(), |ty, key| {
+                auth_state.get(&(ty.clone(), key.to_owned())).copied()
+            }) {
+                Ok(None) => {
+                    accepted.insert(pdu.event_id().clone());
+                }
+                Ok(Some(rejection)) => {
+                    warn!(
+                        "Dropping PDU {} that fails the authorization rules: {rejection:?}",
+                        pdu.event_id()
+                    );
+                    dropped.insert(pdu.event_id().clone());
+                }
+                Err(error) => {
+                    warn!("Dropping PDU {} that could not be authorized: {error}", pdu.event_id());
+                    dropped.insert(pdu.event_id().clone());
+                }
+            }
+        }
+
+        if waiting.is_empty() {
+            break;
+        }
+        if !progress {
+            warn!("Dropping {} PDUs with a cycle in their auth events", waiting.len());
+            break;
+        }
+        pending = waiting;
+    }
+
+    let retain_accepted = |pdus: Vec<(OwnedEventId, CanonicalJsonObject)>| {
+        pdus.into_iter().filter(|(event_id, _)| accepted.contains(event_id)).collect()
+    };
+    Ok((retain_accepted(state), retain_accepted(auth_chain)))
+}
+
+/// The server of the user that authorised a join event in a room with restricted join rules.
+fn authorising_server(event: &CanonicalJsonObject) -> Option<OwnedServerName> {
+    let Some(CanonicalJsonValue::Object(content)) = event.get("content") else {
+        return None;
+    };
+    let Some(CanonicalJsonValue::String(user_id)) = content.get("join_authorised_via_users_server")
+    else {
+        return None;
+    };
+
+    Some(<&UserId>::try_from(user_id.as_str()).ok()?.server_name().to_owned())
+}
+
+/// Whether the given event is a membership event of the given user in the given room, sent by
+/// that user, with the given membership.
+fn is_membership_template(
+    event: &CanonicalJsonObject,
+    room_id: &RoomId,
+    user_id: &UserId,
+    membership: &MembershipState,
+) -> bool {
+    let is_string = |object: &CanonicalJsonObject, key: &str, expected: &str| matches!(object.get(key), Some(CanonicalJsonValue::String(value)) if value == expected);
+    let Some(CanonicalJsonValue::Object(content)) = event.get("content") else {
+        return false;
+    };
+
+    is_string(event, "type", "m.room.member")
+        && is_string(event, "room_id", room_id.as_str())
+        && is_string(event, "sender", user_id.as_str())
+        && is_string(event, "state_key", user_id.as_str())
+        && is_string(content, "membership", membership.as_str())
+}
+
+/// Whether the two events are the same, ignoring their signatures and unsigned data.
+fn is_same_event(a: &CanonicalJsonObject, b: &CanonicalJsonObject) -> bool {
+    let strip = |event: &CanonicalJsonObject| {
+        let mut event = event.clone();
+        event.remove("signatures");
+        event.remove("unsigned");
+        event
+    };
+    strip(a) == strip(b)
+}
+
+fn origin_server_ts(event: &CanonicalJsonObject) -> Option<MilliSecondsSinceUnixEpoch> {
+    match event.get("origin_server_ts") {
+        Some(CanonicalJsonValue::Integer(ts)) => {
+            ts.to_string().parse().ok().map(MilliSecondsSinceUnixEpoch)
+        }
+        _ => None,
+    }
+}
+
+fn to_raw_json<E>(event: &CanonicalJsonObject) -> Result<Box<RawJsonValue>, MembershipError<E>> {
+    Ok(serde_json::value::to_raw_value(event)?)
+}
+
+/// A PDU of the state or auth chain of a room, to check it against the authorization rules.
+struct RoomPdu {
+    event_id: OwnedEventId,
+    pdu: Pdu,
+}
+
+impl RoomPdu {
+    fn new(event_id: &EventId, json: &CanonicalJsonObject) -> serde_json::Result<Self> {
+        // Deserialize from a string, because the content of the PDU is raw JSON.
+        let pdu = from_json_str(&serde_json::to_string(json)?)?;
+        Ok(Self { event_id: event_id.to_owned(), pdu })
+    }
+}
+
+impl Event for RoomPdu {
+    type Id = OwnedEventId;
+
+    fn event_id(&self) -> &Self::Id {
+        &self.event_id
+    }
+
+    fn room_id(&self) -> &RoomId {
+        match &self.pdu {
+            Pdu::RoomV1Pdu(pdu) => &pdu.room_id,
+            Pdu::RoomV3Pdu(pdu) => &pdu.room_id,
+            #[allow(unreachable_patterns)]
+            _ => unreachable!("new PDU version"),
+        }
+    }
+
+    fn sender(&self) -> &UserId {
+        match &self.pdu {
+            Pdu::RoomV1Pdu(pdu) => &pdu.sender,
+            Pdu::RoomV3Pdu(pdu) => &pdu.sender,
+            #[allow(unreachable_patterns)]
+            _ => unreachable!("new PDU version"),
+        }
+    }
+
+    fn origin_server_ts(&self) -> MilliSecondsSinceUnixEpoch {
+        match &self.pdu {
+            Pdu::RoomV1Pdu(pdu) => pdu.origin_server_ts,
+            Pdu::RoomV3Pdu(pdu) => pdu.origin_server_ts,
+            #[allow(unreachable_patterns)]
+            _ => unreachable!("new PDU version"),
+        }
+    }
+
+    fn event_type(&self) -> &TimelineEventType {
+        match &self.pdu {
+            Pdu::RoomV1Pdu(pdu) => &pdu.kind,
+            Pdu::RoomV3Pdu(pdu) => &pdu.kind,
+            #[allow(unreachable_patterns)]
+            _ => unreachable!("new PDU version"),
+        }
+    }
+
+    fn content(&self) -> &RawJsonValue {
+        match &self.pdu {
+            Pdu::RoomV1Pdu(pdu) => &pdu.content,
+            Pdu::RoomV3Pdu(pdu) => &pdu.content,
+            #[allow(unreachable_patterns)]
+            _ => unreachable!("new PDU version"),
+        }
+    }
+
+    fn state_key(&self) -> Option<&str> {
+        match &self.pdu {
+            Pdu::RoomV1Pdu(pdu) => pdu.state_key.as_deref(),
+            Pdu::RoomV3Pdu(pdu) => pdu.state_key.as_deref(),
+            #[allow(unreachable_patterns)]
+            _ => unreachable!("new PDU version"),
+        }
+    }
+
+    fn depth(&self) -> UInt {
+        match &self.pdu {
+            Pdu::RoomV1Pdu(pdu) => pdu.depth,
+            Pdu::RoomV3Pdu(pdu) => pdu.depth,
+            #[allow(unreachable_patterns)]
+            _ => unreachable!("new PDU version"),
+        }
+    }
+
+    fn prev_events(&self) -> Box<dyn DoubleEndedIterator<Item = &Self::Id> + '_> {
+        match &self.pdu {
+            Pdu::RoomV1Pdu(pdu) => Box::new(pdu.prev_events.iter().map(|(id, _)| id)),
+            Pdu::RoomV3Pdu(pdu) => Box::new(pdu.prev_events.iter()),
+            #[allow(unreachable_patterns)]
+            _ => unreachable!("new PDU version"),
+        }
+    }
+
+    fn auth_events(&self) -> Box<dyn DoubleEndedIterator<Item = &Self::Id> + '_> {
+        match &self.pdu {
+            Pdu::RoomV1Pdu(pdu) => Box::new(pdu.auth_events.iter().map(|(id, _)| id)),
+            Pdu::RoomV3Pdu(pdu) => Box::new(pdu.auth_events.iter()),
+            #[allow(unreachable_patterns)]
+            _ => unreachable!("new PDU version"),
+        }
+    }
+
+    fn redacts(&self) -> Option<&Self::Id> {
+        match &self.pdu {
+            Pdu::RoomV1Pdu(pdu) => pdu.redacts.as_ref(),
+            Pdu::RoomV3Pdu(pdu) => pdu.redacts.as_ref(),
+            #[allow(unreachable_patterns)]
+            _ => unreachable!("new PDU version"),
+        }
+    }
+}
+
+/// A room that was joined with [`MembershipHandshakes::join_room()`].
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct JoinedRoom {
+    /// The ID of the room.
+    pub room_id: OwnedRoomId,
+
+    /// The version of the room.
+    pub room_version: RoomVersionId,
+
+    /// The resident server that accepted the join.
+    pub resident_server: OwnedServerName,
+
+    /// The ID of the join event.
+    pub event_id: OwnedEventId,
+
+    /// The join event, signed by the local server and, in rooms with restricted join rules, by
+    /// the resident server.
+    pub event: CanonicalJsonObject,
+
+    /// The state of the room before the join event, with verified signatures and authorized
+    /// against the auth chain.
+    pub state: Vec<(OwnedEventId, CanonicalJsonObject)>,
+
+    /// The auth chain of the state and the join event, with verified signatures and authorized
+    /// against itself.
+    pub auth_chain: Vec<(OwnedEventId, CanonicalJsonObject)>,
+
+    /// Whether the `m.room.member` events were omitted from the state.
+    ///
+    /// If this is `true`, the state is partial and the full state must be fetched later.
+    pub members_omitted: bool,
+
+    /// The servers in the room before the join, if `members_omitted` is `true`.
+    pub servers_in_room: Vec<OwnedServerName>,
+}
+
+/// A membership event that was sent to a resident server.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct SentMembershipEvent {
+    /// The version of the room.
+    pub room_version: RoomVersionId,
+
+    /// The resident server that accepted the event.
+    pub resident_server: OwnedServerName,
+
+    /// The ID of the event.
+    pub event_id: OwnedEventId,
+
+    /// The event, signed by the local server.
+    pub event: CanonicalJsonObject,
+}
+
+/// A room that was knocked on with [`MembershipHandshakes::knock_room()`].
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct KnockedRoom {
+    /// The knock event.
+    pub event: SentMembershipEvent,
+
+    /// The stripped state of the room returned by the resident server.
+    pub knock_room_state: Vec<Raw<AnyStrippedStateEvent>>,
+}
+
+/// An error during a membership handshake.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum MembershipError<E> {
+    /// No server was given to send the requests to.
+    #[error("no server to send the request to")]
+    NoServers,
+
+    /// A request failed.
+    #[error("request failed: {0}")]
+    Request(#[source] E),
+
+    /// The room version is not supported by the local server.
+    #[error("unsupported room version {0}")]
+    UnsupportedRoomVersion(RoomVersionId),
+
+    /// An event is invalid.
+    #[error("invalid event: {0}")]
+    InvalidEvent(&'static str),
+
+    /// An event is not signed by a server that must sign it.
+    #[error("the event is not signed by {0}")]
+    MissingSignature(OwnedServerName),
+
+    /// The state of the room doesn't contain its `m.room.create` event.
+    #[error("the state of the room doesn't contain its m.room.create event")]
+    MissingCreateEvent,
+
+    /// An event is not valid JSON.
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+
+    /// An event could not be redacted.
+    #[error(transparent)]
+    Redaction(#[from] ruma_common::canonical_json::RedactionError),
+
+    /// An event could not be signed, or a signature is invalid.
+    #[error(transparent)]
+    Signatures(#[from] ruma_signatures::Error),
+
+    /// The keys of a server could not be fetched.
+    #[error(transparent)]
+    Keys(#[from] crate::keys::KeyError),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use async_trait::async_trait;
+    use futures_util::FutureExt;
+    use js_int::UInt;
+    use ruma_common::{
+        api::{IncomingResponse, MatrixVersion, OutgoingRequest, SendAccessToken},
+        owned_server_name, room_id, server_name, user_id, CanonicalJsonObject, CanonicalJsonValue,
+        MilliSecondsSinceUnixEpoch, OwnedEventId, RoomVersionId, ServerName,
+    };
+    use ruma_signatures::{hash_and_sign_event, Ed25519KeyPair};
+    use serde_json::{from_slice as from_json_slice, json, Value as JsonValue};
+
+    use super::{JoinedRoom, MembershipError, MembershipHandshakes};
+    use crate::{
+        keys::{InMemoryKeyStorage, KeyManager},
+        sending::FederationSender,
+        signing_keys::SigningKeyRing,
+        test_utils::{key_pair, MockFetcher},
+    };
+
+    #[derive(Debug, thiserror::Error)]
+    #[error("request failed")]
+    struct RequestFailed;
+
+    type Handler = dyn Fn(&ServerName, &str, JsonValue) -> Option<JsonValue> + Send + Sync;
+
+    /// A sender that answers the requests with a handler, from the destination, path and body.
+    struct MockSender(Box<Handler>);
+
+    #[async_trait]
+    impl FederationSender for MockSender {
+        type Error = RequestFailed;
+
+        async fn send_federation_request<R>(
+            &self,
+            destination: &ServerName,
+            request: R,
+        ) -> Result<R::IncomingResponse, Self::Error>
+        where
+            R: OutgoingRequest + Send,
+            R::IncomingResponse: Send,
+        {
+            let request = request
+                .try_into_http_request::<Vec<u8>>(
+                    "https://localhost",
+                    SendAccessToken::None,
+                    &[MatrixVersion::V1_0],
+                )
+                .unwrap();
+            let body = if request.body().is_empty() {
+                JsonValue::Null
+            } else {
+                from_json_slice(request.body()).unwrap()
+            };
+            let path_and_query = request.uri().path_and_query().unwrap().as_str();
+
+            let response = (self.0)(destination, path_and_query, body).ok_or(RequestFailed)?;
+            let response =
+                http::Response::builder().body(serde_json::to_vec(&response).unwrap()).unwrap();
+            Ok(R::IncomingResponse::try_from_http_response(response).unwrap())
+        }
+    }
+
+    fn key_manager(rings: &[&SigningKeyRing]) -> KeyManager<InMemoryKeyStorage, MockFetcher> {
+        let valid_until_ts = MilliSecondsSinceUnixEpoch(UInt::MAX);
+        let keys = rings
+            .iter()
+            .map(|ring| (ring.server_name().to_owned(), ring.server_keys(valid_until_ts).unwrap()))
+            .collect();
+        KeyManager::new(InMemoryKeyStorage::new(), MockFetcher::new(keys))
+    }
+
+    fn object(value: JsonValue) -> CanonicalJsonObject {
+        match CanonicalJsonValue::try_from(value).unwrap() {
+            CanonicalJsonValue::Object(object) => object,
+            _ => unreachable!(),
+        }
+    }
+
+    fn signed_pdu(ring: &SigningKeyRing, value: JsonValue) -> CanonicalJsonObject {
+        signed_pdu_with_key(ring.server_name(), ring.active_key(), value)
+    }
+
+    fn signed_pdu_with_key(
+        server_name: &ServerName,
+        key_pair: &Ed25519KeyPair,
+        value: JsonValue,
+    ) -> CanonicalJsonObject {
+        let mut pdu = object(value);
+        hash_and_sign_event(server_name.as_str(), key_pair, &mut pdu, &RoomVersionId::V10).unwrap();
+        pdu
+    }
+
+    fn pdu_id(pdu: &CanonicalJsonObject) -> OwnedEventId {
+        crate::transactions::event_id(pdu, &RoomVersionId::V10).unwrap()
+    }
+
+    const ROOM_ID: &str = "!room:resident.local";
+
+    /// A state event of the room, sent by `sender` at `ts`.
+    fn state_event(
+        ty: &str,
+        sender: &str,
+        state_key: &str,
+        content: JsonValue,
+        ts: u32,
+        auth_events: &[&OwnedEventId],
+    ) -> JsonValue {
+        json!({
+            "type": ty,
+            "room_id": ROOM_ID,
+            "sender": sender,
+            "state_key": state_key,
+            "content": content,
+            "origin_server_ts": ts,
+            "depth": ts,
+            "auth_events": auth_events,
+            "prev_events": auth_events,
+        })
+    }
+
+    /// The create event of the room and the join event of its creator.
+    fn initial_events(
+        server_name: &ServerName,
+        key_pair: &Ed25519KeyPair,
+    ) -> (CanonicalJsonObject, CanonicalJsonObject) {
+        let create = signed_pdu_with_key(
+            server_name,
+            key_pair,
+            state_event(
+                "m.room.create",
+                "@creator:resident.local",
+                "",
+                json!({ "creator": "@creator:resident.local", "room_version": "10" }),
+                1,
+                &[],
+            ),
+        );
+        let member = signed_pdu_with_key(
+            server_name,
+            key_pair,
+            state_event(
+                "m.room.member",
+                "@creator:resident.local",
+                "@creator:resident.local",
+                json!({ "membership": "join" }),
+                2,
+                &[&pdu_id(&create)],
+            ),
+        );
+        (create, member)
+    }
+
+    /// A resident server that answers the `make_join` request with a template with the given
+    /// content, and the `send_join` request with the given state and auth chain.
+    ///
+    /// If `sign_event` is set, the join event is returned, signed with the given key.
+    fn resident_sender(
+        join_content: JsonValue,
+        state: Vec<CanonicalJsonObject>,
+        auth_chain: Vec<CanonicalJsonObject>,
+        sign_event: Option<Ed25519KeyPair>,
+    ) -> MockSender {
+        MockSender(Box::new(move |destination, path, body| {
+            if destination != "resident.local" {
+                return None;
+            }
+
+            if path.contains("/make_join/") {
+                assert!(path.contains("ver="));
+                Some(json!({
+                    "room_version": "10",
+                    "event": {
+                        "type": "m.room.member",
+                        "room_id": ROOM_ID,
+                        "sender": "@alice:local.test",
+                        "state_key": "@alice:local.test",
+                        "content": join_content,
+                        "depth": 3,
+                        "auth_events": [],
+                        "prev_events": [],
+                    },
+                }))
+            } else if path.contains("/send_join/") {
+                assert!(body["signatures"]["local.test"].is_object());
+                let event = sign_event.as_ref().map(|key_pair| {
+                    let mut event = object(body.clone());
+                    ruma_signatures::sign_json("resident.local", key_pair, &mut event).unwrap();
+                    event
+                });
+                Some(json!({
+                    "origin": "resident.local",
+                    "auth_chain": auth_chain,
+                    "state": state,
+                    "event": event,
+                    "members_omitted": path.ends_with("omit_members=true"),
+                    "servers_in_room": ["resident.local"],
+                }))
+            } else {
+                None
+            }
+        }))
+    }
+
+    fn join(
+        sender: &MockSender,
+        key_manager: &KeyManager<InMemoryKeyStorage, MockFetcher>,
+        omit_members: bool,
+    ) -> Result<JoinedRoom, MembershipError<RequestFailed>> {
+        let local_key = key_pair("1");
+        MembershipHandshakes::new(sender, &local_key, key_manager)
+            .join_room(
+                room_id!("!room:resident.local"),
+                user_id!("@alice:local.test"),
+                &[owned_server_name!("down.local"), owned_server_name!("resident.local")],
+                omit_members,
+            )
+            .now_or_never()
+            .unwrap()
+    }
+
+    fn state_ids(pdus: &[(OwnedEventId, CanonicalJsonObject)]) -> BTreeSet<OwnedEventId> {
+        pdus.iter().map(|(event_id, _)| event_id.clone()).collect()
+    }
+
+    #[test]
+    fn join_room_through_second_server() {
+        let resident =
+            SigningKeyRing::new(owned_server_name!("resident.local"), key_pair("1")).unwrap();
+        let key_manager = key_manager(&[&resident]);
+
+        let (create, member) = initial_events(resident.server_name(), resident.active_key());
+        let mut forged_event = signed_pdu(
+            &resident,
+            state_event(
+                "m.room.name",
+                "@creator:resident.local",
+                "",
+                json!({ "name": "Room" }),
+                3,
+                &[&pdu_id(&create), &pdu_id(&member)],
+            ),
+        );
+        forged_event.insert("sender".to_owned(), "@mallory:resident.local".to_owned().into());
+
+        let sender = resident_sender(
+            json!({ "membership": "join" }),
+            vec![create.clone(), member.clone(), forged_event],
+            Vec::new(),
+            None,
+        );
+        let joined = join(&sender, &key_manager, true).unwrap();
+
+        assert_eq!(joined.room_version, RoomVersionId::V10);
+        assert_eq!(joined.resident_server, "resident.local");
+        assert!(joined.members_omitted);
+        assert_eq!(joined.servers_in_room, [server_name!("resident.local")]);
+        // The forged event was dropped.
+        assert_eq!(state_ids(&joined.state), BTreeSet::from([pdu_id(&create), pdu_id(&member)]));
+
+        // Unsupported room versions are rejected.
+        let local_key = key_pair("1");
+        let handshakes = MembershipHandshakes::new(&sender, &local_key, &key_manager)
+            .supported_room_versions(vec![RoomVersionId::V9]);
+        let error = handshakes
+            .join_room(
+                room_id!("!room:resident.local"),
+                user_id!("@alice:local.test"),
+                &[resident.server_name().to_owned()],
+                false,
+            )
+            .now_or_never()
+            .unwrap()
+            .unwrap_err();
+        assert!(matches!(error, MembershipError::UnsupportedRoomVersion(RoomVersionId::V10)));
+    }
+
+    #[test]
+    fn mismatched_template_is_rejected() {
+        let resident =
+            SigningKeyRing::new(owned_server_name!("resident.local"), key_pair("1")).unwrap();
+        let key_manager = key_manager(&[&resident]);
+        let local_key = key_pair("1");
+
+        // A resident server that returns a join template with the given field replaced.
+        let template_sender = |field: &str, value: JsonValue| {
+            let mut event = json!({
+                "type": "m.room.member",
+                "room_id": ROOM_ID,
+                "sender": "@alice:local.test",
+                "state_key": "@alice:local.test",
+                "content": { "membership": "join" },
+                "depth": 3,
+                "auth_events": [],
+                "prev_events": [],
+            });
+            event[field] = value;
+            MockSender(Box::new(move |_, path, _| {
+                assert!(path.contains("/make_join/") || path.contains("/make_leave/"));
+                Some(json!({ "room_version": "10", "event": event }))
+            }))
+        };
+
+        for (field, value) in [
+            ("type", json!("m.room.name")),
+            ("room_id", json!("!other:resident.local")),
+            ("sender", json!("@mallory:resident.local")),
+            ("state_key", json!("@mallory:resident.local")),
+            ("content", json!({ "membership": "invite" })),
+        ] {
+            let sender = template_sender(field, value);
+            let error = MembershipHandshakes::new(&sender, &local_key, &key_manager)
+                .join_room(
+                    room_id!("!room:resident.local"),
+                    user_id!("@alice:local.test"),
+                    &[resident.server_name().to_owned()],
+                    false,
+                )
+                .now_or_never()
+                .unwrap()
+                .unwrap_err();
+            assert!(matches!(error, MembershipError::InvalidEvent(_)), "{field}: {error}");
+        }
+
+        // A join template is not a valid leave template.
+        let sender = template_sender("depth", json!(3));
+        let error = MembershipHandshakes::new(&sender, &local_key, &key_manager)
+            .leave_room(
+                room_id!("!room:resident.local"),
+                user_id!("@alice:local.test"),
+                &[resident.server_name().to_owned()],
+            )
+            .now_or_never()
+            .unwrap()
+            .unwrap_err();
+        assert!(matches!(error, MembershipError::InvalidEvent(_)));
+    }
+
+    #[test]
+    fn join_room_checks_auth_rules() {
+        let resident =
+            SigningKeyRing::new(owned_server_name!("resident.local"), key_pair("1")).unwrap();
+        let key_manager = key_manager(&[&resident]);
+
+        let (create, member) = initial_events(resident.server_name(), resident.active_key());
+        let name = signed_pdu(
+            &resident,
+            state_event(
+                "m.room.name",
+                "@creator:resident.local",
+                "",
+                json!({ "name": "Room" }),
+                3,
+                &[&pdu_id(&create), &pdu_id(&member)],
+            ),
+        );
+        // The sender is not in the room.
+        let outsider_topic = signed_pdu(
+            &resident,
+            state_event(
+                "m.room.topic",
+                "@outsider:resident.local",
+                "",
+                json!({ "topic": "Hello" }),
+                4,
+                &[&pdu_id(&create)],
+            ),
+        );
+        // An auth event is not in the auth chain.
+        let missing_event_id = OwnedEventId::try_from("$missing").unwrap();
+        let missing_auth_event = signed_pdu(
+            &resident,
+            state_event(
+                "m.room.topic",
+                "@creator:resident.local",
+                "",
+                json!({ "topic": "Hello" }),
+                5,
+                &[&pdu_id(&create), &missing_event_id],
+            ),
+        );
+        // An auth event was dropped.
+        let outsider_member = signed_pdu(
+            &resident,
+            state_event(
+                "m.room.member",
+                "@outsider:resident.local",
+                "@outsider:resident.local",
+                json!({ "membership": "leave" }),
+                6,
+                &[&pdu_id(&create), &pdu_id(&outsider_topic)],
+            ),
+        );
+
+        let sender = resident_sender(
+            json!({ "membership": "join" }),
+            vec![name.clone(), outsider_topic, missing_auth_event, outsider_member],
+            vec![create.clone(), member.clone()],
+            None,
+        );
+        let joined = join(&sender, &key_manager, false).unwrap();
+
+        assert_eq!(state_ids(&joined.state), BTreeSet::from([pdu_id(&name)]));
+        assert_eq!(
+            state_ids(&joined.auth_chain),
+            BTreeSet::from([pdu_id(&create), pdu_id(&member)])
+        );
+    }
+
+    #[test]
+    fn join_room_checks_key_validity_at_event_time() {
+        let old_der = Ed25519KeyPair::generate().unwrap();
+        let old_key = Ed25519KeyPair::from_der(&old_der, "1".to_owned()).unwrap();
+        let mut resident = SigningKeyRing::new(
+            owned_server_name!("resident.local"),
+            Ed25519KeyPair::from_der(&old_der, "1".to_owned()).unwrap(),
+        )
+        .unwrap();
+        resident.rotate(key_pair("2"), MilliSecondsSinceUnixEpoch(3_u32.into())).unwrap();
+        let key_manager = key_manager(&[&resident]);
+
+        // The old key was still valid for these events.
+        let (create, member) = initial_events(resident.server_name(), &old_key);
+        // The old key had expired for this event.
+        let name = signed_pdu_with_key(
+            resident.server_name(),
+            &old_key,
+            state_event(
+                "m.room.name",
+                "@creator:resident.local",
+                "",
+                json!({ "name": "Room" }),
+                4,
+                &[&pdu_id(&create), &pdu_id(&member)],
+            ),
+        );
+        // The new key is valid for this event.
+        let topic = signed_pdu(
+            &resident,
+            state_event(
+                "m.room.topic",
+                "@creator:resident.local",
+                "",
+                json!({ "topic": "Hello" }),
+                5,
+                &[&pdu_id(&create), &pdu_id(&member)],
+            ),
+        );
+
+        let sender = resident_sender(
+            json!({ "membership": "join" }),
+            vec![create.clone(), member.clone(), name, topic.clone()],
+            Vec::new(),
+            None,
+        );
+        let joined = join(&sender, &key_manager, false).unwrap();
+
+        assert_eq!(
+            state_ids(&joined.state),
+            BTreeSet::from([pdu_id(&create), pdu_id(&member), pdu_id(&topic)])
+        );
+    }
+
+    #[test]
+    fn join_restricted_room_verifies_signature_of_resident_server() {
+        let resident_der = Ed25519KeyPair::generate().unwrap();
+        let resident_key = || Ed25519KeyPair::from_der(&resident_der, "1".to_owned()).unwrap();
+        let resident =
+            SigningKeyRing::new(owned_server_name!("resident.local"), resident_key()).unwrap();
+        let key_manager = key_manager(&[&resident]);
+
+        let (create, member) = initial_events(resident.server_name(), resident.active_key());
+        let content = json!({
+            "membership": "join",
+            "join_authorised_via_users_server": "@creator:resident.local",
+        });
+        let state = vec![create, member];
+
+        let sender =
+            resident_sender(content.clone(), state.clone(), Vec::new(), Some(resident_key()));
+        let joined = join(&sender, &key_manager, false).unwrap();
+        let CanonicalJsonValue::Object(signatures) = &joined.event["signatures"] else {
+            panic!("signatures should be an object");
+        };
+        assert!(signatures.contains_key("local.test"));
+        assert!(signatures.contains_key("resident.local"));
+
+        // The event is signed with another key.
+        let sender =
+            resident_sender(content.clone(), state.clone(), Vec::new(), Some(key_pair("1")));
+        assert!(matches!(
+            join(&sender, &key_manager, false).unwrap_err(),
+            MembershipError::Signatures(_)
+        ));
+
+        // The event is not signed by the resident server.
+        let sender = resident_sender(content, state, Vec::new(), Some(key_pair("1")));
+        let sender = MockSender(Box::new(move |destination, path, body| {
+            let mut response = (sender.0)(destination, path, body)?;
+            if let Some(signatures) = response.pointer_mut("/event/signatures") {
+                signatures.as_object_mut().unwrap().remove("resident.local");
+            }
+            Some(response)
+        }));
+        assert!(matches!(
+            join(&sender, &key_manager, false).unwrap_err(),
+            MembershipError::MissingSignature(server) if server == "resident.local"
+        ));
+    }
+
+    #[test]
+    fn invite_user_verifies_signature_of_invitee_server() {
+        let local = SigningKeyRing::new(owned_server_name!("local.test"), key_pair("1")).unwrap();
+        let remote_der = Ed25519KeyPair::generate().unwrap();
+        let remote_key = || Ed25519KeyPair::from_der(&remote_der, "1".to_owned()).unwrap();
+        let remote = SigningKeyRing::new(owned_server_name!("remote.local"), remote_key()).unwrap();
+        let key_manager = key_manager(&[&remote]);
+
+        let event = signed_pdu(
+            &local,
+            json!({
+                "type": "m.room.member",
+                "room_id": "!room:local.test",
+                "sender": "@alice:local.test",
+                "state_key": "@bob:remote.local",
+                "content": { "membership": "invite" },
+                "origin_server_ts": 1,
+                "depth": 3,
+                "auth_events": [],
+                "prev_events": [],
+            }),
+        );
+        let event_id = crate::transactions::event_id(&event, &RoomVersionId::V10).unwrap();
+
+        let invite = |tamper: bool| {
+            let remote_key = remote_key();
+            let sender = MockSender(Box::new(move |destination, path, body| {
+                assert_eq!(destination, "remote.local");
+                assert!(path.contains("/invite/"));
+
+                let mut event = object(body["event"].clone());
+                ruma_signatures::sign_json("remote.local", &remote_key, &mut event).unwrap();
+                if tamper {
+                    event.insert("depth".to_owned(), CanonicalJsonValue::Integer(4_u32.into()));
+                }
+                Some(json!({ "event": event }))
+            }));
+            let handshakes = MembershipHandshakes::new(&sender, local.active_key(), &key_manager);
+            handshakes
+                .invite_user(&RoomVersionId::V10, &event_id, &event, Vec::new())
+                .now_or_never()
+                .unwrap()
+        };
+
+        let signed_event = invite(false).unwrap();
+        let CanonicalJsonValue::Object(signatures) = &signed_event["signatures"] else {
+            panic!("signatures should be an object");
+        };
+        assert!(signatures.contains_key("local.test"));
+        assert!(signatures.contains_key("remote.local"));
+
+        assert!(matches!(invite(true).unwrap_err(), MembershipError::InvalidEvent(_)));
+    }
+}
@@ -0,0 +1,556 @@
+//! Resolution of server names.
+//!
+//! The [`ServerResolver`] implements the [server discovery algorithm] to find the address to
+//! connect to for a server name, and the `Host` header and TLS server name to use. The DNS and
+//! HTTP requests are made by a pluggable [`ResolverBackend`].
+//!
+//! [server discovery algorithm]: https://spec.matrix.org/latest/server-server-api/#resolving-server-names
+
+use std::{
+    collections::BTreeMap,
+    error::Error as StdError,
+    fmt,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime},
+};
+
+use async_trait::async_trait;
+use headers::{CacheControl, Expires, HeaderMapExt};
+use ruma_common::{OwnedServerName, ServerName};
+use tracing::{debug, warn};
+
+/// The default port of the federation API.
+pub const DEFAULT_PORT: u16 = 8448;
+
+/// The maximum duration for which `.well-known` delegations are cached, whatever the caching
+/// headers of the response.
+pub const MAX_WELL_KNOWN_CACHE_DURATION: Duration = Duration::from_secs(48 * 60 * 60);
+
+/// A backend for the DNS and HTTP requests of the [`ServerResolver`].
+#[async_trait]
+pub trait ResolverBackend: Send + Sync {
+    /// The error type of the backend.
+    type Error: StdError + Send + Sync + 'static;
+
+    /// Fetch `https://<hostname>/.well-known/matrix/server`, following redirects.
+    ///
+    /// Should return the `m.server` of the response, or `None` if the response is not a success
+    /// or is not valid, with the cache lifetime from the headers of the response.
+    async fn fetch_well_known(&self, hostname: &str) -> Result<WellKnownResponse, Self::Error>;
+
+    /// Look up the SRV records of the given name, e.g. `_matrix-fed._tcp.example.org`.
+    async fn lookup_srv(&self, name: &str) -> Result<Vec<SrvRecord>, Self::Error>;
+}
+
+/// The result of a `.well-known/matrix/server` request.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct WellKnownResponse {
+    /// The `m.server` of the response, or `None` if the response is not a success or is not
+    /// valid.
+    pub server: Option<OwnedServerName>,
+
+    /// The duration for which the response can be cached, if the response has caching headers.
+    ///
+    /// It can be computed with [`cache_duration_from_headers()`].
+    pub cache_duration: Option<Duration>,
+}
+
+impl WellKnownResponse {
+    /// Creates a new `WellKnownResponse` with the given server and cache lifetime.
+    pub fn new(server: Option<OwnedServerName>, cache_duration: Option<Duration>) -> Self {
+        Self { server, cache_duration }
+    }
+}
+
+/// Get the duration for which a response can be cached from its `Cache-Control` or `Expires`
+/// headers.
+///
+/// The `max-age` directive of `Cache-Control` takes precedence over `Expires`. Returns a zero
+/// duration if the response must not be cached, and `None` if the headers don't say anything
+/// about caching.
+pub fn cache_duration_from_headers(headers: &http::HeaderMap) -> Option<Duration> {
+    if let Some(cache_control) = headers.typed_get::<CacheControl>() {
+        if cache_control.no_store() || cache_control.no_cache() {
+            return Some(Duration::ZERO);
+        }
+        if let Some(max_age) = cache_control.max_age() {
+            return Some(max_age);
+        }
+    }
+
+    let expires = SystemTime::from(headers.typed_get::<Expires>()?);
+    Some(expires.duration_since(SystemTime::now()).unwrap_or_default())
+}
+
+/// A DNS SRV record.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct SrvRecord {
+    /// The priority of the record. Lower values are preferred.
+    pub priority: u16,
+
+    /// The weight of the record, among the records with the same priority. Higher values are
+    /// preferred.
+    pub weight: u16,
+
+    /// The port to connect to.
+    pub port: u16,
+
+    /// The hostname to connect to.
+    pub target: String,
+}
+
+impl SrvRecord {
+    /// Creates a new `SrvRecord` with the given priority, weight, port and target.
+    pub fn new(priority: u16, weight: u16, port: u16, target: String) -> Self {
+        Self { priority, weight, port, target }
+    }
+}
+
+/// The result of the resolution of a server name.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ResolvedDestination {
+    /// The IP literal or hostname to connect to.
+    ///
+    /// IPv6 addresses are enclosed in square brackets.
+    pub host: String,
+
+    /// The port to connect to.
+    pub port: u16,
+
+    /// The value of the `Host` header of the requests.
+    pub host_header: String,
+
+    /// The server name to use for SNI and to validate the TLS certificate, or `None` for IP
+    /// literals.
+    pub tls_server_name: Option<String>,
+}
+
+impl ResolvedDestination {
+    /// The base URL to send requests to, e.g. `https://example.org:8448`.
+    pub fn base_url(&self) -> String {
+        format!("https://{}:{}", self.host, self.port)
+    }
+
+    fn ip_literal(server_name: &ServerName) -> Self {
+        Self {
+            host: server_name.host().to_owned(),
+            port: server_name.port().unwrap_or(DEFAULT_PORT),
+            host_header: server_name.to_string(),
+            tls_server_name: None,
+        }
+    }
+
+    fn with_explicit_port(server_name: &ServerName, port: u16) -> Self {
+        Self {
+            host: server_name.host().to_owned(),
+            port,
+            host_header: server_name.to_string(),
+            tls_server_name: Some(server_name.host().to_owned()),
+        }
+    }
+
+    fn with_hostname(hostname: &str, target: &str, port: u16) -> Self {
+        Self {
+            host: target.to_owned(),
+            port,
+            host_header: hostname.to_owned(),
+            tls_server_name: Some(hostname.to_owned()),
+        }
+    }
+}
+
+/// A resolver of server names, with a cache for the `.well-known` delegations.
+pub struct ServerResolver<B> {
+    backend: B,
+    well_known_cache: Mutex<BTreeMap<String, CachedWellKnown>>,
+    cache_duration: Duration,
+    failure_cache_duration: Duration,
+}
+
+struct CachedWellKnown {
+    server: Option<OwnedServerName>,
+    expires_at: Instant,
+}
+
+impl<B: ResolverBackend> ServerResolver<B> {
+    /// Creates a new `ServerResolver` with the given backend.
+    ///
+    /// `.well-known` delegations are cached according to the caching headers of the response, or
+    /// for 24 hours if it has none, and at most for 48 hours. Missing or invalid `.well-known`
+    /// responses are cached for 1 hour.
+    pub fn new(backend: B) -> Self {
+        Self {
+            backend,
+            well_known_cache: Mutex::new(BTreeMap::new()),
+            cache_duration: Duration::from_secs(24 * 60 * 60),
+            failure_cache_duration: Duration::from_secs(60 * 60),
+        }
+    }
+
+    /// Set the duration for which `.well-known` delegations are cached when the response has no
+    /// caching headers.
+    ///
+    /// The duration is capped at [`MAX_WELL_KNOWN_CACHE_DURATION`].
+    pub fn cache_duration(mut self, duration: Duration) -> Self {
+        self.cache_duration = duration;
+        self
+    }
+
+    /// Set the duration for which missing or invalid `.well-known` responses are cached.
+    pub fn failure_cache_duration(mut self, duration: Duration) -> Self {
+        self.failure_cache_duration = duration;
+        self
+    }
+
+    /// Resolve the given server name.
+    ///
+    /// Errors of the backend are logged and handled like missing results, as required by the
+    /// specification.
+    pub async fn resolve(&self, server_name: &ServerName) -> ResolvedDestination {
+        // 1. IP literal.
+        if server_name.is_ip_literal() {
+            return ResolvedDestination::ip_literal(server_name);
+        }
+
+        // 2. Explicit port.
+        if let Some(port) = server_name.port() {
+            return ResolvedDestination::with_explicit_port(server_name, port);
+        }
+
+        let hostname = server_name.host();
+
+        // 3. Delegation with `.well-known`.
+        if let Some(delegated) = self.well_known(hostname).await {
+            debug!("{server_name} is delegated to {delegated}");
+
+            if delegated.is_ip_literal() {
+                return ResolvedDestination::ip_literal(&delegated);
+            }
+            if let Some(port) = delegated.port() {
+                return ResolvedDestination::with_explicit_port(&delegated, port);
+            }
+
+            return self.resolve_hostname(delegated.host()).await;
+        }
+
+        self.resolve_hostname(hostname).await
+    }
+
+    /// Resolve a hostname without port with SRV records, or fall back to the default port.
+    async fn resolve_hostname(&self, hostname: &str) -> ResolvedDestination {
+        for service in ["_matrix-fed._tcp", "_matrix._tcp"] {
+            if let Some(record) = self.srv(&format!("{service}.{hostname}")).await {
+                return ResolvedDestination::with_hostname(hostname, &record.target, record.port);
+            }
+        }
+
+        ResolvedDestination::with_hostname(hostname, hostname, DEFAULT_PORT)
+    }
+
+    async fn well_known(&self, hostname: &str) -> Option<OwnedServerName> {
+        if let Some(cached) = self.well_known_cache.lock().unwrap().get(hostname) {
+            if cached.expires_at > Instant::now() {
+                return cached.server.clone();
+            }
+        }
+
+        let response = match self.backend.fetch_well_known(hostname).await {
+            Ok(response) => response,
+            Err(error) => {
+                warn!("Failed to fetch the .well-known of {hostname}: {error}");
+                WellKnownResponse::default()
+            }
+        };
+        let server = response.server;
+
+        let cache_duration = match server {
+            Some(_) => response
+                .cache_duration
+                .unwrap_or(self.cache_duration)
+                .min(MAX_WELL_KNOWN_CACHE_DURATION),
+            None => self.failure_cache_duration,
+        };
+        self.well_known_cache.lock().unwrap().insert(
+            hostname.to_owned(),
+            CachedWellKnown { server: server.clone(), expires_at: Instant::now() + cache_duration },
+        );
+
+        server
+    }
+
+    /// Get the preferred SRV record of the given name: the one with the lowest priority, then the
+    /// highest weight.
+    ///
+    /// Records with the target `.`, which means that the service is not available, are ignored.
+    async fn srv(&self, name: &str) -> Option<SrvRecord> {
+        let records = match self.backend.lookup_srv(name).await {
+            Ok(records) => records,
+            Err(error) => {
+                warn!("Failed to look up the SRV records of {name}: {error}");
+                return None;
+            }
+        };
+
+        records
+            .into_iter()
+            .filter_map(|mut record| {
+                // The target of SRV records is a fully-qualified domain name.
+                if let Some(target) = record.target.strip_suffix('.') {
+                    record.target = target.to_owned();
+                }
+                (!record.target.is_empty()).then_some(record)
+            })
+            .min_by_key(|record| (record.priority, std::cmp::Reverse(record.weight)))
+    }
+}
+
+impl<B> fmt::Debug for ServerResolver<B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ServerResolver")
+            .field("cache_duration", &self.cache_duration)
+            .field("failure_cache_duration", &self.failure_cache_duration)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        convert::Infallible,
+        sync::atomic::{AtomicUsize, Ordering},
+        time::{Duration, Instant, SystemTime},
+    };
+
+    use async_trait::async_trait;
+    use futures_util::FutureExt;
+    use headers::{CacheControl, Expires, HeaderMapExt};
+    use ruma_common::{server_name, OwnedServerName, ServerName};
+
+    use super::{
+        cache_duration_from_headers, ResolvedDestination, ResolverBackend, ServerResolver,
+        SrvRecord, WellKnownResponse, MAX_WELL_KNOWN_CACHE_DURATION,
+    };
+
+    #[derive(Default)]
+    struct MockBackend {
+        well_known: BTreeMap<&'static str, OwnedServerName>,
+        well_known_cache_duration: Option<Duration>,
+        srv: BTreeMap<&'static str, Vec<SrvRecord>>,
+        well_known_requests: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl ResolverBackend for MockBackend {
+        type Error = Infallible;
+
+        async fn fetch_well_known(&self, hostname: &str) -> Result<WellKnownResponse, Infallible> {
+            self.well_known_requests.fetch_add(1, Ordering::SeqCst);
+            Ok(WellKnownResponse::new(
+                self.well_known.get(hostname).cloned(),
+                self.well_known_cache_duration,
+            ))
+        }
+
+        async fn lookup_srv(&self, name: &str) -> Result<Vec<SrvRecord>, Infallible> {
+            Ok(self.srv.get(name).cloned().unwrap_or_default())
+        }
+    }
+
+    fn resolve(backend: MockBackend, server_name: &ServerName) -> ResolvedDestination {
+        ServerResolver::new(backend).resolve(server_name).now_or_never().unwrap()
+    }
+
+    fn destination(
+        host: &str,
+        port: u16,
+        host_header: &str,
+        sni: Option<&str>,
+    ) -> ResolvedDestination {
+        ResolvedDestination {
+            host: host.to_owned(),
+            port,
+            host_header: host_header.to_owned(),
+            tls_server_name: sni.map(ToOwned::to_owned),
+        }
+    }
+
+    #[test]
+    fn ip_literal() {
+        assert_eq!(
+            resolve(MockBackend::default(), server_name!("1.2.3.4")),
+            destination("1.2.3.4", 8448, "1.2.3.4", None)
+        );
+        assert_eq!(
+            resolve(MockBackend::default(), server_name!("[::1]:1234")),
+            destination("[::1]", 1234, "[::1]:1234", None)
+        );
+    }
+
+    #[test]
+    fn explicit_port() {
+        let backend = MockBackend {
+            well_known: [("example.org", server_name!("other.org").to_owned())].into(),
+            ..Default::default()
+        };
+        assert_eq!(
+            resolve(backend, server_name!("example.org:1234")),
+            destination("example.org", 1234, "example.org:1234", Some("example.org"))
+        );
+    }
+
+    #[test]
+    fn well_known_delegation() {
+        let backend = MockBackend {
+            well_known: [
+                ("example.org", server_name!("matrix.example.org").to_owned()),
+                ("port.org", server_name!("matrix.port.org:443").to_owned()),
+                ("ip.org", server_name!("1.2.3.4").to_owned()),
+            ]
+            .into(),
+            srv: [(
+                "_matrix-fed._tcp.matrix.example.org",
+                vec![
+                    SrvRecord::new(10, 0, 8000, "backup.example.org.".to_owned()),
+                    SrvRecord::new(0, 0, 8001, "low.example.org.".to_owned()),
+                    SrvRecord::new(0, 5, 8002, "high.example.org.".to_owned()),
+                ],
+            )]
+            .into(),
+            ..Default::default()
+        };
+        let resolver = ServerResolver::new(backend);
+
+        assert_eq!(
+            resolver.resolve(server_name!("example.org")).now_or_never().unwrap(),
+            destination("high.example.org", 8002, "matrix.example.org", Some("matrix.example.org"))
+        );
+        assert_eq!(
+            resolver.resolve(server_name!("port.org")).now_or_never().unwrap(),
+            destination("matrix.port.org", 443, "matrix.port.org:443", Some("matrix.port.org"))
+        );
+        assert_eq!(
+            resolver.resolve(server_name!("ip.org")).now_or_never().unwrap(),
+            destination("1.2.3.4", 8448, "1.2.3.4", None)
+        );
+
+        // The delegation is cached.
+        resolver.resolve(server_name!("example.org")).now_or_never().unwrap();
+        assert_eq!(resolver.backend.well_known_requests.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn srv_without_delegation() {
+        let backend = MockBackend {
+            srv: [
+                (
+                    "_matrix._tcp.legacy.org",
+                    vec![SrvRecord::new(0, 0, 8000, "legacy.host".to_owned())],
+                ),
+                (
+                    "_matrix-fed._tcp.both.org",
+                    vec![SrvRecord::new(0, 0, 8001, "fed.host".to_owned())],
+                ),
+                (
+                    "_matrix._tcp.both.org",
+                    vec![SrvRecord::new(0, 0, 8000, "legacy.host".to_owned())],
+                ),
+            ]
+            .into(),
+            ..Default::default()
+        };
+        let resolver = ServerResolver::new(backend);
+
+        assert_eq!(
+            resolver.resolve(server_name!("legacy.org")).now_or_never().unwrap(),
+            destination("legacy.host", 8000, "legacy.org", Some("legacy.org"))
+        );
+        assert_eq!(
+            resolver.resolve(server_name!("both.org")).now_or_never().unwrap(),
+            destination("fed.host", 8001, "both.org", Some("both.org"))
+        );
+    }
+
+    #[test]
+    fn srv_service_not_available() {
+        let backend = MockBackend {
+            srv: [
+                ("_matrix-fed._tcp.example.org", vec![SrvRecord::new(0, 0, 0, ".".to_owned())]),
+                (
+                    "_matrix._tcp.example.org",
+                    vec![SrvRecord::new(0, 0, 8000, "legacy.host".to_owned())],
+                ),
+                ("_matrix-fed._tcp.none.org", vec![SrvRecord::new(0, 0, 0, ".".to_owned())]),
+                ("_matrix._tcp.none.org", vec![SrvRecord::new(0, 0, 0, String::new())]),
+            ]
+            .into(),
+            ..Default::default()
+        };
+        let resolver = ServerResolver::new(backend);
+
+        assert_eq!(
+            resolver.resolve(server_name!("example.org")).now_or_never().unwrap(),
+            destination("legacy.host", 8000, "example.org", Some("example.org"))
+        );
+        assert_eq!(
+            resolver.resolve(server_name!("none.org")).now_or_never().unwrap(),
+            destination("none.org", 8448, "none.org", Some("none.org"))
+        );
+    }
+
+    #[test]
+    fn default_port() {
+        let resolved = resolve(MockBackend::default(), server_name!("example.org"));
+        assert_eq!(resolved, destination("example.org", 8448, "example.org", Some("example.org")));
+        assert_eq!(resolved.base_url(), "https://example.org:8448");
+    }
+
+    #[test]
+    fn well_known_cache_duration_from_response() {
+        let backend = MockBackend {
+            well_known: [("example.org", server_name!("matrix.example.org").to_owned())].into(),
+            well_known_cache_duration: Some(Duration::ZERO),
+            ..Default::default()
+        };
+        let resolver = ServerResolver::new(backend);
+
+        // The response must not be cached.
+        resolver.resolve(server_name!("example.org")).now_or_never().unwrap();
+        resolver.resolve(server_name!("example.org")).now_or_never().unwrap();
+        assert_eq!(resolver.backend.well_known_requests.load(Ordering::SeqCst), 2);
+
+        // The cache duration is capped.
+        let backend = MockBackend {
+            well_known: [("example.org", server_name!("matrix.example.org").to_owned())].into(),
+            well_known_cache_duration: Some(Duration::from_secs(7 * 24 * 60 * 60)),
+            ..Default::default()
+        };
+        let resolver = ServerResolver::new(backend);
+        resolver.resolve(server_name!("example.org")).now_or_never().unwrap();
+
+        let expires_at = resolver.well_known_cache.lock().unwrap()["example.org"].expires_at;
+        assert!(expires_at <= Instant::now() + MAX_WELL_KNOWN_CACHE_DURATION);
+    }
+
+    #[test]
+    fn cache_duration_headers() {
+        let mut headers = http::HeaderMap::new();
+        assert_eq!(cache_duration_from_headers(&headers), None);
+
+        headers.typed_insert(Expires::from(SystemTime::now() - Duration::from_secs(60)));
+        assert_eq!(cache_duration_from_headers(&headers), Some(Duration::ZERO));
+
+        headers.typed_insert(Expires::from(SystemTime::now() + Duration::from_secs(3_600)));
+        let duration = cache_duration_from_headers(&headers).unwrap();
+        assert!(duration > Duration::from_secs(3_500) && duration <= Duration::from_secs(3_600));
+
+        headers.typed_insert(CacheControl::new().with_max_age(Duration::from_secs(60)));
+        assert_eq!(cache_duration_from_headers(&headers), Some(Duration::from_secs(60)));
+
+        headers.typed_insert(CacheControl::new().with_no_store());
+        assert_eq!(cache_duration_from_headers(&headers), Some(Duration::ZERO));
+    }
+}
@@ -0,0 +1,671 @@
+//! Sending of outgoing federation transactions.
+//!
+//! The [`TransactionQueue`] queues the PDUs and EDUs to send to each destination in a pluggable
+//! [`QueueStorage`], batches them into transactions of at most [`MAX_PDUS`] PDUs and [`MAX_EDUS`]
+//! EDUs, with generated transaction IDs, and sends them with a pluggable [`FederationSender`].
+//! Failed transactions are retried with the same transaction ID after an exponential backoff, and
+//! destinations that fail repeatedly are marked as down.
+//!
+//! The queue doesn't spawn any task: [`TransactionQueue::send_next_transaction()`] should be
+//! called when events are queued for a destination and when its backoff is over.
+//!
+//! [`MAX_PDUS`]: crate::transactions::MAX_PDUS
+//! [`MAX_EDUS`]: crate::transactions::MAX_EDUS
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    convert::Infallible,
+    error::Error as StdError,
+    fmt,
+    sync::Mutex,
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use ruma_common::{
+    api::OutgoingRequest, serde::Raw, MilliSecondsSinceUnixEpoch, OwnedServerName,
+    OwnedTransactionId, ServerName, TransactionId,
+};
+use ruma_federation_api::transactions::{edu::Edu, send_transaction_message::v1::Request};
+use serde_json::value::RawValue as RawJsonValue;
+use thiserror::Error;
+use tracing::{debug, warn};
+
+use crate::transactions::{MAX_EDUS, MAX_PDUS};
+
+/// A persistent storage for the queues of the destinations.
+///
+/// The queue of each destination is a FIFO of PDUs and a FIFO of EDUs.
+#[async_trait]
+pub trait QueueStorage: Send + Sync {
+    /// The error type of the storage.
+    type Error: StdError + Send + Sync + 'static;
+
+    /// Add a PDU at the end of the queue of the given destination.
+    async fn push_pdu(
+        &self,
+        destination: &ServerName,
+        pdu: Box<RawJsonValue>,
+    ) -> Result<(), Self::Error>;
+
+    /// Add an EDU at the end of the queue of the given destination.
+    async fn push_edu(&self, destination: &ServerName, edu: Raw<Edu>) -> Result<(), Self::Error>;
+
+    /// Get at most `max_pdus` PDUs and `max_edus` EDUs from the start of the queue of the given
+    /// destination, without removing them.
+    async fn peek(
+        &self,
+        destination: &ServerName,
+        max_pdus: usize,
+        max_edus: usize,
+    ) -> Result<(Vec<Box<RawJsonValue>>, Vec<Raw<Edu>>), Self::Error>;
+
+    /// Remove `pdus` PDUs and `edus` EDUs from the start of the queue of the given destination,
+    /// after they were sent.
+    async fn remove(
+        &self,
+        destination: &ServerName,
+        pdus: usize,
+        edus: usize,
+    ) -> Result<(), Self::Error>;
+
+    /// Get the state of the given destination, if it was stored before.
+    async fn destination_state(
+        &self,
+        destination: &ServerName,
+    ) -> Result<Option<DestinationState>, Self::Error>;
+
+    /// Store the state of the given destination, replacing the one that was stored before.
+    async fn set_destination_state(
+        &self,
+        destination: &ServerName,
+        state: DestinationState,
+    ) -> Result<(), Self::Error>;
+}
+
+/// A sender of requests to other servers.
+///
+/// It is responsible for resolving the destination, signing the request and sending it over HTTP.
+#[async_trait]
+pub trait FederationSender: Send + Sync {
+    /// The error type of the sender.
+    type Error: StdError + Send + Sync + 'static;
+
+    /// Send the given request to the given destination.
+    async fn send_federation_request<R>(
+        &self,
+        destination: &ServerName,
+        request: R,
+    ) -> Result<R::IncomingResponse, Self::Error>
+    where
+        R: OutgoingRequest + Send,
+        R::IncomingResponse: Send;
+}
+
+/// The state of a destination.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct DestinationState {
+    /// The number of consecutive failures to send a transaction to the destination.
+    pub failures: u32,
+
+    /// The time before which no transaction should be sent to the destination.
+    pub retry_at: Option<MilliSecondsSinceUnixEpoch>,
+
+    /// Whether the destination is considered down.
+    pub down: bool,
+
+    /// The transaction that failed to be sent and must be retried with the same ID and events.
+    pub pending_transaction: Option<PendingTransaction>,
+}
+
+/// A transaction that must be retried.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct PendingTransaction {
+    /// The ID of the transaction.
+    pub transaction_id: OwnedTransactionId,
+
+    /// The number of PDUs at the start of the queue that are in the transaction.
+    pub pdus: usize,
+
+    /// The number of EDUs at the start of the queue that are in the transaction.
+    pub edus: usize,
+}
+
+impl PendingTransaction {
+    /// Creates a new `PendingTransaction` with the given ID and number of PDUs and EDUs.
+    pub fn new(transaction_id: OwnedTransactionId, pdus: usize, edus: usize) -> Self {
+        Self { transaction_id, pdus, edus }
+    }
+}
+
+/// The configuration of the backoff of a [`TransactionQueue`].
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct BackoffConfig {
+    /// The delay before the first retry.
+    ///
+    /// It is doubled after each failure. Defaults to 10 seconds.
+    pub initial_delay: Duration,
+
+    /// The maximum delay between two retries.
+    ///
+    /// Defaults to 1 day.
+    pub max_delay: Duration,
+
+    /// The number of consecutive failures after which a destination is marked as down.
+    ///
+    /// Defaults to 5.
+    pub down_after_failures: u32,
+}
+
+impl BackoffConfig {
+    /// The delay before retrying after the given number of consecutive failures.
+    fn delay(&self, failures: u32) -> Duration {
+        let factor = 2_u32.saturating_pow(failures.saturating_sub(1));
+        self.initial_delay.saturating_mul(factor).min(self.max_delay)
+    }
+}
+
+impl Default for BackoffConfig {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_secs(10),
+            max_delay: Duration::from_secs(24 * 60 * 60),
+            down_after_failures: 5,
+        }
+    }
+}
+
+/// The outcome of [`TransactionQueue::send_next_transaction()`].
+#[derive(Clone, Debug)]
+#[allow(clippy::exhaustive_enums)]
+pub enum SendOutcome {
+    /// There was nothing to send to the destination.
+    Empty,
+
+    /// The transaction with the given ID was sent.
+    Sent(OwnedTransactionId),
+
+    /// Sending the transaction failed, it will be retried at the given time.
+    Failed {
+        /// The time when the transaction should be retried.
+        retry_at: MilliSecondsSinceUnixEpoch,
+    },
+
+    /// The destination is in backoff after a previous failure, nothing was sent.
+    Backoff {
+        /// The time when the transaction should be retried.
+        retry_at: MilliSecondsSinceUnixEpoch,
+    },
+}
+
+/// A queue of outgoing transactions for all the destinations of a homeserver.
+pub struct TransactionQueue<S, T> {
+    origin: OwnedServerName,
+    storage: S,
+    sender: T,
+    backoff: BackoffConfig,
+}
+
+impl<S: QueueStorage, T: FederationSender> TransactionQueue<S, T> {
+    /// Creates a new `TransactionQueue` for the given origin server with the default backoff.
+    pub fn new(origin: OwnedServerName, storage: S, sender: T) -> Self {
+        Self { origin, storage, sender, backoff: BackoffConfig::default() }
+    }
+
+    /// Set the configuration of the backoff.
+    pub fn backoff(mut self, backoff: BackoffConfig) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Queue a PDU to send to the given destination.
+    pub async fn queue_pdu(
+        &self,
+        destination: &ServerName,
+        pdu: Box<RawJsonValue>,
+    ) -> Result<(), QueueError> {
+        self.storage.push_pdu(destination, pdu).await.map_err(QueueError::storage)
+    }
+
+    /// Queue an EDU to send to the given destination.
+    pub async fn queue_edu(
+        &self,
+        destination: &ServerName,
+        edu: Raw<Edu>,
+    ) -> Result<(), QueueError> {
+        self.storage.push_edu(destination, edu).await.map_err(QueueError::storage)
+    }
+
+    /// Whether the given destination is marked as down after repeated failures.
+    pub async fn is_down(&self, destination: &ServerName) -> Result<bool, QueueError> {
+        Ok(self.state(destination).await?.down)
+    }
+
+    /// Reset the backoff of the given destination, for example after receiving a transaction
+    /// from it.
+    ///
+    /// A pending transaction is still retried with the same ID.
+    pub async fn mark_reachable(&self, destination: &ServerName) -> Result<(), QueueError> {
+        let state = self.state(destination).await?;
+        let state = DestinationState {
+            pending_transaction: state.pending_transaction,
+            ..Default::default()
+        };
+        self.storage.set_destination_state(destination, state).await.map_err(QueueError::storage)
+    }
+
+    /// Send the next transaction to the given destination, if it isn't in backoff.
+    ///
+    /// This should be called again as long as it returns [`SendOutcome::Sent`], to empty the
+    /// queue of the destination.
+    pub async fn send_next_transaction(
+        &self,
+        destination: &ServerName,
+    ) -> Result<SendOutcome, QueueError> {
+        let mut state = self.state(destination).await?;
+        let now = MilliSecondsSinceUnixEpoch::now();
+
+        if let Some(retry_at) = state.retry_at.filter(|retry_at| *retry_at > now) {
+            return Ok(SendOutcome::Backoff { retry_at });
+        }
+
+        let (max_pdus, max_edus) = match &state.pending_transaction {
+            Some(pending) => (pending.pdus, pending.edus),
+            None => (MAX_PDUS, MAX_EDUS),
+        };
+        let (pdus, edus) = self
+            .storage
+            .peek(destination, max_pdus, max_edus)
+            .await
+            .map_err(QueueError::storage)?;
+
+        if pdus.is_empty() && edus.is_empty() {
+            return Ok(SendOutcome::Empty);
+        }
+
+        let pending = state.pending_transaction.take().unwrap_or_else(|| {
+            PendingTransaction::new(TransactionId::new(), pdus.len(), edus.len())
+        });
+        let transaction_id = pending.transaction_id.clone();
+
+        let mut request = Request::new(transaction_id.clone(), self.origin.clone(), now);
+        request.pdus = pdus;
+        request.edus = edus;
+
+        match self.sender.send_federation_request(destination, request).await {
+            Ok(response) => {
+                for (event_id, result) in &response.pdus {
+                    if let Err(error) = result {
+                        debug!("{destination} rejected PDU {event_id}: {error}");
+                    }
+                }
+
+                self.storage
+                    .remove(destination, pending.pdus, pending.edus)
+                    .await
+                    .map_err(QueueError::storage)?;
+                self.storage
+                    .set_destination_state(destination, DestinationState::default())
+                    .await
+                    .map_err(QueueError::storage)?;
+
+                Ok(SendOutcome::Sent(transaction_id))
+            }
+            Err(error) => {
+                state.failures = state.failures.saturating_add(1);
+                state.down = state.failures >= self.backoff.down_after_failures;
+                let retry_at = add_duration(now, self.backoff.delay(state.failures));
+                state.retry_at = Some(retry_at);
+                state.pending_transaction = Some(pending);
+
+                warn!(
+                    "Failed to send transaction {transaction_id} to {destination} \
+                     ({} consecutive failures): {error}",
+                    state.failures
+                );
+
+                self.storage
+                    .set_destination_state(destination, state)
+                    .await
+                    .map_err(QueueError::storage)?;
+
+                Ok(SendOutcome::Failed { retry_at })
+            }
+        }
+    }
+
+    async fn state(&self, destination: &ServerName) -> Result<DestinationState, QueueError> {
+        Ok(self
+            .storage
+            .destination_state(destination)
+            .await
+            .map_err(QueueError::storage)?
+            .unwrap_or_default())
+    }
+}
+
+impl<S, T> fmt::Debug for TransactionQueue<S, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TransactionQueue")
+            .field("origin", &self.origin)
+            .field("backoff", &self.backoff)
+            .finish()
+    }
+}
+
+fn add_duration(ts: MilliSecondsSinceUnixEpoch, duration: Duration) -> MilliSecondsSinceUnixEpoch {
+    ts.to_system_time()
+        .and_then(|time| time.checked_add(duration))
+        .and_then(MilliSecondsSinceUnixEpoch::from_system_time)
+        .unwrap_or(ts)
+}
+
+/// A [`QueueStorage`] that keeps the queues in memory.
+#[derive(Debug, Default)]
+pub struct InMemoryQueueStorage {
+    queues: Mutex<BTreeMap<OwnedServerName, DestinationQueue>>,
+}
+
+#[derive(Debug, Default)]
+struct DestinationQueue {
+    pdus: VecDeque<Box<RawJsonValue>>,
+    edus: VecDeque<Raw<Edu>>,
+    state: Option<DestinationState>,
+}
+
+impl InMemoryQueueStorage {
+    /// Creates an empty `InMemoryQueueStorage`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl QueueStorage for InMemoryQueueStorage {
+    type Error = Infallible;
+
+    async fn push_pdu(
+        &self,
+        destination: &ServerName,
+        pdu: Box<RawJsonValue>,
+    ) -> Result<(), Self::Error> {
+        self.queues.lock().unwrap().entry(destination.to_owned()).or_default().pdus.push_back(pdu);
+        Ok(())
+    }
+
+    async fn push_edu(&self, destination: &ServerName, edu: Raw<Edu>) -> Result<(), Self::Error> {
+        self.queues.lock().unwrap().entry(destination.to_owned()).or_default().edus.push_back(edu);
+        Ok(())
+    }
+
+    async fn peek(
+        &self,
+        destination: &ServerName,
+        max_pdus: usize,
+        max_edus: usize,
+    ) -> Result<(Vec<Box<RawJsonValue>>, Vec<Raw<Edu>>), Self::Error> {
+        let queues = self.queues.lock().unwrap();
+        let Some(queue) = queues.get(destination) else {
+            return Ok((Vec::new(), Vec::new()));
+        };
+
+        Ok((
+            queue.pdus.iter().take(max_pdus).cloned().collect(),
+            queue.edus.iter().take(max_edus).cloned().collect(),
+        ))
+    }
+
+    async fn remove(
+        &self,
+        destination: &ServerName,
+        pdus: usize,
+        edus: usize,
+    ) -> Result<(), Self::Error> {
+        if let Some(queue) = self.queues.lock().unwrap().get_mut(destination) {
+            queue.pdus.drain(..pdus.min(queue.pdus.len()));
+            queue.edus.drain(..edus.min(queue.edus.len()));
+        }
+        Ok(())
+    }
+
+    async fn destination_state(
+        &self,
+        destination: &ServerName,
+    ) -> Result<Option<DestinationState>, Self::Error> {
+        Ok(self.queues.lock().unwrap().get(destination).and_then(|queue| queue.state.clone()))
+    }
+
+    async fn set_destination_state(
+        &self,
+        destination: &ServerName,
+        state: DestinationState,
+    ) -> Result<(), Self::Error> {
+        self.queues.lock().unwrap().entry(destination.to_owned()).or_default().state = Some(state);
+        Ok(())
+    }
+}
+
+/// An error when using a [`TransactionQueue`].
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum QueueError {
+    /// The storage returned an error.
+    #[error("storage error: {0}")]
+    Storage(#[source] Box<dyn StdError + Send + Sync>),
+}
+
+impl QueueError {
+    fn storage<E: StdError + Send + Sync + 'static>(error: E) -> Self {
+        Self::Storage(Box::new(error))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{self, BufRead, BufReader, Read, Write},
+        net::{TcpListener, TcpStream},
+        sync::{Arc, Mutex},
+        thread,
+        time::Duration,
+    };
+
+    use async_trait::async_trait;
+    use futures_util::FutureExt;
+    use ruma_common::{
+        api::{IncomingResponse, MatrixVersion, OutgoingRequest, SendAccessToken},
+        owned_server_name, server_name, ServerName,
+    };
+    use serde_json::{json, value::to_raw_value as to_raw_json_value};
+
+    use super::{
+        BackoffConfig, FederationSender, InMemoryQueueStorage, SendOutcome, TransactionQueue,
+        MAX_PDUS,
+    };
+
+    /// A local HTTP server that answers each request with the next scripted status code, and
+    /// records the paths of the requests.
+    struct MockServer {
+        base_url: String,
+        paths: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl MockServer {
+        fn start(statuses: Vec<u16>) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let base_url = format!("http://{}", listener.local_addr().unwrap());
+            let paths = Arc::new(Mutex::new(Vec::new()));
+
+            let server_paths = paths.clone();
+            thread::spawn(move || {
+                for (stream, status) in listener.incoming().zip(statuses) {
+                    let mut stream = stream.unwrap();
+                    let path = read_request(&mut stream);
+                    server_paths.lock().unwrap().push(path);
+
+                    let body = if status == 200 { r#"{"pdus":{}}"# } else { "{}" };
+                    write!(
+                        stream,
+                        "HTTP/1.1 {status} Status\r\ncontent-type: application/json\r\n\
+                         content-length: {}\r\nconnection: close\r\n\r\n{body}",
+                        body.len()
+                    )
+                    .unwrap();
+                }
+            });
+
+            Self { base_url, paths }
+        }
+    }
+
+    /// Read an HTTP request and return its path.
+    fn read_request(stream: &mut TcpStream) -> String {
+        let mut reader = BufReader::new(stream);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).unwrap();
+
+        let mut content_length = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if line == "\r\n" {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse().unwrap();
+                }
+            }
+        }
+        reader.read_exact(&mut vec![0; content_length]).unwrap();
+
+        request_line.split(' ').nth(1).unwrap().to_owned()
+    }
+
+    /// A minimal HTTP/1.1 client that sends requests to the mock server.
+    struct HttpSender {
+        base_url: String,
+    }
+
+    #[async_trait]
+    impl FederationSender for HttpSender {
+        type Error = io::Error;
+
+        async fn send_federation_request<R>(
+            &self,
+            _destination: &ServerName,
+            request: R,
+        ) -> Result<R::IncomingResponse, io::Error>
+        where
+            R: OutgoingRequest + Send,
+            R::IncomingResponse: Send,
+        {
+            let request = request
+                .try_into_http_request::<Vec<u8>>(
+                    &self.base_url,
+                    SendAccessToken::None,
+                    &[MatrixVersion::V1_0],
+                )
+                .unwrap();
+            let authority = request.uri().authority().unwrap().as_str();
+
+            let mut stream = TcpStream::connect(authority)?;
+            write!(
+                stream,
+                "{} {} HTTP/1.1\r\nhost: {authority}\r\ncontent-length: {}\r\n\
+                 connection: close\r\n\r\n",
+                request.method(),
+                request.uri().path(),
+                request.body().len()
+            )?;
+            stream.write_all(request.body())?;
+
+            let mut response = String::new();
+            stream.read_to_string(&mut response)?;
+            let (head, body) = response.split_once("\r\n\r\n").unwrap();
+            let status = head.split(' ').nth(1).unwrap().parse::<u16>().unwrap();
+
+            let response =
+                http::Response::builder().status(status).body(body.as_bytes().to_vec()).unwrap();
+            R::IncomingResponse::try_from_http_response(response)
+                .map_err(|error| io::Error::new(io::ErrorKind::Other, error.to_string()))
+        }
+    }
+
+    #[test]
+    fn batch_and_retry_against_mock_server() {
+        let server = MockServer::start(vec![500, 200, 200]);
+        let queue = TransactionQueue::new(
+            owned_server_name!("origin"),
+            InMemoryQueueStorage::new(),
+            HttpSender { base_url: server.base_url.clone() },
+        )
+        .backoff(BackoffConfig {
+            initial_delay: Duration::ZERO,
+            max_delay: Duration::ZERO,
+            down_after_failures: 1,
+        });
+        let destination = server_name!("destination");
+
+        for i in 0..=MAX_PDUS {
+            let pdu = to_raw_json_value(&json!({ "depth": i })).unwrap();
+            queue.queue_pdu(destination, pdu).now_or_never().unwrap().unwrap();
+        }
+
+        // The first attempt fails and marks the destination as down.
+        let outcome = queue.send_next_transaction(destination).now_or_never().unwrap().unwrap();
+        assert!(matches!(outcome, SendOutcome::Failed { .. }));
+        assert!(queue.is_down(destination).now_or_never().unwrap().unwrap());
+
+        // The retry uses the same transaction ID.
+        let outcome = queue.send_next_transaction(destination).now_or_never().unwrap().unwrap();
+        let SendOutcome::Sent(first_id) = outcome else { panic!("unexpected outcome {outcome:?}") };
+        assert!(!queue.is_down(destination).now_or_never().unwrap().unwrap());
+
+        // The PDU that didn't fit in the first transaction is sent in a second one.
+        let outcome = queue.send_next_transaction(destination).now_or_never().unwrap().unwrap();
+        let SendOutcome::Sent(second_id) = outcome else {
+            panic!("unexpected outcome {outcome:?}")
+        };
+        assert_ne!(first_id, second_id);
+
+        let outcome = queue.send_next_transaction(destination).now_or_never().unwrap().unwrap();
+        assert!(matches!(outcome, SendOutcome::Empty));
+
+        let paths = server.paths.lock().unwrap();
+        assert_eq!(
+            *paths,
+            [
+                format!("/_matrix/federation/v1/send/{first_id}"),
+                format!("/_matrix/federation/v1/send/{first_id}"),
+                format!("/_matrix/federation/v1/send/{second_id}"),
+            ]
+        );
+    }
+
+    #[test]
+    fn backoff_after_failure() {
+        let server = MockServer::start(vec![500]);
+        let queue = TransactionQueue::new(
+            owned_server_name!("origin"),
+            InMemoryQueueStorage::new(),
+            HttpSender { base_url: server.base_url.clone() },
+        );
+        let destination = server_name!("destination");
+        let pdu = to_raw_json_value(&json!({})).unwrap();
+        queue.queue_pdu(destination, pdu).now_or_never().unwrap().unwrap();
+
+        let outcome = queue.send_next_transaction(destination).now_or_never().unwrap().unwrap();
+        let SendOutcome::Failed { retry_at } = outcome else {
+            panic!("unexpected outcome {outcome:?}")
+        };
+        assert!(!queue.is_down(destination).now_or_never().unwrap().unwrap());
+
+        let outcome = queue.send_next_transaction(destination).now_or_never().unwrap().unwrap();
+        assert!(matches!(outcome, SendOutcome::Backoff { retry_at: at } if at == retry_at));
+        assert_eq!(server.paths.lock().unwrap().len(), 1);
+    }
+}
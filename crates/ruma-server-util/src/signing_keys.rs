@@ -0,0 +1,246 @@
+//! Management of the signing keys of the homeserver.
+//!
+//! The [`SigningKeyRing`] keeps the key that is currently used to sign events and requests, and
+//! the keys that were used before, so they can be published in the response of the
+//! [`get_server_keys`] endpoint.
+//!
+//! [`get_server_keys`]: ruma_federation_api::discovery::get_server_keys
+
+use std::{collections::BTreeMap, fmt};
+
+use ruma_common::{
+    canonical_json::to_canonical_value,
+    serde::{Base64, Raw},
+    CanonicalJsonValue, IdParseError, MilliSecondsSinceUnixEpoch, OwnedServerName,
+    OwnedServerSigningKeyId, ServerName,
+};
+use ruma_federation_api::discovery::{OldVerifyKey, ServerSigningKeys, VerifyKey};
+use ruma_signatures::{sign_json, Ed25519KeyPair, KeyPair, Signature};
+use serde_json::value::to_raw_value as to_raw_json_value;
+use thiserror::Error;
+
+/// The signing keys of a homeserver.
+///
+/// The active key is used to sign events and requests, and is published in the `verify_keys`
+/// of the server keys. The keys that were retired by [`SigningKeyRing::rotate()`] or added with
+/// [`SigningKeyRing::add_old_verify_key()`] are published in the `old_verify_keys`, so other
+/// servers can still verify what was signed with them.
+///
+/// It implements [`KeyPair`] by signing with the active key.
+pub struct SigningKeyRing {
+    server_name: OwnedServerName,
+    active_key: Ed25519KeyPair,
+    active_key_id: OwnedServerSigningKeyId,
+    old_verify_keys: BTreeMap<OwnedServerSigningKeyId, OldVerifyKey>,
+}
+
+impl SigningKeyRing {
+    /// Creates a new `SigningKeyRing` for the given server, with the given active key.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the version of the key is not a valid key name.
+    pub fn new(
+        server_name: OwnedServerName,
+        active_key: Ed25519KeyPair,
+    ) -> Result<Self, SigningKeyRingError> {
+        let active_key_id = key_id(&active_key)?;
+        Ok(Self { server_name, active_key, active_key_id, old_verify_keys: BTreeMap::new() })
+    }
+
+    /// The name of the server that owns the keys.
+    pub fn server_name(&self) -> &ServerName {
+        &self.server_name
+    }
+
+    /// The key that is currently used for signing.
+    pub fn active_key(&self) -> &Ed25519KeyPair {
+        &self.active_key
+    }
+
+    /// The ID of the key that is currently used for signing.
+    pub fn active_key_id(&self) -> &OwnedServerSigningKeyId {
+        &self.active_key_id
+    }
+
+    /// The keys that were used for signing before, with the time when they stopped being used.
+    pub fn old_verify_keys(&self) -> &BTreeMap<OwnedServerSigningKeyId, OldVerifyKey> {
+        &self.old_verify_keys
+    }
+
+    /// Add a key that was used for signing before.
+    ///
+    /// This can be used to restore the retired keys when the key ring is loaded.
+    pub fn add_old_verify_key(&mut self, key_id: OwnedServerSigningKeyId, key: OldVerifyKey) {
+        self.old_verify_keys.insert(key_id, key);
+    }
+
+    /// Replace the active key with the given key.
+    ///
+    /// The previous active key is retired with the given expiration time, which should be the
+    /// current time.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the version of the key is not a valid key name, or if the ID of the key
+    /// is the ID of the active key or of an old key.
+    pub fn rotate(
+        &mut self,
+        new_key: Ed25519KeyPair,
+        expired_ts: MilliSecondsSinceUnixEpoch,
+    ) -> Result<(), SigningKeyRingError> {
+        let new_key_id = key_id(&new_key)?;
+        if new_key_id == self.active_key_id || self.old_verify_keys.contains_key(&new_key_id) {
+            return Err(SigningKeyRingError::DuplicateKeyId(new_key_id));
+        }
+
+        let old_key = std::mem::replace(&mut self.active_key, new_key);
+        let old_key_id = std::mem::replace(&mut self.active_key_id, new_key_id);
+
+        self.old_verify_keys
+            .insert(old_key_id, OldVerifyKey::new(expired_ts, public_key(&old_key)));
+
+        Ok(())
+    }
+
+    /// Build the keys of the server, signed with the active key.
+    ///
+    /// This is the `server_key` of the response of the [`get_server_keys`] endpoint.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the keys could not be serialized or signed.
+    ///
+    /// [`get_server_keys`]: ruma_federation_api::discovery::get_server_keys
+    pub fn server_keys(
+        &self,
+        valid_until_ts: MilliSecondsSinceUnixEpoch,
+    ) -> Result<Raw<ServerSigningKeys>, SigningKeyRingError> {
+        let mut keys = ServerSigningKeys::new(self.server_name.clone(), valid_until_ts);
+        keys.verify_keys
+            .insert(self.active_key_id.clone(), VerifyKey::new(public_key(&self.active_key)));
+        keys.old_verify_keys = self.old_verify_keys.clone();
+
+        let CanonicalJsonValue::Object(mut object) = to_canonical_value(&keys)? else {
+            unreachable!("ServerSigningKeys serializes to an object");
+        };
+        object.remove("signatures");
+        sign_json(self.server_name.as_str(), &self.active_key, &mut object)?;
+
+        Ok(Raw::from_json(to_raw_json_value(&object)?))
+    }
+}
+
+impl KeyPair for SigningKeyRing {
+    fn sign(&self, message: &[u8]) -> Signature {
+        self.active_key.sign(message)
+    }
+}
+
+impl fmt::Debug for SigningKeyRing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SigningKeyRing")
+            .field("server_name", &self.server_name)
+            .field("active_key_id", &self.active_key_id)
+            .field("old_verify_keys", &self.old_verify_keys)
+            .finish()
+    }
+}
+
+fn key_id(key: &Ed25519KeyPair) -> Result<OwnedServerSigningKeyId, SigningKeyRingError> {
+    format!("ed25519:{}", key.version()).try_into().map_err(SigningKeyRingError::InvalidKeyId)
+}
+
+fn public_key(key: &Ed25519KeyPair) -> Base64 {
+    Base64::new(key.public_key().to_vec())
+}
+
+/// An error when managing the signing keys of the homeserver.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum SigningKeyRingError {
+    /// The version of a key is not a valid key name.
+    #[error("invalid key ID: {0}")]
+    InvalidKeyId(#[source] IdParseError),
+
+    /// The ID of the new key is already used by the active key or an old key.
+    #[error("the key ID {0} is already used")]
+    DuplicateKeyId(OwnedServerSigningKeyId),
+
+    /// The keys could not be serialized.
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+
+    /// The keys could not be canonicalized.
+    #[error(transparent)]
+    CanonicalJson(#[from] ruma_common::CanonicalJsonError),
+
+    /// The keys could not be signed.
+    #[error(transparent)]
+    Signatures(#[from] ruma_signatures::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use ruma_common::{server_name, MilliSecondsSinceUnixEpoch, OwnedServerSigningKeyId};
+    use ruma_federation_api::discovery::OldVerifyKey;
+
+    use super::{public_key, SigningKeyRing, SigningKeyRingError};
+    use crate::{keys::verify_server_keys, test_utils::key_pair};
+
+    #[test]
+    fn server_keys_after_rotation() {
+        let first_key = key_pair("1");
+        let first_public_key = public_key(&first_key);
+        let mut key_ring =
+            SigningKeyRing::new(server_name!("origin").to_owned(), first_key).unwrap();
+        key_ring.add_old_verify_key(
+            "ed25519:0".try_into().unwrap(),
+            OldVerifyKey::new(
+                MilliSecondsSinceUnixEpoch(500_u32.into()),
+                public_key(&key_pair("0")),
+            ),
+        );
+
+        let second_key = key_pair("2");
+        let second_public_key = public_key(&second_key);
+        key_ring.rotate(second_key, MilliSecondsSinceUnixEpoch(1_000_u32.into())).unwrap();
+        assert_eq!(key_ring.active_key_id(), "ed25519:2");
+
+        let raw = key_ring.server_keys(MilliSecondsSinceUnixEpoch(2_000_u32.into())).unwrap();
+        let keys = verify_server_keys(&raw, server_name!("origin")).unwrap();
+
+        assert_eq!(keys.valid_until_ts, MilliSecondsSinceUnixEpoch(2_000_u32.into()));
+        assert_eq!(keys.verify_keys.len(), 1);
+        assert_eq!(keys.verify_keys[key_ring.active_key_id()].key, second_public_key);
+        assert_eq!(keys.old_verify_keys.len(), 2);
+        let old_key_id: OwnedServerSigningKeyId = "ed25519:1".try_into().unwrap();
+        let old_key = &keys.old_verify_keys[&old_key_id];
+        assert_eq!(old_key.key, first_public_key);
+        assert_eq!(old_key.expired_ts, MilliSecondsSinceUnixEpoch(1_000_u32.into()));
+        assert!(keys.signatures[server_name!("origin")].contains_key(key_ring.active_key_id()));
+    }
+
+    #[test]
+    fn rotate_rejects_duplicate_key_id() {
+        let mut key_ring =
+            SigningKeyRing::new(server_name!("origin").to_owned(), key_pair("1")).unwrap();
+        key_ring.rotate(key_pair("2"), MilliSecondsSinceUnixEpoch(1_000_u32.into())).unwrap();
+
+        for version in ["1", "2"] {
+            let result =
+                key_ring.rotate(key_pair(version), MilliSecondsSinceUnixEpoch(2_000_u32.into()));
+            assert!(matches!(
+                result,
+                Err(SigningKeyRingError::DuplicateKeyId(key_id)) if key_id == format!("ed25519:{version}").as_str()
+            ));
+        }
+        assert_eq!(key_ring.active_key_id(), "ed25519:2");
+        assert_eq!(key_ring.old_verify_keys().len(), 1);
+    }
+
+    #[test]
+    fn invalid_key_version() {
+        assert!(SigningKeyRing::new(server_name!("origin").to_owned(), key_pair("")).is_err());
+    }
+}
@@ -0,0 +1,77 @@
+//! Fixtures shared by the tests of the modules of this crate.
+
+use std::{
+    collections::BTreeMap,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use async_trait::async_trait;
+use ruma_common::{
+    serde::{Base64, Raw},
+    MilliSecondsSinceUnixEpoch, OwnedServerName, ServerName,
+};
+use ruma_federation_api::discovery::ServerSigningKeys;
+use ruma_signatures::{Ed25519KeyPair, PublicKeySet};
+
+use crate::keys::KeyFetcher;
+
+/// A new random key pair with the given version.
+pub(crate) fn key_pair(version: &str) -> Ed25519KeyPair {
+    Ed25519KeyPair::from_der(&Ed25519KeyPair::generate().unwrap(), version.to_owned()).unwrap()
+}
+
+/// The public key of the given key pair, as the only key of a set.
+pub(crate) fn public_key_set(key_pair: &Ed25519KeyPair) -> PublicKeySet {
+    BTreeMap::from([(
+        format!("ed25519:{}", key_pair.version()),
+        Base64::new(key_pair.public_key().to_vec()),
+    )])
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("not found")]
+pub(crate) struct NotFound;
+
+/// A fetcher that returns the keys it was given, and counts the requests.
+#[derive(Default)]
+pub(crate) struct MockFetcher {
+    /// The keys returned by each server.
+    pub(crate) server_keys: BTreeMap<OwnedServerName, Raw<ServerSigningKeys>>,
+
+    /// The keys returned by each notary server, for each server.
+    pub(crate) notary_keys: BTreeMap<(OwnedServerName, OwnedServerName), Raw<ServerSigningKeys>>,
+
+    /// The number of requests that were made.
+    pub(crate) requests: AtomicUsize,
+}
+
+impl MockFetcher {
+    /// A fetcher that returns the given keys of each server.
+    pub(crate) fn new(server_keys: BTreeMap<OwnedServerName, Raw<ServerSigningKeys>>) -> Self {
+        Self { server_keys, ..Default::default() }
+    }
+}
+
+#[async_trait]
+impl KeyFetcher for MockFetcher {
+    type Error = NotFound;
+
+    async fn fetch_server_keys(
+        &self,
+        server_name: &ServerName,
+    ) -> Result<Raw<ServerSigningKeys>, Self::Error> {
+        self.requests.fetch_add(1, Ordering::SeqCst);
+        self.server_keys.get(server_name).cloned().ok_or(NotFound)
+    }
+
+    async fn fetch_server_keys_from_notary(
+        &self,
+        notary: &ServerName,
+        server_name: &ServerName,
+        _minimum_valid_until_ts: MilliSecondsSinceUnixEpoch,
+    ) -> Result<Vec<Raw<ServerSigningKeys>>, Self::Error> {
+        self.requests.fetch_add(1, Ordering::SeqCst);
+        let keys = self.notary_keys.get(&(notary.to_owned(), server_name.to_owned()));
+        keys.cloned().map(|keys| vec![keys]).ok_or(NotFound)
+    }
+}
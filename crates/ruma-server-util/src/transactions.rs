@@ -0,0 +1,363 @@
+//! Processing of incoming federation transactions.
+//!
+//! [`handle_transaction()`] parses and validates the PDUs and EDUs of a
+//! [`send_transaction_message`] request, routes them to a [`PduHandler`] and an [`EduHandler`],
+//! and builds the response with the result of the processing of each PDU.
+//!
+//! [`send_transaction_message`]: ruma_federation_api::transactions::send_transaction_message
+
+use std::{collections::BTreeMap, error::Error as StdError};
+
+use async_trait::async_trait;
+use ruma_common::{
+    CanonicalJsonObject, CanonicalJsonValue, EventId, OwnedEventId, OwnedRoomId, RoomId,
+    RoomVersionId, ServerName,
+};
+use ruma_events::pdu::Pdu;
+use ruma_federation_api::transactions::{
+    edu::Edu,
+    send_transaction_message::v1::{Request, Response},
+};
+use ruma_signatures::reference_hash;
+use ruma_state_res::{room_version::EventFormatVersion, RoomVersion};
+use serde_json::from_str as from_json_str;
+use thiserror::Error;
+use tracing::warn;
+
+/// The maximum number of PDUs in a transaction.
+pub const MAX_PDUS: usize = 50;
+
+/// The maximum number of EDUs in a transaction.
+pub const MAX_EDUS: usize = 100;
+
+/// A handler for the PDUs of incoming transactions.
+#[async_trait]
+pub trait PduHandler: Send + Sync {
+    /// The error type of the handler.
+    type Error: StdError + Send + Sync + 'static;
+
+    /// Get the version of the given room, if the room is known.
+    ///
+    /// The PDUs of rooms with an unknown version are ignored, since their event ID can't be
+    /// computed.
+    async fn room_version(&self, room_id: &RoomId) -> Result<Option<RoomVersionId>, Self::Error>;
+
+    /// Handle a PDU with a valid format.
+    ///
+    /// The PDUs of a room are handled in the order of the transaction. An error is returned to the
+    /// origin in the response as the result of the PDU.
+    async fn handle_pdu(
+        &self,
+        origin: &ServerName,
+        room_version: &RoomVersionId,
+        event_id: &EventId,
+        pdu: CanonicalJsonObject,
+    ) -> Result<(), Self::Error>;
+}
+
+/// A handler for the EDUs of incoming transactions.
+#[async_trait]
+pub trait EduHandler: Send + Sync {
+    /// The error type of the handler.
+    type Error: StdError + Send + Sync + 'static;
+
+    /// Handle an EDU.
+    ///
+    /// Errors are only logged, since the results of EDUs are not part of the response.
+    async fn handle_edu(&self, origin: &ServerName, edu: Edu) -> Result<(), Self::Error>;
+}
+
+/// Process an incoming transaction.
+///
+/// The PDUs are grouped by room, and their event ID is computed according to the version of the
+/// room. The PDUs that can't be parsed, that are for an unknown room or that are duplicates are
+/// ignored. The PDUs that don't have a valid format for the room version get an error in the
+/// response, and the others are passed to the `pdu_handler`.
+///
+/// The EDUs that can't be deserialized are ignored, and the others are passed to the
+/// `edu_handler`.
+///
+/// # Errors
+///
+/// Returns an error if the transaction contains more than [`MAX_PDUS`] PDUs or [`MAX_EDUS`]
+/// EDUs.
+pub async fn handle_transaction<P, E>(
+    request: Request,
+    pdu_handler: &P,
+    edu_handler: &E,
+) -> Result<Response, TransactionError>
+where
+    P: PduHandler,
+    E: EduHandler,
+{
+    if request.pdus.len() > MAX_PDUS {
+        return Err(TransactionError::TooManyPdus(request.pdus.len()));
+    }
+    if request.edus.len() > MAX_EDUS {
+        return Err(TransactionError::TooManyEdus(request.edus.len()));
+    }
+
+    let origin = &request.origin;
+    let mut pdus_by_room = BTreeMap::<OwnedRoomId, Vec<_>>::new();
+
+    for pdu in request.pdus {
+        let object = match from_json_str::<CanonicalJsonObject>(pdu.get()) {
+            Ok(object) => object,
+            Err(error) => {
+                warn!("Ignoring PDU from {origin} that is not a JSON object: {error}");
+                continue;
+            }
+        };
+        let Some(room_id) = object.get("room_id").and_then(|room_id| match room_id {
+            CanonicalJsonValue::String(room_id) => OwnedRoomId::try_from(room_id.as_str()).ok(),
+            _ => None,
+        }) else {
+            warn!("Ignoring PDU from {origin} without a valid room ID");
+            continue;
+        };
+
+        pdus_by_room.entry(room_id).or_default().push((pdu, object));
+    }
+
+    let mut results = BTreeMap::new();
+
+    for (room_id, pdus) in pdus_by_room {
+        let room_version = match pdu_handler.room_version(&room_id).await {
+            Ok(Some(room_version)) => room_version,
+            Ok(None) => {
+                warn!("Ignoring {} PDUs from {origin} for unknown room {room_id}", pdus.len());
+                continue;
+            }
+            Err(error) => {
+                warn!("Ignoring {} PDUs from {origin} for room {room_id}: {error}", pdus.len());
+                continue;
+            }
+        };
+        let format_rules = match RoomVersion::new(&room_version) {
+            Ok(rules) => rules.pdu_format_rules(),
+            Err(error) => {
+                warn!("Ignoring {} PDUs from {origin} for room {room_id}: {error}", pdus.len());
+                continue;
+            }
+        };
+
+        for (pdu, object) in pdus {
+            let Some(event_id) = event_id(&object, &room_version) else {
+                warn!("Ignoring PDU from {origin} in {room_id} without a valid event ID");
+                continue;
+            };
+            if results.contains_key(&event_id) {
+                warn!("Ignoring duplicate PDU {event_id} from {origin}");
+                continue;
+            }
+
+            let result = match Pdu::from_json_with_rules(&pdu, &format_rules) {
+                Ok(_) => pdu_handler
+                    .handle_pdu(origin, &room_version, &event_id, object)
+                    .await
+                    .map_err(|error| error.to_string()),
+                Err(error) => Err(error.to_string()),
+            };
+
+            results.insert(event_id, result);
+        }
+    }
+
+    for edu in request.edus {
+        let edu = match edu.deserialize() {
+            Ok(edu) => edu,
+            Err(error) => {
+                warn!("Ignoring EDU from {origin} that can't be deserialized: {error}");
+                continue;
+            }
+        };
+
+        if let Err(error) = edu_handler.handle_edu(origin, edu).await {
+            warn!("Failed to handle EDU from {origin}: {error}");
+        }
+    }
+
+    Ok(Response::new(results))
+}
+
+/// Compute the event ID of the given PDU for the given room version.
+pub(crate) fn event_id(
+    object: &CanonicalJsonObject,
+    room_version: &RoomVersionId,
+) -> Option<OwnedEventId> {
+    match RoomVersion::new(room_version).ok()?.event_format {
+        EventFormatVersion::V1 => match object.get("event_id") {
+            Some(CanonicalJsonValue::String(event_id)) => event_id.as_str().try_into().ok(),
+            _ => None,
+        },
+        _ => {
+            let hash = reference_hash(object, room_version).ok()?;
+            format!("${hash}").try_into().ok()
+        }
+    }
+}
+
+/// An error when processing a transaction.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum TransactionError {
+    /// The transaction contains more than [`MAX_PDUS`] PDUs.
+    #[error("the transaction contains {0} PDUs, more than the maximum of {MAX_PDUS}")]
+    TooManyPdus(usize),
+
+    /// The transaction contains more than [`MAX_EDUS`] EDUs.
+    #[error("the transaction contains {0} EDUs, more than the maximum of {MAX_EDUS}")]
+    TooManyEdus(usize),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::Infallible, sync::Mutex};
+
+    use async_trait::async_trait;
+    use futures_util::FutureExt;
+    use ruma_common::{
+        owned_room_id, owned_server_name, owned_user_id, room_id, serde::Raw, CanonicalJsonObject,
+        EventId, MilliSecondsSinceUnixEpoch, OwnedEventId, RoomId, RoomVersionId, ServerName,
+    };
+    use ruma_federation_api::transactions::{
+        edu::{Edu, TypingContent},
+        send_transaction_message::v1::Request,
+    };
+    use ruma_signatures::{hash_and_sign_event, Ed25519KeyPair};
+    use serde_json::{
+        from_value as from_json_value, json, value::to_raw_value as to_raw_json_value,
+    };
+    use thiserror::Error;
+
+    use super::{handle_transaction, EduHandler, PduHandler, TransactionError, MAX_PDUS};
+    use crate::test_utils::key_pair;
+
+    #[derive(Debug, Error)]
+    #[error("rejected")]
+    struct Rejected;
+
+    #[derive(Default)]
+    struct MockHandler {
+        pdus: Mutex<Vec<OwnedEventId>>,
+        edus: Mutex<Vec<Edu>>,
+        rejected: Mutex<Option<OwnedEventId>>,
+    }
+
+    #[async_trait]
+    impl PduHandler for MockHandler {
+        type Error = Rejected;
+
+        async fn room_version(&self, room_id: &RoomId) -> Result<Option<RoomVersionId>, Rejected> {
+            Ok((room_id == room_id!("!room:origin")).then_some(RoomVersionId::V6))
+        }
+
+        async fn handle_pdu(
+            &self,
+            _origin: &ServerName,
+            _room_version: &RoomVersionId,
+            event_id: &EventId,
+            _pdu: CanonicalJsonObject,
+        ) -> Result<(), Rejected> {
+            if self.rejected.lock().unwrap().as_deref() == Some(event_id) {
+                return Err(Rejected);
+            }
+            self.pdus.lock().unwrap().push(event_id.to_owned());
+            Ok(())
+        }
+    }
+
+    #[async_trait]
+    impl EduHandler for MockHandler {
+        type Error = Infallible;
+
+        async fn handle_edu(&self, _origin: &ServerName, edu: Edu) -> Result<(), Infallible> {
+            self.edus.lock().unwrap().push(edu);
+            Ok(())
+        }
+    }
+
+    fn pdu(room_id: &str, body: &str, key_pair: &Ed25519KeyPair) -> CanonicalJsonObject {
+        let mut pdu = from_json_value(json!({
+            "auth_events": [],
+            "content": { "msgtype": "m.text", "body": body },
+            "depth": 3,
+            "origin": "origin",
+            "origin_server_ts": 1_000_000,
+            "prev_events": [],
+            "room_id": room_id,
+            "sender": "@alice:origin",
+            "type": "m.room.message",
+        }))
+        .unwrap();
+        hash_and_sign_event("origin", key_pair, &mut pdu, &RoomVersionId::V6).unwrap();
+        pdu
+    }
+
+    fn request(pdus: &[CanonicalJsonObject]) -> Request {
+        let mut request = Request::new(
+            "txn".into(),
+            owned_server_name!("origin"),
+            MilliSecondsSinceUnixEpoch::now(),
+        );
+        request.pdus = pdus.iter().map(|pdu| to_raw_json_value(pdu).unwrap()).collect();
+        request
+    }
+
+    #[test]
+    fn handle_pdus_and_edus() {
+        let key_pair = key_pair("1");
+        let accepted = pdu("!room:origin", "accepted", &key_pair);
+        let rejected = pdu("!room:origin", "rejected", &key_pair);
+        let unknown_room = pdu("!unknown:origin", "unknown", &key_pair);
+        let mut invalid = pdu("!room:origin", "invalid", &key_pair);
+        invalid.insert("event_id".to_owned(), "$invalid:origin".into());
+
+        let mut request = request(&[accepted, rejected.clone(), unknown_room, invalid]);
+        request.edus = vec![
+            Raw::new(&Edu::Typing(TypingContent::new(
+                owned_room_id!("!room:origin"),
+                owned_user_id!("@alice:origin"),
+                true,
+            )))
+            .unwrap(),
+            Raw::from_json(to_raw_json_value(&json!({ "edu_type": "m.typing" })).unwrap()),
+        ];
+
+        let handler = MockHandler::default();
+        let rejected_id = super::event_id(&rejected, &RoomVersionId::V6).unwrap();
+        *handler.rejected.lock().unwrap() = Some(rejected_id.clone());
+
+        let response =
+            handle_transaction(request, &handler, &handler).now_or_never().unwrap().unwrap();
+
+        assert_eq!(response.pdus.len(), 3);
+        let accepted_ids = handler.pdus.lock().unwrap().clone();
+        assert_eq!(accepted_ids.len(), 1);
+        assert_eq!(response.pdus[&accepted_ids[0]], Ok(()));
+        assert_eq!(response.pdus[&rejected_id], Err("rejected".to_owned()));
+        assert_eq!(
+            response.pdus.values().filter(|result| result.is_err()).count(),
+            2,
+            "the PDU with an invalid format is rejected"
+        );
+
+        let edus = handler.edus.lock().unwrap();
+        assert_eq!(edus.len(), 1);
+        assert!(matches!(edus[0], Edu::Typing(_)));
+    }
+
+    #[test]
+    fn too_many_pdus() {
+        let key_pair = key_pair("1");
+        let pdus = (0..=MAX_PDUS)
+            .map(|i| pdu("!room:origin", &i.to_string(), &key_pair))
+            .collect::<Vec<_>>();
+        let handler = MockHandler::default();
+
+        let result = handle_transaction(request(&pdus), &handler, &handler).now_or_never().unwrap();
+
+        assert!(matches!(result, Err(TransactionError::TooManyPdus(51))));
+        assert!(handler.pdus.lock().unwrap().is_empty());
+    }
+}
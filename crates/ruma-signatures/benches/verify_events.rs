@@ -0,0 +1,75 @@
+// `cargo bench` works, but if you use `cargo bench -- --save-baseline <name>`
+// or pass any other args to it, it fails with the error
+// `cargo bench unknown option --save-baseline`.
+// To pass args to criterion, use this form
+// `cargo bench --features criterion --bench <name of the bench> -- --save-baseline <name>`.
+
+use std::collections::BTreeMap;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use ruma_common::{serde::Base64, CanonicalJsonObject, RoomVersionId};
+use ruma_signatures::{
+    hash_and_sign_event, verify_event, verify_events, Ed25519KeyPair, PublicKeyMap,
+};
+use serde_json::json;
+
+const EVENTS_COUNT: usize = 1_000;
+
+fn signed_events() -> (PublicKeyMap, Vec<CanonicalJsonObject>) {
+    let key_pair =
+        Ed25519KeyPair::from_der(&Ed25519KeyPair::generate().unwrap(), "1".to_owned()).unwrap();
+    let public_key_map = BTreeMap::from([(
+        "domain".to_owned(),
+        BTreeMap::from([("ed25519:1".to_owned(), Base64::new(key_pair.public_key().to_vec()))]),
+    )]);
+
+    let events = (0..EVENTS_COUNT)
+        .map(|i| {
+            let mut event = serde_json::from_value(json!({
+                "auth_events": [],
+                "content": { "membership": "join" },
+                "depth": i,
+                "origin": "domain",
+                "origin_server_ts": 1_000_000 + i,
+                "prev_events": [],
+                "room_id": "!x:domain",
+                "sender": format!("@user{i}:domain"),
+                "state_key": format!("@user{i}:domain"),
+                "type": "m.room.member",
+            }))
+            .unwrap();
+            hash_and_sign_event("domain", &key_pair, &mut event, &RoomVersionId::V10).unwrap();
+            event
+        })
+        .collect();
+
+    (public_key_map, events)
+}
+
+fn verify_one_by_one(c: &mut Criterion) {
+    let (public_key_map, events) = signed_events();
+
+    c.bench_function("verify 1000 events one by one", |b| {
+        b.iter(|| {
+            for event in &events {
+                verify_event(&public_key_map, event, &RoomVersionId::V10).unwrap();
+            }
+        });
+    });
+}
+
+fn verify_all(c: &mut Criterion) {
+    let (public_key_map, events) = signed_events();
+
+    c.bench_function("verify 1000 events at once", |b| {
+        b.iter(|| {
+            for result in verify_events(&public_key_map, &events, &RoomVersionId::V10) {
+                result.unwrap();
+            }
+        });
+    });
+}
+
+criterion_group!(benches, verify_one_by_one, verify_all);
+
+criterion_main!(benches);
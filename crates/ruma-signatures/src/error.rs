@@ -153,6 +153,16 @@ pub enum VerificationError {
     /// For when [`ed25519_dalek`] cannot verify a signature.
     #[error("Could not verify signature: {0}")]
     Signature(#[source] ed25519_dalek::SignatureError),
+
+    /// For when the public key used for a signature was not valid at the time of the event.
+    #[error("Public key {key_id} of {entity:?} was not valid at the time of the event")]
+    PublicKeyNotValidAtEventTime {
+        /// The entity that made the signature.
+        entity: OwnedServerName,
+
+        /// The ID of the public key.
+        key_id: String,
+    },
 }
 
 impl VerificationError {
@@ -203,6 +213,12 @@ pub enum ParseError {
         found: pkcs8::ObjectIdentifier,
     },
 
+    /// For when a line of a signing key file is not in the format `<algorithm> <version> <seed>`.
+    ///
+    /// Contains the number of the line, starting at 1.
+    #[error("Invalid line {0} in signing key file")]
+    SigningKeyLine(usize),
+
     /// For when [`ed25519_dalek`] cannot parse a secret/private key.
     #[error("Could not parse secret key")]
     SecretKey,
@@ -9,7 +9,10 @@ use ed25519_dalek::{pkcs8::ALGORITHM_OID, SecretKey, Signer, SigningKey, PUBLIC_
 use pkcs8::{
     der::zeroize::Zeroizing, DecodePrivateKey, EncodePrivateKey, ObjectIdentifier, PrivateKeyInfo,
 };
-use ruma_common::serde::Base64;
+use ruma_common::{
+    serde::{base64::Standard, Base64},
+    MilliSecondsSinceUnixEpoch,
+};
 
 use crate::{signatures::Signature, Algorithm, Error, ParseError};
 
@@ -106,6 +109,54 @@ impl Ed25519KeyPair {
         Ok(Self { signing_key, version })
     }
 
+    /// Constructs a key pair from the 32-byte seed of the private key.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the seed doesn't have the correct length.
+    pub fn from_seed(seed: &[u8], version: String) -> Result<Self, Error> {
+        let secret_key: &SecretKey = seed.try_into().map_err(|_| ParseError::SecretKey)?;
+        Ok(Self { signing_key: SigningKey::from_bytes(secret_key), version })
+    }
+
+    /// Parses the key pairs in a signing key file, as used by Synapse.
+    ///
+    /// Each non-empty line of the file contains a key, in the format
+    /// `<algorithm> <version> <seed>`, where `seed` is the unpadded base64-encoded seed of the
+    /// private key, e.g. `ed25519 a_xyz YJDBA9Xnr2sVqXD9Vj7XVUnmFZcZrlw8Md7kMW+3XA1`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a line is malformed, if a key uses another algorithm than ed25519 or if
+    /// a version contains invalid characters.
+    pub fn from_signing_key_file(contents: &str) -> Result<Vec<Self>, Error> {
+        contents
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(index, line)| {
+                let mut parts = line.split_whitespace();
+                let (Some(algorithm), Some(version), Some(seed), None) =
+                    (parts.next(), parts.next(), parts.next(), parts.next())
+                else {
+                    return Err(ParseError::SigningKeyLine(index + 1).into());
+                };
+
+                if algorithm != Algorithm::Ed25519.as_ref() {
+                    return Err(Error::UnsupportedAlgorithm(algorithm.to_owned()));
+                }
+
+                if !version.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_') {
+                    return Err(Error::InvalidVersion(version.to_owned()));
+                }
+
+                // Don't include the seed in the error, it is a secret.
+                let seed = Base64::<Standard>::parse(seed).map_err(|_| ParseError::SecretKey)?;
+                Self::from_seed(seed.as_bytes(), version.to_owned())
+            })
+            .collect()
+    }
+
     /// Constructs a key pair from [`pkcs8::PrivateKeyInfo`].
     pub fn from_pkcs8_oak(oak: PrivateKeyInfo<'_>, version: String) -> Result<Self, Error> {
         Self::new(oak.algorithm.oid, oak.private_key, oak.public_key, version)
@@ -142,6 +193,26 @@ impl Ed25519KeyPair {
         Ok(signing_key.to_pkcs8_der().map_err(Error::DerParse)?.to_bytes())
     }
 
+    /// Exports the key pair as a DER-encoded PKCS#8 v2 document (with public key).
+    ///
+    /// This is the format accepted by [`Ed25519KeyPair::from_der()`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the encoding failed.
+    pub fn to_der(&self) -> Result<Zeroizing<Vec<u8>>, Error> {
+        Ok(self.signing_key.to_pkcs8_der().map_err(Error::DerParse)?.to_bytes())
+    }
+
+    /// Exports the key pair as a line of a signing key file, as used by Synapse.
+    ///
+    /// This is the format accepted by [`Ed25519KeyPair::from_signing_key_file()`]. It doesn't
+    /// include a line break.
+    pub fn to_signing_key_line(&self) -> Zeroizing<String> {
+        let seed = Zeroizing::new(Base64::<Standard, _>::new(self.signing_key.as_bytes()).encode());
+        Zeroizing::new(format!("{} {} {}", Algorithm::Ed25519, self.version, *seed))
+    }
+
     /// Returns the version string for this keypair.
     pub fn version(&self) -> &str {
         &self.version
@@ -183,9 +254,47 @@ pub type PublicKeyMap = BTreeMap<String, PublicKeySet>;
 /// This is represented as a map from key ID to base64-encoded signature.
 pub type PublicKeySet = BTreeMap<String, Base64>;
 
+/// A map from entity names to sets of public keys for that entity, with the time until which each
+/// key is valid.
+///
+/// This is used by [`verify_event_with_key_validity`] to reject signatures made by keys that were
+/// not valid at the time of the event.
+///
+/// [`verify_event_with_key_validity`]: crate::verify_event_with_key_validity
+pub type TimedPublicKeyMap = BTreeMap<String, TimedPublicKeySet>;
+
+/// A set of public keys for a single homeserver, with the time until which each key is valid.
+///
+/// This is represented as a map from key ID to public key.
+pub type TimedPublicKeySet = BTreeMap<String, TimedPublicKey>;
+
+/// A public key, with the time until which it is valid.
+#[derive(Clone, Debug)]
+#[cfg_attr(not(feature = "unstable-exhaustive-types"), non_exhaustive)]
+pub struct TimedPublicKey {
+    /// The base64-encoded public key.
+    pub key: Base64,
+
+    /// The time until which the key is valid.
+    ///
+    /// This is the `valid_until_ts` of the server keys response for a key in `verify_keys`, and
+    /// the `expired_ts` of the key for a key in `old_verify_keys`.
+    pub valid_until_ts: MilliSecondsSinceUnixEpoch,
+}
+
+impl TimedPublicKey {
+    /// Creates a new `TimedPublicKey` with the given key and validity time.
+    pub fn new(key: Base64, valid_until_ts: MilliSecondsSinceUnixEpoch) -> Self {
+        Self { key, valid_until_ts }
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use assert_matches2::assert_matches;
+
     use super::Ed25519KeyPair;
+    use crate::{Error, ParseError};
 
     const WELL_FORMED_DOC: &[u8] = &[
         0x30, 0x72, 0x02, 0x01, 0x01, 0x30, 0x05, 0x06, 0x03, 0x2B, 0x65, 0x70, 0x04, 0x22, 0x04,
@@ -216,6 +325,51 @@ mod tests {
         assert_eq!(keypair.public_key(), WELL_FORMED_PUBKEY);
     }
 
+    #[test]
+    fn der_roundtrip() {
+        let keypair = Ed25519KeyPair::from_der(WELL_FORMED_DOC, "1".to_owned()).unwrap();
+        let document = keypair.to_der().unwrap();
+        let keypair = Ed25519KeyPair::from_der(&document, "1".to_owned()).unwrap();
+
+        assert_eq!(keypair.public_key(), WELL_FORMED_PUBKEY);
+    }
+
+    #[test]
+    fn signing_key_file() {
+        let keypair = Ed25519KeyPair::from_der(WELL_FORMED_DOC, "a_xyz".to_owned()).unwrap();
+        let line = keypair.to_signing_key_line();
+        assert_eq!(*line, "ed25519 a_xyz 1O5y2/kTWErVttjx92n4rTr+fCjL8dT74Jeoj0R1WEI");
+
+        let other =
+            Ed25519KeyPair::from_der(&Ed25519KeyPair::generate().unwrap(), "2".to_owned()).unwrap();
+        let contents = format!("{}\n\n{}\n", *line, *other.to_signing_key_line());
+        let keypairs = Ed25519KeyPair::from_signing_key_file(&contents).unwrap();
+
+        assert_eq!(keypairs.len(), 2);
+        assert_eq!(keypairs[0].version(), "a_xyz");
+        assert_eq!(keypairs[0].public_key(), WELL_FORMED_PUBKEY);
+        assert_eq!(keypairs[1].version(), "2");
+        assert_eq!(keypairs[1].public_key(), other.public_key());
+    }
+
+    #[test]
+    fn invalid_signing_key_file() {
+        assert_matches!(
+            Ed25519KeyPair::from_signing_key_file("ed25519 a_xyz"),
+            Err(Error::Parse(ParseError::SigningKeyLine(1)))
+        );
+        assert_matches!(
+            Ed25519KeyPair::from_signing_key_file(
+                "\nrsa a_xyz 1O5y2/kTWErVttjx92n4rTr+fCjL8dT74Jeoj0R1WEI"
+            ),
+            Err(Error::UnsupportedAlgorithm(_))
+        );
+        assert_matches!(
+            Ed25519KeyPair::from_signing_key_file("ed25519 a_xyz AAAA"),
+            Err(Error::Parse(ParseError::SecretKey))
+        );
+    }
+
     #[cfg(feature = "ring-compat")]
     mod ring_compat {
         use super::Ed25519KeyPair;
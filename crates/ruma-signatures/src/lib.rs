@@ -50,9 +50,12 @@ pub use self::{
     error::{Error, JsonError, ParseError, VerificationError},
     functions::{
         canonical_json, content_hash, hash_and_sign_event, reference_hash, sign_json, verify_event,
-        verify_json,
+        verify_event_with_key_validity, verify_events, verify_json,
+    },
+    keys::{
+        Ed25519KeyPair, KeyPair, PublicKeyMap, PublicKeySet, TimedPublicKey, TimedPublicKeyMap,
+        TimedPublicKeySet,
     },
-    keys::{Ed25519KeyPair, KeyPair, PublicKeyMap, PublicKeySet},
     signatures::Signature,
     verification::Verified,
 };
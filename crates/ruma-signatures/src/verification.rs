@@ -1,8 +1,11 @@
 //! Verification of digital signatures.
 
+use std::collections::BTreeMap;
+
 use ed25519_dalek::{Verifier as _, VerifyingKey};
+use ruma_common::{serde::Base64, MilliSecondsSinceUnixEpoch};
 
-use crate::{Error, ParseError, VerificationError};
+use crate::{Error, ParseError, TimedPublicKey, VerificationError};
 
 /// A digital signature verifier.
 pub(crate) trait Verifier {
@@ -32,18 +35,108 @@ impl Verifier for Ed25519Verifier {
         signature: &[u8],
         message: &[u8],
     ) -> Result<(), Error> {
-        VerifyingKey::from_bytes(
-            public_key
-                .try_into()
-                .map_err(|_| ParseError::PublicKey(ed25519_dalek::SignatureError::new()))?,
-        )
-        .map_err(ParseError::PublicKey)?
-        .verify(message, &signature.try_into().map_err(ParseError::Signature)?)
-        .map_err(VerificationError::Signature)
-        .map_err(Error::from)
+        parse_public_key(public_key)?
+            .verify(message, &signature.try_into().map_err(ParseError::Signature)?)
+            .map_err(VerificationError::Signature)
+            .map_err(Error::from)
+    }
+}
+
+/// Parse the raw bytes of an Ed25519 public key.
+fn parse_public_key(public_key: &[u8]) -> Result<VerifyingKey, Error> {
+    Ok(VerifyingKey::from_bytes(
+        public_key
+            .try_into()
+            .map_err(|_| ParseError::PublicKey(ed25519_dalek::SignatureError::new()))?,
+    )
+    .map_err(ParseError::PublicKey)?)
+}
+
+/// A public key in a map of public keys.
+pub(crate) trait PublicKeyEntry {
+    /// The base64-encoded public key.
+    fn public_key(&self) -> &Base64;
+
+    /// The time until which the key is valid, if it is known.
+    fn valid_until_ts(&self) -> Option<MilliSecondsSinceUnixEpoch>;
+}
+
+impl PublicKeyEntry for Base64 {
+    fn public_key(&self) -> &Base64 {
+        self
+    }
+
+    fn valid_until_ts(&self) -> Option<MilliSecondsSinceUnixEpoch> {
+        None
+    }
+}
+
+impl PublicKeyEntry for TimedPublicKey {
+    fn public_key(&self) -> &Base64 {
+        &self.key
+    }
+
+    fn valid_until_ts(&self) -> Option<MilliSecondsSinceUnixEpoch> {
+        Some(self.valid_until_ts)
     }
 }
 
+/// A public key parsed by a [`PublicKeyCache`].
+#[derive(Clone, Copy)]
+pub(crate) struct ParsedPublicKey {
+    /// The parsed key.
+    pub(crate) verifying_key: VerifyingKey,
+
+    /// The time until which the key is valid, if it is known.
+    pub(crate) valid_until_ts: Option<MilliSecondsSinceUnixEpoch>,
+}
+
+/// The public keys of a map of public keys, that are only parsed the first time they are used.
+pub(crate) struct PublicKeyCache<'a, K> {
+    public_key_map: &'a BTreeMap<String, BTreeMap<String, K>>,
+    parsed: BTreeMap<(&'a str, &'a str), ParsedPublicKey>,
+}
+
+impl<'a, K: PublicKeyEntry> PublicKeyCache<'a, K> {
+    pub(crate) fn new(public_key_map: &'a BTreeMap<String, BTreeMap<String, K>>) -> Self {
+        Self { public_key_map, parsed: BTreeMap::new() }
+    }
+
+    /// The public keys of the given entity.
+    pub(crate) fn entity_keys(&self, entity_id: &str) -> Option<EntityPublicKeys<'a, K>> {
+        let (entity_id, public_keys) = self.public_key_map.get_key_value(entity_id)?;
+        Some(EntityPublicKeys { entity_id, public_keys })
+    }
+
+    /// Get the parsed public key with the given ID of the entity, if it exists.
+    pub(crate) fn get(
+        &mut self,
+        entity_keys: &EntityPublicKeys<'a, K>,
+        key_id: &str,
+    ) -> Result<Option<ParsedPublicKey>, Error> {
+        let Some((key_id, public_key)) = entity_keys.public_keys.get_key_value(key_id) else {
+            return Ok(None);
+        };
+
+        if let Some(key) = self.parsed.get(&(entity_keys.entity_id, key_id.as_str())) {
+            return Ok(Some(*key));
+        }
+
+        let key = ParsedPublicKey {
+            verifying_key: parse_public_key(public_key.public_key().as_bytes())?,
+            valid_until_ts: public_key.valid_until_ts(),
+        };
+        self.parsed.insert((entity_keys.entity_id, key_id), key);
+        Ok(Some(key))
+    }
+}
+
+/// The public keys of an entity in a [`PublicKeyCache`].
+pub(crate) struct EntityPublicKeys<'a, K> {
+    entity_id: &'a str,
+    public_keys: &'a BTreeMap<String, K>,
+}
+
 /// A value returned when an event is successfully verified.
 ///
 /// Event verification involves verifying both signatures and a content hash. It is possible for
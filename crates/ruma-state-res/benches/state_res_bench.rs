@@ -10,6 +10,7 @@
 use std::{
     borrow::Borrow,
     collections::{HashMap, HashSet},
+    future,
     sync::{
         atomic::{AtomicU64, Ordering::SeqCst},
         Arc,
@@ -18,6 +19,7 @@ use std::{
 
 use criterion::{criterion_group, criterion_main, Criterion};
 use event::PduEvent;
+use futures_util::FutureExt;
 use js_int::{int, uint};
 use maplit::{btreemap, hashmap, hashset};
 use ruma_common::{
@@ -87,42 +89,9 @@ fn resolution_shallow_auth_chain(c: &mut Criterion) {
 
 fn resolve_deeper_event_set(c: &mut Criterion) {
     c.bench_function("resolve state of 10 events 3 conflicting", |b| {
-        let mut inner = INITIAL_EVENTS();
-        let ban = BAN_STATE_SET();
-
-        inner.extend(ban);
+        let (inner, state_set_a, state_set_b) = deeper_event_set();
         let store = TestStore(inner.clone());
 
-        let state_set_a = [
-            inner.get(&event_id("CREATE")).unwrap(),
-            inner.get(&event_id("IJR")).unwrap(),
-            inner.get(&event_id("IMA")).unwrap(),
-            inner.get(&event_id("IMB")).unwrap(),
-            inner.get(&event_id("IMC")).unwrap(),
-            inner.get(&event_id("MB")).unwrap(),
-            inner.get(&event_id("PA")).unwrap(),
-        ]
-        .iter()
-        .map(|ev| {
-            (ev.event_type().with_state_key(ev.state_key().unwrap()), ev.event_id().to_owned())
-        })
-        .collect::<StateMap<_>>();
-
-        let state_set_b = [
-            inner.get(&event_id("CREATE")).unwrap(),
-            inner.get(&event_id("IJR")).unwrap(),
-            inner.get(&event_id("IMA")).unwrap(),
-            inner.get(&event_id("IMB")).unwrap(),
-            inner.get(&event_id("IMC")).unwrap(),
-            inner.get(&event_id("IME")).unwrap(),
-            inner.get(&event_id("PA")).unwrap(),
-        ]
-        .iter()
-        .map(|ev| {
-            (ev.event_type().with_state_key(ev.state_key().unwrap()), ev.event_id().to_owned())
-        })
-        .collect::<StateMap<_>>();
-
         b.iter(|| {
             let state_sets = [&state_set_a, &state_set_b];
             let _ = match state_res::resolve(
@@ -143,11 +112,35 @@ fn resolve_deeper_event_set(c: &mut Criterion) {
     });
 }
 
+fn resolve_deeper_event_set_incremental(c: &mut Criterion) {
+    c.bench_function("resolve state of 10 events 3 conflicting with a warm cache", |b| {
+        let (inner, state_set_a, state_set_b) = deeper_event_set();
+        let mut cache = state_res::InMemoryResolutionCache::new();
+
+        b.iter(|| {
+            let state_sets = [&state_set_a, &state_set_b];
+            let _ = match state_res::resolve_incremental(
+                &RoomVersionId::V6,
+                state_sets,
+                &mut cache,
+                |id| future::ready(Ok(inner.get(id).map(Arc::clone))),
+            )
+            .now_or_never()
+            .expect("fetching events never yields")
+            {
+                Ok(state) => state,
+                Err(_) => panic!("resolution failed during benchmarking"),
+            };
+        });
+    });
+}
+
 criterion_group!(
     benches,
     lexico_topo_sort,
     resolution_shallow_auth_chain,
-    resolve_deeper_event_set
+    resolve_deeper_event_set,
+    resolve_deeper_event_set_incremental
 );
 
 criterion_main!(benches);
@@ -157,6 +150,43 @@ criterion_main!(benches);
 //  IMPLEMENTATION DETAILS AHEAD
 //
 /////////////////////////////////////////////////////////////////////*/
+/// The events of the "10 events 3 conflicting" benchmarks, and the two state sets to resolve.
+fn deeper_event_set(
+) -> (HashMap<OwnedEventId, Arc<PduEvent>>, StateMap<OwnedEventId>, StateMap<OwnedEventId>) {
+    let mut inner = INITIAL_EVENTS();
+    let ban = BAN_STATE_SET();
+
+    inner.extend(ban);
+
+    let state_set_a = [
+        inner.get(&event_id("CREATE")).unwrap(),
+        inner.get(&event_id("IJR")).unwrap(),
+        inner.get(&event_id("IMA")).unwrap(),
+        inner.get(&event_id("IMB")).unwrap(),
+        inner.get(&event_id("IMC")).unwrap(),
+        inner.get(&event_id("MB")).unwrap(),
+        inner.get(&event_id("PA")).unwrap(),
+    ]
+    .iter()
+    .map(|ev| (ev.event_type().with_state_key(ev.state_key().unwrap()), ev.event_id().to_owned()))
+    .collect::<StateMap<_>>();
+
+    let state_set_b = [
+        inner.get(&event_id("CREATE")).unwrap(),
+        inner.get(&event_id("IJR")).unwrap(),
+        inner.get(&event_id("IMA")).unwrap(),
+        inner.get(&event_id("IMB")).unwrap(),
+        inner.get(&event_id("IMC")).unwrap(),
+        inner.get(&event_id("IME")).unwrap(),
+        inner.get(&event_id("PA")).unwrap(),
+    ]
+    .iter()
+    .map(|ev| (ev.event_type().with_state_key(ev.state_key().unwrap()), ev.event_id().to_owned()))
+    .collect::<StateMap<_>>();
+
+    (inner, state_set_a, state_set_b)
+}
+
 struct TestStore<E: Event>(HashMap<OwnedEventId, Arc<E>>);
 
 #[allow(unused)]
@@ -529,6 +559,7 @@ impl EventTypeExt for &TimelineEventType {
 }
 
 mod event {
+    use js_int::UInt;
     use ruma_common::{MilliSecondsSinceUnixEpoch, OwnedEventId, RoomId, UserId};
     use ruma_events::{pdu::Pdu, TimelineEventType};
     use ruma_state_res::Event;
@@ -596,6 +627,15 @@ mod event {
             }
         }
 
+        fn depth(&self) -> UInt {
+            match &self.rest {
+                Pdu::RoomV1Pdu(ev) => ev.depth,
+                Pdu::RoomV3Pdu(ev) => ev.depth,
+                #[allow(unreachable_patterns)]
+                _ => unreachable!("new PDU version"),
+            }
+        }
+
         fn prev_events(&self) -> Box<dyn DoubleEndedIterator<Item = &Self::Id> + '_> {
             match &self.rest {
                 Pdu::RoomV1Pdu(ev) => Box::new(ev.prev_events.iter().map(|(id, _)| id)),
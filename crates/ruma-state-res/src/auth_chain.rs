@@ -0,0 +1,241 @@
+//! Computation of auth chains and of the auth difference between state sets.
+
+use std::{
+    borrow::Borrow,
+    collections::{HashMap, HashSet},
+    future::Future,
+    hash::Hash,
+    sync::Arc,
+};
+
+use ruma_common::EventId;
+use tracing::{debug, warn};
+
+use crate::{Error, Event, Result, StateMap};
+
+/// A cache for the auth chains of single events.
+///
+/// The auth chain of an event never changes, so it can be kept for as long as the event itself.
+pub trait AuthChainCache<Id> {
+    /// Get the auth chain of the event with the given ID, if it was cached before.
+    fn get_auth_chain(&mut self, event_id: &EventId) -> Option<Arc<HashSet<Id>>>;
+
+    /// Cache the auth chain of the event with the given ID.
+    fn insert_auth_chain(&mut self, event_id: Id, auth_chain: Arc<HashSet<Id>>);
+}
+
+impl<Id> AuthChainCache<Id> for HashMap<Id, Arc<HashSet<Id>>>
+where
+    Id: Eq + Hash + Borrow<EventId>,
+{
+    fn get_auth_chain(&mut self, event_id: &EventId) -> Option<Arc<HashSet<Id>>> {
+        self.get(event_id).cloned()
+    }
+
+    fn insert_auth_chain(&mut self, event_id: Id, auth_chain: Arc<HashSet<Id>>) {
+        self.insert(event_id, auth_chain);
+    }
+}
+
+/// Get the auth chain of the given events.
+///
+/// The auth chain of an event is the recursive set of its `auth_events`, without the event
+/// itself. The returned set is the union of the auth chains of all the `event_ids`.
+///
+/// The auth chain of every event that is walked through is stored in `cache`, and auth chains that
+/// are already in `cache` are not computed again.
+///
+/// ## Errors
+///
+/// Returns `Error::NotFound` if one of the events or of their auth events could not be fetched,
+/// and any error returned by `fetch_event`.
+pub async fn auth_chain<E, Fut>(
+    event_ids: impl IntoIterator<Item = E::Id>,
+    cache: &mut impl AuthChainCache<E::Id>,
+    fetch_event: impl Fn(&EventId) -> Fut,
+) -> Result<HashSet<E::Id>>
+where
+    E: Event,
+    Fut: Future<Output = Result<Option<E>>>,
+{
+    let mut auth_chain = HashSet::new();
+
+    for event_id in event_ids {
+        let event_auth_chain = event_auth_chain(event_id, cache, &fetch_event).await?;
+        auth_chain.extend(event_auth_chain.iter().cloned());
+    }
+
+    Ok(auth_chain)
+}
+
+/// Get the auth chains of each of the given state sets.
+///
+/// The returned list can be used as the `auth_chain_sets` argument of [`resolve`] and
+/// [`resolve_async`].
+///
+/// [`resolve`]: crate::resolve
+/// [`resolve_async`]: crate::resolve_async
+pub async fn auth_chain_sets<'a, E, Fut>(
+    state_sets: impl IntoIterator<Item = &'a StateMap<E::Id>>,
+    cache: &mut impl AuthChainCache<E::Id>,
+    fetch_event: impl Fn(&EventId) -> Fut,
+) -> Result<Vec<HashSet<E::Id>>>
+where
+    E: Event,
+    E::Id: 'a,
+    Fut: Future<Output = Result<Option<E>>>,
+{
+    let mut auth_chain_sets = Vec::new();
+
+    for state_set in state_sets {
+        auth_chain_sets.push(auth_chain(state_set.values().cloned(), cache, &fetch_event).await?);
+    }
+
+    Ok(auth_chain_sets)
+}
+
+/// Get the auth difference of the given auth chains.
+///
+/// This is the set of event IDs that appear in some of the `auth_chain_sets` but not in all of
+/// them.
+pub fn auth_chain_difference<Id>(auth_chain_sets: Vec<HashSet<Id>>) -> HashSet<Id>
+where
+    Id: Eq + Hash,
+{
+    let num_sets = auth_chain_sets.len();
+
+    let mut id_counts: HashMap<Id, usize> = HashMap::new();
+    for id in auth_chain_sets.into_iter().flatten() {
+        *id_counts.entry(id).or_default() += 1;
+    }
+
+    id_counts.into_iter().filter_map(|(id, count)| (count < num_sets).then_some(id)).collect()
+}
+
+/// Get the auth chain of a single event, using and filling the cache.
+async fn event_auth_chain<E, Fut>(
+    event_id: E::Id,
+    cache: &mut impl AuthChainCache<E::Id>,
+    fetch_event: &impl Fn(&EventId) -> Fut,
+) -> Result<Arc<HashSet<E::Id>>>
+where
+    E: Event,
+    Fut: Future<Output = Result<Option<E>>>,
+{
+    enum Step<Id> {
+        /// Fetch the event and schedule its auth events.
+        Visit(Id),
+        /// All the auth events have been visited, build the auth chain of the event.
+        Build(Id, Vec<Id>),
+    }
+
+    if let Some(auth_chain) = cache.get_auth_chain(event_id.borrow()) {
+        return Ok(auth_chain);
+    }
+
+    debug!("computing auth chain of {event_id}");
+
+    // Depth-first walk, so the auth chains of the auth events are always cached before the auth
+    // chain of the event that references them is built.
+    let mut in_progress = HashSet::new();
+    let mut stack = vec![Step::Visit(event_id.clone())];
+
+    while let Some(step) = stack.pop() {
+        match step {
+            Step::Visit(id) => {
+                if in_progress.contains(&id) || cache.get_auth_chain(id.borrow()).is_some() {
+                    continue;
+                }
+
+                let event = fetch_event(id.borrow())
+                    .await?
+                    .ok_or_else(|| Error::NotFound(format!("Failed to find {id}")))?;
+                let auth_events = event.auth_events().cloned().collect::<Vec<_>>();
+
+                in_progress.insert(id.clone());
+                stack.push(Step::Build(id, auth_events.clone()));
+                stack.extend(auth_events.into_iter().map(Step::Visit));
+            }
+            Step::Build(id, auth_events) => {
+                let mut auth_chain = HashSet::new();
+
+                for aid in auth_events {
+                    match cache.get_auth_chain(aid.borrow()) {
+                        Some(aid_auth_chain) => auth_chain.extend(aid_auth_chain.iter().cloned()),
+                        // Only happens if the auth events contain a cycle
+                        None => warn!("auth chain of {aid} is not known while building {id}"),
+                    }
+                    auth_chain.insert(aid);
+                }
+
+                in_progress.remove::<E::Id>(&id);
+                cache.insert_auth_chain(id, Arc::new(auth_chain));
+            }
+        }
+    }
+
+    cache
+        .get_auth_chain(event_id.borrow())
+        .ok_or_else(|| Error::NotFound(format!("Failed to find auth chain of {event_id}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{HashMap, HashSet},
+        future,
+        sync::Arc,
+    };
+
+    use futures_util::FutureExt;
+    use ruma_common::{EventId, OwnedEventId};
+
+    use super::{auth_chain, auth_chain_difference};
+    use crate::test_utils::{event_id, INITIAL_EVENTS};
+
+    #[test]
+    fn auth_chain_of_initial_events() {
+        let events = INITIAL_EVENTS();
+        let fetch_event = |id: &EventId| future::ready(Ok(events.get(id).map(Arc::clone)));
+        let mut cache = HashMap::new();
+
+        let chain =
+            auth_chain([event_id("IMB")], &mut cache, fetch_event).now_or_never().unwrap().unwrap();
+
+        assert_eq!(
+            chain,
+            ["CREATE", "IMA", "IPOWER", "IJR"].into_iter().map(event_id).collect::<HashSet<_>>()
+        );
+
+        // The auth chains of all the walked events were cached.
+        assert_eq!(
+            cache.keys().cloned().collect::<HashSet<OwnedEventId>>(),
+            ["CREATE", "IMA", "IPOWER", "IJR", "IMB"]
+                .into_iter()
+                .map(event_id)
+                .collect::<HashSet<_>>()
+        );
+    }
+
+    #[test]
+    fn auth_chain_missing_event() {
+        let events = INITIAL_EVENTS();
+        let fetch_event = |id: &EventId| future::ready(Ok(events.get(id).map(Arc::clone)));
+
+        auth_chain([event_id("MISSING")], &mut HashMap::new(), fetch_event)
+            .now_or_never()
+            .unwrap()
+            .unwrap_err();
+    }
+
+    #[test]
+    fn auth_difference() {
+        let a = ["CREATE", "IMA", "IPOWER"].into_iter().map(event_id).collect::<HashSet<_>>();
+        let b = ["CREATE", "IMA", "IJR"].into_iter().map(event_id).collect::<HashSet<_>>();
+
+        assert_eq!(
+            auth_chain_difference(vec![a, b]),
+            ["IPOWER", "IJR"].into_iter().map(event_id).collect::<HashSet<_>>()
+        );
+    }
+}
@@ -0,0 +1,137 @@
+use std::{
+    borrow::Borrow,
+    collections::{HashMap, HashSet},
+    fmt,
+    hash::Hash,
+    sync::Arc,
+};
+
+use js_int::Int;
+use ruma_common::EventId;
+
+use crate::AuthChainCache;
+
+/// A cache for the data computed about single events during state resolution.
+///
+/// This data only depends on the events themselves, so it can be reused across calls to
+/// [`resolve_incremental`] for as long as the events are kept.
+///
+/// [`resolve_incremental`]: crate::resolve_incremental
+pub trait ResolutionCache<Id>: AuthChainCache<Id> {
+    /// Get the power level of the sender of the event with the given ID, according to the
+    /// `m.room.power_levels` event in its auth events, if it was cached before.
+    fn get_sender_power_level(&mut self, event_id: &EventId) -> Option<Int>;
+
+    /// Cache the power level of the sender of the event with the given ID.
+    fn insert_sender_power_level(&mut self, event_id: Id, power_level: Int);
+
+    /// Get the depth of the event with the given ID in the mainline of the given
+    /// `m.room.power_levels` event, if it was cached before.
+    fn get_mainline_depth(&mut self, mainline: &EventId, event_id: &EventId) -> Option<usize>;
+
+    /// Cache the depth of the event with the given ID in the mainline of the given
+    /// `m.room.power_levels` event.
+    fn insert_mainline_depth(&mut self, mainline: Id, event_id: Id, depth: usize);
+}
+
+/// A [`ResolutionCache`] that keeps all the data in memory.
+pub struct InMemoryResolutionCache<Id> {
+    auth_chains: HashMap<Id, Arc<HashSet<Id>>>,
+    sender_power_levels: HashMap<Id, Int>,
+    mainline_depths: HashMap<Id, HashMap<Id, usize>>,
+}
+
+impl<Id> InMemoryResolutionCache<Id> {
+    /// Creates an empty `InMemoryResolutionCache`.
+    pub fn new() -> Self {
+        Self {
+            auth_chains: HashMap::new(),
+            sender_power_levels: HashMap::new(),
+            mainline_depths: HashMap::new(),
+        }
+    }
+
+    /// Removes all the data from the cache.
+    pub fn clear(&mut self) {
+        self.auth_chains.clear();
+        self.sender_power_levels.clear();
+        self.mainline_depths.clear();
+    }
+}
+
+impl<Id> Default for InMemoryResolutionCache<Id> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<Id> fmt::Debug for InMemoryResolutionCache<Id> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InMemoryResolutionCache")
+            .field("auth_chains", &self.auth_chains.len())
+            .field("sender_power_levels", &self.sender_power_levels.len())
+            .field("mainline_depths", &self.mainline_depths.len())
+            .finish()
+    }
+}
+
+impl<Id> AuthChainCache<Id> for InMemoryResolutionCache<Id>
+where
+    Id: Eq + Hash + Borrow<EventId>,
+{
+    fn get_auth_chain(&mut self, event_id: &EventId) -> Option<Arc<HashSet<Id>>> {
+        self.auth_chains.get_auth_chain(event_id)
+    }
+
+    fn insert_auth_chain(&mut self, event_id: Id, auth_chain: Arc<HashSet<Id>>) {
+        self.auth_chains.insert_auth_chain(event_id, auth_chain);
+    }
+}
+
+impl<Id> ResolutionCache<Id> for InMemoryResolutionCache<Id>
+where
+    Id: Eq + Hash + Borrow<EventId>,
+{
+    fn get_sender_power_level(&mut self, event_id: &EventId) -> Option<Int> {
+        self.sender_power_levels.get(event_id).copied()
+    }
+
+    fn insert_sender_power_level(&mut self, event_id: Id, power_level: Int) {
+        self.sender_power_levels.insert(event_id, power_level);
+    }
+
+    fn get_mainline_depth(&mut self, mainline: &EventId, event_id: &EventId) -> Option<usize> {
+        self.mainline_depths.get(mainline)?.get(event_id).copied()
+    }
+
+    fn insert_mainline_depth(&mut self, mainline: Id, event_id: Id, depth: usize) {
+        self.mainline_depths.entry(mainline).or_default().insert(event_id, depth);
+    }
+}
+
+/// A [`ResolutionCache`] that doesn't keep anything, used when resolving state without a cache.
+///
+/// It can't be used to compute auth chains, since those are read back from the cache.
+pub(crate) struct NoResolutionCache;
+
+impl<Id> AuthChainCache<Id> for NoResolutionCache {
+    fn get_auth_chain(&mut self, _event_id: &EventId) -> Option<Arc<HashSet<Id>>> {
+        None
+    }
+
+    fn insert_auth_chain(&mut self, _event_id: Id, _auth_chain: Arc<HashSet<Id>>) {}
+}
+
+impl<Id> ResolutionCache<Id> for NoResolutionCache {
+    fn get_sender_power_level(&mut self, _event_id: &EventId) -> Option<Int> {
+        None
+    }
+
+    fn insert_sender_power_level(&mut self, _event_id: Id, _power_level: Int) {}
+
+    fn get_mainline_depth(&mut self, _mainline: &EventId, _event_id: &EventId) -> Option<usize> {
+        None
+    }
+
+    fn insert_mainline_depth(&mut self, _mainline: Id, _event_id: Id, _depth: usize) {}
+}
@@ -1,4 +1,4 @@
-use std::{borrow::Borrow, collections::BTreeSet};
+use std::{borrow::Borrow, collections::BTreeSet, future::Future};
 
 use js_int::{int, Int};
 use ruma_common::{
@@ -25,7 +25,7 @@ use crate::{
         deserialize_power_levels_content_invite, deserialize_power_levels_content_redact,
     },
     room_version::RoomVersion,
-    Error, Event, Result, StateEventType, TimelineEventType,
+    AuthRejection, Error, Event, EventTypeExt, Result, StateEventType, StateMap, TimelineEventType,
 };
 
 // FIXME: field extracting could be bundled for `content`
@@ -127,6 +127,21 @@ pub fn auth_check<E: Event>(
     current_third_party_invite: Option<impl Event>,
     fetch_state: impl Fn(&StateEventType, &str) -> Option<E>,
 ) -> Result<bool> {
+    check_auth_rules(room_version, incoming_event, current_third_party_invite, fetch_state)
+        .map(|rejection| rejection.is_none())
+}
+
+/// Authenticate the incoming `event` like [`auth_check`], but return the reason why it was
+/// rejected.
+///
+/// Returns `Ok(None)` if the event passes the authorization rules, and `Ok(Some(_))` with the
+/// rule that the event breaks otherwise.
+pub fn check_auth_rules<E: Event>(
+    room_version: &RoomVersion,
+    incoming_event: impl Event,
+    current_third_party_invite: Option<impl Event>,
+    fetch_state: impl Fn(&StateEventType, &str) -> Option<E>,
+) -> Result<Option<AuthRejection>> {
     info!(
         "auth_check beginning for {} ({})",
         incoming_event.event_id(),
@@ -158,37 +173,37 @@ pub fn auth_check<E: Event>(
         // If it has any previous events, reject
         if incoming_event.prev_events().next().is_some() {
             warn!("the room creation event had previous events");
-            return Ok(false);
+            return Ok(Some(AuthRejection::InvalidCreateEvent));
         }
 
         // If the domain of the room_id does not match the domain of the sender, reject
         let Some(room_id_server_name) = incoming_event.room_id().server_name() else {
             warn!("room ID has no servername");
-            return Ok(false);
+            return Ok(Some(AuthRejection::InvalidCreateEvent));
         };
 
         if room_id_server_name != sender.server_name() {
             warn!("servername of room ID does not match servername of sender");
-            return Ok(false);
+            return Ok(Some(AuthRejection::InvalidCreateEvent));
         }
 
         // If content.room_version is present and is not a recognized version, reject
         let content: RoomCreateContentFields = from_json_str(incoming_event.content().get())?;
         if content.room_version.map(|v| v.deserialize().is_err()).unwrap_or(false) {
             warn!("invalid room version found in m.room.create event");
-            return Ok(false);
+            return Ok(Some(AuthRejection::InvalidCreateEvent));
         }
 
         if !room_version.use_room_create_sender {
             // If content has no creator field, reject
             if content.creator.is_none() {
                 warn!("no creator field found in m.room.create content");
-                return Ok(false);
+                return Ok(Some(AuthRejection::InvalidCreateEvent));
             }
         }
 
         info!("m.room.create event was allowed");
-        return Ok(true);
+        return Ok(None);
     }
 
     /*
@@ -219,7 +234,7 @@ pub fn auth_check<E: Event>(
     let room_create_event = match fetch_state(&StateEventType::RoomCreate, "") {
         None => {
             warn!("no m.room.create event in auth chain");
-            return Ok(false);
+            return Ok(Some(AuthRejection::MissingCreateEvent));
         }
         Some(e) => e,
     };
@@ -228,7 +243,7 @@ pub fn auth_check<E: Event>(
     if !incoming_event.auth_events().any(|id| id.borrow() == room_create_event.event_id().borrow())
     {
         warn!("no m.room.create event in auth events");
-        return Ok(false);
+        return Ok(Some(AuthRejection::CreateEventMismatch));
     }
 
     // If the create event content has the field m.federate set to false and the sender domain of
@@ -244,7 +259,7 @@ pub fn auth_check<E: Event>(
         && room_create_event.sender().server_name() != incoming_event.sender().server_name()
     {
         warn!("room is not federated and event's sender domain does not match create event's sender domain");
-        return Ok(false);
+        return Ok(Some(AuthRejection::NotFederated));
     }
 
     // Only in some room versions 6 and below
@@ -256,11 +271,11 @@ pub fn auth_check<E: Event>(
             // If sender's domain doesn't matches state_key, reject
             if incoming_event.state_key() != Some(sender.server_name().as_str()) {
                 warn!("state_key does not match sender");
-                return Ok(false);
+                return Ok(Some(AuthRejection::AliasesServerMismatch));
             }
 
             info!("m.room.aliases event was allowed");
-            return Ok(true);
+            return Ok(None);
         }
     }
 
@@ -273,7 +288,7 @@ pub fn auth_check<E: Event>(
         let state_key = match incoming_event.state_key() {
             None => {
                 warn!("no statekey in member event");
-                return Ok(false);
+                return Ok(Some(AuthRejection::InvalidMemberEvent));
             }
             Some(s) => s,
         };
@@ -281,7 +296,7 @@ pub fn auth_check<E: Event>(
         let content: RoomMemberContentFields = from_json_str(incoming_event.content().get())?;
         if content.membership.as_ref().and_then(|m| m.deserialize().ok()).is_none() {
             warn!("no valid membership field found for m.room.member event content");
-            return Ok(false);
+            return Ok(Some(AuthRejection::InvalidMemberEvent));
         }
 
         let target_user =
@@ -297,7 +312,7 @@ pub fn auth_check<E: Event>(
             .map(|mem| mem.membership)
             .unwrap_or(MembershipState::Leave);
 
-        if !valid_membership_change(
+        if let Some(reason) = valid_membership_change(
             room_version,
             target_user,
             fetch_state(&StateEventType::RoomMember, target_user.as_str()).as_ref(),
@@ -311,11 +326,11 @@ pub fn auth_check<E: Event>(
             &user_for_join_auth_membership,
             room_create_event,
         )? {
-            return Ok(false);
+            return Ok(Some(reason));
         }
 
         info!("m.room.member event was allowed");
-        return Ok(true);
+        return Ok(None);
     }
 
     // If the sender's current membership state is not join, reject
@@ -323,7 +338,7 @@ pub fn auth_check<E: Event>(
         Some(mem) => mem,
         None => {
             warn!("sender not found in room");
-            return Ok(false);
+            return Ok(Some(AuthRejection::SenderNotJoined));
         }
     };
 
@@ -336,7 +351,7 @@ pub fn auth_check<E: Event>(
 
     if !matches!(membership_state, MembershipState::Join) {
         warn!("sender's membership is not join");
-        return Ok(false);
+        return Ok(Some(AuthRejection::SenderNotJoined));
     }
 
     // If type is m.room.third_party_invite
@@ -377,18 +392,23 @@ pub fn auth_check<E: Event>(
 
         if sender_power_level < invite_level {
             warn!("sender's cannot send invites in this room");
-            return Ok(false);
+            return Ok(Some(AuthRejection::InsufficientPowerLevel {
+                required: invite_level,
+                actual: sender_power_level,
+            }));
         }
 
         info!("m.room.third_party_invite event was allowed");
-        return Ok(true);
+        return Ok(None);
     }
 
     // If the event type's required power level is greater than the sender's power level, reject
     // If the event has a state_key that starts with an @ and does not match the sender, reject.
-    if !can_send_event(&incoming_event, power_levels_event.as_ref(), sender_power_level) {
+    if let Some(rejection) =
+        can_send_event(&incoming_event, power_levels_event.as_ref(), sender_power_level)
+    {
         warn!("user cannot send event");
-        return Ok(false);
+        return Ok(Some(rejection));
     }
 
     // If type is m.room.power_levels
@@ -403,11 +423,11 @@ pub fn auth_check<E: Event>(
         ) {
             if !required_pwr_lvl {
                 warn!("power level was not allowed");
-                return Ok(false);
+                return Ok(Some(AuthRejection::InvalidPowerLevelsChange));
             }
         } else {
             warn!("power level was not allowed");
-            return Ok(false);
+            return Ok(Some(AuthRejection::InvalidPowerLevelsChange));
         }
         info!("power levels event allowed");
     }
@@ -430,12 +450,66 @@ pub fn auth_check<E: Event>(
         };
 
         if !check_redaction(room_version, incoming_event, sender_power_level, redact_level)? {
-            return Ok(false);
+            warn!("user cannot redact this event");
+            return Ok(Some(AuthRejection::InsufficientPowerLevel {
+                required: redact_level,
+                actual: sender_power_level,
+            }));
         }
     }
 
     info!("allowing event passed all checks");
-    Ok(true)
+    Ok(None)
+}
+
+/// Authenticate the incoming `event`, fetching the needed state asynchronously.
+///
+/// This runs the same checks as [`auth_check`]. The state events listed by
+/// [`auth_types_for_event`] are gathered through `fetch_state` before the checks start, and any
+/// error returned by `fetch_state` is returned to the caller instead of the state event being
+/// treated as missing.
+pub async fn auth_check_async<E, Fut>(
+    room_version: &RoomVersion,
+    incoming_event: impl Event,
+    current_third_party_invite: Option<impl Event>,
+    fetch_state: impl Fn(&StateEventType, &str) -> Fut,
+) -> Result<bool>
+where
+    E: Event,
+    Fut: Future<Output = Result<Option<E>>>,
+{
+    let auth_state = fetch_auth_state(&incoming_event, fetch_state).await?;
+
+    auth_check(room_version, incoming_event, current_third_party_invite, |ty, key| {
+        auth_state.get(&ty.with_state_key(key))
+    })
+}
+
+/// Gather the state events listed by [`auth_types_for_event`] for the incoming `event` through
+/// `fetch_state`.
+pub(crate) async fn fetch_auth_state<E, Fut>(
+    incoming_event: &impl Event,
+    fetch_state: impl Fn(&StateEventType, &str) -> Fut,
+) -> Result<StateMap<E>>
+where
+    E: Event,
+    Fut: Future<Output = Result<Option<E>>>,
+{
+    let auth_types = auth_types_for_event(
+        incoming_event.event_type(),
+        incoming_event.sender(),
+        incoming_event.state_key(),
+        incoming_event.content(),
+    )?;
+
+    let mut auth_state = StateMap::new();
+    for (event_type, state_key) in auth_types {
+        if let Some(event) = fetch_state(&event_type, &state_key).await? {
+            auth_state.insert((event_type, state_key), event);
+        }
+    }
+
+    Ok(auth_state)
 }
 
 // TODO deserializing the member, power, join_rules event contents is done in conduit
@@ -447,6 +521,8 @@ pub fn auth_check<E: Event>(
 ///
 /// This is generated by calling `auth_types_for_event` with the membership event and the current
 /// State.
+///
+/// Returns the reason why the membership change is not allowed, or `None` if it is.
 #[allow(clippy::too_many_arguments)]
 fn valid_membership_change(
     room_version: &RoomVersion,
@@ -461,7 +537,7 @@ fn valid_membership_change(
     user_for_join_auth: Option<&UserId>,
     user_for_join_auth_membership: &MembershipState,
     create_room: impl Event,
-) -> Result<bool> {
+) -> Result<Option<AuthRejection>> {
     #[derive(Deserialize)]
     struct GetThirdPartyInvite {
         third_party_invite: Option<Raw<ThirdPartyInvite>>,
@@ -532,6 +608,9 @@ fn valid_membership_change(
         false
     };
 
+    let sender_level = || *sender_power.unwrap_or(&power_levels.users_default);
+    let target_level = || *target_power.unwrap_or(&power_levels.users_default);
+
     Ok(match target_membership {
         MembershipState::Join => {
             // 1. If the only previous event is an m.room.create and the state_key is the creator,
@@ -560,25 +639,28 @@ fn valid_membership_change(
                 };
 
                 if is_creator {
-                    return Ok(true);
+                    return Ok(None);
                 }
             }
 
             if sender != target_user {
                 // If the sender does not match state_key, reject.
                 warn!("Can't make other user join");
-                false
+                Some(AuthRejection::SenderMismatch)
             } else if let MembershipState::Ban = target_user_current_membership {
                 // If the sender is banned, reject.
                 warn!(?target_user_membership_event_id, "Banned user can't join");
-                false
+                Some(AuthRejection::InvalidMembershipTransition {
+                    from: MembershipState::Ban,
+                    to: MembershipState::Join,
+                })
             } else if (join_rules == JoinRule::Invite
                     || room_version.allow_knocking && join_rules == JoinRule::Knock)
                 // If the join_rule is invite then allow if membership state is invite or join
                     && (target_user_current_membership == MembershipState::Join
                         || target_user_current_membership == MembershipState::Invite)
             {
-                true
+                None
             } else if room_version.restricted_join_rules
                 && matches!(join_rules, JoinRule::Restricted(_))
                 || room_version.knock_restricted_join_rule
@@ -590,17 +672,23 @@ fn valid_membership_change(
                     MembershipState::Invite | MembershipState::Join
                 ) {
                     // If membership state is join or invite, allow.
-                    true
-                } else {
+                    None
+                } else if user_for_join_auth_is_valid {
                     // If the join_authorised_via_users_server key in content is not a user with
                     // sufficient permission to invite other users, reject.
                     // Otherwise, allow.
-                    user_for_join_auth_is_valid
+                    None
+                } else {
+                    warn!(?user_for_join_auth, "Join authorising user can't invite users");
+                    Some(AuthRejection::InvalidJoinAuthorisation)
                 }
-            } else {
+            } else if join_rules == JoinRule::Public {
                 // If the join_rule is public, allow.
+                None
+            } else {
                 // Otherwise, reject.
-                join_rules == JoinRule::Public
+                warn!("Join rule doesn't allow joining");
+                Some(AuthRejection::ForbiddenByJoinRule { join_rule: join_rules })
             }
         }
         MembershipState::Invite => {
@@ -608,88 +696,127 @@ fn valid_membership_change(
             if let Some(tp_id) = third_party_invite.and_then(|i| i.deserialize().ok()) {
                 if target_user_current_membership == MembershipState::Ban {
                     warn!(?target_user_membership_event_id, "Can't invite banned user");
-                    false
+                    Some(AuthRejection::InvalidMembershipTransition {
+                        from: MembershipState::Ban,
+                        to: MembershipState::Invite,
+                    })
+                } else if verify_third_party_invite(
+                    Some(target_user),
+                    sender,
+                    &tp_id,
+                    current_third_party_invite,
+                ) {
+                    None
                 } else {
-                    let allow = verify_third_party_invite(
-                        Some(target_user),
-                        sender,
-                        &tp_id,
-                        current_third_party_invite,
-                    );
-                    if !allow {
-                        warn!("Third party invite invalid");
-                    }
-                    allow
+                    warn!("Third party invite invalid");
+                    Some(AuthRejection::BadThirdPartyInvite)
                 }
-            } else if !sender_is_joined
-                || target_user_current_membership == MembershipState::Join
+            } else if !sender_is_joined {
+                warn!(?sender_membership_event_id, "Can't invite user if sender not joined");
+                Some(AuthRejection::SenderNotJoined)
+            } else if target_user_current_membership == MembershipState::Join
                 || target_user_current_membership == MembershipState::Ban
             {
                 warn!(
                     ?target_user_membership_event_id,
-                    ?sender_membership_event_id,
-                    "Can't invite user if sender not joined or the user is currently joined or \
-                     banned",
+                    "Can't invite user if the user is currently joined or banned",
                 );
-                false
+                Some(AuthRejection::InvalidMembershipTransition {
+                    from: target_user_current_membership,
+                    to: MembershipState::Invite,
+                })
+            } else if sender_power.filter(|&p| p >= &power_levels.invite).is_some() {
+                None
             } else {
-                let allow = sender_power.filter(|&p| p >= &power_levels.invite).is_some();
-                if !allow {
-                    warn!(
-                        ?target_user_membership_event_id,
-                        ?power_levels_event_id,
-                        "User does not have enough power to invite",
-                    );
-                }
-                allow
+                warn!(
+                    ?target_user_membership_event_id,
+                    ?power_levels_event_id,
+                    "User does not have enough power to invite",
+                );
+                Some(AuthRejection::InsufficientPowerLevel {
+                    required: power_levels.invite,
+                    actual: sender_level(),
+                })
             }
         }
         MembershipState::Leave => {
             if sender == target_user {
-                let allow = target_user_current_membership == MembershipState::Join
-                    || target_user_current_membership == MembershipState::Invite;
-                if !allow {
+                if target_user_current_membership == MembershipState::Join
+                    || target_user_current_membership == MembershipState::Invite
+                {
+                    None
+                } else {
                     warn!(?target_user_membership_event_id, "Can't leave if not invited or joined");
+                    Some(AuthRejection::InvalidMembershipTransition {
+                        from: target_user_current_membership,
+                        to: MembershipState::Leave,
+                    })
                 }
-                allow
-            } else if !sender_is_joined
-                || target_user_current_membership == MembershipState::Ban
-                    && sender_power.filter(|&p| p < &power_levels.ban).is_some()
+            } else if !sender_is_joined {
+                warn!(?sender_membership_event_id, "Can't kick if sender not joined");
+                Some(AuthRejection::SenderNotJoined)
+            } else if target_user_current_membership == MembershipState::Ban
+                && sender_power.filter(|&p| p < &power_levels.ban).is_some()
             {
                 warn!(
                     ?target_user_membership_event_id,
-                    ?sender_membership_event_id,
-                    "Can't kick if sender not joined or user is already banned",
+                    ?power_levels_event_id,
+                    "User does not have enough power to unban",
+                );
+                Some(AuthRejection::InsufficientPowerLevel {
+                    required: power_levels.ban,
+                    actual: sender_level(),
+                })
+            } else if sender_power.filter(|&p| p >= &power_levels.kick).is_none() {
+                warn!(
+                    ?target_user_membership_event_id,
+                    ?power_levels_event_id,
+                    "User does not have enough power to kick",
                 );
-                false
+                Some(AuthRejection::InsufficientPowerLevel {
+                    required: power_levels.kick,
+                    actual: sender_level(),
+                })
+            } else if target_power >= sender_power {
+                warn!(
+                    ?target_user_membership_event_id,
+                    ?power_levels_event_id,
+                    "User does not have more power than the kicked user",
+                );
+                Some(AuthRejection::TargetPowerLevelNotLower {
+                    target: target_level(),
+                    actual: sender_level(),
+                })
             } else {
-                let allow = sender_power.filter(|&p| p >= &power_levels.kick).is_some()
-                    && target_power < sender_power;
-                if !allow {
-                    warn!(
-                        ?target_user_membership_event_id,
-                        ?power_levels_event_id,
-                        "User does not have enough power to kick",
-                    );
-                }
-                allow
+                None
             }
         }
         MembershipState::Ban => {
             if !sender_is_joined {
                 warn!(?sender_membership_event_id, "Can't ban user if sender is not joined");
-                false
+                Some(AuthRejection::SenderNotJoined)
+            } else if sender_power.filter(|&p| p >= &power_levels.ban).is_none() {
+                warn!(
+                    ?target_user_membership_event_id,
+                    ?power_levels_event_id,
+                    "User does not have enough power to ban",
+                );
+                Some(AuthRejection::InsufficientPowerLevel {
+                    required: power_levels.ban,
+                    actual: sender_level(),
+                })
+            } else if target_power >= sender_power {
+                warn!(
+                    ?target_user_membership_event_id,
+                    ?power_levels_event_id,
+                    "User does not have more power than the banned user",
+                );
+                Some(AuthRejection::TargetPowerLevelNotLower {
+                    target: target_level(),
+                    actual: sender_level(),
+                })
             } else {
-                let allow = sender_power.filter(|&p| p >= &power_levels.ban).is_some()
-                    && target_power < sender_power;
-                if !allow {
-                    warn!(
-                        ?target_user_membership_event_id,
-                        ?power_levels_event_id,
-                        "User does not have enough power to ban",
-                    );
-                }
-                allow
+                None
             }
         }
         MembershipState::Knock if room_version.allow_knocking => {
@@ -699,7 +826,7 @@ fn valid_membership_change(
                     && matches!(join_rules, JoinRule::KnockRestricted(_))
             {
                 warn!("Join rule is not set to knock or knock_restricted, knocking is not allowed");
-                false
+                Some(AuthRejection::ForbiddenByJoinRule { join_rule: join_rules })
             } else {
                 // 2. If `sender` does not match `state_key`, reject.
                 // 3. If the `sender`'s current membership is not `ban` or `join`, allow.
@@ -710,22 +837,25 @@ fn valid_membership_change(
                         ?target_user,
                         "Can't make another user join, sender did not match target"
                     );
-                    false
+                    Some(AuthRejection::SenderMismatch)
                 } else if matches!(sender_membership, MembershipState::Ban | MembershipState::Join)
                 {
                     warn!(
                         ?target_user_membership_event_id,
                         "Membership state of ban or join are invalid",
                     );
-                    false
+                    Some(AuthRejection::InvalidMembershipTransition {
+                        from: sender_membership,
+                        to: MembershipState::Knock,
+                    })
                 } else {
-                    true
+                    None
                 }
             }
         }
-        _ => {
+        membership => {
             warn!("Unknown membership transition");
-            false
+            Some(AuthRejection::UnknownMembership(membership))
         }
     })
 }
@@ -733,22 +863,32 @@ fn valid_membership_change(
 /// Is the user allowed to send a specific event based on the rooms power levels.
 ///
 /// Does the event have the correct userId as its state_key if it's not the "" state_key.
-fn can_send_event(event: impl Event, ple: Option<impl Event>, user_level: Int) -> bool {
+///
+/// Returns the reason why the user is not allowed to send the event, or `None` if they are.
+fn can_send_event(
+    event: impl Event,
+    ple: Option<impl Event>,
+    user_level: Int,
+) -> Option<AuthRejection> {
     let event_type_power_level = get_send_level(event.event_type(), event.state_key(), ple);
 
     debug!("{} ev_type {event_type_power_level} usr {user_level}", event.event_id());
 
     if user_level < event_type_power_level {
-        return false;
+        return Some(AuthRejection::InsufficientPowerLevel {
+            required: event_type_power_level,
+            actual: user_level,
+        });
     }
 
     if event.state_key().is_some_and(|k| k.starts_with('@'))
         && event.state_key() != Some(event.sender().as_str())
     {
-        return false; // permission required to post in this room
+        // permission required to post in this room
+        return Some(AuthRejection::SenderMismatch);
     }
 
-    true
+    None
 }
 
 /// Confirm that the event sender has the required power levels.
@@ -1010,6 +1150,7 @@ fn verify_third_party_invite(
 mod tests {
     use std::sync::Arc;
 
+    use js_int::int;
     use ruma_events::{
         room::{
             join_rules::{
@@ -1019,15 +1160,15 @@ mod tests {
         },
         StateEventType, TimelineEventType,
     };
-    use serde_json::value::to_raw_value as to_raw_json_value;
+    use serde_json::{json, value::to_raw_value as to_raw_json_value};
 
     use crate::{
-        event_auth::valid_membership_change,
+        event_auth::{check_auth_rules, valid_membership_change},
         test_utils::{
             alice, charlie, ella, event_id, member_content_ban, member_content_join, room_id,
             to_pdu_event, PduEvent, INITIAL_EVENTS, INITIAL_EVENTS_CREATE_ROOM,
         },
-        Event, EventTypeExt, RoomVersion, StateMap,
+        AuthRejection, Event, EventTypeExt, RoomVersion, StateMap,
     };
 
     #[test]
@@ -1069,7 +1210,8 @@ mod tests {
             &MembershipState::Leave,
             fetch_state(StateEventType::RoomCreate, "".to_owned()).unwrap(),
         )
-        .unwrap());
+        .unwrap()
+        .is_none());
     }
 
     #[test]
@@ -1097,7 +1239,7 @@ mod tests {
         let target_user = charlie();
         let sender = charlie();
 
-        assert!(!valid_membership_change(
+        assert!(valid_membership_change(
             &RoomVersion::V6,
             target_user,
             fetch_state(StateEventType::RoomMember, target_user.to_string()),
@@ -1111,7 +1253,8 @@ mod tests {
             &MembershipState::Leave,
             fetch_state(StateEventType::RoomCreate, "".to_owned()).unwrap(),
         )
-        .unwrap());
+        .unwrap()
+        .is_some());
     }
 
     #[test]
@@ -1153,7 +1296,8 @@ mod tests {
             &MembershipState::Leave,
             fetch_state(StateEventType::RoomCreate, "".to_owned()).unwrap(),
         )
-        .unwrap());
+        .unwrap()
+        .is_none());
     }
 
     #[test]
@@ -1181,7 +1325,7 @@ mod tests {
         let target_user = alice();
         let sender = charlie();
 
-        assert!(!valid_membership_change(
+        let rejection = valid_membership_change(
             &RoomVersion::V6,
             target_user,
             fetch_state(StateEventType::RoomMember, target_user.to_string()),
@@ -1195,7 +1339,42 @@ mod tests {
             &MembershipState::Leave,
             fetch_state(StateEventType::RoomCreate, "".to_owned()).unwrap(),
         )
-        .unwrap());
+        .unwrap();
+
+        assert_eq!(
+            rejection,
+            Some(AuthRejection::InsufficientPowerLevel { required: int!(50), actual: int!(0) })
+        );
+    }
+
+    #[test]
+    fn test_sender_not_joined() {
+        let _ =
+            tracing::subscriber::set_default(tracing_subscriber::fmt().with_test_writer().finish());
+        let events = INITIAL_EVENTS();
+
+        let auth_events = events
+            .values()
+            .map(|ev| (ev.event_type().with_state_key(ev.state_key().unwrap()), Arc::clone(ev)))
+            .collect::<StateMap<_>>();
+
+        let requester = to_pdu_event(
+            "HELLO",
+            ella(),
+            TimelineEventType::RoomTopic,
+            Some(""),
+            to_raw_json_value(&json!({ "topic": "Hello" })).unwrap(),
+            &["CREATE", "IPOWER"],
+            &["IMC"],
+        );
+
+        let rejection =
+            check_auth_rules(&RoomVersion::V6, &requester, None::<PduEvent>, |ty, key| {
+                auth_events.get(&ty.with_state_key(key))
+            })
+            .unwrap();
+
+        assert_eq!(rejection, Some(AuthRejection::SenderNotJoined));
     }
 
     #[test]
@@ -1254,9 +1433,10 @@ mod tests {
             &MembershipState::Join,
             fetch_state(StateEventType::RoomCreate, "".to_owned()).unwrap(),
         )
-        .unwrap());
+        .unwrap()
+        .is_none());
 
-        assert!(!valid_membership_change(
+        assert!(valid_membership_change(
             &RoomVersion::V9,
             target_user,
             fetch_state(StateEventType::RoomMember, target_user.to_string()),
@@ -1270,7 +1450,8 @@ mod tests {
             &MembershipState::Leave,
             fetch_state(StateEventType::RoomCreate, "".to_owned()).unwrap(),
         )
-        .unwrap());
+        .unwrap()
+        .is_some());
     }
 
     #[test]
@@ -1321,6 +1502,7 @@ mod tests {
             &MembershipState::Leave,
             fetch_state(StateEventType::RoomCreate, "".to_owned()).unwrap(),
         )
-        .unwrap());
+        .unwrap()
+        .is_none());
     }
 }
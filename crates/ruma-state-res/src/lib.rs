@@ -2,9 +2,12 @@ use std::{
     borrow::Borrow,
     cmp::Reverse,
     collections::{BinaryHeap, HashMap, HashSet},
+    future::{self, Future},
     hash::Hash,
 };
 
+use auth_chain::auth_chain_sets;
+use futures_util::FutureExt;
 use itertools::Itertools;
 use js_int::{int, Int};
 use ruma_common::{EventId, MilliSecondsSinceUnixEpoch, RoomVersionId};
@@ -15,18 +18,31 @@ use ruma_events::{
 use serde_json::from_str as from_json_str;
 use tracing::{debug, info, trace, warn};
 
+pub mod auth_chain;
+mod cache;
 mod error;
 pub mod event_auth;
+pub mod pdu_builder;
+pub mod pdu_checks;
 mod power_levels;
+mod rejection;
+mod report;
 pub mod room_version;
 mod state_event;
 #[cfg(test)]
 mod test_utils;
+mod v1;
 
+pub use auth_chain::{auth_chain_difference, AuthChainCache};
+use cache::NoResolutionCache;
+pub use cache::{InMemoryResolutionCache, ResolutionCache};
 pub use error::{Error, Result};
-pub use event_auth::{auth_check, auth_types_for_event};
+pub use event_auth::{auth_check, auth_check_async, auth_types_for_event, check_auth_rules};
 use power_levels::PowerLevelsContentFields;
+pub use rejection::AuthRejection;
+pub use report::{RejectedEvent, ResolutionReport};
 pub use room_version::RoomVersion;
+use room_version::StateResolutionVersion;
 pub use state_event::Event;
 
 /// A mapping of event type and state_key to some value `T`, usually an `EventId`.
@@ -43,7 +59,7 @@ pub type StateMap<T> = HashMap<(StateEventType, String), T>;
 ///   the state of a room.
 ///
 /// * `auth_chain_sets` - The full recursive set of `auth_events` for each event in the
-///   `state_sets`.
+///   `state_sets`. It is not used by the state resolution algorithm of room version 1.
 ///
 /// * `fetch_event` - Any event not found in the `event_map` will defer to this closure to find the
 ///   event.
@@ -63,6 +79,118 @@ where
     E::Id: 'a,
     SetIter: Iterator<Item = &'a StateMap<E::Id>> + Clone,
 {
+    // The fetcher futures are always ready, so resolution completes on the first poll.
+    resolve_async(room_version, state_sets, auth_chain_sets, |id| {
+        future::ready(Ok(fetch_event(id)))
+    })
+    .now_or_never()
+    .expect("state resolution with a synchronous fetcher never yields")
+}
+
+/// Resolve sets of state events as they come in, fetching events asynchronously.
+///
+/// This runs the same algorithm as [`resolve`], but `fetch_event` returns a future resolving to
+/// `Ok(None)` if the event could not be found. Any error returned by `fetch_event` aborts the
+/// resolution and is returned to the caller, instead of the event being treated as missing.
+///
+/// ## Arguments
+///
+/// * `state_sets` - The incoming state to resolve. Each `StateMap` represents a possible fork in
+///   the state of a room.
+///
+/// * `auth_chain_sets` - The full recursive set of `auth_events` for each event in the
+///   `state_sets`. It is not used by the state resolution algorithm of room version 1.
+///
+/// * `fetch_event` - Called to get any event needed to resolve the state.
+///
+/// ## Invariants
+///
+/// The caller of `resolve_async` must ensure that all the events are from the same room.
+pub async fn resolve_async<'a, E, SetIter, Fut>(
+    room_version: &RoomVersionId,
+    state_sets: impl IntoIterator<IntoIter = SetIter>,
+    auth_chain_sets: Vec<HashSet<E::Id>>,
+    fetch_event: impl Fn(&EventId) -> Fut,
+) -> Result<StateMap<E::Id>>
+where
+    E: Event + Clone,
+    E::Id: 'a,
+    SetIter: Iterator<Item = &'a StateMap<E::Id>> + Clone,
+    Fut: Future<Output = Result<Option<E>>>,
+{
+    resolve_with_report(room_version, state_sets, auth_chain_sets, fetch_event)
+        .await
+        .map(|(resolved_state, _)| resolved_state)
+}
+
+/// Resolve sets of state events, and report the decisions taken along the way.
+///
+/// This runs the same algorithm as [`resolve_async`], and also returns a [`ResolutionReport`]
+/// containing the conflicted set, the order in which the events were authenticated and the events
+/// that were rejected, with the reason why.
+pub async fn resolve_with_report<'a, E, SetIter, Fut>(
+    room_version: &RoomVersionId,
+    state_sets: impl IntoIterator<IntoIter = SetIter>,
+    auth_chain_sets: Vec<HashSet<E::Id>>,
+    fetch_event: impl Fn(&EventId) -> Fut,
+) -> Result<(StateMap<E::Id>, ResolutionReport<E::Id>)>
+where
+    E: Event + Clone,
+    E::Id: 'a,
+    SetIter: Iterator<Item = &'a StateMap<E::Id>> + Clone,
+    Fut: Future<Output = Result<Option<E>>>,
+{
+    resolve_inner(room_version, state_sets, auth_chain_sets, fetch_event, &mut NoResolutionCache)
+        .await
+}
+
+/// Resolve sets of state events, reusing the data computed about single events in previous calls.
+///
+/// This runs the same algorithm as [`resolve_with_auth_chain_cache`], but also looks up and adds
+/// to `cache` the power level of the sender of each control event and the mainline depth of the
+/// other conflicted events, so they are computed only once across calls.
+pub async fn resolve_incremental<'a, E, SetIter, Fut>(
+    room_version: &RoomVersionId,
+    state_sets: impl IntoIterator<IntoIter = SetIter>,
+    cache: &mut impl ResolutionCache<E::Id>,
+    fetch_event: impl Fn(&EventId) -> Fut,
+) -> Result<StateMap<E::Id>>
+where
+    E: Event + Clone,
+    E::Id: 'a,
+    SetIter: Iterator<Item = &'a StateMap<E::Id>> + Clone,
+    Fut: Future<Output = Result<Option<E>>>,
+{
+    let state_sets = state_sets.into_iter();
+    let auth_chain_sets = auth_chain_sets(state_sets.clone(), cache, &fetch_event).await?;
+
+    resolve_inner(room_version, state_sets, auth_chain_sets, fetch_event, cache)
+        .await
+        .map(|(resolved_state, _)| resolved_state)
+}
+
+async fn resolve_inner<'a, E, SetIter, Fut>(
+    room_version: &RoomVersionId,
+    state_sets: impl IntoIterator<IntoIter = SetIter>,
+    auth_chain_sets: Vec<HashSet<E::Id>>,
+    fetch_event: impl Fn(&EventId) -> Fut,
+    cache: &mut impl ResolutionCache<E::Id>,
+) -> Result<(StateMap<E::Id>, ResolutionReport<E::Id>)>
+where
+    E: Event + Clone,
+    E::Id: 'a,
+    SetIter: Iterator<Item = &'a StateMap<E::Id>> + Clone,
+    Fut: Future<Output = Result<Option<E>>>,
+{
+    let room_version = RoomVersion::new(room_version)?;
+    let mut report = ResolutionReport::new();
+
+    if let StateResolutionVersion::V1 = room_version.state_res {
+        let resolved_state =
+            v1::resolve(&room_version, state_sets.into_iter(), &fetch_event, &mut report).await?;
+        return Ok((resolved_state, report));
+    }
+
     info!("State resolution starting");
 
     // Split non-conflicting and conflicting state
@@ -73,44 +201,80 @@ where
 
     if conflicting.is_empty() {
         info!("no conflicting state found");
-        return Ok(clean);
+        return Ok((clean, report));
     }
 
     info!("conflicting events: {}", conflicting.len());
     debug!("{conflicting:?}");
 
+    let mut full_conflicted_set = auth_chain_difference(auth_chain_sets);
+    let conflicted_state = conflicting.into_values().flatten().collect::<HashSet<_>>();
+
+    #[cfg(feature = "unstable-msc4297")]
+    if matches!(room_version.state_res, StateResolutionVersion::V2_1) {
+        let subgraph = conflicted_state_subgraph(&conflicted_state, &fetch_event).await?;
+
+        debug!("conflicted state subgraph: {}", subgraph.len());
+        trace!("{subgraph:?}");
+
+        full_conflicted_set.extend(subgraph);
+    }
+
+    full_conflicted_set.extend(conflicted_state);
+
     // `all_conflicted` contains unique items
     // synapse says `full_set = {eid for eid in full_conflicted_set if eid in event_map}`
-    let all_conflicted: HashSet<_> = get_auth_chain_diff(auth_chain_sets)
-        .chain(conflicting.into_values().flatten())
+    let mut all_conflicted = HashSet::new();
+    for id in full_conflicted_set {
         // Don't honor events we cannot "verify"
-        .filter(|id| fetch_event(id.borrow()).is_some())
-        .collect();
+        if fetch_event(id.borrow()).await?.is_some() {
+            all_conflicted.insert(id);
+        }
+    }
 
     info!("full conflicted set: {}", all_conflicted.len());
     debug!("{all_conflicted:?}");
 
+    report.conflicted_set = all_conflicted.clone();
+
     // We used to check that all events are events from the correct room
     // this is now a check the caller of `resolve` must make.
 
     // Get only the control events with a state_key: "" or ban/kick event (sender != state_key)
-    let control_events = all_conflicted
-        .iter()
-        .filter(|&id| is_power_event_id(id.borrow(), &fetch_event))
-        .cloned()
-        .collect::<Vec<_>>();
+    let mut control_events = Vec::new();
+    for id in &all_conflicted {
+        if is_power_event_id(id.borrow(), &fetch_event).await? {
+            control_events.push(id.clone());
+        }
+    }
 
     // Sort the control events based on power_level/clock/event_id and outgoing/incoming edges
     let sorted_control_levels =
-        reverse_topological_power_sort(control_events, &all_conflicted, &fetch_event)?;
+        reverse_topological_power_sort(control_events, &all_conflicted, &fetch_event, cache)
+            .await?;
 
     debug!("sorted control events: {}", sorted_control_levels.len());
     trace!("{sorted_control_levels:?}");
 
-    let room_version = RoomVersion::new(room_version)?;
+    report.sorted_control_events = sorted_control_levels.clone();
+
+    // State resolution v2.1 starts from an empty state instead of the unconflicted state, which
+    // is only applied at the end.
+    let initial_state = match room_version.state_res {
+        #[cfg(feature = "unstable-msc4297")]
+        StateResolutionVersion::V2_1 => StateMap::new(),
+        _ => clean.clone(),
+    };
+
     // Sequentially auth check each control event.
-    let resolved_control =
-        iterative_auth_check(&room_version, &sorted_control_levels, clean.clone(), &fetch_event)?;
+    let resolved_control = iterative_auth_check(
+        &room_version,
+        &sorted_control_levels,
+        initial_state,
+        &fetch_event,
+        &mut report,
+    )
+    .await?;
 
     debug!("resolved control events: {}", resolved_control.len());
     trace!("{resolved_control:?}");
@@ -135,21 +299,50 @@ where
 
     debug!("power event: {power_event:?}");
 
-    let sorted_left_events = mainline_sort(&events_to_resolve, power_event.cloned(), &fetch_event)?;
+    let sorted_left_events =
+        mainline_sort(&events_to_resolve, power_event.cloned(), &fetch_event, cache).await?;
 
     trace!("events left, sorted: {sorted_left_events:?}");
 
+    report.mainline_ordered_events = sorted_left_events.clone();
+
     let mut resolved_state = iterative_auth_check(
         &room_version,
         &sorted_left_events,
         resolved_control, // The control events are added to the final resolved state
         &fetch_event,
-    )?;
+        &mut report,
+    )
+    .await?;
 
     // Add unconflicted state to the resolved state
     // We priorities the unconflicting state
     resolved_state.extend(clean);
-    Ok(resolved_state)
+    Ok((resolved_state, report))
+}
+
+/// Resolve sets of state events, computing their auth chains along the way.
+///
+/// This runs the same algorithm as [`resolve_async`], but builds the `auth_chain_sets` itself from
+/// the events returned by `fetch_event`. The auth chain of each event that is walked through is
+/// looked up in and added to `auth_chain_cache`, so it is computed only once across calls.
+pub async fn resolve_with_auth_chain_cache<'a, E, SetIter, Fut>(
+    room_version: &RoomVersionId,
+    state_sets: impl IntoIterator<IntoIter = SetIter>,
+    auth_chain_cache: &mut impl AuthChainCache<E::Id>,
+    fetch_event: impl Fn(&EventId) -> Fut,
+) -> Result<StateMap<E::Id>>
+where
+    E: Event + Clone,
+    E::Id: 'a,
+    SetIter: Iterator<Item = &'a StateMap<E::Id>> + Clone,
+    Fut: Future<Output = Result<Option<E>>>,
+{
+    let state_sets = state_sets.into_iter();
+    let auth_chain_sets =
+        auth_chain_sets(state_sets.clone(), auth_chain_cache, &fetch_event).await?;
+
+    resolve_async(room_version, state_sets, auth_chain_sets, fetch_event).await
 }
 
 /// Split the events that have no conflicts from those that are conflicting.
@@ -187,19 +380,59 @@ where
     (unconflicted_state, conflicted_state)
 }
 
-/// Returns a Vec of deduped EventIds that appear in some chains but not others.
-fn get_auth_chain_diff<Id>(auth_chain_sets: Vec<HashSet<Id>>) -> impl Iterator<Item = Id>
+/// Get the conflicted state subgraph of the given conflicted state events.
+///
+/// This is the set of events that are both reachable from a conflicted state event and from
+/// which a conflicted state event is reachable, by following `auth_events`. The conflicted state
+/// events are part of it.
+#[cfg(feature = "unstable-msc4297")]
+async fn conflicted_state_subgraph<E, Fut>(
+    conflicted_state: &HashSet<E::Id>,
+    fetch_event: &impl Fn(&EventId) -> Fut,
+) -> Result<HashSet<E::Id>>
 where
-    Id: Eq + Hash,
+    E: Event,
+    Fut: Future<Output = Result<Option<E>>>,
 {
-    let num_sets = auth_chain_sets.len();
+    // The events that reference a given event in their `auth_events`, for every event reachable
+    // from the conflicted state.
+    let mut referenced_by: HashMap<E::Id, Vec<E::Id>> = HashMap::new();
+    let mut seen = HashSet::new();
+    let mut stack = conflicted_state.iter().cloned().collect::<Vec<_>>();
+
+    while let Some(event_id) = stack.pop() {
+        if !seen.insert(event_id.clone()) {
+            continue;
+        }
+
+        let Some(event) = fetch_event(event_id.borrow()).await? else {
+            warn!("missing event {event_id} while computing conflicted state subgraph");
+            continue;
+        };
 
-    let mut id_counts: HashMap<Id, usize> = HashMap::new();
-    for id in auth_chain_sets.into_iter().flatten() {
-        *id_counts.entry(id).or_default() += 1;
+        for aid in event.auth_events() {
+            referenced_by.entry(aid.clone()).or_default().push(event_id.clone());
+            stack.push(aid.clone());
+        }
     }
 
-    id_counts.into_iter().filter_map(move |(id, count)| (count < num_sets).then_some(id))
+    // Walk back up from the conflicted state, to only keep the events that lead to it.
+    let mut subgraph = HashSet::new();
+    let mut stack = conflicted_state.iter().cloned().collect::<Vec<_>>();
+
+    while let Some(event_id) = stack.pop() {
+        if subgraph.contains(&event_id) {
+            continue;
+        }
+
+        if let Some(referencing) = referenced_by.get(event_id.borrow()) {
+            stack.extend(referencing.iter().cloned());
+        }
+
+        subgraph.insert(event_id);
+    }
+
+    Ok(subgraph)
 }
 
 /// Events are sorted from "earliest" to "latest".
@@ -209,39 +442,45 @@ where
 ///
 /// The power level is negative because a higher power level is equated to an earlier (further back
 /// in time) origin server timestamp.
-fn reverse_topological_power_sort<E: Event>(
+async fn reverse_topological_power_sort<E, Fut>(
     events_to_sort: Vec<E::Id>,
     auth_diff: &HashSet<E::Id>,
-    fetch_event: impl Fn(&EventId) -> Option<E>,
-) -> Result<Vec<E::Id>> {
+    fetch_event: &impl Fn(&EventId) -> Fut,
+    cache: &mut impl ResolutionCache<E::Id>,
+) -> Result<Vec<E::Id>>
+where
+    E: Event,
+    Fut: Future<Output = Result<Option<E>>>,
+{
     debug!("reverse topological sort of power events");
 
     let mut graph = HashMap::new();
     for event_id in events_to_sort {
-        add_event_and_auth_chain_to_graph(&mut graph, event_id, auth_diff, &fetch_event);
-
-        // TODO: if these functions are ever made async here
-        // is a good place to yield every once in a while so other
-        // tasks can make progress
+        add_event_and_auth_chain_to_graph(&mut graph, event_id, auth_diff, fetch_event).await?;
     }
 
     // This is used in the `key_fn` passed to the lexico_topo_sort fn
     let mut event_to_pl = HashMap::new();
     for event_id in graph.keys() {
-        let pl = get_power_level_for_sender(event_id.borrow(), &fetch_event)?;
-        info!("{event_id} power level {pl}");
+        let ev = fetch_event(event_id.borrow())
+            .await?
+            .ok_or_else(|| Error::NotFound(format!("Failed to find {event_id}")))?;
 
-        event_to_pl.insert(event_id.clone(), pl);
+        let pl = match cache.get_sender_power_level(event_id.borrow()) {
+            Some(pl) => pl,
+            None => {
+                let pl = get_power_level_for_sender(event_id.borrow(), fetch_event).await?;
+                cache.insert_sender_power_level(event_id.clone(), pl);
+                pl
+            }
+        };
+        info!("{event_id} power level {pl}");
 
-        // TODO: if these functions are ever made async here
-        // is a good place to yield every once in a while so other
-        // tasks can make progress
+        event_to_pl.insert(event_id.clone(), (pl, ev.origin_server_ts()));
     }
 
     lexicographical_topological_sort(&graph, |event_id| {
-        let ev = fetch_event(event_id).ok_or_else(|| Error::NotFound("".into()))?;
-        let pl = *event_to_pl.get(event_id).ok_or_else(|| Error::NotFound("".into()))?;
-        Ok((pl, ev.origin_server_ts()))
+        event_to_pl.get(event_id).copied().ok_or_else(|| Error::NotFound("".into()))
     })
 }
 
@@ -339,17 +578,21 @@ where
 /// Do NOT use this any where but topological sort, we find the power level for the eventId
 /// at the eventId's generation (we walk backwards to `EventId`s most recent previous power level
 /// event).
-fn get_power_level_for_sender<E: Event>(
+async fn get_power_level_for_sender<E, Fut>(
     event_id: &EventId,
-    fetch_event: impl Fn(&EventId) -> Option<E>,
-) -> serde_json::Result<Int> {
+    fetch_event: &impl Fn(&EventId) -> Fut,
+) -> Result<Int>
+where
+    E: Event,
+    Fut: Future<Output = Result<Option<E>>>,
+{
     info!("fetch event ({event_id}) senders power level");
 
-    let event = fetch_event(event_id);
+    let event = fetch_event(event_id).await?;
     let mut pl = None;
 
     for aid in event.as_ref().map(|pdu| pdu.auth_events()).into_iter().flatten() {
-        if let Some(aev) = fetch_event(aid.borrow()) {
+        if let Some(aev) = fetch_event(aid.borrow()).await? {
             if is_type_and_key(&aev, &TimelineEventType::RoomPowerLevels, "") {
                 pl = Some(aev);
                 break;
@@ -380,13 +623,19 @@ fn get_power_level_for_sender<E: Event>(
 /// `event_auth::auth_check` will be excluded from the returned state map.
 ///
 /// For each `events_to_check` event we gather the events needed to auth it from the the
-/// `fetch_event` closure and verify each event using the `event_auth::auth_check` function.
-fn iterative_auth_check<E: Event + Clone>(
+/// `fetch_event` closure and verify each event using the `event_auth::auth_check` function. The
+/// events that fail are added to the `rejected_events` of the `report`.
+async fn iterative_auth_check<E, Fut>(
     room_version: &RoomVersion,
     events_to_check: &[E::Id],
     unconflicted_state: StateMap<E::Id>,
-    fetch_event: impl Fn(&EventId) -> Option<E>,
-) -> Result<StateMap<E::Id>> {
+    fetch_event: &impl Fn(&EventId) -> Fut,
+    report: &mut ResolutionReport<E::Id>,
+) -> Result<StateMap<E::Id>>
+where
+    E: Event + Clone,
+    Fut: Future<Output = Result<Option<E>>>,
+{
     info!("starting iterative auth check");
 
     debug!("performing auth checks on {events_to_check:?}");
@@ -395,6 +644,7 @@ fn iterative_auth_check<E: Event + Clone>(
 
     for event_id in events_to_check {
         let event = fetch_event(event_id.borrow())
+            .await?
             .ok_or_else(|| Error::NotFound(format!("Failed to find {event_id}")))?;
         let state_key = event
             .state_key()
@@ -402,7 +652,7 @@ fn iterative_auth_check<E: Event + Clone>(
 
         let mut auth_events = StateMap::new();
         for aid in event.auth_events() {
-            if let Some(ev) = fetch_event(aid.borrow()) {
+            if let Some(ev) = fetch_event(aid.borrow()).await? {
                 // TODO synapse check "rejected_reason" which is most likely
                 // related to soft-failing
                 auth_events.insert(
@@ -423,7 +673,7 @@ fn iterative_auth_check<E: Event + Clone>(
             event.content(),
         )? {
             if let Some(ev_id) = resolved_state.get(&key) {
-                if let Some(event) = fetch_event(ev_id.borrow()) {
+                if let Some(event) = fetch_event(ev_id.borrow()).await? {
                     // TODO synapse checks `rejected_reason` is None here
                     auth_events.insert(key.to_owned(), event);
                 }
@@ -438,19 +688,18 @@ fn iterative_auth_check<E: Event + Clone>(
             (*pdu.event_type() == TimelineEventType::RoomThirdPartyInvite).then_some(pdu)
         });
 
-        if auth_check(room_version, &event, current_third_party, |ty, key| {
-            auth_events.get(&ty.with_state_key(key))
-        })? {
-            // add event to resolved state map
-            resolved_state.insert(event.event_type().with_state_key(state_key), event_id.clone());
-        } else {
+        if let Some(reason) =
+            check_auth_rules(room_version, &event, current_third_party, |ty, key| {
+                auth_events.get(&ty.with_state_key(key))
+            })?
+        {
             // synapse passes here on AuthError. We do not add this event to resolved_state.
             warn!("event {event_id} failed the authentication check");
+            report.rejected_events.push(RejectedEvent::new(event_id.clone(), reason));
+        } else {
+            // add event to resolved state map
+            resolved_state.insert(event.event_type().with_state_key(state_key), event_id.clone());
         }
-
-        // TODO: if these functions are ever made async here
-        // is a good place to yield every once in a while so other
-        // tasks can make progress
     }
     Ok(resolved_state)
 }
@@ -462,11 +711,16 @@ fn iterative_auth_check<E: Event + Clone>(
 /// power_level event. If there have been two power events the after the most recent are depth 0,
 /// the events before (with the first power level as a parent) will be marked as depth 1. depth 1 is
 /// "older" than depth 0.
-fn mainline_sort<E: Event>(
+async fn mainline_sort<E, Fut>(
     to_sort: &[E::Id],
     resolved_power_level: Option<E::Id>,
-    fetch_event: impl Fn(&EventId) -> Option<E>,
-) -> Result<Vec<E::Id>> {
+    fetch_event: &impl Fn(&EventId) -> Fut,
+    cache: &mut impl ResolutionCache<E::Id>,
+) -> Result<Vec<E::Id>>
+where
+    E: Event,
+    Fut: Future<Output = Result<Option<E>>>,
+{
     debug!("mainline sort of events");
 
     // There are no EventId's to sort, bail.
@@ -474,65 +728,99 @@ fn mainline_sort<E: Event>(
         return Ok(vec![]);
     }
 
+    // The mainline is only built if the mainline depth of one of the events is not cached.
+    let mut mainline_map = None;
+
+    let mut order_map = HashMap::new();
+    for ev_id in to_sort.iter() {
+        let Some(event) = fetch_event(ev_id.borrow()).await? else {
+            continue;
+        };
+        let origin_server_ts = event.origin_server_ts();
+
+        let cached_depth = resolved_power_level
+            .as_ref()
+            .and_then(|pl| cache.get_mainline_depth(pl.borrow(), ev_id.borrow()));
+        let depth = match cached_depth {
+            Some(depth) => depth,
+            None => {
+                let mainline_map = match &mut mainline_map {
+                    Some(mainline_map) => mainline_map,
+                    None => mainline_map
+                        .insert(get_mainline_map(resolved_power_level.clone(), fetch_event).await?),
+                };
+
+                match get_mainline_depth(Some(event), mainline_map, fetch_event).await {
+                    Ok(depth) => {
+                        if let Some(pl) = &resolved_power_level {
+                            cache.insert_mainline_depth(pl.clone(), ev_id.clone(), depth);
+                        }
+                        depth
+                    }
+                    // Events with missing power level ancestors are left out of the sort
+                    Err(Error::NotFound(_)) => continue,
+                    Err(e) => return Err(e),
+                }
+            }
+        };
+
+        order_map.insert(ev_id, (depth, origin_server_ts, ev_id));
+    }
+
+    // Sort the event_ids by their depth, timestamp and EventId
+    // unwrap is OK order map and sort_event_ids are from to_sort (the same Vec)
+    let mut sort_event_ids = order_map.keys().map(|&k| k.clone()).collect::<Vec<_>>();
+    sort_event_ids.sort_by_key(|sort_id| order_map.get(sort_id).unwrap());
+
+    Ok(sort_event_ids)
+}
+
+/// Get the mainline of the given power level event, mapped to the depth of each event in it.
+///
+/// The mainline is the given power level event and its ancestors, found by following the power
+/// level event in the auth events of each of them.
+async fn get_mainline_map<E, Fut>(
+    resolved_power_level: Option<E::Id>,
+    fetch_event: &impl Fn(&EventId) -> Fut,
+) -> Result<HashMap<E::Id, usize>>
+where
+    E: Event,
+    Fut: Future<Output = Result<Option<E>>>,
+{
     let mut mainline = vec![];
     let mut pl = resolved_power_level;
     while let Some(p) = pl {
         mainline.push(p.clone());
 
         let event = fetch_event(p.borrow())
+            .await?
             .ok_or_else(|| Error::NotFound(format!("Failed to find {p}")))?;
         pl = None;
         for aid in event.auth_events() {
             let ev = fetch_event(aid.borrow())
+                .await?
                 .ok_or_else(|| Error::NotFound(format!("Failed to find {aid}")))?;
             if is_type_and_key(&ev, &TimelineEventType::RoomPowerLevels, "") {
                 pl = Some(aid.to_owned());
                 break;
             }
         }
-        // TODO: if these functions are ever made async here
-        // is a good place to yield every once in a while so other
-        // tasks can make progress
     }
 
-    let mainline_map = mainline
-        .iter()
-        .rev()
-        .enumerate()
-        .map(|(idx, eid)| ((*eid).clone(), idx))
-        .collect::<HashMap<_, _>>();
-
-    let mut order_map = HashMap::new();
-    for ev_id in to_sort.iter() {
-        if let Some(event) = fetch_event(ev_id.borrow()) {
-            if let Ok(depth) = get_mainline_depth(Some(event), &mainline_map, &fetch_event) {
-                order_map.insert(
-                    ev_id,
-                    (depth, fetch_event(ev_id.borrow()).map(|ev| ev.origin_server_ts()), ev_id),
-                );
-            }
-        }
-
-        // TODO: if these functions are ever made async here
-        // is a good place to yield every once in a while so other
-        // tasks can make progress
-    }
-
-    // Sort the event_ids by their depth, timestamp and EventId
-    // unwrap is OK order map and sort_event_ids are from to_sort (the same Vec)
-    let mut sort_event_ids = order_map.keys().map(|&k| k.clone()).collect::<Vec<_>>();
-    sort_event_ids.sort_by_key(|sort_id| order_map.get(sort_id).unwrap());
-
-    Ok(sort_event_ids)
+    Ok(mainline.into_iter().rev().enumerate().map(|(idx, eid)| (eid, idx)).collect())
 }
 
 /// Get the mainline depth from the `mainline_map` or finds a power_level event that has an
 /// associated mainline depth.
-fn get_mainline_depth<E: Event>(
+async fn get_mainline_depth<E, Fut>(
     mut event: Option<E>,
     mainline_map: &HashMap<E::Id, usize>,
-    fetch_event: impl Fn(&EventId) -> Option<E>,
-) -> Result<usize> {
+    fetch_event: &impl Fn(&EventId) -> Fut,
+) -> Result<usize>
+where
+    E: Event,
+    Fut: Future<Output = Result<Option<E>>>,
+{
     while let Some(sort_ev) = event {
         debug!("mainline event_id {}", sort_ev.event_id());
         let id = sort_ev.event_id();
@@ -543,6 +831,7 @@ fn get_mainline_depth<E: Event>(
         event = None;
         for aid in sort_ev.auth_events() {
             let aev = fetch_event(aid.borrow())
+                .await?
                 .ok_or_else(|| Error::NotFound(format!("Failed to find {aid}")))?;
             if is_type_and_key(&aev, &TimelineEventType::RoomPowerLevels, "") {
                 event = Some(aev);
@@ -554,18 +843,26 @@ fn get_mainline_depth<E: Event>(
     Ok(0)
 }
 
-fn add_event_and_auth_chain_to_graph<E: Event>(
+async fn add_event_and_auth_chain_to_graph<E, Fut>(
     graph: &mut HashMap<E::Id, HashSet<E::Id>>,
     event_id: E::Id,
     auth_diff: &HashSet<E::Id>,
-    fetch_event: impl Fn(&EventId) -> Option<E>,
-) {
+    fetch_event: &impl Fn(&EventId) -> Fut,
+) -> Result<()>
+where
+    E: Event,
+    Fut: Future<Output = Result<Option<E>>>,
+{
     let mut state = vec![event_id];
     while let Some(eid) = state.pop() {
         graph.entry(eid.clone()).or_default();
         // Prefer the store to event as the store filters dedups the events
-        for aid in
-            fetch_event(eid.borrow()).as_ref().map(|ev| ev.auth_events()).into_iter().flatten()
+        for aid in fetch_event(eid.borrow())
+            .await?
+            .as_ref()
+            .map(|ev| ev.auth_events())
+            .into_iter()
+            .flatten()
         {
             if auth_diff.contains(aid.borrow()) {
                 if !graph.contains_key(aid.borrow()) {
@@ -577,13 +874,22 @@ fn add_event_and_auth_chain_to_graph<E: Event>(
             }
         }
     }
+
+    Ok(())
 }
 
-fn is_power_event_id<E: Event>(event_id: &EventId, fetch: impl Fn(&EventId) -> Option<E>) -> bool {
-    match fetch(event_id).as_ref() {
+async fn is_power_event_id<E, Fut>(
+    event_id: &EventId,
+    fetch: &impl Fn(&EventId) -> Fut,
+) -> Result<bool>
+where
+    E: Event,
+    Fut: Future<Output = Result<Option<E>>>,
+{
+    Ok(match fetch(event_id).await?.as_ref() {
         Some(state) => is_power_event(state),
         _ => false,
-    }
+    })
 }
 
 fn is_type_and_key(ev: impl Event, ev_type: &TimelineEventType, state_key: &str) -> bool {
@@ -638,15 +944,20 @@ where
 mod tests {
     use std::{
         collections::{HashMap, HashSet},
+        future,
         sync::Arc,
     };
 
+    use futures_util::FutureExt;
     use js_int::{int, uint};
     use maplit::{hashmap, hashset};
     use rand::seq::SliceRandom;
-    use ruma_common::{MilliSecondsSinceUnixEpoch, OwnedEventId, RoomVersionId};
+    use ruma_common::{EventId, MilliSecondsSinceUnixEpoch, OwnedEventId, RoomVersionId};
     use ruma_events::{
-        room::join_rules::{JoinRule, RoomJoinRulesEventContent},
+        room::{
+            join_rules::{JoinRule, RoomJoinRulesEventContent},
+            member::MembershipState,
+        },
         StateEventType, TimelineEventType,
     };
     use serde_json::{json, value::to_raw_value as to_raw_json_value};
@@ -659,7 +970,7 @@ mod tests {
             alice, bob, charlie, do_check, ella, event_id, member_content_ban, member_content_join,
             room_id, to_init_pdu_event, to_pdu_event, zara, PduEvent, TestStore, INITIAL_EVENTS,
         },
-        Event, EventTypeExt, StateMap,
+        AuthRejection, Error, Event, EventTypeExt, InMemoryResolutionCache, StateMap,
     };
 
     fn test_event_sort() {
@@ -680,18 +991,29 @@ mod tests {
             .map(|pdu| pdu.event_id.clone())
             .collect::<Vec<_>>();
 
-        let sorted_power_events =
-            crate::reverse_topological_power_sort(power_events, &auth_chain, |id| {
-                events.get(id).map(Arc::clone)
-            })
-            .unwrap();
+        let fetch_event = |id: &EventId| future::ready(Ok(events.get(id).map(Arc::clone)));
+
+        let mut cache = InMemoryResolutionCache::new();
+
+        let sorted_power_events = crate::reverse_topological_power_sort(
+            power_events,
+            &auth_chain,
+            &fetch_event,
+            &mut cache,
+        )
+        .now_or_never()
+        .unwrap()
+        .unwrap();
 
         let resolved_power = crate::iterative_auth_check(
             &RoomVersion::V6,
             &sorted_power_events,
             HashMap::new(), // unconflicted events
-            |id| events.get(id).map(Arc::clone),
+            &fetch_event,
+            &mut crate::ResolutionReport::new(),
         )
+        .now_or_never()
+        .unwrap()
         .expect("iterative auth check failed on resolved events");
 
         // don't remove any events so we know it sorts them all correctly
@@ -702,23 +1024,32 @@ mod tests {
         let power_level =
             resolved_power.get(&(StateEventType::RoomPowerLevels, "".to_owned())).cloned();
 
+        let expected = vec![
+            "$CREATE:foo",
+            "$IMA:foo",
+            "$IPOWER:foo",
+            "$IJR:foo",
+            "$IMB:foo",
+            "$IMC:foo",
+            "$START:foo",
+            "$END:foo",
+        ];
+
         let sorted_event_ids =
-            crate::mainline_sort(&events_to_sort, power_level, |id| events.get(id).map(Arc::clone))
+            crate::mainline_sort(&events_to_sort, power_level.clone(), &fetch_event, &mut cache)
+                .now_or_never()
+                .unwrap()
                 .unwrap();
+        assert_eq!(expected, sorted_event_ids.iter().map(|id| id.to_string()).collect::<Vec<_>>());
 
-        assert_eq!(
-            vec![
-                "$CREATE:foo",
-                "$IMA:foo",
-                "$IPOWER:foo",
-                "$IJR:foo",
-                "$IMB:foo",
-                "$IMC:foo",
-                "$START:foo",
-                "$END:foo"
-            ],
-            sorted_event_ids.iter().map(|id| id.to_string()).collect::<Vec<_>>()
-        );
+        // The mainline depths are now cached, the result is the same.
+        events_to_sort.shuffle(&mut rand::thread_rng());
+        let sorted_event_ids =
+            crate::mainline_sort(&events_to_sort, power_level, &fetch_event, &mut cache)
+                .now_or_never()
+                .unwrap()
+                .unwrap();
+        assert_eq!(expected, sorted_event_ids.iter().map(|id| id.to_string()).collect::<Vec<_>>());
     }
 
     #[test]
@@ -1065,6 +1396,124 @@ mod tests {
         assert_eq!(expected, resolved);
     }
 
+    #[test]
+    fn test_resolve_async() {
+        let _ =
+            tracing::subscriber::set_default(tracing_subscriber::fmt().with_test_writer().finish());
+
+        let mut store = TestStore::<PduEvent>(hashmap! {});
+
+        // build up the DAG
+        let (state_at_bob, state_at_charlie, expected) = store.set_up();
+
+        let ev_map = store.0.clone();
+        let state_sets = [state_at_bob, state_at_charlie];
+        let resolved = crate::resolve_async(
+            &RoomVersionId::V2,
+            &state_sets,
+            state_sets
+                .iter()
+                .map(|map| {
+                    store.auth_event_ids(room_id(), map.values().cloned().collect()).unwrap()
+                })
+                .collect(),
+            |id| future::ready(Ok(ev_map.get(id).map(Arc::clone))),
+        )
+        .now_or_never()
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(expected, resolved);
+    }
+
+    #[test]
+    fn test_resolve_with_auth_chain_cache() {
+        let _ =
+            tracing::subscriber::set_default(tracing_subscriber::fmt().with_test_writer().finish());
+
+        let mut store = TestStore::<PduEvent>(hashmap! {});
+
+        // build up the DAG
+        let (state_at_bob, state_at_charlie, expected) = store.set_up();
+
+        let mut auth_chain_cache = HashMap::new();
+        let state_sets = [state_at_bob, state_at_charlie];
+        let resolved = crate::resolve_with_auth_chain_cache(
+            &RoomVersionId::V2,
+            &state_sets,
+            &mut auth_chain_cache,
+            |id| future::ready(Ok(store.0.get(id).map(Arc::clone))),
+        )
+        .now_or_never()
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(expected, resolved);
+        assert!(auth_chain_cache.contains_key(&event_id("IMB")));
+        assert!(auth_chain_cache.contains_key(&event_id("IMC")));
+    }
+
+    #[test]
+    fn test_resolve_incremental() {
+        let _ =
+            tracing::subscriber::set_default(tracing_subscriber::fmt().with_test_writer().finish());
+
+        let mut store = TestStore::<PduEvent>(hashmap! {});
+
+        // build up the DAG
+        let (state_at_bob, state_at_charlie, expected) = store.set_up();
+
+        let mut cache = InMemoryResolutionCache::new();
+        let state_sets = [state_at_bob, state_at_charlie];
+
+        // The second resolution reuses the data cached by the first one.
+        for _ in 0..2 {
+            let resolved =
+                crate::resolve_incremental(&RoomVersionId::V2, &state_sets, &mut cache, |id| {
+                    future::ready(Ok(store.0.get(id).map(Arc::clone)))
+                })
+                .now_or_never()
+                .unwrap()
+                .unwrap();
+
+            assert_eq!(expected, resolved);
+        }
+    }
+
+    #[test]
+    fn test_resolve_async_fetch_error() {
+        let _ =
+            tracing::subscriber::set_default(tracing_subscriber::fmt().with_test_writer().finish());
+
+        let mut store = TestStore::<PduEvent>(hashmap! {});
+
+        // build up the DAG
+        let (state_at_bob, state_at_charlie, _) = store.set_up();
+
+        let state_sets = [state_at_bob, state_at_charlie];
+        let result = crate::resolve_async(
+            &RoomVersionId::V2,
+            &state_sets,
+            state_sets
+                .iter()
+                .map(|map| {
+                    store.auth_event_ids(room_id(), map.values().cloned().collect()).unwrap()
+                })
+                .collect(),
+            |id| {
+                future::ready(if id == event_id("IMC") {
+                    Err(Error::Custom("database unavailable".into()))
+                } else {
+                    Ok(store.0.get(id).map(Arc::clone))
+                })
+            },
+        )
+        .now_or_never()
+        .unwrap();
+
+        assert!(matches!(result, Err(Error::Custom(_))));
+    }
+
     #[test]
     fn test_lexicographical_sort() {
         let _ =
@@ -1180,6 +1629,59 @@ mod tests {
         assert_eq!(expected.len(), resolved.len());
     }
 
+    #[test]
+    fn test_resolve_with_report() {
+        let _ =
+            tracing::subscriber::set_default(tracing_subscriber::fmt().with_test_writer().finish());
+        let mut events = INITIAL_EVENTS();
+        events.extend(BAN_STATE_SET());
+        let store = TestStore(events);
+
+        let state_set = |membership: &str| {
+            ["CREATE", "IJR", "IMA", "IMB", "IMC", "PA", membership]
+                .into_iter()
+                .map(|id| {
+                    let ev = &store.0[&event_id(id)];
+                    (ev.event_type().with_state_key(ev.state_key().unwrap()), ev.event_id.clone())
+                })
+                .collect::<StateMap<_>>()
+        };
+        let state_sets = [state_set("MB"), state_set("IME")];
+
+        let (resolved, report) = crate::resolve_with_report(
+            &RoomVersionId::V6,
+            &state_sets,
+            state_sets
+                .iter()
+                .map(|map| {
+                    store.auth_event_ids(room_id(), map.values().cloned().collect()).unwrap()
+                })
+                .collect(),
+            |id| future::ready(Ok(store.0.get(id).map(Arc::clone))),
+        )
+        .now_or_never()
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(resolved[&(StateEventType::RoomMember, ella().to_string())], event_id("MB"));
+
+        assert!(report.conflicted_set.contains(&event_id("MB")));
+        assert!(report.conflicted_set.contains(&event_id("IME")));
+        assert!(report.sorted_control_events.contains(&event_id("MB")));
+        assert_eq!(report.mainline_ordered_events, vec![event_id("IME")]);
+
+        // ella's join is checked against the resolved ban.
+        assert_eq!(report.rejected_events.len(), 1);
+        assert_eq!(report.rejected_events[0].event_id, event_id("IME"));
+        assert_eq!(
+            report.rejected_events[0].reason,
+            AuthRejection::InvalidMembershipTransition {
+                from: MembershipState::Ban,
+                to: MembershipState::Join,
+            }
+        );
+    }
+
     #[test]
     fn join_rule_with_auth_chain() {
         let join_rule = JOIN_RULE();
@@ -1194,6 +1696,138 @@ mod tests {
         do_check(&join_rule.values().cloned().collect::<Vec<_>>(), edges, expected_state_ids);
     }
 
+    #[cfg(feature = "unstable-msc4297")]
+    #[test]
+    fn test_conflicted_state_subgraph() {
+        let events = INITIAL_EVENTS();
+        let fetch_event = |id: &EventId| future::ready(Ok(events.get(id).map(Arc::clone)));
+
+        let conflicted_state = ["IMA", "IMB"].into_iter().map(event_id).collect::<HashSet<_>>();
+        let subgraph = crate::conflicted_state_subgraph(&conflicted_state, &fetch_event)
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+
+        // CREATE is reachable from both, but doesn't lead back to the conflicted state.
+        assert_eq!(
+            subgraph,
+            ["IMA", "IPOWER", "IJR", "IMB"].into_iter().map(event_id).collect::<HashSet<_>>()
+        );
+    }
+
+    #[cfg(feature = "unstable-msc4297")]
+    #[test]
+    fn test_event_map_none_v2_1() {
+        let _ =
+            tracing::subscriber::set_default(tracing_subscriber::fmt().with_test_writer().finish());
+
+        let mut store = TestStore::<PduEvent>(hashmap! {});
+
+        // build up the DAG
+        let (state_at_bob, state_at_charlie, expected) = store.set_up();
+
+        let ev_map = store.0.clone();
+        let state_sets = [state_at_bob, state_at_charlie];
+        let resolved = crate::resolve(
+            &RoomVersionId::try_from("org.matrix.msc4297").unwrap(),
+            &state_sets,
+            state_sets
+                .iter()
+                .map(|map| {
+                    store.auth_event_ids(room_id(), map.values().cloned().collect()).unwrap()
+                })
+                .collect(),
+            |id| ev_map.get(id).map(Arc::clone),
+        )
+        .unwrap();
+
+        assert_eq!(expected, resolved);
+    }
+
+    #[cfg(feature = "unstable-msc4297")]
+    #[test]
+    fn power_levels_of_banned_sender_v2_1() {
+        let _ =
+            tracing::subscriber::set_default(tracing_subscriber::fmt().with_test_writer().finish());
+
+        let mut events = INITIAL_EVENTS();
+        events.extend(
+            [
+                to_pdu_event(
+                    "PB",
+                    alice(),
+                    TimelineEventType::RoomPowerLevels,
+                    Some(""),
+                    to_raw_json_value(&json!({ "users": { alice(): 100, bob(): 50 } })).unwrap(),
+                    &["CREATE", "IMA", "IPOWER"],
+                    &["IMC"],
+                ),
+                to_pdu_event(
+                    "PC",
+                    bob(),
+                    TimelineEventType::RoomPowerLevels,
+                    Some(""),
+                    to_raw_json_value(
+                        &json!({ "users": { alice(): 100, bob(): 50, charlie(): 50 } }),
+                    )
+                    .unwrap(),
+                    &["CREATE", "IMB", "PB"],
+                    &["PB"],
+                ),
+                to_pdu_event(
+                    "MB",
+                    alice(),
+                    TimelineEventType::RoomMember,
+                    Some(bob().as_str()),
+                    member_content_ban(),
+                    &["CREATE", "IMA", "IMB", "PB"],
+                    &["PB"],
+                ),
+            ]
+            .into_iter()
+            .map(|ev| (ev.event_id.clone(), ev)),
+        );
+        let store = TestStore(events);
+
+        // Both forks agree that bob is banned, but disagree on the power levels.
+        let state_set = |power_levels: &str| {
+            ["CREATE", "IJR", "IMA", "MB", "IMC", power_levels]
+                .into_iter()
+                .map(|id| {
+                    let ev = &store.0[&event_id(id)];
+                    (ev.event_type().with_state_key(ev.state_key().unwrap()), ev.event_id.clone())
+                })
+                .collect::<StateMap<_>>()
+        };
+        let state_sets = [state_set("PB"), state_set("PC")];
+
+        let resolve = |room_version: &RoomVersionId| {
+            crate::resolve(
+                room_version,
+                &state_sets,
+                state_sets
+                    .iter()
+                    .map(|map| {
+                        store.auth_event_ids(room_id(), map.values().cloned().collect()).unwrap()
+                    })
+                    .collect(),
+                |id| store.0.get(id).map(Arc::clone),
+            )
+            .unwrap()
+        };
+        let power_levels_key = (StateEventType::RoomPowerLevels, "".to_owned());
+
+        // v2 checks the power levels of bob against the unconflicted state, where he is banned.
+        let resolved = resolve(&RoomVersionId::V11);
+        assert_eq!(resolved[&power_levels_key], event_id("PB"));
+        assert_eq!(resolved[&(StateEventType::RoomMember, bob().to_string())], event_id("MB"));
+
+        // v2.1 starts from an empty state, so only the auth events of bob's event are considered.
+        let resolved = resolve(&RoomVersionId::try_from("org.matrix.msc4297").unwrap());
+        assert_eq!(resolved[&power_levels_key], event_id("PC"));
+        assert_eq!(resolved[&(StateEventType::RoomMember, bob().to_string())], event_id("MB"));
+    }
+
     #[allow(non_snake_case)]
     fn BAN_STATE_SET() -> HashMap<OwnedEventId, Arc<PduEvent>> {
         vec![
@@ -0,0 +1,408 @@
+//! Building new PDUs.
+//!
+//! See the [server-server API specification] for more information about the format of PDUs.
+//!
+//! [server-server API specification]: https://spec.matrix.org/latest/server-server-api/#pdus
+
+use std::{collections::BTreeMap, future::Future};
+
+use js_int::uint;
+use ruma_common::{
+    CanonicalJsonObject, CanonicalJsonValue, EventId, MilliSecondsSinceUnixEpoch, OwnedEventId,
+    RoomId, RoomVersionId, UserId,
+};
+use ruma_events::{pdu::Pdu, StateEventType, TimelineEventType};
+use ruma_signatures::{hash_and_sign_event, reference_hash, KeyPair};
+use serde_json::{from_str as from_json_str, value::RawValue as RawJsonValue};
+use tracing::debug;
+
+use crate::{
+    auth_types_for_event,
+    room_version::{EventFormatVersion, RoomVersion},
+    Error, Event, Result,
+};
+
+/// A builder for a new PDU.
+///
+/// It computes the `auth_events`, `prev_events` and `depth` of the PDU, then hashes and signs it
+/// with the key of the sender's server.
+///
+/// This doesn't check that the PDU passes the authorization rules, which should be done with
+/// [`check_auth_rules`] before sending it.
+///
+/// [`check_auth_rules`]: crate::check_auth_rules
+#[derive(Clone, Debug)]
+pub struct PduBuilder {
+    event_type: TimelineEventType,
+    content: Box<RawJsonValue>,
+    state_key: Option<String>,
+    redacts: Option<OwnedEventId>,
+    origin_server_ts: Option<MilliSecondsSinceUnixEpoch>,
+}
+
+impl PduBuilder {
+    /// Creates a new `PduBuilder` for an event with the given type and content.
+    pub fn new(event_type: TimelineEventType, content: Box<RawJsonValue>) -> Self {
+        Self { event_type, content, state_key: None, redacts: None, origin_server_ts: None }
+    }
+
+    /// Creates a new `PduBuilder` for a state event with the given type, state key and content.
+    pub fn state(
+        event_type: TimelineEventType,
+        state_key: impl Into<String>,
+        content: Box<RawJsonValue>,
+    ) -> Self {
+        Self { state_key: Some(state_key.into()), ..Self::new(event_type, content) }
+    }
+
+    /// Set the event ID that is redacted by this event, for `m.room.redaction` events.
+    ///
+    /// Since room version 11, the `redacts` field must also be in the content of the event.
+    pub fn redacts(mut self, event_id: OwnedEventId) -> Self {
+        self.redacts = Some(event_id);
+        self
+    }
+
+    /// Set the timestamp of the event.
+    ///
+    /// Defaults to the current time.
+    pub fn origin_server_ts(mut self, origin_server_ts: MilliSecondsSinceUnixEpoch) -> Self {
+        self.origin_server_ts = Some(origin_server_ts);
+        self
+    }
+
+    /// Build the PDU, and hash and sign it.
+    ///
+    /// ## Arguments
+    ///
+    /// * `room_id` - The ID of the room of the event.
+    ///
+    /// * `sender` - The sender of the event. Its server is the origin of the PDU.
+    ///
+    /// * `forward_extremities` - The forward extremities of the room. The deepest ones are used as
+    ///   the `prev_events` of the PDU. This must be empty for an `m.room.create` event.
+    ///
+    /// * `fetch_state` - Called to get a state event from the current state of the room, to compute
+    ///   the `auth_events` of the PDU.
+    ///
+    /// * `key_pair` - The signing key of the sender's server.
+    ///
+    /// In room versions 1 and 2, the event ID is generated randomly, and the `auth_events` and
+    /// `prev_events` include the [`Event::reference_hash`] of each event.
+    ///
+    /// ## Errors
+    ///
+    /// Returns `Error::InvalidPdu` if the forward extremities are invalid for the event, or if the
+    /// reference hash of an event is missing in room versions 1 and 2, and any error returned by
+    /// the fetcher.
+    pub async fn build<E, Fut>(
+        self,
+        room_version_id: &RoomVersionId,
+        room_id: &RoomId,
+        sender: &UserId,
+        forward_extremities: &[E],
+        fetch_state: impl Fn(&StateEventType, &str) -> Fut,
+        key_pair: &impl KeyPair,
+    ) -> Result<NewPdu>
+    where
+        E: Event,
+        Fut: Future<Output = Result<Option<E>>>,
+    {
+        let room_version = RoomVersion::new(room_version_id)?;
+
+        let is_create = self.event_type == TimelineEventType::RoomCreate;
+        if is_create && !forward_extremities.is_empty() {
+            return Err(Error::InvalidPdu("m.room.create event with prev events".to_owned()));
+        }
+        if !is_create && forward_extremities.is_empty() {
+            return Err(Error::InvalidPdu("no prev events for the event".to_owned()));
+        }
+
+        let mut prev_events = forward_extremities.iter().collect::<Vec<_>>();
+        prev_events.sort_by_key(|ev| std::cmp::Reverse(ev.depth()));
+        prev_events.truncate(Pdu::MAX_PREV_EVENTS);
+
+        let depth = prev_events
+            .iter()
+            .map(|ev| ev.depth())
+            .max()
+            .map_or(uint!(1), |depth| depth.saturating_add(uint!(1)));
+
+        let auth_types = auth_types_for_event(
+            &self.event_type,
+            sender,
+            self.state_key.as_deref(),
+            &self.content,
+        )?;
+
+        let mut auth_events = Vec::new();
+        for (event_type, state_key) in &auth_types {
+            if let Some(auth_event) = fetch_state(event_type, state_key).await? {
+                auth_events.push(auth_event);
+            }
+        }
+
+        let mut json = CanonicalJsonObject::new();
+        let event_id = match room_version.event_format {
+            EventFormatVersion::V1 => {
+                let event_id = EventId::new(sender.server_name());
+                json.insert(
+                    "event_id".to_owned(),
+                    CanonicalJsonValue::String(event_id.to_string()),
+                );
+                Some(event_id)
+            }
+            _ => None,
+        };
+        json.insert("room_id".to_owned(), CanonicalJsonValue::String(room_id.to_string()));
+        json.insert("sender".to_owned(), CanonicalJsonValue::String(sender.to_string()));
+        json.insert(
+            "origin".to_owned(),
+            CanonicalJsonValue::String(sender.server_name().to_string()),
+        );
+        json.insert(
+            "origin_server_ts".to_owned(),
+            CanonicalJsonValue::Integer(
+                self.origin_server_ts.unwrap_or_else(MilliSecondsSinceUnixEpoch::now).0.into(),
+            ),
+        );
+        json.insert("type".to_owned(), CanonicalJsonValue::String(self.event_type.to_string()));
+        json.insert("content".to_owned(), from_json_str(self.content.get())?);
+        if let Some(state_key) = self.state_key {
+            json.insert("state_key".to_owned(), CanonicalJsonValue::String(state_key));
+        }
+        if let Some(redacts) = self.redacts {
+            json.insert("redacts".to_owned(), CanonicalJsonValue::String(redacts.into()));
+        }
+        json.insert("depth".to_owned(), CanonicalJsonValue::Integer(depth.into()));
+        json.insert(
+            "auth_events".to_owned(),
+            event_references(auth_events.iter(), &room_version.event_format)?,
+        );
+        json.insert(
+            "prev_events".to_owned(),
+            event_references(prev_events.iter().copied(), &room_version.event_format)?,
+        );
+
+        hash_and_sign_event(sender.server_name().as_str(), key_pair, &mut json, room_version_id)
+            .map_err(Error::custom)?;
+
+        let event_id = match event_id {
+            Some(event_id) => event_id,
+            None => {
+                let hash = reference_hash(&json, room_version_id).map_err(Error::custom)?;
+                OwnedEventId::try_from(format!("${hash}"))
+                    .map_err(|e| Error::InvalidPdu(e.to_string()))?
+            }
+        };
+
+        debug!("built PDU {event_id}");
+
+        Ok(NewPdu { event_id, json })
+    }
+}
+
+/// A new PDU, hashed and signed.
+#[derive(Clone, Debug)]
+#[cfg_attr(not(feature = "unstable-exhaustive-types"), non_exhaustive)]
+pub struct NewPdu {
+    /// The ID of the event.
+    ///
+    /// It is computed from the reference hash of the PDU, except in room versions 1 and 2 where it
+    /// is generated randomly and part of the PDU.
+    pub event_id: OwnedEventId,
+
+    /// The JSON of the PDU, as it should be sent to other servers.
+    pub json: CanonicalJsonObject,
+}
+
+/// Serialize references to the given events, in the given event format.
+fn event_references<'a, E: Event + 'a>(
+    events: impl Iterator<Item = &'a E>,
+    event_format: &EventFormatVersion,
+) -> Result<CanonicalJsonValue> {
+    events
+        .map(|ev| {
+            let event_id = CanonicalJsonValue::String(ev.event_id().to_string());
+
+            match event_format {
+                EventFormatVersion::V1 => {
+                    let hash = ev.reference_hash().ok_or_else(|| {
+                        Error::InvalidPdu(format!("missing reference hash of {}", ev.event_id()))
+                    })?;
+                    let hashes = BTreeMap::from([(
+                        "sha256".to_owned(),
+                        CanonicalJsonValue::String(hash.sha256),
+                    )]);
+
+                    Ok(CanonicalJsonValue::Array(vec![
+                        event_id,
+                        CanonicalJsonValue::Object(hashes),
+                    ]))
+                }
+                _ => Ok(event_id),
+            }
+        })
+        .collect::<Result<_>>()
+        .map(CanonicalJsonValue::Array)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{BTreeMap, HashMap},
+        future,
+        sync::Arc,
+    };
+
+    use futures_util::FutureExt;
+    use js_int::uint;
+    use ruma_common::{
+        CanonicalJsonValue, MilliSecondsSinceUnixEpoch, OwnedEventId, RoomVersionId,
+    };
+    use ruma_events::{pdu::Pdu, TimelineEventType};
+    use ruma_signatures::{verify_event, Ed25519KeyPair, Verified};
+    use serde_json::{json, value::to_raw_value as to_raw_json_value};
+
+    use super::{NewPdu, PduBuilder};
+    use crate::{
+        test_utils::{
+            alice, event_id, key_pair, public_key_map, room_id, PduEvent, INITIAL_EVENTS,
+        },
+        Error, Event, EventTypeExt, RoomVersion, StateMap,
+    };
+
+    fn build(
+        builder: PduBuilder,
+        room_version_id: &RoomVersionId,
+        key_pair: &Ed25519KeyPair,
+    ) -> Result<NewPdu, Error> {
+        build_with_events(builder, room_version_id, key_pair, &INITIAL_EVENTS())
+    }
+
+    fn build_with_events(
+        builder: PduBuilder,
+        room_version_id: &RoomVersionId,
+        key_pair: &Ed25519KeyPair,
+        events: &HashMap<OwnedEventId, Arc<PduEvent>>,
+    ) -> Result<NewPdu, Error> {
+        let state = events
+            .values()
+            .map(|ev| (ev.event_type().with_state_key(ev.state_key().unwrap()), (**ev).clone()))
+            .collect::<StateMap<_>>();
+        let extremities = if builder.event_type == TimelineEventType::RoomCreate {
+            Vec::new()
+        } else {
+            vec![(*events[&event_id("IMC")]).clone()]
+        };
+
+        builder
+            .build::<PduEvent, _>(
+                room_version_id,
+                room_id(),
+                alice(),
+                &extremities,
+                |ty, key| future::ready(Ok(state.get(&ty.with_state_key(key)).cloned())),
+                key_pair,
+            )
+            .now_or_never()
+            .unwrap()
+    }
+
+    #[test]
+    fn build_topic() {
+        let builder = PduBuilder::state(
+            TimelineEventType::RoomTopic,
+            "",
+            to_raw_json_value(&json!({ "topic": "Hello" })).unwrap(),
+        )
+        .origin_server_ts(MilliSecondsSinceUnixEpoch(uint!(1_000)));
+        let key_pair = key_pair();
+        let pdu = build(builder, &RoomVersionId::V6, &key_pair).unwrap();
+
+        assert!(pdu.event_id.as_str().starts_with('$'));
+        assert_eq!(pdu.json.get("depth"), Some(&CanonicalJsonValue::Integer(1.into())));
+        assert_eq!(pdu.json.get("origin"), Some(&CanonicalJsonValue::String("foo".to_owned())));
+        assert_eq!(
+            pdu.json.get("prev_events"),
+            Some(&CanonicalJsonValue::Array(vec![CanonicalJsonValue::String(
+                "$IMC:foo".to_owned()
+            )]))
+        );
+        assert_eq!(
+            pdu.json.get("auth_events"),
+            Some(&CanonicalJsonValue::Array(
+                ["$IPOWER:foo", "$IMA:foo", "$CREATE:foo"]
+                    .into_iter()
+                    .map(|id| CanonicalJsonValue::String(id.to_owned()))
+                    .collect()
+            ))
+        );
+
+        assert!(matches!(
+            verify_event(&public_key_map(&key_pair), &pdu.json, &RoomVersionId::V6),
+            Ok(Verified::All)
+        ));
+    }
+
+    #[test]
+    fn build_create() {
+        let builder = PduBuilder::state(
+            TimelineEventType::RoomCreate,
+            "",
+            to_raw_json_value(&json!({ "room_version": "6" })).unwrap(),
+        );
+        let pdu = build(builder, &RoomVersionId::V6, &key_pair()).unwrap();
+
+        assert_eq!(pdu.json.get("depth"), Some(&CanonicalJsonValue::Integer(1.into())));
+        assert_eq!(pdu.json.get("auth_events"), Some(&CanonicalJsonValue::Array(Vec::new())));
+        assert_eq!(pdu.json.get("prev_events"), Some(&CanonicalJsonValue::Array(Vec::new())));
+    }
+
+    #[test]
+    fn build_with_v1_event_format() {
+        let builder = PduBuilder::new(
+            TimelineEventType::RoomMessage,
+            to_raw_json_value(&json!({ "msgtype": "m.text", "body": "Hi" })).unwrap(),
+        );
+        let key_pair = key_pair();
+        let events = INITIAL_EVENTS();
+        let pdu = build_with_events(builder, &RoomVersionId::V1, &key_pair, &events).unwrap();
+
+        assert_eq!(pdu.event_id.server_name().map(|s| s.as_str()), Some("foo"));
+        assert_eq!(
+            pdu.json.get("event_id"),
+            Some(&CanonicalJsonValue::String(pdu.event_id.to_string()))
+        );
+
+        let reference = |id: &str| {
+            let hash = events[&event_id(id)].reference_hash().unwrap();
+            CanonicalJsonValue::Array(vec![
+                CanonicalJsonValue::String(format!("${id}:foo")),
+                CanonicalJsonValue::Object(BTreeMap::from([(
+                    "sha256".to_owned(),
+                    CanonicalJsonValue::String(hash.sha256),
+                )])),
+            ])
+        };
+        assert_eq!(
+            pdu.json.get("prev_events"),
+            Some(&CanonicalJsonValue::Array(vec![reference("IMC")]))
+        );
+        assert_eq!(
+            pdu.json.get("auth_events"),
+            Some(&CanonicalJsonValue::Array(vec![
+                reference("IPOWER"),
+                reference("IMA"),
+                reference("CREATE")
+            ]))
+        );
+
+        assert!(matches!(
+            verify_event(&public_key_map(&key_pair), &pdu.json, &RoomVersionId::V1),
+            Ok(Verified::All)
+        ));
+        let rules = RoomVersion::V1.pdu_format_rules();
+        assert!(Pdu::from_json_with_rules(&to_raw_json_value(&pdu.json).unwrap(), &rules).is_ok());
+    }
+}
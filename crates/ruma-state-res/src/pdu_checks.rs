@@ -0,0 +1,510 @@
+//! The checks performed on receipt of a PDU.
+//!
+//! See the [server-server API specification] for more information.
+//!
+//! [server-server API specification]: https://spec.matrix.org/latest/server-server-api/#checks-performed-on-receipt-of-a-pdu
+
+use std::{borrow::Borrow, future::Future};
+
+use ruma_common::{
+    canonical_json::{redact, RedactionError},
+    CanonicalJsonObject, CanonicalJsonValue, EventId, RoomVersionId,
+};
+use ruma_events::{
+    pdu::{Pdu, PduFormatError},
+    StateEventType, TimelineEventType,
+};
+use ruma_signatures::{reference_hash, verify_event, PublicKeyMap, Verified};
+use serde::de::DeserializeOwned;
+use serde_json::{from_str as from_json_str, value::RawValue as RawJsonValue};
+use tracing::{debug, warn};
+
+use crate::{
+    auth_types_for_event, check_auth_rules,
+    event_auth::fetch_auth_state,
+    room_version::{EventFormatVersion, RoomVersion},
+    AuthRejection, Error, Event, EventTypeExt, Result, StateMap,
+};
+
+/// The outcome of the checks performed on receipt of a PDU.
+#[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
+#[cfg_attr(not(feature = "unstable-exhaustive-types"), non_exhaustive)]
+pub enum PduCheckOutcome<E> {
+    /// The PDU passed all the checks.
+    Accepted(CheckedPdu<E>),
+
+    /// The PDU passed the authorization rules based on its auth events and on the state before
+    /// it, but not based on the current state of the room.
+    ///
+    /// It should be soft failed: it is not added to the forward extremities of the room and not
+    /// sent to clients.
+    SoftFailed(CheckedPdu<E>, AuthRejection),
+
+    /// The PDU failed the authorization rules based on its auth events or on the state before it.
+    ///
+    /// It should be stored as rejected.
+    Rejected(CheckedPdu<E>, PduRejection),
+
+    /// The PDU is not a valid event, or its signatures are invalid.
+    ///
+    /// It should be dropped.
+    Dropped(PduDropReason),
+}
+
+/// A PDU that passed the format and signature checks.
+#[derive(Clone, Debug)]
+#[cfg_attr(not(feature = "unstable-exhaustive-types"), non_exhaustive)]
+pub struct CheckedPdu<E> {
+    /// The PDU, parsed as an event.
+    pub event: E,
+
+    /// The JSON of the PDU, as it should be stored.
+    ///
+    /// This is the redacted PDU if its content hash didn't match.
+    pub json: CanonicalJsonObject,
+
+    /// Whether the PDU was redacted because its content hash didn't match.
+    pub redacted: bool,
+}
+
+/// The reason why a PDU was rejected.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(not(feature = "unstable-exhaustive-types"), non_exhaustive)]
+pub enum PduRejection {
+    /// The PDU failed the authorization rules based on its auth events.
+    AuthEvents(AuthRejection),
+
+    /// The PDU failed the authorization rules based on the state before it.
+    StateBefore(AuthRejection),
+}
+
+/// The reason why a PDU was dropped.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum PduDropReason {
+    /// The PDU is not a valid event for the room version.
+    InvalidFormat(PduFormatError),
+
+    /// The signatures of the PDU are invalid, or its reference hash could not be computed.
+    InvalidSignatures(ruma_signatures::Error),
+
+    /// The content hash of the PDU didn't match, and it could not be redacted.
+    Redaction(RedactionError),
+}
+
+/// Run the checks performed on receipt of a PDU, in the order of the specification.
+///
+/// 1. The PDU must be a valid event for the room version, otherwise it is dropped.
+/// 2. It must pass the signature checks, otherwise it is dropped.
+/// 3. It must pass the hash checks, otherwise it is redacted before being processed further.
+/// 4. It must pass the authorization rules based on its auth events, otherwise it is rejected.
+/// 5. It must pass the authorization rules based on the state before it, otherwise it is rejected.
+/// 6. It must pass the authorization rules based on the current state of the room, otherwise it is
+///    soft failed.
+///
+/// ## Arguments
+///
+/// * `pdu` - The PDU as it was received.
+///
+/// * `public_key_map` - The public keys of the servers that must have signed the PDU.
+///
+/// * `fetch_event` - Called to get the auth events of the PDU.
+///
+/// * `fetch_state_before` - Called to get a state event from the state before the PDU.
+///
+/// * `fetch_current_state` - Called to get a state event from the current state of the room.
+///
+/// For room versions where the event ID is not part of the event format, it is computed from the
+/// reference hash of the PDU and added to the JSON that is deserialized to `E`.
+///
+/// ## Errors
+///
+/// Returns `Error::NotFound` if one of the auth events of the PDU could not be fetched, so they can
+/// be fetched from another server before trying again, and any error returned by the fetchers.
+pub async fn check_pdu<E, Fut, StateBeforeFut, CurrentStateFut>(
+    room_version_id: &RoomVersionId,
+    pdu: &RawJsonValue,
+    public_key_map: &PublicKeyMap,
+    fetch_event: impl Fn(&EventId) -> Fut,
+    fetch_state_before: impl Fn(&StateEventType, &str) -> StateBeforeFut,
+    fetch_current_state: impl Fn(&StateEventType, &str) -> CurrentStateFut,
+) -> Result<PduCheckOutcome<E>>
+where
+    E: Event + DeserializeOwned,
+    Fut: Future<Output = Result<Option<E>>>,
+    StateBeforeFut: Future<Output = Result<Option<E>>>,
+    CurrentStateFut: Future<Output = Result<Option<E>>>,
+{
+    let room_version = RoomVersion::new(room_version_id)?;
+
+    // 1. Format checks.
+    if let Err(e) = Pdu::from_json_with_rules(pdu, &room_version.pdu_format_rules()) {
+        return Ok(PduCheckOutcome::Dropped(PduDropReason::InvalidFormat(e)));
+    }
+
+    let mut json = match from_json_str::<CanonicalJsonObject>(pdu.get()) {
+        Ok(json) => json,
+        Err(e) => return Ok(PduCheckOutcome::Dropped(PduDropReason::InvalidFormat(e.into()))),
+    };
+
+    if let Err(reason) = parse_event::<E>(&room_version, room_version_id, &json) {
+        return Ok(PduCheckOutcome::Dropped(reason));
+    }
+
+    // 2. Signature checks, and 3. hash checks.
+    let redacted = match verify_event(public_key_map, &json, room_version_id) {
+        Ok(Verified::All) => false,
+        Ok(Verified::Signatures) => {
+            warn!("content hash of PDU doesn't match, redacting it");
+            json = match redact(json, room_version_id, None) {
+                Ok(json) => json,
+                Err(e) => return Ok(PduCheckOutcome::Dropped(PduDropReason::Redaction(e))),
+            };
+            true
+        }
+        Err(e) => return Ok(PduCheckOutcome::Dropped(PduDropReason::InvalidSignatures(e))),
+    };
+
+    let event = match parse_event::<E>(&room_version, room_version_id, &json) {
+        Ok(event) => event,
+        Err(reason) => return Ok(PduCheckOutcome::Dropped(reason)),
+    };
+
+    debug!("checking authorization of PDU {}", event.event_id());
+
+    // 4. Authorization based on the auth events.
+    let auth_events = match fetch_auth_events(&event, &fetch_event).await? {
+        Ok(auth_events) => auth_events,
+        Err(rejection) => {
+            let pdu = CheckedPdu { event, json, redacted };
+            return Ok(PduCheckOutcome::Rejected(pdu, PduRejection::AuthEvents(rejection)));
+        }
+    };
+
+    if let Some(rejection) = check_against_state(&room_version, &event, &auth_events)? {
+        let pdu = CheckedPdu { event, json, redacted };
+        return Ok(PduCheckOutcome::Rejected(pdu, PduRejection::AuthEvents(rejection)));
+    }
+
+    // 5. Authorization based on the state before the event.
+    let state_before = fetch_auth_state(&event, fetch_state_before).await?;
+    if let Some(rejection) = check_against_state(&room_version, &event, &state_before)? {
+        let pdu = CheckedPdu { event, json, redacted };
+        return Ok(PduCheckOutcome::Rejected(pdu, PduRejection::StateBefore(rejection)));
+    }
+
+    // 6. Authorization based on the current state.
+    let current_state = fetch_auth_state(&event, fetch_current_state).await?;
+    if let Some(rejection) = check_against_state(&room_version, &event, &current_state)? {
+        let pdu = CheckedPdu { event, json, redacted };
+        return Ok(PduCheckOutcome::SoftFailed(pdu, rejection));
+    }
+
+    Ok(PduCheckOutcome::Accepted(CheckedPdu { event, json, redacted }))
+}
+
+/// Deserialize the PDU, adding its event ID if it is not part of the event format.
+fn parse_event<E: DeserializeOwned>(
+    room_version: &RoomVersion,
+    room_version_id: &RoomVersionId,
+    json: &CanonicalJsonObject,
+) -> std::result::Result<E, PduDropReason> {
+    let mut json = json.clone();
+
+    match room_version.event_format {
+        EventFormatVersion::V1 => {
+            if !matches!(json.get("event_id"), Some(CanonicalJsonValue::String(_))) {
+                return Err(PduDropReason::InvalidFormat(PduFormatError::MissingEventId));
+            }
+        }
+        _ => {
+            let hash =
+                reference_hash(&json, room_version_id).map_err(PduDropReason::InvalidSignatures)?;
+            json.insert("event_id".to_owned(), CanonicalJsonValue::String(format!("${hash}")));
+        }
+    }
+
+    // Deserialize from a string, so `E` can contain raw JSON values.
+    let json = serde_json::to_string(&json)
+        .map_err(|e| PduDropReason::InvalidFormat(PduFormatError::Deserialization(e)))?;
+    from_json_str(&json)
+        .map_err(|e| PduDropReason::InvalidFormat(PduFormatError::Deserialization(e)))
+}
+
+/// Fetch the auth events of the event.
+///
+/// Returns `Ok(Err(_))` if the auth events contain several events for the same state key, or
+/// events that are not needed to authorize the event.
+async fn fetch_auth_events<E, Fut>(
+    event: &E,
+    fetch_event: &impl Fn(&EventId) -> Fut,
+) -> Result<std::result::Result<StateMap<E>, AuthRejection>>
+where
+    E: Event,
+    Fut: Future<Output = Result<Option<E>>>,
+{
+    let auth_types = auth_types_for_event(
+        event.event_type(),
+        event.sender(),
+        event.state_key(),
+        event.content(),
+    )?;
+
+    let mut auth_events = StateMap::new();
+    for aid in event.auth_events() {
+        let auth_event = fetch_event(aid.borrow())
+            .await?
+            .ok_or_else(|| Error::NotFound(format!("Failed to find auth event {aid}")))?;
+
+        let Some(state_key) = auth_event.state_key() else {
+            warn!("auth event {aid} is not a state event");
+            return Ok(Err(AuthRejection::InvalidAuthEvents));
+        };
+        let key = auth_event.event_type().with_state_key(state_key);
+
+        if !auth_types.contains(&key) {
+            warn!("auth event {aid} is not needed to authorize the event");
+            return Ok(Err(AuthRejection::InvalidAuthEvents));
+        }
+        if auth_events.contains_key(&key) {
+            warn!("auth events contain several events for {key:?}");
+            return Ok(Err(AuthRejection::InvalidAuthEvents));
+        }
+
+        auth_events.insert(key, auth_event);
+    }
+
+    Ok(Ok(auth_events))
+}
+
+/// Authorize the event against the given state.
+fn check_against_state<E: Event>(
+    room_version: &RoomVersion,
+    event: &E,
+    state: &StateMap<E>,
+) -> Result<Option<AuthRejection>> {
+    let current_third_party_invite =
+        state.values().find(|ev| *ev.event_type() == TimelineEventType::RoomThirdPartyInvite);
+
+    check_auth_rules(room_version, event, current_third_party_invite, |ty, key| {
+        state.get(&ty.with_state_key(key))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, future};
+
+    use futures_util::FutureExt;
+    use ruma_common::{CanonicalJsonObject, CanonicalJsonValue, RoomVersionId};
+    use ruma_events::{pdu::PduFormatError, StateEventType, TimelineEventType};
+    use ruma_signatures::{hash_and_sign_event, Ed25519KeyPair, PublicKeyMap};
+    use serde_json::{
+        from_value as from_json_value, json, value::to_raw_value as to_raw_json_value,
+    };
+
+    use super::{check_pdu, PduCheckOutcome, PduDropReason, PduRejection};
+    use crate::{
+        test_utils::{
+            alice, ella, key_pair, member_content_ban, public_key_map, to_pdu_event, PduEvent,
+            INITIAL_EVENTS,
+        },
+        AuthRejection, Event, EventTypeExt, StateMap,
+    };
+
+    /// The given ID of an event of `INITIAL_EVENTS`, in the format of room version 6.
+    fn v6_event_id(event_id: &str) -> String {
+        let name = event_id.trim_start_matches('$').split(':').next().unwrap();
+        format!("${name:A<43}")
+    }
+
+    /// A topic event sent by `sender`, signed by the `foo` server.
+    fn signed_topic(
+        sender: &str,
+        auth_events: &[&str],
+        key_pair: &Ed25519KeyPair,
+    ) -> CanonicalJsonObject {
+        let auth_events = auth_events.iter().map(|id| v6_event_id(id)).collect::<Vec<_>>();
+        let mut pdu = from_json_value(json!({
+            "auth_events": auth_events,
+            "content": { "topic": "Hello" },
+            "depth": 10,
+            "origin": "foo",
+            "origin_server_ts": 1_000,
+            "prev_events": [v6_event_id("$IMC:foo")],
+            "room_id": "!test:foo",
+            "sender": sender,
+            "state_key": "",
+            "type": "m.room.topic",
+        }))
+        .unwrap();
+        hash_and_sign_event("foo", key_pair, &mut pdu, &RoomVersionId::V6).unwrap();
+        pdu
+    }
+
+    const ALICE_AUTH_EVENTS: &[&str] = &["$CREATE:foo", "$IPOWER:foo", "$IMA:foo"];
+
+    fn check(
+        pdu: &CanonicalJsonObject,
+        public_key_map: &PublicKeyMap,
+        current_state: &StateMap<PduEvent>,
+    ) -> PduCheckOutcome<PduEvent> {
+        let events = INITIAL_EVENTS()
+            .into_values()
+            .map(|ev| {
+                let mut ev = (*ev).clone();
+                ev.event_id = v6_event_id(ev.event_id.as_str()).try_into().unwrap();
+                (ev.event_id.clone(), ev)
+            })
+            .collect::<BTreeMap<_, _>>();
+        let state = events
+            .values()
+            .map(|ev| (ev.event_type().with_state_key(ev.state_key().unwrap()), ev.clone()))
+            .collect::<StateMap<_>>();
+
+        check_pdu(
+            &RoomVersionId::V6,
+            &to_raw_json_value(pdu).unwrap(),
+            public_key_map,
+            |id| future::ready(Ok(events.get(id).cloned())),
+            |ty, key| future::ready(Ok(state.get(&ty.with_state_key(key)).cloned())),
+            |ty, key| {
+                future::ready(Ok(current_state
+                    .get(&ty.with_state_key(key))
+                    .or_else(|| state.get(&ty.with_state_key(key)))
+                    .cloned()))
+            },
+        )
+        .now_or_never()
+        .unwrap()
+        .unwrap()
+    }
+
+    #[test]
+    fn accepted() {
+        let key_pair = key_pair();
+        let pdu = signed_topic("@alice:foo", ALICE_AUTH_EVENTS, &key_pair);
+
+        match check(&pdu, &public_key_map(&key_pair), &StateMap::new()) {
+            PduCheckOutcome::Accepted(checked) => {
+                assert!(!checked.redacted);
+                assert_eq!(checked.event.sender(), alice());
+                assert!(checked.event.event_id().as_str().starts_with('$'));
+            }
+            outcome => panic!("unexpected outcome: {outcome:?}"),
+        }
+    }
+
+    #[test]
+    fn redacted_on_hash_mismatch() {
+        let key_pair = key_pair();
+        let mut pdu = signed_topic("@alice:foo", ALICE_AUTH_EVENTS, &key_pair);
+        pdu.insert(
+            "content".to_owned(),
+            CanonicalJsonValue::Object(from_json_value(json!({ "topic": "Changed" })).unwrap()),
+        );
+
+        match check(&pdu, &public_key_map(&key_pair), &StateMap::new()) {
+            PduCheckOutcome::Accepted(checked) => {
+                assert!(checked.redacted);
+                assert_eq!(checked.event.content().get(), "{}");
+            }
+            outcome => panic!("unexpected outcome: {outcome:?}"),
+        }
+    }
+
+    #[test]
+    fn dropped_on_invalid_signature() {
+        let pdu = signed_topic("@alice:foo", ALICE_AUTH_EVENTS, &key_pair());
+
+        assert!(matches!(
+            check(&pdu, &public_key_map(&key_pair()), &StateMap::new()),
+            PduCheckOutcome::Dropped(PduDropReason::InvalidSignatures(_))
+        ));
+    }
+
+    #[test]
+    fn dropped_on_invalid_format() {
+        let key_pair = key_pair();
+        let mut pdu = signed_topic("@alice:foo", ALICE_AUTH_EVENTS, &key_pair);
+        pdu.remove("sender");
+
+        assert!(matches!(
+            check(&pdu, &public_key_map(&key_pair), &StateMap::new()),
+            PduCheckOutcome::Dropped(PduDropReason::InvalidFormat(
+                PduFormatError::Deserialization(_)
+            ))
+        ));
+
+        let mut pdu = signed_topic("@alice:foo", ALICE_AUTH_EVENTS, &key_pair);
+        pdu.insert(
+            "prev_events".to_owned(),
+            CanonicalJsonValue::Array(vec![CanonicalJsonValue::String("$IMC:foo".to_owned())]),
+        );
+
+        assert!(matches!(
+            check(&pdu, &public_key_map(&key_pair), &StateMap::new()),
+            PduCheckOutcome::Dropped(PduDropReason::InvalidFormat(PduFormatError::InvalidEventId(
+                "prev_events"
+            )))
+        ));
+    }
+
+    #[test]
+    fn rejected_by_auth_events() {
+        let key_pair = key_pair();
+        let pdu = signed_topic("@ella:foo", &["$CREATE:foo", "$IPOWER:foo"], &key_pair);
+
+        match check(&pdu, &public_key_map(&key_pair), &StateMap::new()) {
+            PduCheckOutcome::Rejected(checked, rejection) => {
+                assert_eq!(checked.event.sender(), ella());
+                assert_eq!(rejection, PduRejection::AuthEvents(AuthRejection::SenderNotJoined));
+            }
+            outcome => panic!("unexpected outcome: {outcome:?}"),
+        }
+    }
+
+    #[test]
+    fn rejected_by_unexpected_auth_event() {
+        let key_pair = key_pair();
+        // alice's membership is not needed to authorize an event from ella.
+        let pdu = signed_topic("@ella:foo", ALICE_AUTH_EVENTS, &key_pair);
+
+        match check(&pdu, &public_key_map(&key_pair), &StateMap::new()) {
+            PduCheckOutcome::Rejected(_, rejection) => {
+                assert_eq!(rejection, PduRejection::AuthEvents(AuthRejection::InvalidAuthEvents));
+            }
+            outcome => panic!("unexpected outcome: {outcome:?}"),
+        }
+    }
+
+    #[test]
+    fn soft_failed_by_current_state() {
+        let key_pair = key_pair();
+        let pdu = signed_topic("@alice:foo", ALICE_AUTH_EVENTS, &key_pair);
+
+        // alice was banned since the event was sent.
+        let ban = to_pdu_event(
+            "BAN",
+            alice(),
+            TimelineEventType::RoomMember,
+            Some(alice().as_str()),
+            member_content_ban(),
+            &["CREATE", "IMA", "IPOWER"],
+            &["IMC"],
+        );
+        let current_state = StateMap::from([(
+            StateEventType::RoomMember.with_state_key(alice().as_str()),
+            (*ban).clone(),
+        )]);
+
+        match check(&pdu, &public_key_map(&key_pair), &current_state) {
+            PduCheckOutcome::SoftFailed(checked, rejection) => {
+                assert_eq!(checked.event.sender(), alice());
+                assert_eq!(rejection, AuthRejection::SenderNotJoined);
+            }
+            outcome => panic!("unexpected outcome: {outcome:?}"),
+        }
+    }
+}
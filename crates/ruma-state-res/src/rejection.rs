@@ -0,0 +1,101 @@
+use js_int::Int;
+use ruma_events::room::{join_rules::JoinRule, member::MembershipState};
+use thiserror::Error;
+
+/// The reason why an event was rejected by the authorization rules.
+#[derive(Error, Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum AuthRejection {
+    /// The `m.room.create` event has previous events, an unknown room version, no creator, or a
+    /// sender from another server than the room ID.
+    #[error("invalid m.room.create event")]
+    InvalidCreateEvent,
+
+    /// The auth events of the event contain several events for the same state key, or events
+    /// that are not needed to authorize it.
+    #[error("invalid auth events")]
+    InvalidAuthEvents,
+
+    /// There is no `m.room.create` event in the state.
+    #[error("no m.room.create event in the state")]
+    MissingCreateEvent,
+
+    /// The auth events of the event don't include the `m.room.create` event of the room.
+    #[error("the auth events don't include the m.room.create event of the room")]
+    CreateEventMismatch,
+
+    /// The room doesn't federate, and the sender is not on the server of the room creator.
+    #[error("the room is not federated and the sender is on another server than the creator")]
+    NotFederated,
+
+    /// The state key of an `m.room.aliases` event doesn't match the server of its sender.
+    #[error("the state key of the m.room.aliases event doesn't match the sender's server")]
+    AliasesServerMismatch,
+
+    /// The `m.room.member` event has no state key, or no valid `membership`.
+    #[error("invalid m.room.member event")]
+    InvalidMemberEvent,
+
+    /// The sender is not joined to the room.
+    #[error("the sender is not joined to the room")]
+    SenderNotJoined,
+
+    /// The state key of the event is a user ID that doesn't match the sender.
+    #[error("the state key doesn't match the sender")]
+    SenderMismatch,
+
+    /// The power level of the sender is lower than the one required to send the event.
+    #[error("the sender's power level {actual} is lower than the required power level {required}")]
+    InsufficientPowerLevel {
+        /// The power level required to send the event.
+        required: Int,
+
+        /// The power level of the sender.
+        actual: Int,
+    },
+
+    /// The power level of the sender is not higher than the power level of the target user.
+    #[error(
+        "the sender's power level {actual} is not higher than the target's power level {target}"
+    )]
+    TargetPowerLevelNotLower {
+        /// The power level of the target user.
+        target: Int,
+
+        /// The power level of the sender.
+        actual: Int,
+    },
+
+    /// The changes to the `m.room.power_levels` are not allowed.
+    #[error("invalid change of power levels")]
+    InvalidPowerLevelsChange,
+
+    /// The membership of the target user can't change from `from` to `to`.
+    #[error("invalid membership transition from {from} to {to}")]
+    InvalidMembershipTransition {
+        /// The current membership of the target user.
+        from: MembershipState,
+
+        /// The membership of the event.
+        to: MembershipState,
+    },
+
+    /// The join rule of the room doesn't allow the membership.
+    #[error("the join rule `{}` doesn't allow this membership", .join_rule.as_str())]
+    ForbiddenByJoinRule {
+        /// The join rule of the room.
+        join_rule: JoinRule,
+    },
+
+    /// The user in `join_authorised_via_users_server` is not allowed to invite users.
+    #[error("the join authorising user is not allowed to invite users")]
+    InvalidJoinAuthorisation,
+
+    /// The third-party invite doesn't match an `m.room.third_party_invite` event.
+    #[error("invalid third-party invite")]
+    BadThirdPartyInvite,
+
+    /// The membership is unknown or not supported by the room version.
+    #[error("unknown membership {0}")]
+    UnknownMembership(MembershipState),
+}
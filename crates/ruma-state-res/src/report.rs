@@ -0,0 +1,59 @@
+use std::collections::HashSet;
+
+use crate::AuthRejection;
+
+/// A report of the decisions taken during state resolution.
+///
+/// It is returned by [`resolve_with_report`] alongside the resolved state, to help understand why
+/// an event was or wasn't selected.
+///
+/// [`resolve_with_report`]: crate::resolve_with_report
+#[derive(Clone, Debug)]
+#[cfg_attr(not(feature = "unstable-exhaustive-types"), non_exhaustive)]
+pub struct ResolutionReport<Id> {
+    /// The full conflicted set.
+    ///
+    /// With the room version 1 algorithm, this only contains the conflicted state events.
+    pub conflicted_set: HashSet<Id>,
+
+    /// The control events of the conflicted set, in the order in which they were authenticated.
+    ///
+    /// This is always empty with the room version 1 algorithm.
+    pub sorted_control_events: Vec<Id>,
+
+    /// The events that failed the authorization rules, in the order in which they were checked.
+    pub rejected_events: Vec<RejectedEvent<Id>>,
+
+    /// The remaining events of the conflicted set, in the order given by the mainline sort.
+    ///
+    /// This is always empty with the room version 1 algorithm.
+    pub mainline_ordered_events: Vec<Id>,
+}
+
+impl<Id> ResolutionReport<Id> {
+    pub(crate) fn new() -> Self {
+        Self {
+            conflicted_set: HashSet::new(),
+            sorted_control_events: Vec::new(),
+            rejected_events: Vec::new(),
+            mainline_ordered_events: Vec::new(),
+        }
+    }
+}
+
+/// An event that failed the authorization rules during state resolution.
+#[derive(Clone, Debug)]
+#[cfg_attr(not(feature = "unstable-exhaustive-types"), non_exhaustive)]
+pub struct RejectedEvent<Id> {
+    /// The ID of the event.
+    pub event_id: Id,
+
+    /// The authorization rule that rejected the event.
+    pub reason: AuthRejection,
+}
+
+impl<Id> RejectedEvent<Id> {
+    pub(crate) fn new(event_id: Id, reason: AuthRejection) -> Self {
+        Self { event_id, reason }
+    }
+}
@@ -1,4 +1,6 @@
 use ruma_common::RoomVersionId;
+pub use ruma_events::pdu::EventFormatVersion;
+use ruma_events::pdu::PduFormatRules;
 
 use crate::{Error, Result};
 
@@ -11,17 +13,6 @@ pub enum RoomDisposition {
     Unstable,
 }
 
-#[derive(Debug)]
-#[cfg_attr(not(feature = "unstable-exhaustive-types"), non_exhaustive)]
-pub enum EventFormatVersion {
-    /// $id:server event id format
-    V1,
-    /// MSC1659-style $hash event id format: introduced for room v3
-    V2,
-    /// MSC1884-style $hash format: introduced for room v4
-    V3,
-}
-
 #[derive(Debug)]
 #[cfg_attr(not(feature = "unstable-exhaustive-types"), non_exhaustive)]
 pub enum StateResolutionVersion {
@@ -29,6 +20,12 @@ pub enum StateResolutionVersion {
     V1,
     /// State resolution for room at version 2 or later.
     V2,
+    /// State resolution v2.1, which starts the iterative auth checks from an empty state and adds
+    /// the conflicted state subgraph to the full conflicted set.
+    ///
+    /// See: [MSC4297](https://github.com/matrix-org/matrix-spec-proposals/pull/4297) for more information.
+    #[cfg(feature = "unstable-msc4297")]
+    V2_1,
 }
 
 #[cfg_attr(not(feature = "unstable-exhaustive-types"), non_exhaustive)]
@@ -125,6 +122,15 @@ impl RoomVersion {
 
     pub const V11: Self = Self { use_room_create_sender: true, ..Self::V10 };
 
+    /// Room version 11 with state resolution v2.1, used to test
+    /// [MSC4297](https://github.com/matrix-org/matrix-spec-proposals/pull/4297).
+    #[cfg(feature = "unstable-msc4297")]
+    pub const MSC4297: Self = Self {
+        disposition: RoomDisposition::Unstable,
+        state_res: StateResolutionVersion::V2_1,
+        ..Self::V11
+    };
+
     pub fn new(version: &RoomVersionId) -> Result<Self> {
         Ok(match version {
             RoomVersionId::V1 => Self::V1,
@@ -138,7 +144,14 @@ impl RoomVersion {
             RoomVersionId::V9 => Self::V9,
             RoomVersionId::V10 => Self::V10,
             RoomVersionId::V11 => Self::V11,
+            #[cfg(feature = "unstable-msc4297")]
+            ver if ver.as_str() == "org.matrix.msc4297" => Self::MSC4297,
             ver => return Err(Error::Unsupported(format!("found version `{ver}`"))),
         })
     }
+
+    /// The rules of this room version for the format of its PDUs.
+    pub fn pdu_format_rules(&self) -> PduFormatRules {
+        PduFormatRules::new(self.event_format, self.strict_canonicaljson)
+    }
 }
@@ -5,8 +5,9 @@ use std::{
     sync::Arc,
 };
 
+use js_int::UInt;
 use ruma_common::{EventId, MilliSecondsSinceUnixEpoch, RoomId, UserId};
-use ruma_events::TimelineEventType;
+use ruma_events::{pdu::EventHash, TimelineEventType};
 use serde_json::value::RawValue as RawJsonValue;
 
 /// Abstraction of a PDU so users can have their own PDU types.
@@ -34,6 +35,9 @@ pub trait Event {
     /// The state key for this event.
     fn state_key(&self) -> Option<&str>;
 
+    /// The depth of this event in the room's DAG.
+    fn depth(&self) -> UInt;
+
     /// The events before this event.
     // Requires GATs to avoid boxing (and TAIT for making it convenient).
     fn prev_events(&self) -> Box<dyn DoubleEndedIterator<Item = &Self::Id> + '_>;
@@ -44,6 +48,14 @@ pub trait Event {
 
     /// If this event is a redaction event this is the event it redacts.
     fn redacts(&self) -> Option<&Self::Id>;
+
+    /// The reference hash of this event.
+    ///
+    /// It is only needed to reference this event in a new PDU in room versions 1 and 2, where the
+    /// `auth_events` and `prev_events` include the hash of each event. Returns `None` by default.
+    fn reference_hash(&self) -> Option<EventHash> {
+        None
+    }
 }
 
 impl<T: Event> Event for &T {
@@ -77,6 +89,10 @@ impl<T: Event> Event for &T {
         (*self).state_key()
     }
 
+    fn depth(&self) -> UInt {
+        (*self).depth()
+    }
+
     fn prev_events(&self) -> Box<dyn DoubleEndedIterator<Item = &Self::Id> + '_> {
         (*self).prev_events()
     }
@@ -88,6 +104,10 @@ impl<T: Event> Event for &T {
     fn redacts(&self) -> Option<&Self::Id> {
         (*self).redacts()
     }
+
+    fn reference_hash(&self) -> Option<EventHash> {
+        (*self).reference_hash()
+    }
 }
 
 impl<T: Event> Event for Arc<T> {
@@ -121,6 +141,10 @@ impl<T: Event> Event for Arc<T> {
         (**self).state_key()
     }
 
+    fn depth(&self) -> UInt {
+        (**self).depth()
+    }
+
     fn prev_events(&self) -> Box<dyn DoubleEndedIterator<Item = &Self::Id> + '_> {
         (**self).prev_events()
     }
@@ -132,4 +156,8 @@ impl<T: Event> Event for Arc<T> {
     fn redacts(&self) -> Option<&Self::Id> {
         (**self).redacts()
     }
+
+    fn reference_hash(&self) -> Option<EventHash> {
+        (**self).reference_hash()
+    }
 }
@@ -9,8 +9,8 @@ use std::{
 
 use js_int::{int, uint};
 use ruma_common::{
-    event_id, room_id, user_id, EventId, MilliSecondsSinceUnixEpoch, OwnedEventId, RoomId,
-    RoomVersionId, UserId,
+    event_id, room_id, serde::Base64, user_id, EventId, MilliSecondsSinceUnixEpoch, OwnedEventId,
+    RoomId, RoomVersionId, UserId,
 };
 use ruma_events::{
     pdu::{EventHash, Pdu, RoomV3Pdu},
@@ -20,6 +20,7 @@ use ruma_events::{
     },
     TimelineEventType,
 };
+use ruma_signatures::{Ed25519KeyPair, PublicKeyMap};
 use serde_json::{
     json,
     value::{to_raw_value as to_raw_json_value, RawValue as RawJsonValue},
@@ -361,6 +362,18 @@ pub(crate) fn room_id() -> &'static RoomId {
     room_id!("!test:foo")
 }
 
+/// A new random key pair with the version `1`, to sign events of the `foo` server.
+pub(crate) fn key_pair() -> Ed25519KeyPair {
+    Ed25519KeyPair::from_der(&Ed25519KeyPair::generate().unwrap(), "1".to_owned()).unwrap()
+}
+
+/// The public keys of the `foo` server, with the given key pair.
+pub(crate) fn public_key_map(key_pair: &Ed25519KeyPair) -> PublicKeyMap {
+    let public_key_set =
+        BTreeMap::from([("ed25519:1".to_owned(), Base64::new(key_pair.public_key().to_vec()))]);
+    BTreeMap::from([("foo".to_owned(), public_key_set)])
+}
+
 pub(crate) fn member_content_ban() -> Box<RawJsonValue> {
     to_raw_json_value(&RoomMemberEventContent::new(MembershipState::Ban)).unwrap()
 }
@@ -546,10 +559,20 @@ pub(crate) fn INITIAL_EDGES() -> Vec<OwnedEventId> {
 }
 
 pub(crate) mod event {
-    use ruma_common::{MilliSecondsSinceUnixEpoch, OwnedEventId, RoomId, UserId};
-    use ruma_events::{pdu::Pdu, TimelineEventType};
-    use serde::{Deserialize, Serialize};
-    use serde_json::value::RawValue as RawJsonValue;
+    use js_int::UInt;
+    use ruma_common::{
+        CanonicalJsonObject, MilliSecondsSinceUnixEpoch, OwnedEventId, RoomId, RoomVersionId,
+        UserId,
+    };
+    use ruma_events::{
+        pdu::{EventHash, Pdu},
+        TimelineEventType,
+    };
+    use serde::{de::Error as _, Deserialize, Deserializer, Serialize};
+    use serde_json::{
+        from_str as from_json_str, from_value as from_json_value, value::RawValue as RawJsonValue,
+        Map as JsonObject,
+    };
 
     use crate::Event;
 
@@ -614,6 +637,15 @@ pub(crate) mod event {
             }
         }
 
+        fn depth(&self) -> UInt {
+            match &self.rest {
+                Pdu::RoomV1Pdu(ev) => ev.depth,
+                Pdu::RoomV3Pdu(ev) => ev.depth,
+                #[allow(unreachable_patterns)]
+                _ => unreachable!("new PDU version"),
+            }
+        }
+
         fn prev_events(&self) -> Box<dyn DoubleEndedIterator<Item = &Self::Id> + '_> {
             match &self.rest {
                 Pdu::RoomV1Pdu(ev) => Box::new(ev.prev_events.iter().map(|(id, _)| id)),
@@ -640,13 +672,38 @@ pub(crate) mod event {
                 _ => unreachable!("new PDU version"),
             }
         }
+
+        fn reference_hash(&self) -> Option<EventHash> {
+            let json =
+                from_json_value::<CanonicalJsonObject>(serde_json::to_value(self).ok()?).ok()?;
+            ruma_signatures::reference_hash(&json, &RoomVersionId::V1).ok().map(EventHash::new)
+        }
     }
 
-    #[derive(Clone, Debug, Deserialize, Serialize)]
+    #[derive(Clone, Debug, Serialize)]
     #[allow(clippy::exhaustive_structs)]
     pub(crate) struct PduEvent {
         pub(crate) event_id: OwnedEventId,
         #[serde(flatten)]
         pub(crate) rest: Pdu,
     }
+
+    impl<'de> Deserialize<'de> for PduEvent {
+        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            // `#[serde(flatten)]` doesn't work with the raw JSON content of `Pdu`, so the event ID
+            // is extracted manually.
+            let mut json = JsonObject::deserialize(deserializer)?;
+            let event_id =
+                json.remove("event_id").ok_or_else(|| D::Error::missing_field("event_id"))?;
+
+            Ok(Self {
+                event_id: from_json_value(event_id).map_err(D::Error::custom)?,
+                rest: from_json_str(&serde_json::to_string(&json).map_err(D::Error::custom)?)
+                    .map_err(D::Error::custom)?,
+            })
+        }
+    }
 }
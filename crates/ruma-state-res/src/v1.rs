@@ -0,0 +1,496 @@
+//! The state resolution algorithm of room version 1.
+//!
+//! See the [room version 1 specification] for more information.
+//!
+//! [room version 1 specification]: https://spec.matrix.org/latest/rooms/v1/#state-resolution
+
+use std::{borrow::Borrow, cmp::Reverse, future::Future};
+
+use ruma_common::EventId;
+use ruma_events::{StateEventType, TimelineEventType};
+use sha1::{Digest, Sha1};
+use tracing::{debug, info, trace, warn};
+
+use crate::{
+    auth_types_for_event, check_auth_rules, AuthRejection, Error, Event, EventTypeExt,
+    RejectedEvent, ResolutionReport, Result, RoomVersion, StateMap,
+};
+
+/// Resolve the given state sets with the room version 1 algorithm.
+///
+/// Unlike the later algorithm, this doesn't need the auth chains of the state sets: conflicted
+/// events are only authenticated against the unconflicted state and the events resolved before
+/// them.
+///
+/// The conflicted events and the events that fail the authentication check are added to the
+/// `report`.
+pub(crate) async fn resolve<'a, E, Fut>(
+    room_version: &RoomVersion,
+    state_sets: impl Iterator<Item = &'a StateMap<E::Id>>,
+    fetch_event: &impl Fn(&EventId) -> Fut,
+    report: &mut ResolutionReport<E::Id>,
+) -> Result<StateMap<E::Id>>
+where
+    E: Event + Clone,
+    E::Id: 'a,
+    Fut: Future<Output = Result<Option<E>>>,
+{
+    info!("State resolution v1 starting");
+
+    let (mut unconflicted_state, conflicted_state) = separate(state_sets);
+
+    if conflicted_state.is_empty() {
+        info!("no conflicting state found");
+        return Ok(unconflicted_state);
+    }
+
+    // Events we cannot find are ignored. If this leaves a single event for a key, it is not
+    // conflicted anymore.
+    let mut conflicted_events = StateMap::new();
+    for (key, event_ids) in conflicted_state {
+        let mut events = Vec::new();
+        for event_id in event_ids {
+            if let Some(event) = fetch_event(event_id.borrow()).await? {
+                events.push(event);
+            }
+        }
+
+        match events.len() {
+            0 => {}
+            1 => {
+                unconflicted_state.insert(key, events[0].event_id().clone());
+            }
+            _ => {
+                conflicted_events.insert(key, events);
+            }
+        }
+    }
+
+    debug!("conflicting events: {}", conflicted_events.len());
+
+    report.conflicted_set =
+        conflicted_events.values().flatten().map(|event| event.event_id().clone()).collect();
+
+    // The auth events of the conflicted events are only picked from the unconflicted state.
+    let mut auth_events = StateMap::new();
+    for event in conflicted_events.values().flatten() {
+        for key in auth_types_for_event(
+            event.event_type(),
+            event.sender(),
+            event.state_key(),
+            event.content(),
+        )? {
+            if auth_events.contains_key(&key) {
+                continue;
+            }
+
+            if let Some(event_id) = unconflicted_state.get(&key) {
+                if let Some(auth_event) = fetch_event(event_id.borrow()).await? {
+                    auth_events.insert(key, auth_event);
+                }
+            }
+        }
+    }
+
+    let mut resolved_state = StateMap::new();
+
+    // The power levels are resolved first, then the join rules, then the memberships, each step
+    // using the result of the previous ones to authenticate the events.
+    let power_levels_key = (StateEventType::RoomPowerLevels, "".to_owned());
+    if let Some(events) = conflicted_events.remove(&power_levels_key) {
+        let event = resolve_auth_events(room_version, events, &auth_events, report)?;
+        resolved_state.insert(power_levels_key, event);
+    }
+    auth_events.extend(resolved_state.iter().map(|(k, ev)| (k.clone(), ev.clone())));
+
+    for event_type in [StateEventType::RoomJoinRules, StateEventType::RoomMember] {
+        let keys = conflicted_events
+            .keys()
+            .filter(|(ty, _)| *ty == event_type)
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let mut resolved = StateMap::new();
+        for key in keys {
+            let events = conflicted_events.remove(&key).expect("key comes from the map");
+            resolved.insert(key, resolve_auth_events(room_version, events, &auth_events, report)?);
+        }
+
+        auth_events.extend(resolved.iter().map(|(k, ev)| (k.clone(), ev.clone())));
+        resolved_state.extend(resolved);
+    }
+
+    for (key, events) in conflicted_events {
+        let event = resolve_normal_events(room_version, events, &auth_events, report)?;
+        resolved_state.insert(key, event);
+    }
+
+    trace!("resolved conflicted state: {:?}", resolved_state.keys().collect::<Vec<_>>());
+
+    unconflicted_state.extend(resolved_state.into_iter().map(|(k, ev)| (k, ev.event_id().clone())));
+    Ok(unconflicted_state)
+}
+
+/// Split the events that have no conflicts from those that are conflicting.
+///
+/// The return tuple looks like `(unconflicted, conflicted)`.
+///
+/// Contrary to the later algorithm, state is only determined to be conflicting if there is more
+/// than one event ID for the given key among the state sets that contain it.
+fn separate<'a, Id>(
+    state_sets: impl Iterator<Item = &'a StateMap<Id>>,
+) -> (StateMap<Id>, StateMap<Vec<Id>>)
+where
+    Id: Clone + Eq + 'a,
+{
+    let mut unconflicted_state: StateMap<Id> = StateMap::new();
+    let mut conflicted_state: StateMap<Vec<Id>> = StateMap::new();
+
+    for state_set in state_sets {
+        for (key, id) in state_set {
+            if let Some(event_ids) = conflicted_state.get_mut(key) {
+                if !event_ids.contains(id) {
+                    event_ids.push(id.clone());
+                }
+            } else if let Some(unconflicted_id) = unconflicted_state.get(key) {
+                if unconflicted_id != id {
+                    let unconflicted_id =
+                        unconflicted_state.remove(key).expect("key is in the unconflicted state");
+                    conflicted_state.insert(key.clone(), vec![unconflicted_id, id.clone()]);
+                }
+            } else {
+                unconflicted_state.insert(key.clone(), id.clone());
+            }
+        }
+    }
+
+    (unconflicted_state, conflicted_state)
+}
+
+/// Resolve conflicted auth events, like `m.room.power_levels`.
+///
+/// Starting from the event with the lowest depth, each event is authenticated with the previous one
+/// in the auth events. The last event that passes is returned.
+fn resolve_auth_events<E: Event + Clone>(
+    room_version: &RoomVersion,
+    events: Vec<E>,
+    auth_events: &StateMap<E>,
+    report: &mut ResolutionReport<E::Id>,
+) -> Result<E> {
+    let mut auth_types = Vec::new();
+    for event in &events {
+        auth_types.extend(auth_types_for_event(
+            event.event_type(),
+            event.sender(),
+            event.state_key(),
+            event.content(),
+        )?);
+    }
+
+    let mut auth_events = auth_types
+        .into_iter()
+        .filter_map(|key| auth_events.get(&key).map(|ev| (key, ev.clone())))
+        .collect::<StateMap<_>>();
+
+    let mut events = ordered_events(events).into_iter().rev();
+    let mut prev_event = events.next().expect("there are at least 2 conflicted events");
+
+    for event in events {
+        let state_key = prev_event
+            .state_key()
+            .ok_or_else(|| Error::InvalidPdu("State event had no state key".to_owned()))?;
+        auth_events.insert(prev_event.event_type().with_state_key(state_key), prev_event.clone());
+
+        if let Some(reason) = check_event(room_version, &event, &auth_events)? {
+            warn!("auth event {} failed the authentication check", event.event_id());
+            report.rejected_events.push(RejectedEvent::new(event.event_id().clone(), reason));
+            break;
+        }
+
+        prev_event = event;
+    }
+
+    Ok(prev_event)
+}
+
+/// Resolve conflicted events that are not auth events.
+///
+/// The event with the highest depth that passes the authentication check is returned, or the one
+/// with the lowest depth if none of them pass.
+fn resolve_normal_events<E: Event>(
+    room_version: &RoomVersion,
+    events: Vec<E>,
+    auth_events: &StateMap<E>,
+    report: &mut ResolutionReport<E::Id>,
+) -> Result<E> {
+    let mut events = ordered_events(events).into_iter();
+    let mut event = events.next().expect("there are at least 2 conflicted events");
+
+    loop {
+        let Some(reason) = check_event(room_version, &event, auth_events)? else {
+            return Ok(event);
+        };
+
+        warn!("event {} failed the authentication check", event.event_id());
+        report.rejected_events.push(RejectedEvent::new(event.event_id().clone(), reason));
+
+        match events.next() {
+            Some(next) => event = next,
+            // Use the last event if they all fail the authentication check.
+            None => return Ok(event),
+        }
+    }
+}
+
+/// Sort the events by decreasing depth, then by the SHA-1 hash of their event ID.
+fn ordered_events<E: Event>(mut events: Vec<E>) -> Vec<E> {
+    events.sort_by_cached_key(|event| {
+        let event_id: &EventId = event.event_id().borrow();
+        (Reverse(event.depth()), Sha1::digest(event_id.as_bytes()))
+    });
+    events
+}
+
+/// Authenticate the event against the given auth events.
+///
+/// Returns the reason why the event was rejected, or `None` if it was allowed.
+fn check_event<E: Event>(
+    room_version: &RoomVersion,
+    event: &E,
+    auth_events: &StateMap<E>,
+) -> Result<Option<AuthRejection>> {
+    let current_third_party_invite =
+        auth_events.values().find(|ev| *ev.event_type() == TimelineEventType::RoomThirdPartyInvite);
+
+    check_auth_rules(room_version, event, current_third_party_invite, |ty, key| {
+        auth_events.get(&ty.with_state_key(key))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use js_int::UInt;
+    use ruma_common::{OwnedEventId, RoomVersionId};
+    use ruma_events::{pdu::Pdu, StateEventType, TimelineEventType};
+    use serde_json::{json, value::to_raw_value as to_raw_json_value};
+
+    use crate::{
+        test_utils::{
+            alice, bob, charlie, event_id, member_content_ban, member_content_join, to_pdu_event,
+            PduEvent, INITIAL_EVENTS,
+        },
+        Event, EventTypeExt, StateMap,
+    };
+
+    fn with_depth(event: Arc<PduEvent>, depth: u32) -> Arc<PduEvent> {
+        let mut event = (*event).clone();
+        if let Pdu::RoomV3Pdu(pdu) = &mut event.rest {
+            pdu.depth = UInt::from(depth);
+        }
+        Arc::new(event)
+    }
+
+    fn state_set(
+        events: &HashMap<OwnedEventId, Arc<PduEvent>>,
+        ids: &[&str],
+    ) -> StateMap<OwnedEventId> {
+        ids.iter()
+            .map(|id| {
+                let ev = &events[&event_id(id)];
+                (ev.event_type().with_state_key(ev.state_key().unwrap()), ev.event_id.clone())
+            })
+            .collect()
+    }
+
+    fn resolve(
+        events: &HashMap<OwnedEventId, Arc<PduEvent>>,
+        state_sets: &[StateMap<OwnedEventId>],
+    ) -> StateMap<OwnedEventId> {
+        crate::resolve(&RoomVersionId::V1, state_sets, Vec::new(), |id| {
+            events.get(id).map(Arc::clone)
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn missing_key_is_not_conflicted() {
+        let _ =
+            tracing::subscriber::set_default(tracing_subscriber::fmt().with_test_writer().finish());
+
+        let mut events = INITIAL_EVENTS();
+        let topic = with_depth(
+            to_pdu_event(
+                "T1",
+                alice(),
+                TimelineEventType::RoomTopic,
+                Some(""),
+                to_raw_json_value(&json!({ "topic": "one" })).unwrap(),
+                &["CREATE", "IMA", "IPOWER"],
+                &["IMC"],
+            ),
+            7,
+        );
+        events.insert(topic.event_id.clone(), topic);
+
+        let state_sets = [
+            state_set(&events, &["CREATE", "IMA", "IPOWER", "IJR", "IMB", "T1"]),
+            state_set(&events, &["CREATE", "IMA", "IPOWER", "IJR", "IMB", "IMC"]),
+        ];
+
+        let resolved = resolve(&events, &state_sets);
+
+        assert_eq!(
+            resolved,
+            state_set(&events, &["CREATE", "IMA", "IPOWER", "IJR", "IMB", "IMC", "T1"])
+        );
+    }
+
+    #[test]
+    fn power_levels_resolved_from_lowest_depth() {
+        let _ =
+            tracing::subscriber::set_default(tracing_subscriber::fmt().with_test_writer().finish());
+
+        let mut events = INITIAL_EVENTS();
+        for event in [
+            with_depth(
+                to_pdu_event(
+                    "PA",
+                    alice(),
+                    TimelineEventType::RoomPowerLevels,
+                    Some(""),
+                    to_raw_json_value(&json!({ "users": { alice(): 100, bob(): 50 } })).unwrap(),
+                    &["CREATE", "IMA", "IPOWER"],
+                    &["IMC"],
+                ),
+                7,
+            ),
+            // Bob can't give himself more power than he has.
+            with_depth(
+                to_pdu_event(
+                    "PB",
+                    bob(),
+                    TimelineEventType::RoomPowerLevels,
+                    Some(""),
+                    to_raw_json_value(&json!({ "users": { alice(): 100, bob(): 100 } })).unwrap(),
+                    &["CREATE", "IMB", "PA"],
+                    &["PA"],
+                ),
+                8,
+            ),
+            // Bob can give charlie as much power as he has.
+            with_depth(
+                to_pdu_event(
+                    "PC",
+                    bob(),
+                    TimelineEventType::RoomPowerLevels,
+                    Some(""),
+                    to_raw_json_value(
+                        &json!({ "users": { alice(): 100, bob(): 50, charlie(): 50 } }),
+                    )
+                    .unwrap(),
+                    &["CREATE", "IMB", "PA"],
+                    &["PA"],
+                ),
+                8,
+            ),
+        ] {
+            events.insert(event.event_id.clone(), event);
+        }
+
+        let power_levels_key = (StateEventType::RoomPowerLevels, "".to_owned());
+
+        let state_sets = [
+            state_set(&events, &["CREATE", "IMA", "IJR", "IMB", "PA"]),
+            state_set(&events, &["CREATE", "IMA", "IJR", "IMB", "PB"]),
+        ];
+        assert_eq!(resolve(&events, &state_sets)[&power_levels_key], event_id("PA"));
+
+        let state_sets = [
+            state_set(&events, &["CREATE", "IMA", "IJR", "IMB", "PA"]),
+            state_set(&events, &["CREATE", "IMA", "IJR", "IMB", "PC"]),
+        ];
+        assert_eq!(resolve(&events, &state_sets)[&power_levels_key], event_id("PC"));
+    }
+
+    #[test]
+    fn normal_events_resolved_from_highest_depth() {
+        let _ =
+            tracing::subscriber::set_default(tracing_subscriber::fmt().with_test_writer().finish());
+
+        let mut events = INITIAL_EVENTS();
+        for (id, sender, depth) in [("TA", alice(), 7), ("TB", bob(), 8), ("TC", alice(), 9)] {
+            let event = with_depth(
+                to_pdu_event(
+                    id,
+                    sender,
+                    TimelineEventType::RoomTopic,
+                    Some(""),
+                    to_raw_json_value(&json!({ "topic": id })).unwrap(),
+                    &["CREATE", "IMA", "IMB", "IPOWER"],
+                    &["IMC"],
+                ),
+                depth,
+            );
+            events.insert(event.event_id.clone(), event);
+        }
+
+        let topic_key = (StateEventType::RoomTopic, "".to_owned());
+        let state_set_with_topic =
+            |topic| state_set(&events, &["CREATE", "IMA", "IPOWER", "IJR", "IMB", "IMC", topic]);
+
+        // Bob doesn't have the power to change the topic, even if his event is more recent.
+        let state_sets = [state_set_with_topic("TA"), state_set_with_topic("TB")];
+        assert_eq!(resolve(&events, &state_sets)[&topic_key], event_id("TA"));
+
+        // Both events are allowed, the one with the highest depth wins.
+        let state_sets = [state_set_with_topic("TA"), state_set_with_topic("TC")];
+        assert_eq!(resolve(&events, &state_sets)[&topic_key], event_id("TC"));
+    }
+
+    #[test]
+    fn conflicted_membership() {
+        let _ =
+            tracing::subscriber::set_default(tracing_subscriber::fmt().with_test_writer().finish());
+
+        let mut events = INITIAL_EVENTS();
+        for event in [
+            with_depth(
+                to_pdu_event(
+                    "MC",
+                    charlie(),
+                    TimelineEventType::RoomMember,
+                    Some(charlie().as_str()),
+                    member_content_join(),
+                    &["CREATE", "IJR", "IPOWER"],
+                    &["IJR"],
+                ),
+                5,
+            ),
+            with_depth(
+                to_pdu_event(
+                    "BC",
+                    alice(),
+                    TimelineEventType::RoomMember,
+                    Some(charlie().as_str()),
+                    member_content_ban(),
+                    &["CREATE", "IMA", "IPOWER", "MC"],
+                    &["MC"],
+                ),
+                6,
+            ),
+        ] {
+            events.insert(event.event_id.clone(), event);
+        }
+
+        // Alice banned charlie on one side, after he joined.
+        let state_sets = [
+            state_set(&events, &["CREATE", "IMA", "IPOWER", "IJR", "MC"]),
+            state_set(&events, &["CREATE", "IMA", "IPOWER", "IJR", "BC"]),
+        ];
+        let resolved = resolve(&events, &state_sets);
+
+        assert_eq!(resolved[&(StateEventType::RoomMember, charlie().to_string())], event_id("BC"));
+    }
+}